@@ -0,0 +1,100 @@
+/* The C interface of xsz, built with
+ *
+ *     cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * See src/capi.rs.  Each call scans with its own options; calls from
+ * several threads run one at a time.  Panics are caught and reported as
+ * XSZ_ERR_PANIC, so don't build the library with panic = "abort".
+ */
+#ifndef XSZ_H
+#define XSZ_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define XSZ_ROWS 9
+
+struct xsz_row {
+	/* as in the usage table: "none", "zstd", "prealloc"... */
+	char name[16];
+	uint64_t disk;
+	uint64_t uncomp;
+	uint64_t refd;
+	uint64_t nfile;
+};
+
+struct xsz_stats {
+	uint64_t disk;
+	uint64_t uncomp;
+	uint64_t refd;
+	/* bytes in holes */
+	uint64_t sparse;
+	uint64_t nfile;
+	uint32_t nrows;
+	struct xsz_row rows[XSZ_ROWS];
+};
+
+/* A path skipped, with the errno if it was an I/O error, else 0, or a
+ * note with a NULL path.  The strings are only valid during the call; the
+ * user_data is that of xsz_scan.
+ */
+typedef void (*xsz_report)(const char *path, int errno_, const char *message,
+			   void *user_data);
+
+struct xsz_options {
+	/* threads, 0 for one per CPU; the first call of the process starts
+	 * them, later ones split their work as asked but between as many */
+	uint8_t jobs;
+	bool one_fs;
+	bool tree_scan;
+	/* seconds, 0 for none */
+	uint64_t timeout;
+	/* called for each path skipped and each note on how the scan went,
+	 * NULL to print them to stderr */
+	xsz_report report;
+};
+
+enum {
+	XSZ_OK = 0,
+	XSZ_ERR_IO = 1,
+	XSZ_ERR_NOT_BTRFS = 2,
+	XSZ_ERR_PARSE = 3,
+	XSZ_ERR_SETUP = 4,
+	XSZ_ERR_CANCELLED = 5,
+	/* xsz panicked, a bug */
+	XSZ_ERR_PANIC = 6,
+	/* returned without a call, for a NULL callback */
+	XSZ_ERR_INVALID = 7,
+};
+
+/* The strings are only valid during the callback. */
+struct xsz_error {
+	int kind;
+	/* with XSZ_ERR_IO, else 0 */
+	int errno_;
+	/* NULL if the error is about no path */
+	const char *path;
+	const char *message;
+};
+
+typedef void (*xsz_callback)(const struct xsz_stats *stats,
+			     const struct xsz_error *error, void *user_data);
+
+/* Scan the NULL terminated array paths and call callback once, with the
+ * usage table or with why the scan stopped, the other one NULL.  options
+ * may be NULL for the defaults.  Returns XSZ_OK or the kind of the error,
+ * XSZ_ERR_INVALID without a call if callback is NULL.  Paths are taken as
+ * bytes, whatever their encoding.
+ */
+int xsz_scan(const char *const *paths, const struct xsz_options *options,
+	     xsz_callback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
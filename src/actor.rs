@@ -1,9 +1,12 @@
 #![allow(async_fn_in_trait)]
 use kanal::AsyncReceiver as Receiver;
 
+use crate::{error::Error, global::set_err};
+
 pub trait Actor {
     type Message;
-    async fn handle(&mut self, msg: Self::Message) -> Result<(), ()>;
+    /// An error stops this actor and, through [`set_err`], the whole scan.
+    async fn handle(&mut self, msg: Self::Message) -> Result<(), Error>;
 }
 
 pub trait Runnable: Actor {
@@ -12,8 +15,8 @@ pub trait Runnable: Actor {
         Self: Sized,
     {
         while let Ok(msg) = receiver.recv().await {
-            let ret = self.handle(msg).await;
-            if ret.is_err() {
+            if let Err(e) = self.handle(msg).await {
+                set_err(e);
                 break;
             }
         }
@@ -26,4 +29,8 @@ impl<T: Actor> Runnable for T {}
 pub trait Sink {
     type Item;
     fn consume(&mut self, f: Self::Item) -> impl Future + Send;
+    /// Pass on what is buffered, before going idle.
+    fn flush(&mut self) -> impl Future + Send {
+        async {}
+    }
 }
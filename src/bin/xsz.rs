@@ -1,490 +1,1038 @@
 use std::{
-    collections::HashSet,
-    fmt::Display,
-    io::{Write, stdout},
-    num::NonZeroU64,
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, BufWriter, Read, Write, stdout},
+    net::{IpAddr, Shutdown, TcpListener, TcpStream},
+    os::{
+        fd::AsFd,
+        unix::net::{UnixListener, UnixStream},
+    },
     path::{Path, PathBuf},
     process::exit,
     sync::{
-        Arc,
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
     },
+    thread::{self, sleep},
+    time::{Duration, Instant},
 };
 
-use kanal::bounded_async as bounded;
 use mimalloc::MiMalloc;
-use nohash::IntSet;
+use rustix::{
+    fs::{Mode, OFlags, fstat, major, minor, open},
+    rand::{GetRandomFlags, getrandom},
+};
 use xsz::{
-    actor::{Actor, Runnable, Sink},
     btrfs::{
-        ExtentInfo, SizeStat,
-        tree::{Compression, ExtentType},
+        max_ino, qgroups, subvol_id, subvolumes,
+        tree::{RootItem, objectid, qgroup_status},
     },
-    executor::block_on,
-    fs_util::File_,
-    global::{config, get_err},
-    scan_tree, spawn,
-    taskpak::TaskPak,
-    walkdir::WalkDir,
-    worker::Worker,
+    cache,
+    collector::{Collector, CompsizeStat, ExtentInfoSink},
+    daemon, diag,
+    diff::{Saved, fmt_diff},
+    error::exit_status,
+    fs_util::{find_subvol_root, is_btrfs},
+    global::{Command, Config, Format, config, take_skipped},
+    hmac,
+    json::Json,
+    report::json_str,
+    scale::Scale,
+    scan_tree, scanner, spill,
 };
 
-#[derive(Clone, Copy)]
-pub enum Scale {
-    Bytes,
-    Human,
-}
-impl Scale {
-    pub fn scale(&self, num: u64) -> String {
-        const UNITS: &[u8; 7] = b"BKMGTPE";
-
-        match self {
-            Scale::Bytes => format!("{}", num),
-            Scale::Human => {
-                let base = 1024;
-                let mut cnt = 0;
-                while num >= base << (cnt * 10) {
-                    cnt += 1;
-                }
-                let bits = cnt * 10;
-                let integer = num >> bits;
-                let tail = num & ((1 << bits) - 1);
-                let real_v = (num as f64) / (1u64 << bits) as f64;
-                if tail == 0 || integer >= 10 {
-                    return format!("{:.0}{}", real_v, UNITS[cnt] as char);
-                }
-                format!("{:.1}{}", real_v, UNITS[cnt] as char)
-            }
-        }
-    }
+/// Counters and stats of a finished scan, as exchanged between
+/// `xsz agent` and `xsz collect`.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    nfile: u64,
+    nextent_unique: u64,
+    nextent: u64,
+    ninline: u64,
+    stat: CompsizeStat,
+    /// with `--per-arg`, the usage table of each file-or-dir, or subvolume
+    /// of a `--subvol-scan`, by name
+    roots: Vec<(String, CompsizeStat)>,
 }
 
-trait ExtentInfoSink {
-    fn duplic(&mut self, extent: &ExtentInfo);
-    fn unique(&mut self, extent: &ExtentInfo);
-    fn fmt(&self, f: &mut dyn Write, use_bytes: bool) -> std::io::Result<()>;
-}
+impl Summary {
+    const MAGIC: &str = "xsz-report 1";
 
-#[derive(Debug)]
-struct FragStat {
-    min: u64,
-    max: u64,
-    count: u64,
-    sum: u64,
-    bins: [u64; Self::FRAG_BINS],
-}
+    fn new(collector: &Collector) -> Option<Self> {
+        Some(Self {
+            nfile: collector.nfile(),
+            nextent_unique: collector.nextent_unique(),
+            nextent: collector.nextent(),
+            ninline: collector.ninline(),
+            stat: collector.compsize()?.clone(),
+            roots: (collector.root_names().iter().enumerate())
+                .filter_map(|(i, name)| Some((name.clone(), collector.root_compsize(i)?.clone())))
+                .collect(),
+        })
+    }
 
-impl FragStat {
-    const FRAG_BINS: usize = 16;
-    fn new() -> Self {
-        Self {
-            min: u64::MAX,
-            max: u64::MIN,
-            count: 0,
-            sum: 0,
-            bins: [0; Self::FRAG_BINS],
-        }
+    fn merge(&mut self, other: &Self) {
+        self.nfile += other.nfile;
+        self.nextent_unique += other.nextent_unique;
+        self.nextent += other.nextent;
+        self.ninline += other.ninline;
+        self.stat.merge(&other.stat);
     }
-    fn record(&mut self, len: u64) {
-        if len == 0 {
-            return;
+
+    fn encode(&self, f: &mut dyn Write, host: &str) -> std::io::Result<()> {
+        writeln!(f, "{}", Self::MAGIC)?;
+        writeln!(f, "host {}", host)?;
+        writeln!(
+            f,
+            "counts {} {} {} {}",
+            self.nfile, self.nextent_unique, self.nextent, self.ninline
+        )?;
+        Self::encode_stat(f, &self.stat)?;
+        // only when needed, older receivers reject these lines
+        for (name, stat) in &self.roots {
+            writeln!(f, "root {}", name.replace('\n', "?"))?;
+            writeln!(f, "files {}", stat.nfile_total)?;
+            Self::encode_stat(f, stat)?;
         }
-        self.min = self.min.min(len);
-        self.max = self.max.max(len);
-        self.count += 1;
-        self.sum += len;
-        let idx = if len < 4096 {
-            0
-        } else {
-            let bit = 63 - len.leading_zeros() as usize;
-            (bit.saturating_sub(12)).min(Self::FRAG_BINS - 1)
-        };
-        self.bins[idx] += 1;
+        writeln!(f, "end")
     }
 
-    fn avg(&self) -> u64 {
-        self.sum.checked_div(self.count).unwrap_or(0)
+    fn encode_stat(f: &mut dyn Write, stat: &CompsizeStat) -> std::io::Result<()> {
+        // unused rows are left out: they read back as zero, and older
+        // receivers don't know all of them
+        let rows = stat.rows().zip(stat.nfile);
+        for ((name, s), nfile) in rows.filter(|((_, s), nfile)| !s.is_empty() || *nfile > 0) {
+            writeln!(
+                f,
+                "stat {} {} {} {} {}",
+                name, s.disk, s.uncomp, s.refd, nfile
+            )?;
+        }
+        // only when needed, older receivers reject the line
+        if stat.sparse > 0 {
+            writeln!(f, "sparse {}", stat.sparse)?;
+        }
+        Ok(())
     }
 
-    fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
-        let scale = Scale::Human;
-        struct BinLabel {
-            lo: u64,
-            hi: Option<NonZeroU64>,
+    /// Parse a pushed report, returning the sending host and the summary
+    /// itself.  The rows after a `root` line are those of that
+    /// file-or-dir, the ones before are the totals.
+    fn decode(input: &str) -> Result<(String, Self), String> {
+        let mut lines = input.lines();
+        if lines.next() != Some(Self::MAGIC) {
+            return Err("not an xsz report".into());
         }
-
-        impl Display for BinLabel {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                let s = Scale::Human;
-                match self.hi {
-                    Some(hi) => write!(f, "{:>4}- {:>4}", s.scale(self.lo), s.scale(hi.into())),
-                    None => write!(f, "    >={:>4}", s.scale(self.lo)),
+        let num = |s: Option<&str>| {
+            s.and_then(|s| s.parse::<u64>().ok())
+                .ok_or_else(|| "malformed number".to_string())
+        };
+        let (mut host, mut ret) = (None, Self::default());
+        for line in lines {
+            let mut words = line.split(' ');
+            // host names and paths may have spaces
+            let rest = line.split_once(' ').map(|(_, rest)| rest.to_string());
+            let stat = match ret.roots.last_mut() {
+                Some((_, stat)) => stat,
+                None => &mut ret.stat,
+            };
+            match words.next() {
+                Some("host") => host = rest,
+                Some("root") => ret
+                    .roots
+                    .push((rest.unwrap_or_default(), CompsizeStat::default())),
+                Some("files") => stat.nfile_total = num(words.next())?,
+                Some("counts") => {
+                    ret.nfile = num(words.next())?;
+                    ret.stat.nfile_total = ret.nfile;
+                    ret.nextent_unique = num(words.next())?;
+                    ret.nextent = num(words.next())?;
+                    ret.ninline = num(words.next())?;
                 }
-            }
-        }
-
-        let bin_label = |idx| {
-            if idx == 0 {
-                BinLabel {
-                    lo: 0,
-                    hi: NonZeroU64::new(4096),
+                Some("stat") => {
+                    let name = words.next().unwrap_or_default();
+                    let (s, nfile) = stat
+                        .row_mut(name)
+                        .ok_or_else(|| format!("unknown stat row '{}'", name))?;
+                    s.disk = num(words.next())?;
+                    s.uncomp = num(words.next())?;
+                    s.refd = num(words.next())?;
+                    // older agents don't send file counts
+                    *nfile = words.next().map_or(Ok(0), |n| num(Some(n)))?;
                 }
-            } else {
-                let lo = 1u64 << (idx + 12);
-                if idx < Self::FRAG_BINS - 1 {
-                    BinLabel {
-                        lo,
-                        hi: NonZeroU64::new(lo << 1),
-                    }
-                } else {
-                    BinLabel { lo, hi: None }
+                Some("sparse") => stat.sparse = num(words.next())?,
+                Some("end") => {
+                    let host = host.ok_or("report without host")?;
+                    return Ok((host, ret));
                 }
+                _ => return Err(format!("unexpected line '{}'", line)),
             }
-        };
+        }
+        Err("truncated report".into())
+    }
 
+    fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
         writeln!(
             f,
-            "  Count: {}, Min: {}, Max: {}, Avg: {}",
-            self.count,
-            scale.scale(self.min),
-            scale.scale(self.max),
-            scale.scale(self.avg()),
+            "Processed {} files, {} regular extents ({} refs), {} inline.",
+            self.nfile,
+            self.nextent_unique,
+            self.nextent.saturating_sub(self.ninline),
+            self.ninline,
         )?;
-        if self.count == 0 {
-            return Ok(());
-        }
-        writeln!(f, "  Distribution:")?;
-        for (i, &cnt) in self.bins.iter().enumerate() {
-            if cnt > 0 {
-                let pct = cnt * 1000 / self.count;
-                let label = bin_label(i);
-                writeln!(
-                    f,
-                    "    {}: {:>6} ({:>2}.{}%)",
-                    label,
-                    cnt,
-                    pct / 10,
-                    pct % 10
-                )?;
-            }
+        self.stat.fmt(f, config().bytes)
+    }
+}
+
+fn read_psk(path: &Option<String>) -> Option<String> {
+    let path = path.as_ref()?;
+    match std::fs::read_to_string(path) {
+        Ok(psk) => Some(psk.trim().to_string()),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            exit(1);
         }
-        Ok(())
     }
 }
 
-struct XFragStat {
-    refd: FragStat,
+fn hostname() -> String {
+    rustix::system::uname()
+        .nodename()
+        .to_string_lossy()
+        .into_owned()
 }
 
-impl XFragStat {
-    fn new() -> Self {
-        Self {
-            refd: FragStat::new(),
-        }
-    }
+/// How long an agent and the collector wait for each other, for the whole
+/// report.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The MAC an agent holding `psk` sends with `report`, for the collector
+/// that sent `nonce`: the key itself never goes over the wire, and a
+/// report recorded on the way can't be pushed again.
+fn report_mac(psk: &str, nonce: &str, report: &str) -> String {
+    hmac::hex(&hmac::hmac_sha256(
+        psk.as_bytes(),
+        &[nonce.as_bytes(), b"\n", report.as_bytes()],
+    ))
 }
 
-impl ExtentInfoSink for XFragStat {
-    fn duplic(&mut self, extent: &ExtentInfo) {
-        self.unique(extent);
+fn push(summary: &Summary, url: &str, psk: Option<&str>) -> std::io::Result<()> {
+    let addr = url.strip_prefix("tcp://").unwrap_or(url);
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(REPORT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut buf = vec![];
+    summary.encode(&mut buf, &hostname())?;
+    if let Some(psk) = psk {
+        // the collector speaks first when it wants a key
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let Some(nonce) = line.trim_end().strip_prefix("nonce ") else {
+            return Err(std::io::Error::other(
+                "the collector sent no challenge, does it have a --psk-file?",
+            ));
+        };
+        let report = String::from_utf8_lossy(&buf);
+        let mac = report_mac(psk, nonce, &report);
+        (&stream).write_all(format!("mac {}\n", mac).as_bytes())?;
     }
-
-    fn unique(&mut self, extent: &ExtentInfo) {
-        self.refd.record(extent.stat().uncomp);
+    (&stream).write_all(&buf)?;
+    stream.shutdown(Shutdown::Write)?;
+    // wait for the collector to acknowledge (or reject) the report
+    let mut reply = String::new();
+    reader.read_to_string(&mut reply)?;
+    match reply.trim() {
+        "ok" => Ok(()),
+        e => Err(std::io::Error::other(e.to_string())),
     }
+}
 
-    fn fmt(&self, f: &mut dyn Write, _: bool) -> std::io::Result<()> {
-        writeln!(f, "File extent size distribution:")?;
-        self.refd.fmt(f)?;
-        Ok(())
-    }
+/// Reads a stream until `deadline`, however slowly it trickles in.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
 }
 
-#[derive(Debug, Default)]
-pub struct CompsizeStat {
-    prealloc: SizeStat,
-    stat: [SizeStat; 4],
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
 }
 
-impl ExtentInfoSink for CompsizeStat {
-    fn duplic(&mut self, extent: &ExtentInfo) {
-        let comp = extent.comp();
-        let stat = extent.stat();
-        match extent.r#type() {
-            ExtentType::Inline | ExtentType::Regular => {
-                self.stat[comp.as_usize()].refd += stat.refd;
-            }
-            ExtentType::Prealloc => {
-                self.prealloc.refd += stat.refd;
-            }
+/// Read, check and acknowledge the report an agent pushes on `stream`,
+/// returning its address, the host it names and the summary.
+fn receive(mut stream: TcpStream, psk: Option<&str>) -> Option<(IpAddr, String, Summary)> {
+    // thousands of subvolumes take a few megabytes
+    const MAX_REPORT: u64 = 64 << 20;
+    let peer = stream.peer_addr().ok()?;
+    let deadline = Instant::now() + REPORT_TIMEOUT;
+    let mut nonce = None;
+    if psk.is_some() {
+        let mut buf = [0; 16];
+        if let Err(e) = getrandom(&mut buf, GetRandomFlags::empty()) {
+            eprintln!("{}: no nonce: {}", peer, e);
+            return None;
         }
+        nonce = Some(hmac::hex(&buf));
     }
+    let mut input = String::new();
+    let ret = (nonce.as_ref())
+        .map_or(Ok(()), |nonce| writeln!(stream, "nonce {}", nonce))
+        .and_then(|()| {
+            let reader = Deadline {
+                stream: &stream,
+                deadline,
+            };
+            reader.take(MAX_REPORT).read_to_string(&mut input)
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|n| match n as u64 {
+            MAX_REPORT => Err("report too large".into()),
+            _ => Ok(()),
+        })
+        .and_then(|()| {
+            let report = match (psk, &nonce, input.split_once('\n')) {
+                (Some(psk), Some(nonce), Some((mac, report)))
+                    if mac.strip_prefix("mac ").is_some_and(|mac| {
+                        hmac::ct_eq(mac.as_bytes(), report_mac(psk, nonce, report).as_bytes())
+                    }) =>
+                {
+                    report
+                }
+                (Some(_), _, _) => return Err("bad pre-shared key".into()),
+                (None, _, _) => &input,
+            };
+            Summary::decode(report)
+        });
+    match ret {
+        Ok((host, summary)) => {
+            writeln!(stream, "ok").ok();
+            Some((peer.ip(), host, summary))
+        }
+        Err(e) => {
+            eprintln!("{}: {}", peer, e);
+            writeln!(stream, "{}", e).ok();
+            None
+        }
+    }
+}
+
+fn collect(listen: &str, expect: Option<u32>, psk: Option<String>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen.strip_prefix("tcp://").unwrap_or(listen))?;
+    if !listener.local_addr()?.ip().is_loopback() {
+        eprintln!(
+            "Reports are not encrypted: across untrusted networks, push them through a \
+             tunnel such as ssh -L, stunnel or WireGuard."
+        );
+    }
+    collect_on(listener, expect, psk, &mut stdout())
+}
 
-    fn unique(&mut self, extent: &ExtentInfo) {
-        let comp = extent.comp();
-        let stat = extent.stat();
-        match extent.r#type() {
-            ExtentType::Inline | ExtentType::Regular => {
-                self.stat[comp.as_usize()].disk += stat.disk;
-                self.stat[comp.as_usize()].uncomp += stat.uncomp;
-                self.stat[comp.as_usize()].refd += stat.refd;
+/// [`collect`] the reports of agents connecting to `listener`, printing
+/// the merged ones to `out` after each.
+fn collect_on(
+    listener: TcpListener,
+    expect: Option<u32>,
+    psk: Option<String>,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    // agents at once, each of which may take REPORT_TIMEOUT
+    const RECEIVERS: usize = 16;
+    // a few agents at a time, so a slow one holds up no other and many
+    // can't take all the memory
+    let (streams, rx) = kanal::bounded::<TcpStream>(0);
+    let (tx, reports_rx) = mpsc::channel();
+    for _ in 0..RECEIVERS {
+        let (rx, tx, psk) = (rx.clone(), tx.clone(), psk.clone());
+        thread::spawn(move || {
+            for stream in rx {
+                if let Some(report) = receive(stream, psk.as_deref()) {
+                    tx.send(report).ok();
+                }
             }
-            ExtentType::Prealloc => {
-                self.prealloc.disk += stat.disk;
-                self.prealloc.uncomp += stat.uncomp;
-                self.prealloc.refd += stat.refd;
+        });
+    }
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream.map(|s| streams.send(s)) {
+                Ok(Ok(())) => (),
+                // the collector is done
+                Ok(Err(_)) => break,
+                Err(e) => eprintln!("accept: {}", e),
             }
         }
-    }
-    // example compsize output format:
-    // Processed 3356969 files, 653492 regular extents (2242077 refs), 2018321 inline.
-    // Type       Perc     Disk Usage   Uncompressed Referenced
-    // TOTAL       78%     100146085502 127182733170 481020538738
-    // none       100%     88797796415  88797796415  364255758399
-    // zstd        29%     11348289087  38384936755  116764780339
-    fn fmt(&self, f: &mut dyn Write, use_bytes: bool) -> std::io::Result<()> {
-        let scale = if use_bytes {
-            Scale::Bytes
-        } else {
-            Scale::Human
+    });
+    // Keyed by host, so an agent that pushes again replaces its old report.
+    let mut reports = BTreeMap::new();
+    for (nreceived, (peer, host, summary)) in (1..).zip(reports_rx) {
+        // another agent by the same name gets its address added
+        let host = match reports.get(&host) {
+            Some((ip, _)) if *ip != peer => format!("{} ({})", host, peer),
+            _ => host,
         };
-        // total
-        self.write_total(f, scale)?;
-        // normal
-        let mut write_stat = |name, s: &SizeStat| {
-            if !s.is_empty() {
-                write_table(
-                    f,
-                    name,
-                    format!("{:>3}%", s.get_percent()),
-                    scale.scale(s.disk),
-                    scale.scale(s.uncomp),
-                    scale.scale(s.refd),
-                )?;
+        if summary.nextent == 0 {
+            eprintln!("{}: no extents in the report, left out", host);
+            reports.remove(&host);
+        } else {
+            reports.insert(host, (peer, summary));
+        }
+
+        let mut merged = Summary::default();
+        for (_, s) in reports.values() {
+            merged.merge(s);
+        }
+        writeln!(out, "Merged reports from {} hosts.", reports.len())?;
+        merged.fmt(out)?;
+        for (host, (_, summary)) in &reports {
+            for (name, stat) in &summary.roots {
+                writeln!(out, "\n== {}:{} ==", host, name)?;
+                stat.fmt(out, config().bytes)?;
             }
-            Ok::<_, std::io::Error>(())
-        };
-        for (i, s0) in self.stat.iter().enumerate() {
-            write_stat(Compression::from_u8(i as _).name(), s0)?;
         }
-        // prealloc
-        write_stat("prealloc", &self.prealloc)?;
-        Ok(())
+        out.flush()?;
+        if expect.is_some_and(|n| nreceived >= n) {
+            break;
+        }
     }
+    Ok(())
 }
 
-impl CompsizeStat {
-    fn write_total(&self, f: &mut dyn Write, scale: Scale) -> Result<(), std::io::Error> {
-        let total_disk = self.prealloc.disk + self.stat.iter().map(|s| s.disk).sum::<u64>();
-        let total_uncomp = self.prealloc.uncomp + self.stat.iter().map(|s| s.uncomp).sum::<u64>();
-        let total_refd = self.prealloc.refd + self.stat.iter().map(|s| s.refd).sum::<u64>();
-        write_table(
-            f,
-            "Type",
-            "Perc",
-            "Disk Usage",
-            "Uncompressed",
-            "Referenced",
-        )?;
-        let total_percentage = total_disk * 100 / total_uncomp;
-        write_table(
-            f,
-            "TOTAL",
-            format!("{:>3}%", total_percentage),
-            scale.scale(total_disk),
-            scale.scale(total_uncomp),
-            scale.scale(total_refd),
-        )?;
-        Ok(())
+/// The longest `--daemon` request line read.
+const MAX_REQUEST: u64 = 64 << 10;
+
+/// The paths of a `--daemon` request, a JSON object on one line, and the
+/// options given with them, if any.
+fn request(line: &str) -> Result<(Vec<PathBuf>, Option<Vec<String>>), String> {
+    let req = Json::parse(line)?;
+    if let Some((key, _)) = req
+        .members()
+        .iter()
+        .find(|(key, _)| key != "paths" && key != "options")
+    {
+        return Err(format!("unknown key '{}'", key));
+    }
+    let strings = |key: &str| -> Option<Result<Vec<String>, String>> {
+        let items = req.get(key)?.items();
+        Some(
+            items
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| format!("{} must be strings", key))
+                })
+                .collect(),
+        )
+    };
+    let paths = strings("paths").ok_or("expected {\"paths\": [...]}")??;
+    if paths.iter().any(|p| !p.starts_with('/')) {
+        return Err("paths must be absolute".into());
+    }
+    if paths.is_empty() {
+        return Err("at least one path is required".into());
     }
+    let paths = paths.into_iter().map(PathBuf::from).collect();
+    Ok((paths, strings("options").transpose()?))
 }
 
-fn write_table(
-    f: &mut dyn Write,
-    ty: impl Display,
-    percentage: impl Display,
-    disk_usage: impl Display,
-    uncomp_usage: impl Display,
-    refd_usage: impl Display,
-) -> std::io::Result<()> {
-    writeln!(
-        f,
-        "{:<10} {:>4} {:>14} {:>16} {:>16}",
-        ty, percentage, disk_usage, uncomp_usage, refd_usage
-    )
-}
+/// Scans run one at a time: the counters and sets they fill are global.
+static SCAN: Mutex<()> = Mutex::new(());
+/// Connections being answered, so `--idle-exit` waits for them.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
 
-pub struct Collector {
-    stat: Box<dyn ExtentInfoSink>,
-    nextent: u64,
-    ninline: u64,
-    extent_set: IntSet<u64>,
-    inline_ino_set: IntSet<u64>,
+/// Read one request from `stream` and send back the document of its scan.
+fn serve(stream: UnixStream) {
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    let mut line = String::new();
+    let ret = BufReader::new((&stream).take(MAX_REQUEST + 1))
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())
+        .and_then(|n| {
+            if n as u64 > MAX_REQUEST {
+                return Err(format!("request longer than {} bytes", MAX_REQUEST));
+            }
+            request(&line)
+        })
+        .and_then(|(paths, options)| {
+            let _scan = SCAN.lock().unwrap_or_else(PoisonError::into_inner);
+            diag::reset();
+            let json = |collector: &Collector| {
+                let mut doc = vec![];
+                collector.fmt_json(&mut doc).map(|()| doc)
+            };
+            match options {
+                Some(options) => {
+                    let config = Config::from_request(&options, paths.clone())?;
+                    scanner::collect_paths_with(config, &paths, json)
+                }
+                None => scanner::collect_paths(&paths, false).map(|c| json(&c)),
+            }
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())
+        });
+    let mut out = BufWriter::new(&stream);
+    let sent = match ret {
+        Ok(doc) => out.write_all(&doc),
+        Err(e) => writeln!(out, "{{\"error\": {}}}", json_str(&e)),
+    };
+    if let Err(e) = sent.and_then(|()| out.flush()) {
+        eprintln!("reply: {}", e);
+    }
 }
 
-impl Collector {
-    pub fn new() -> Self {
-        let stat: Box<dyn ExtentInfoSink> = if config().frag {
-            Box::new(XFragStat::new())
-        } else {
-            Box::new(CompsizeStat::default())
+/// Answer scan requests on the socket passed by systemd, or bound at
+/// `socket`: each connection sends one `{"paths": [...]}` line, with
+/// `"options": [...]` for the scan if wanted, and gets the `--format json`
+/// document of the scan back, or `{"error": ...}`.
+fn daemon(socket: Option<&Path>) -> std::io::Result<()> {
+    // Safety: called once, before the daemon starts any thread
+    let fds = unsafe { daemon::listen_fds() };
+    let listener = match (fds.into_iter().next(), socket) {
+        (Some(fd), _) => UnixListener::from(fd),
+        (None, Some(path)) => daemon::bind(path)?,
+        (None, None) => {
+            return Err(std::io::Error::other(
+                "--daemon needs --socket PATH or socket activation",
+            ));
+        }
+    };
+    // opened here, the requests with options of their own share it too
+    cache::open().map_err(|e| std::io::Error::other(e.to_string()))?;
+    // read before any request runs with a configuration of its own
+    let idle_exit = config().idle_exit;
+    loop {
+        if let Some(secs) = idle_exit
+            && !daemon::wait_for_connection(&listener, Duration::from_secs(secs))?
+        {
+            if ACTIVE.load(Ordering::Acquire) == 0 {
+                return Ok(());
+            }
+            continue;
+        }
+        let stream = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(e) => {
+                eprintln!("accept: {}", e);
+                continue;
+            }
         };
-        Self {
-            stat,
-            nextent: 0,
-            ninline: 0,
-            extent_set: Default::default(),
-            inline_ino_set: Default::default(),
+        if let Err(uid) = daemon::peer_allowed(&stream) {
+            eprintln!("refused a request from uid {}", uid.as_raw());
+            continue;
+        }
+        ACTIVE.fetch_add(1, Ordering::AcqRel);
+        let spawned = thread::Builder::new()
+            .name("xsz-request".into())
+            .spawn(move || {
+                serve(stream);
+                ACTIVE.fetch_sub(1, Ordering::AcqRel);
+            });
+        if let Err(e) = spawned {
+            ACTIVE.fetch_sub(1, Ordering::AcqRel);
+            eprintln!("spawn: {}", e);
         }
     }
-    pub fn nextent_unique(&self) -> u64 {
-        self.extent_set.len() as _
+}
+
+/// Describe what a scan with the current options would cover.
+fn plan(f: &mut dyn Write) -> std::io::Result<()> {
+    let cfg = config();
+    writeln!(f, "Plan only, nothing will be scanned.")?;
+    writeln!(
+        f,
+        "Mode: {}, {} jobs",
+        if cfg.subvol_scan {
+            "btrfs tree scan of all subvolumes".to_string()
+        } else if cfg.tree_scan {
+            "btrfs tree scan".to_string()
+        } else {
+            format!("directory walk with {} walkers", cfg.nwalkers())
+        },
+        cfg.jobs
+    )?;
+    let mut filters = vec![];
+    if cfg.one_fs {
+        filters.push("one filesystem (-x)".to_string());
     }
-    pub fn nextent(&self) -> u64 {
-        self.nextent
+    if let Some(generation) = cfg.since_generation {
+        filters.push(format!("extents written after generation {}", generation));
     }
-    pub fn ninline(&self) -> u64 {
-        self.ninline
+    if let Some(depth) = cfg.max_depth() {
+        filters.push(format!("max depth {}", depth));
     }
-    pub fn fmt(&self, f: &mut dyn Write, nfile: u64) -> std::io::Result<()> {
-        if nfile == 0 {
-            eprintln!("No Files.");
-            return Ok(());
+    if let Some(size) = &cfg.min_size {
+        filters.push(format!("min size {}", size));
+    }
+    if let Some(size) = &cfg.max_size {
+        filters.push(format!("max size {}", size));
+    }
+    if let Some(age) = &cfg.newer_than {
+        filters.push(format!("newer than {}", age));
+    }
+    if let Some(age) = &cfg.older_than {
+        filters.push(format!("older than {}", age));
+    }
+    if !cfg.exclude.is_empty() {
+        filters.push(format!("exclude {}", cfg.exclude.join(" ")));
+    }
+    if filters.is_empty() {
+        filters.push("none".to_string());
+    }
+    writeln!(f, "Filters: {}", filters.join(", "))?;
+
+    // (device, subvolume id) -> highest inode number
+    let mut subvols = HashMap::new();
+    for arg in &cfg.args {
+        writeln!(f, "{}", arg.display())?;
+        if arg.as_os_str() == "-" {
+            writeln!(f, "  paths read from stdin")?;
+            continue;
         }
-        if self.nextent == 0 {
-            eprintln!("All empty or still-delalloced files.");
-            return Ok(());
+        // validated in `Config::check`
+        let path = &cfg.scan_path(arg).unwrap_or_else(|_| arg.clone());
+        let fd = match open(path, OFlags::NOFOLLOW | OFlags::PATH, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(e) => {
+                writeln!(f, "  {}", e)?;
+                continue;
+            }
+        };
+        let st = fstat(&fd).map_err(std::io::Error::from)?;
+        let dev = format!("{}:{}", major(st.st_dev), minor(st.st_dev));
+        if !is_btrfs(fd.as_fd()).unwrap_or(false) {
+            writeln!(f, "  device {}, not btrfs, will be skipped", dev)?;
+            continue;
         }
-        writeln!(
+        let Ok(root) = find_subvol_root(path) else {
+            writeln!(f, "  device {}, btrfs, subvolume root not found", dev)?;
+            continue;
+        };
+        let inodes = scan_tree::open_subvol(&root).ok().and_then(|fd| {
+            let id = subvol_id(fd.as_fd()).ok()?;
+            let ino = *subvols
+                .entry((st.st_dev, id))
+                .or_insert_with(|| max_ino(fd.as_fd(), 0).unwrap_or(0));
+            Some((id, ino))
+        });
+        match inodes {
+            Some((id, ino)) => writeln!(
+                f,
+                "  device {}, btrfs, subvolume {} at {}, ~{} inodes",
+                dev,
+                id,
+                root.display(),
+                ino.saturating_sub(objectid::FIRST_FREE)
+            )?,
+            None => writeln!(
+                f,
+                "  device {}, btrfs, subvolume at {} (id unknown, needs root)",
+                dev,
+                root.display()
+            )?,
+        }
+    }
+    let ninode: u64 = subvols
+        .values()
+        .map(|ino| ino.saturating_sub(objectid::FIRST_FREE))
+        .sum();
+    // One u64 per extent in the dedup set, plus hash table overhead, or
+    // with --low-memory two bytes in a sparse container, assuming about one
+    // extent per inode.
+    let per_extent = if config().low_memory { 2 } else { 16 };
+    writeln!(
+        f,
+        "Estimated dedup memory: ~{} for ~{} inodes in {} subvolumes",
+        Scale::BINARY.scale(ninode * per_extent),
+        ninode,
+        subvols.len()
+    )?;
+    // with --max-memory, the extents are kept as a whole until spilled
+    if let Some((memory, disk)) = spill::estimate(ninode) {
+        write!(
             f,
-            "Processed {} files, {} regular extents ({} refs), {} inline.",
-            nfile,
-            self.nextent_unique(),
-            self.nextent - self.ninline,
-            self.ninline,
+            "With --max-memory: ~{} in memory",
+            Scale::BINARY.scale(memory)
         )?;
-        self.stat.fmt(f, config().bytes)
-    }
-}
-
-impl Actor for Collector {
-    type Message = Box<[ExtentInfo]>;
-
-    async fn handle(&mut self, msg: Self::Message) -> Result<(), ()> {
-        get_err()?;
-        for extent in msg {
-            self.nextent += 1;
-            let bytenr = extent.disk_bytenr();
-            if bytenr == 0 {
-                if self.inline_ino_set.insert(extent.objectid()) {
-                    self.ninline += 1;
-                    self.stat.unique(&extent);
-                } else {
-                    self.stat.duplic(&extent);
-                }
-            } else if self.extent_set.insert(bytenr) {
-                self.stat.unique(&extent);
-            } else {
-                self.stat.duplic(&extent);
-            }
+        match disk {
+            0 => writeln!(f, ", nothing spilled")?,
+            _ => writeln!(
+                f,
+                ", ~{} spilled to {}",
+                Scale::BINARY.scale(disk),
+                std::env::temp_dir().display()
+            )?,
         }
-        Ok(())
     }
+    Ok(())
 }
 
-struct F {
-    taskpak: TaskPak<File_>,
-    global_nfile: Arc<AtomicU64>,
-    local_nfile: u64,
-}
-impl Sink for F {
-    type Item = File_;
-    fn consume(&mut self, f: File_) -> impl Future + Send {
-        self.local_nfile += 1;
-        self.taskpak.push(f)
-    }
+/// The subvolumes of the filesystem mounted at `mount`, by the name
+/// `--subvol-scan` gives them, to find those of its roots again.
+fn subvols_by_name(mount: &Path) -> std::io::Result<HashMap<String, (u64, RootItem)>> {
+    let fd = open(mount, OFlags::DIRECTORY | OFlags::NOFOLLOW, Mode::RUSR)?;
+    Ok(subvolumes(fd.as_fd())?
+        .into_iter()
+        .map(|(id, item)| (scanner::subvol_name(fd.as_fd(), id), (id, item)))
+        .collect())
 }
-impl Drop for F {
-    fn drop(&mut self) {
-        self.global_nfile
-            .fetch_add(self.local_nfile as _, Ordering::Relaxed);
+
+/// `xsz subvolumes`: a row per subvolume the `--subvol-scan` of `mount`
+/// found, with its id and flags looked up again by name.
+fn fmt_subvolumes(f: &mut dyn Write, mount: &Path, collector: &Collector) -> std::io::Result<()> {
+    let items = subvols_by_name(mount)?;
+    let scale = Scale::from_config();
+    writeln!(
+        f,
+        "{:>8} {:>5} {:>14} {:>16} {:>16}  {:<7}  Path",
+        "ID", "Perc", "Disk Usage", "Uncompressed", "Referenced", "Flags"
+    )?;
+    for (i, name) in collector.root_names().iter().enumerate() {
+        let stat = collector
+            .root_compsize(i)
+            .map(CompsizeStat::total)
+            .unwrap_or_default();
+        let perc = match stat.uncomp {
+            0 => "-".to_string(),
+            _ => format!("{}%", stat.get_percent()),
+        };
+        // gone or renamed since the scan
+        let (id, flags) = match items.get(name) {
+            None => ("?".to_string(), "?"),
+            Some((id, item)) => (
+                id.to_string(),
+                match (item.is_readonly(), item.is_snapshot()) {
+                    (true, true) => "ro,snap",
+                    (true, false) => "ro",
+                    (false, true) => "snap",
+                    (false, false) => "-",
+                },
+            ),
+        };
+        writeln!(
+            f,
+            "{:>8} {:>5} {:>14} {:>16} {:>16}  {:<7}  {}",
+            id,
+            perc,
+            scale.scale(stat.disk),
+            scale.scale(stat.uncomp),
+            scale.scale(stat.refd),
+            flags,
+            name
+        )?;
     }
+    Ok(())
 }
 
-struct S(TaskPak<ExtentInfo>);
-impl Sink for S {
-    type Item = ExtentInfo;
-    fn consume(&mut self, f: ExtentInfo) -> impl Future + Send {
-        self.0.push(f)
+/// `--qgroup`: the usage btrfs quotas account to each subvolume the
+/// `--subvol-scan` of `mount` found, next to what the scan counted.  The
+/// qgroups also hold the subvolume's metadata, so they should be a bit
+/// larger; rows where they are smaller are flagged.
+fn fmt_qgroups(f: &mut dyn Write, mount: &Path, collector: &Collector) -> std::io::Result<()> {
+    let fd = open(mount, OFlags::DIRECTORY | OFlags::NOFOLLOW, Mode::RUSR)?;
+    let (flags, qgroups) = match qgroups(fd.as_fd()) {
+        Ok(ret) => ret,
+        Err(rustix::io::Errno::NOENT) => {
+            writeln!(f, "\nQuotas are not enabled on {}.", mount.display())?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let qgroups: HashMap<u64, _> = qgroups.into_iter().collect();
+    let items = subvols_by_name(mount)?;
+    let scale = Scale::from_config();
+    writeln!(f)?;
+    writeln!(
+        f,
+        "{:<10} {:>14} {:>14} {:>14} {:>14}  Subvolume",
+        "Qgroup", "Referenced", "Counted", "Exclusive", "Counted"
+    )?;
+    let mut nflagged = 0;
+    for (i, name) in collector.root_names().iter().enumerate() {
+        let Some((id, _)) = items.get(name) else {
+            continue;
+        };
+        let (exclusive, shared) = collector.root_sharing(i);
+        let (rfer, excl, flag) = match qgroups.get(id) {
+            Some(q) => {
+                let short = q.rfer < exclusive + shared || q.excl < exclusive;
+                nflagged += short as u32;
+                (
+                    scale.scale(q.rfer),
+                    scale.scale(q.excl),
+                    if short { " !" } else { "" },
+                )
+            }
+            None => ("-".to_string(), "-".to_string(), ""),
+        };
+        writeln!(
+            f,
+            "{:<10} {:>14} {:>14} {:>14} {:>14}  {}{}",
+            format!("0/{}", id),
+            rfer,
+            scale.scale(exclusive + shared),
+            excl,
+            scale.scale(exclusive),
+            name,
+            flag
+        )?;
     }
+    if nflagged > 0 {
+        writeln!(
+            f,
+            "!: the qgroup accounts less than the scan counted, see `btrfs quota rescan`"
+        )?;
+    }
+    if flags & qgroup_status::RESCAN != 0 {
+        writeln!(
+            f,
+            "A quota rescan is running, the qgroups are not up to date."
+        )?;
+    } else if flags & qgroup_status::INCONSISTENT != 0 {
+        writeln!(
+            f,
+            "btrfs marks the qgroups inconsistent, `btrfs quota rescan` fixes them."
+        )?;
+    }
+    Ok(())
 }
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+fn scan() -> Collector {
+    match scanner::collect(true) {
+        Ok(collector) => collector,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(e.exit_code())
+        }
+    }
+}
+
+/// Scan every `interval` seconds, printing the full report once and then
+/// what changed since the scan before.
+fn watch(interval: u64) -> ! {
+    let start = Instant::now();
+    let collector = scan();
+    collector.fmt(&mut stdout()).unwrap();
+    let mut last = Saved::of(&collector).unwrap();
+    loop {
+        sleep(Duration::from_secs(interval));
+        diag::reset();
+        let collector = scan();
+        let saved = Saved::of(&collector).unwrap();
+        let mut f = stdout().lock();
+        writeln!(f, "\n== +{}s ==", start.elapsed().as_secs()).unwrap();
+        fmt_diff(&mut f, &last, &saved, Scale::from_config()).unwrap();
+        f.flush().unwrap();
+        last = saved;
+    }
+}
+
 fn main() {
-    let nworkers = config().jobs;
-    let (sender, r) = bounded(nworkers as usize);
-    let collector = Collector::new();
-    let nfile = Arc::new(AtomicU64::new(0));
-
-    if config().tree_scan {
-        use xsz::fs_util::find_subvol_root;
-        let mut roots = HashSet::new();
-        for arg in &config().args {
-            if let Ok(root) = find_subvol_root(Path::new(arg)) {
-                roots.insert(root);
+    if config().plan {
+        plan(&mut stdout()).unwrap();
+        return;
+    }
+    if let Some(Command::Collect {
+        listen,
+        expect,
+        psk_file,
+    }) = &config().command
+    {
+        let psk = read_psk(psk_file);
+        if let Err(e) = collect(listen, *expect, psk) {
+            eprintln!("{}: {}", listen, e);
+            exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Diff { old, new }) = &config().command {
+        let (old, new) = match (Saved::load(old), Saved::load(new)) {
+            (Ok(old), Ok(new)) => (old, new),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{}", e);
+                exit(1);
             }
+        };
+        fmt_diff(&mut stdout(), &old, &new, Scale::from_config()).unwrap();
+        return;
+    }
+    if config().daemon {
+        if let Err(e) = daemon(config().socket.as_deref()) {
+            eprintln!("{}", e);
+            exit(1);
         }
-        let roots: Arc<Vec<PathBuf>> = Arc::new(roots.into_iter().collect());
-        let next_root = Arc::new(AtomicUsize::new(0));
-        let n_tree_workers = (nworkers - 1).max(1) as usize;
-        for _ in 0..n_tree_workers.min(roots.len()) {
-            let sender = sender.clone();
-            let nfile = nfile.clone();
-            let roots = Arc::clone(&roots);
-            let next_root = Arc::clone(&next_root);
-            spawn(async move {
-                loop {
-                    let idx = next_root.fetch_add(1, Ordering::Relaxed);
-                    if idx >= roots.len() {
-                        break;
-                    }
-                    let sink = S(TaskPak::new(sender.clone()));
-                    match scan_tree::scan_subvol(sink, &roots[idx]).await {
-                        Ok(cnt) => {
-                            nfile.fetch_add(cnt, Ordering::Relaxed);
-                        }
-                        Err(()) => {}
-                    }
-                }
-            });
+        return;
+    }
+    if let Some(interval) = config().watch {
+        watch(interval);
+    }
+    let collector = scan();
+    if let Some(Command::Subvolumes { mount }) = &config().command {
+        if let Err(e) =
+            fmt_subvolumes(&mut stdout(), mount, &collector).and_then(|()| match config().qgroup {
+                true => fmt_qgroups(&mut stdout(), mount, &collector),
+                false => Ok(()),
+            })
+        {
+            eprintln!("{}: {}", mount.display(), e);
+            exit(1);
         }
-        drop(sender);
-    } else {
-        let (worker_tx, worker_rx) = bounded(nworkers as usize);
-        let fcb = {
-            let nfile = nfile.clone();
-            move || F {
-                taskpak: TaskPak::new(worker_tx.clone()),
-                global_nfile: nfile.clone(),
-                local_nfile: 0,
-            }
+        return;
+    }
+    if let Some(Command::Agent {
+        push: url,
+        psk_file,
+        ..
+    }) = &config().command
+    {
+        let Some(summary) = Summary::new(&collector) else {
+            eprintln!("agent mode can't push fragment statistics");
+            exit(1);
         };
-        WalkDir::spawn(fcb, &config().args, nworkers);
-        for _ in 0..nworkers {
-            let sender = sender.clone();
-            let worker = Worker::new(S(TaskPak::new(sender)));
-            spawn(worker.run(worker_rx.clone()));
+        let psk = read_psk(psk_file);
+        if let Err(e) = push(&summary, url, psk.as_deref()) {
+            eprintln!("{}: {}", url, e);
+            exit(1);
         }
-        drop(sender);
+        return;
+    }
+    match config().format {
+        Format::Text => collector.fmt(&mut stdout()).unwrap(),
+        Format::Json => collector.fmt_json(&mut stdout()).unwrap(),
+        Format::Csv => collector.fmt_delimited(&mut stdout(), ',').unwrap(),
+        Format::Tsv => collector.fmt_delimited(&mut stdout(), '\t').unwrap(),
+        Format::Openmetrics => collector.fmt_openmetrics(&mut stdout()).unwrap(),
+        // already printed while scanning
+        Format::Ndjson => (),
+    }
+    if config().qgroup
+        && let Ok(mount) = config().scan_path(&config().args[0])
+        && let Err(e) = fmt_qgroups(&mut stdout(), &mount, &collector)
+    {
+        eprintln!("{}: {}", config().args[0].display(), e);
+        exit(1);
+    }
+    if let Some(path) = &config().output
+        && let Err(e) = collector.save(path)
+    {
+        eprintln!("{}: {}", path.display(), e);
+        exit(1);
+    }
+    exit(if take_skipped() > 0 || collector.is_partial() {
+        if config().strict {
+            exit_status::FATAL
+        } else {
+            exit_status::SKIPPED
+        }
+    } else if collector.nfile() == 0 {
+        exit_status::NO_MATCH
+    } else {
+        exit_status::SUCCESS
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = "xsz-report 1
+host web 1
+counts 3 2 4 1
+stat zstd 10 30 40 2
+sparse 5
+root /data/a b
+files 2
+stat none 7 7 7 2
+end
+";
+
+    #[test]
+    fn summary_round_trip() {
+        let (host, summary) = Summary::decode(REPORT).unwrap();
+        assert_eq!(host, "web 1");
+        assert_eq!(summary.nfile, 3);
+        assert_eq!(summary.roots.len(), 1);
+        assert_eq!(summary.roots[0].0, "/data/a b");
+        let mut buf = vec![];
+        summary.encode(&mut buf, &host).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), REPORT);
+    }
+
+    #[test]
+    fn summary_malformed() {
+        for input in [
+            "",
+            "xsz-report 2\nhost h\nend\n",
+            "xsz-report 1\nhost h\ncounts 1 x 0 0\nend\n",
+            "xsz-report 1\nhost h\nstat lz4 1 1 1\nend\n",
+            "xsz-report 1\nhost h\nstat zstd 1\nend\n",
+            "xsz-report 1\nhost h\n",
+            "xsz-report 1\nend\n",
+            "xsz-report 1\nhost h\nbogus\nend\n",
+            // keys are no longer sent
+            "xsz-report 1\npsk k\nhost h\nend\n",
+        ] {
+            assert!(Summary::decode(input).is_err(), "{:?}", input);
+        }
+    }
+
+    /// Run a collector on a free port until it has `expect` reports, with
+    /// `agents` connecting to it, and return what it printed.
+    fn run_collector(
+        expect: u32,
+        psk: Option<&str>,
+        agents: impl FnOnce(String) + Send + 'static,
+    ) -> String {
+        xsz::global::set_config(Config::default()).ok();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let agents = thread::spawn(move || agents(addr));
+        let mut out = vec![];
+        collect_on(listener, Some(expect), psk.map(str::to_string), &mut out).unwrap();
+        agents.join().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Send `report` as is, returning the collector's reply.
+    fn send(addr: &str, report: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(report.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn collect_empty_report() {
+        let out = run_collector(2, None, |addr| {
+            assert_eq!(send(&addr, "xsz-report 1\nhost h\nend\n"), "ok\n");
+            assert_eq!(send(&addr, REPORT), "ok\n");
+        });
+        let (first, second) = out.split_once("Merged reports from 1 hosts.").unwrap();
+        assert!(first.starts_with("Merged reports from 0 hosts.\nProcessed 0 files"));
+        assert!(second.contains("== web 1:/data/a b =="));
     }
 
-    let collector = block_on(collector.run(r));
-    if get_err().is_err() {
-        exit(1)
+    #[test]
+    fn collect_checks_key() {
+        let out = run_collector(1, Some("s3cret"), |addr| {
+            let (_, summary) = Summary::decode(REPORT).unwrap();
+            let url = format!("tcp://{}", addr);
+            assert!(push(&summary, &url, Some("wrong")).is_err());
+            // an old agent sending the key itself
+            let old = REPORT.replacen("\n", "\npsk s3cret\n", 1);
+            assert!(send(&addr, &old).ends_with("bad pre-shared key\n"));
+            push(&summary, &url, Some("s3cret")).unwrap();
+        });
+        assert!(out.contains("Processed 3 files"), "{}", out);
     }
-    collector
-        .fmt(&mut stdout(), nfile.load(Ordering::Relaxed))
-        .unwrap();
 }
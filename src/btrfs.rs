@@ -1,14 +1,35 @@
-use std::{hint::cold_path, iter::FusedIterator, marker::PhantomData, os::fd::BorrowedFd};
+use std::{
+    ffi::OsStr,
+    hint::cold_path,
+    iter::FusedIterator,
+    marker::PhantomData,
+    os::{fd::BorrowedFd, unix::ffi::OsStrExt},
+    path::PathBuf,
+};
 
-use ioctl::{BTRFS_IOCTL_SEARCH_V2, SearchHeader, Sv2Args};
+use ioctl::{
+    BTRFS_FS_INFO_FLAG_GENERATION, BTRFS_IOCTL_FS_INFO, BTRFS_IOCTL_INO_LOOKUP, FsInfoArgs,
+    InoLookupArgs, IoctlSearchKey, SearchHeader, Sv2Args,
+};
 use rustix::{
     io::Errno,
     ioctl::{Updater, ioctl},
 };
 
-use crate::btrfs::tree::{Compression, ExtentData, ExtentType, TreeItem};
+use search::TreeSearch;
+
+use crate::{
+    btrfs::tree::{
+        Compression, ExtentData, ExtentType, InodeRef, QgroupInfo, RootItem, RootRef, TreeItem,
+        objectid, r#type,
+    },
+    fs_util::{FsId, RootId, SubvolId},
+    global::config,
+    profile::{self, Counter},
+};
 
 pub mod ioctl;
+pub mod search;
 pub mod tree;
 
 pub struct IoctlSearchItem<T> {
@@ -40,6 +61,12 @@ pub struct ExtentInfo {
     disk_bytenr: u64,
     r#type: ExtentType,
     compression: Compression,
+    /// fits in padding; 0 until set by the producer
+    root: RootId,
+    /// the same
+    fs: FsId,
+    /// the same
+    subvol: SubvolId,
     stat: SizeStat,
 }
 
@@ -67,30 +94,203 @@ impl ExtentInfo {
     pub fn stat(&self) -> SizeStat {
         self.stat
     }
+
+    pub fn root(&self) -> RootId {
+        self.root
+    }
+
+    pub fn fs(&self) -> FsId {
+        self.fs
+    }
+
+    pub fn subvol(&self) -> SubvolId {
+        self.subvol
+    }
+
+    /// Tag the extent with the filesystem it is on, see
+    /// [`crate::fs_util::fs_id`].
+    pub fn with_fs(self, fs: FsId) -> Self {
+        Self { fs, ..self }
+    }
+
+    /// Tag the extent with the subvolume of the file referencing it, see
+    /// [`crate::fs_util::register_subvol`].
+    pub fn with_subvol(self, subvol: SubvolId) -> Self {
+        Self { subvol, ..self }
+    }
+
+    /// Tag the extent with the command line argument it was found under.
+    pub fn with_root(self, root: RootId) -> Self {
+        Self { root, ..self }
+    }
+
+    /// An extent of inode `objectid` learned some other way than from its
+    /// extent item, see [`crate::fiemap`].
+    pub(crate) fn new(
+        objectid: u64,
+        offset: u64,
+        disk_bytenr: u64,
+        r#type: ExtentType,
+        compression: Compression,
+        stat: SizeStat,
+    ) -> Self {
+        Self {
+            objectid,
+            offset,
+            disk_bytenr,
+            r#type,
+            compression,
+            root: 0,
+            fs: 0,
+            subvol: 0,
+            stat,
+        }
+    }
+
+    /// A hole of `len` bytes at `offset`: nothing on disk, only referenced.
+    fn hole(objectid: u64, offset: u64, len: u64) -> Self {
+        Self {
+            objectid,
+            offset,
+            disk_bytenr: 0,
+            r#type: ExtentType::Regular,
+            compression: Compression::None,
+            root: 0,
+            fs: 0,
+            subvol: 0,
+            stat: SizeStat {
+                disk: 0,
+                uncomp: 0,
+                refd: len,
+            },
+        }
+    }
+
+    /// Holes share `disk_bytenr == 0` with inline extents.
+    pub fn is_hole(&self) -> bool {
+        self.disk_bytenr == 0 && self.r#type != ExtentType::Inline
+    }
+
+    /// Bytes written by [`encode`](Self::encode).
+    pub(crate) const ENCODED_LEN: usize = 42;
+
+    /// Append everything but the objectid and root, in little endian.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.disk_bytenr.to_le_bytes());
+        buf.push(self.r#type as u8);
+        // the row: unknown types read back as one
+        buf.push(self.compression.as_usize() as u8);
+        for n in [self.stat.disk, self.stat.uncomp, self.stat.refd] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+
+    /// The extent [`encode`](Self::encode) wrote to `buf`, as one of inode
+    /// `objectid`; `None` if `buf` holds something else.
+    pub(crate) fn decode(objectid: u64, buf: &[u8; Self::ENCODED_LEN]) -> Option<Self> {
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        let (r#type, compression) = (buf[16], buf[17]);
+        let compression = *Compression::ALL.get(compression as usize)?;
+        Some(Self {
+            objectid,
+            offset: u64_at(0),
+            disk_bytenr: u64_at(8),
+            r#type: ExtentType::from_u8(r#type)?,
+            compression,
+            root: 0,
+            fs: 0,
+            subvol: 0,
+            stat: SizeStat {
+                disk: u64_at(18),
+                uncomp: u64_at(26),
+                refd: u64_at(34),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+impl ExtentInfo {
+    /// A zstd extent of inode `objectid` taking `disk` bytes at
+    /// `disk_bytenr`, an inline one at 0.
+    pub(crate) fn for_test(objectid: u64, disk_bytenr: u64, disk: u64) -> Self {
+        Self {
+            objectid,
+            offset: 0,
+            disk_bytenr,
+            r#type: match disk_bytenr {
+                0 => ExtentType::Inline,
+                _ => ExtentType::Regular,
+            },
+            compression: Compression::Zstd,
+            root: 0,
+            fs: 0,
+            subvol: 0,
+            stat: SizeStat {
+                disk,
+                uncomp: disk * 3,
+                refd: disk * 3,
+            },
+        }
+    }
+}
+
+/// Finds the holes between the extents of a file, which have no item of
+/// their own on filesystems with the `no-holes` feature.  Holes after the
+/// last extent can't be seen this way.
+#[derive(Default)]
+pub struct HoleFinder {
+    objectid: u64,
+    end: u64,
+}
+
+impl HoleFinder {
+    /// Start over, for a new file that may have the same objectid.
+    pub fn reset(&mut self) {
+        self.end = 0;
+    }
+
+    /// The implicit hole right before `extent`, if any.  Extents of a file
+    /// must come in offset order, as the tree search returns them.
+    pub fn before(&mut self, extent: &ExtentInfo) -> Option<ExtentInfo> {
+        if extent.objectid != self.objectid {
+            self.objectid = extent.objectid;
+            self.end = 0;
+        }
+        let hole = (extent.offset > self.end)
+            .then(|| ExtentInfo::hole(extent.objectid, self.end, extent.offset - self.end));
+        self.end = self.end.max(extent.offset + extent.stat.refd);
+        hole
+    }
 }
 
 impl IoctlSearchItem<ExtentData> {
-    pub fn parse(&self) -> Result<Option<ExtentInfo>, String> {
+    pub fn parse(&self) -> Result<ExtentInfo, String> {
         let hlen = self.header.len;
         let ram_bytes = self.item.ram_bytes;
-        let compression = Compression::from_u8(self.item.compression);
-        let r#type = ExtentType::from_u8(self.item.r#type);
+        let compression = self.item.encoding();
+        let r#type = ExtentType::from_u8(self.item.r#type)
+            .ok_or_else(|| format!("Invalid extent type {}", self.item.r#type))?;
         let objectid = self.header.objectid;
         let offset = self.header.offset;
         if self.item.is_inline() {
             let disk_num_bytes = hlen as u64 - ExtentData::inline_header_size() as u64;
-            return Ok(Some(ExtentInfo {
+            return Ok(ExtentInfo {
                 objectid,
                 offset,
                 disk_bytenr: 0,
                 r#type,
                 compression,
+                root: 0,
+                fs: 0,
+                subvol: 0,
                 stat: SizeStat {
                     disk: disk_num_bytes,
                     uncomp: ram_bytes,
                     refd: ram_bytes,
                 },
-            }));
+            });
         }
         if hlen != self.item.raw_size() {
             cold_path();
@@ -98,9 +298,8 @@ impl IoctlSearchItem<ExtentData> {
             return Err(errmsg);
         }
         let disk_bytenr = self.item.disk_bytenr;
-        // is hole
         if disk_bytenr == 0 {
-            return Ok(None);
+            return Ok(ExtentInfo::hole(objectid, offset, self.item.num_bytes));
         }
         // check 4k alignment
         if disk_bytenr & 0xfff != 0 {
@@ -112,18 +311,21 @@ impl IoctlSearchItem<ExtentData> {
         let disk_bytenr = disk_bytenr >> 12;
         let disk_bytes = self.item.disk_num_bytes;
         let refd_bytes = self.item.num_bytes;
-        Ok(Some(ExtentInfo {
+        Ok(ExtentInfo {
             objectid,
             offset,
             disk_bytenr,
             r#type,
             compression,
+            root: 0,
+            fs: 0,
+            subvol: 0,
             stat: SizeStat {
                 disk: disk_bytes,
                 uncomp: ram_bytes,
                 refd: refd_bytes,
             },
-        }))
+        })
     }
 }
 
@@ -148,7 +350,7 @@ fn advance_key(objectid: &mut u64, r#type: &mut u32, offset: &mut u64) {
 
 #[derive(Debug)]
 pub struct Sv2Wrapper {
-    sv2_arg: Box<Sv2Args>,
+    sv2_arg: Sv2Args,
     pos: usize,
     nrest_item: u32,
     last: bool,
@@ -156,15 +358,30 @@ pub struct Sv2Wrapper {
 
 impl Sv2Wrapper {
     fn call_ioctl(&mut self, fd: BorrowedFd) -> Result<(), Errno> {
-        unsafe {
-            let ctl = Updater::<'_, BTRFS_IOCTL_SEARCH_V2, _>::new(&mut *self.sv2_arg);
-            ioctl(fd, ctl)?;
-        }
+        self.sv2_arg.search(fd)?;
         self.nrest_item = self.sv2_arg.key.nr_items;
+        if config().profile {
+            self.profile();
+        }
         self.last = self.nrest_item <= 4;
         self.pos = 0;
         Ok(())
     }
+    /// Count the search just done for `--profile`.
+    #[cold]
+    fn profile(&self) {
+        let buf = self.sv2_arg.buf();
+        let mut len = 0;
+        for _ in 0..self.nrest_item {
+            let Some(header) = buf.get(len..).and_then(SearchHeader::from_raw) else {
+                break;
+            };
+            len += size_of::<SearchHeader>() + header.len as usize;
+        }
+        profile::add(Counter::Searches, 1);
+        profile::add(Counter::SearchItems, self.nrest_item as u64);
+        profile::add(Counter::SearchBytes, len as u64);
+    }
     #[inline]
     fn need_ioctl(&self) -> bool {
         self.nrest_item == 0 && !self.last
@@ -184,31 +401,38 @@ impl Sv2Wrapper {
             return None;
         }
         let buf_len = self.sv2_arg.buf().len();
-        assert!(self.pos + size_of::<SearchHeader>() <= buf_len);
-        let header = unsafe { SearchHeader::from_raw(&self.sv2_arg.buf()[self.pos..]) };
+        let header = SearchHeader::from_raw(&self.sv2_arg.buf()[self.pos..]);
         let item_start = self.pos + size_of::<SearchHeader>();
+        let Some(header) = header.filter(|h| item_start + h.len as usize <= buf_len) else {
+            cold_path();
+            // the kernel said there were more items than fit, the rest of
+            // the search can't be trusted
+            self.nrest_item = 0;
+            self.last = true;
+            return Some(Err(Errno::UCLEAN));
+        };
         let item_end = item_start + header.len as usize;
-        assert!(item_end <= buf_len);
         self.pos = item_end;
         self.nrest_item -= 1;
         // Check AFTER decrement so the last item in a batch triggers key advancement.
         // Must update key BEFORE taking the buf slice to avoid borrow-conflict with sv2_arg.
         if self.need_ioctl() {
-            self.sv2_arg.key.min_objectid = header.objectid;
-            self.sv2_arg.key.min_type = header.r#type;
-            self.sv2_arg.key.min_offset = header.offset;
+            let key = &mut self.sv2_arg.key;
+            key.min_objectid = header.objectid;
+            key.min_type = header.r#type;
+            key.min_offset = header.offset;
             advance_key(
-                &mut self.sv2_arg.key.min_objectid,
-                &mut self.sv2_arg.key.min_type,
-                &mut self.sv2_arg.key.min_offset,
+                &mut key.min_objectid,
+                &mut key.min_type,
+                &mut key.min_offset,
             );
-            self.sv2_arg.key.nr_items = u32::MAX;
+            key.nr_items = u32::MAX;
         }
         let buf: &[u8] = &self.sv2_arg.buf()[item_start..item_end];
         Some(Ok((header, buf)))
     }
 
-    pub fn new(sv2_arg: Box<Sv2Args>) -> Self {
+    pub fn new(sv2_arg: Sv2Args) -> Self {
         Self {
             sv2_arg,
             pos: 0,
@@ -235,23 +459,233 @@ impl Sv2Wrapper {
     }
 }
 
+/// Id of the subvolume `fd` lives in.
+pub fn subvol_id(fd: BorrowedFd) -> Result<u64, Errno> {
+    let mut args = Box::new(InoLookupArgs::new(0, objectid::FIRST_FREE));
+    unsafe {
+        let ctl = Updater::<'_, BTRFS_IOCTL_INO_LOOKUP, _>::new(&mut *args);
+        ioctl(fd, ctl)?;
+    }
+    Ok(args.treeid)
+}
+
+/// Path of subvolume `id` relative to the top level subvolume, following
+/// its ROOT_BACKREF items in the root tree up to FS_TREE.
+pub fn subvol_path(fd: BorrowedFd, id: u64) -> Result<PathBuf, Errno> {
+    let mut args = Sv2Args::from_sk(IoctlSearchKey::new(
+        objectid::ROOT_TREE,
+        0,
+        0,
+        0,
+        u64::MAX,
+        0,
+        u64::MAX,
+        r#type::ROOT_BACKREF,
+        r#type::ROOT_BACKREF,
+    ));
+    // innermost component first
+    let mut parts: Vec<Vec<u8>> = Vec::new();
+    let mut cur = id;
+    while cur != objectid::FS_TREE {
+        args.key.min_objectid = cur;
+        args.key.max_objectid = cur;
+        args.key.min_offset = 0;
+        args.key.nr_items = 1;
+        args.search(fd)?;
+        if args.key.nr_items == 0 {
+            return Err(Errno::NOENT);
+        }
+        let buf = args.buf();
+        let header = SearchHeader::from_raw(buf).ok_or(Errno::UCLEAN)?;
+        let root_ref = buf[size_of::<SearchHeader>()..]
+            .get(..header.len as usize)
+            .and_then(RootRef::parse)
+            .ok_or(Errno::UCLEAN)?;
+        parts.push(root_ref.name.to_vec());
+
+        let parent = header.offset;
+        let mut lookup = Box::new(InoLookupArgs::new(parent, root_ref.dirid));
+        unsafe {
+            let ctl = Updater::<'_, BTRFS_IOCTL_INO_LOOKUP, _>::new(&mut *lookup);
+            ioctl(fd, ctl)?;
+        }
+        parts.push(lookup.name().to_vec());
+        cur = parent;
+    }
+    Ok(parts
+        .iter()
+        .rev()
+        .filter(|p| !p.is_empty())
+        .map(|p| OsStr::from_bytes(p))
+        .collect())
+}
+
+/// Path of inode `ino` of subvolume `tree_id`, 0 for the one of `fd`,
+/// relative to the subvolume root, following the first INODE_REF of each
+/// inode up to it.  Inodes whose names only fit in INODE_EXTREF items
+/// give `ENOENT`.
+pub fn ino_path(fd: BorrowedFd, tree_id: u64, ino: u64) -> Result<PathBuf, Errno> {
+    // innermost component first
+    let mut parts: Vec<Vec<u8>> = Vec::new();
+    let mut cur = ino;
+    while cur != objectid::FIRST_FREE {
+        // a cycle, in a corrupted tree
+        if parts.len() > 4096 {
+            return Err(Errno::LOOP);
+        }
+        let search = TreeSearch::new(tree_id).objectid(cur);
+        let (header, refs) = search
+            .items::<Vec<InodeRef>>(fd)
+            .next()
+            .ok_or(Errno::NOENT)??;
+        parts.push(refs.into_iter().next().ok_or(Errno::UCLEAN)?.name);
+        cur = header.offset;
+    }
+    Ok(parts.iter().rev().map(|p| OsStr::from_bytes(p)).collect())
+}
+
+/// The fsid of the filesystem `fd` is on, and its current generation if
+/// the kernel tells.
+pub fn fs_info(fd: BorrowedFd) -> Result<([u8; 16], Option<u64>), Errno> {
+    let mut args = Box::new(FsInfoArgs::new(BTRFS_FS_INFO_FLAG_GENERATION));
+    unsafe {
+        let ctl = Updater::<'_, BTRFS_IOCTL_FS_INFO, _>::new(&mut *args);
+        ioctl(fd, ctl)?;
+    }
+    let generation = (args.flags & BTRFS_FS_INFO_FLAG_GENERATION != 0).then_some(args.generation);
+    Ok((args.fsid, generation))
+}
+
+/// Whether any tree leaf holding the inode item or extents of `ino`, in
+/// the subvolume of `fd`, was written in `generation` or later.  Leaves
+/// are rewritten for their neighbours' sake too, so `true` only means the
+/// inode may have changed.
+pub fn inode_changed_since(fd: BorrowedFd, ino: u64, generation: u64) -> Result<bool, Errno> {
+    let mut args = Sv2Args::from_sk(IoctlSearchKey::new(
+        0,
+        ino,
+        ino,
+        0,
+        u64::MAX,
+        generation,
+        u64::MAX,
+        r#type::INODE_ITEM,
+        r#type::EXTENT_DATA,
+    ));
+    args.key.nr_items = 1;
+    args.search(fd)?;
+    Ok(args.key.nr_items > 0)
+}
+
+/// Ids and root items of all subvolumes of the filesystem `fd` is on, by
+/// id, the top level one included and those still being deleted left out.
+pub fn subvolumes(fd: BorrowedFd) -> Result<Vec<(u64, RootItem)>, Errno> {
+    let search =
+        TreeSearch::new(objectid::ROOT_TREE).objectids(objectid::FS_TREE..=objectid::LAST_FREE);
+    let mut ret: Vec<(u64, RootItem)> = vec![];
+    for item in search.items::<RootItem>(fd) {
+        let (header, root) = item?;
+        let id = header.objectid;
+        if (id != objectid::FS_TREE && id < objectid::FIRST_FREE)
+            || ret.last().is_some_and(|(last, _)| *last == id)
+        {
+            continue;
+        }
+        if root.refs != 0 {
+            ret.push((id, root));
+        }
+    }
+    Ok(ret)
+}
+
+/// The flags of the quota status, see [`tree::qgroup_status`], and the
+/// level 0 qgroups by subvolume id, of the filesystem `fd` is on.
+/// `ENOENT` if quotas are not enabled.
+pub fn qgroups(fd: BorrowedFd) -> Result<(u64, Vec<(u64, QgroupInfo)>), Errno> {
+    let search = TreeSearch::new(objectid::QUOTA_TREE)
+        .objectid(0)
+        .types(r#type::QGROUP_STATUS..=r#type::QGROUP_INFO);
+    let mut raw = search.raw(fd);
+    let (mut flags, mut ret) = (0, vec![]);
+    while let Some(item) = raw.next_item() {
+        let (header, buf) = item?;
+        match header.r#type as u8 {
+            // struct btrfs_qgroup_status_item { version, generation, flags, ... }
+            r#type::QGROUP_STATUS => {
+                let b = buf.get(16..24).ok_or(Errno::UCLEAN)?;
+                flags = u64::from_le_bytes(b.try_into().unwrap());
+            }
+            // the level is in the upper 16 bits of the qgroup id
+            r#type::QGROUP_INFO if header.offset >> 48 == 0 => {
+                ret.push((header.offset, QgroupInfo::parse(buf).ok_or(Errno::UCLEAN)?));
+            }
+            _ => (),
+        }
+    }
+    Ok((flags, ret))
+}
+
+/// Highest inode number in subvolume `tree_id`, 0 for the one of `fd`,
+/// found by bisecting the objectid space with single-item searches.  Inode
+/// numbers are handed out incrementally, so this is a cheap upper bound of
+/// the inode count.
+pub fn max_ino(fd: BorrowedFd, tree_id: u64) -> Result<u64, Errno> {
+    let mut args = Sv2Args::from_sk(IoctlSearchKey::new(
+        tree_id,
+        objectid::FIRST_FREE,
+        objectid::LAST_FREE,
+        0,
+        u64::MAX,
+        0,
+        u64::MAX,
+        r#type::INODE_ITEM,
+        r#type::INODE_ITEM,
+    ));
+    // objectid of the first item at or after `min`
+    let mut first_from = |min: u64| -> Result<Option<u64>, Errno> {
+        args.key.min_objectid = min;
+        args.key.min_type = r#type::INODE_ITEM as _;
+        args.key.min_offset = 0;
+        args.key.nr_items = 1;
+        args.search(fd)?;
+        if args.key.nr_items == 0 {
+            return Ok(None);
+        }
+        let header = SearchHeader::from_raw(args.buf()).ok_or(Errno::UCLEAN)?;
+        Ok(Some(header.objectid))
+    };
+    let Some(mut lo) = first_from(objectid::FIRST_FREE)? else {
+        return Ok(0);
+    };
+    let mut hi = objectid::LAST_FREE;
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        match first_from(mid)? {
+            Some(found) => lo = found,
+            None => hi = mid - 1,
+        }
+    }
+    Ok(lo)
+}
+
 #[derive(Debug)]
 pub struct Sv2ItemIter<'inner, 'fd, T> {
     inner: &'inner mut Sv2Wrapper,
     fd: BorrowedFd<'fd>,
     _phantom: PhantomData<T>,
 }
+/// The search failing comes as the outer error, an item that doesn't parse
+/// as the inner one, and the search goes on past it.
 impl<T: TreeItem> Iterator for Sv2ItemIter<'_, '_, T> {
-    type Item = Result<IoctlSearchItem<T>, Errno>;
+    type Item = Result<Result<IoctlSearchItem<T>, String>, Errno>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let (header, buf) = match self.inner.next(self.fd)? {
             Ok((header, buf)) => (header, buf),
             Err(e) => return Some(Err(e)),
         };
-        let item = unsafe { T::from_le_raw(buf) };
-        let ret = IoctlSearchItem { header, item };
-        Some(Ok(ret))
+        let item = T::from_le_raw(buf).map(|item| IoctlSearchItem { header, item });
+        Some(Ok(item))
     }
 }
 impl<T: TreeItem> FusedIterator for Sv2ItemIter<'_, '_, T> {}
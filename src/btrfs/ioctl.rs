@@ -1,7 +1,26 @@
-use rustix::ioctl::{Opcode, opcode::read_write};
+use std::{
+    alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error},
+    ops::{Deref, DerefMut},
+    os::fd::BorrowedFd,
+    ptr::NonNull,
+    slice,
+    sync::Mutex,
+};
+
+use rustix::{
+    io::Errno,
+    ioctl::{
+        Opcode, Updater, ioctl,
+        opcode::{read, read_write},
+    },
+};
+
+use crate::global::config;
 
 pub const BTRFS_IOCTL_MAGIC: u8 = 0x94;
-pub const BTRFS_IOCTL_SEARCH_V2: Opcode = read_write::<Sv2Args>(BTRFS_IOCTL_MAGIC, 17);
+pub const BTRFS_IOCTL_SEARCH_V2: Opcode = read_write::<Sv2Head>(BTRFS_IOCTL_MAGIC, 17);
+pub const BTRFS_IOCTL_INO_LOOKUP: Opcode = read_write::<InoLookupArgs>(BTRFS_IOCTL_MAGIC, 18);
+pub const BTRFS_IOCTL_FS_INFO: Opcode = read::<FsInfoArgs>(BTRFS_IOCTL_MAGIC, 31);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct IoctlSearchKey {
@@ -54,28 +73,107 @@ impl IoctlSearchKey {
     }
 }
 
-// should be reused for different files
+/// `struct btrfs_ioctl_search_args_v2` without its buffer.
 #[derive(Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
-pub struct Sv2Args {
+pub struct Sv2Head {
     pub key: IoctlSearchKey,
     buf_size: u64,
-    buf: [u8; 16384],
 }
 
+/// Default size of the SEARCH_V2 result buffer, see `--search-buffer`.
+pub const SV2_BUF_SIZE: usize = 16 << 10;
+
+/// Buffers of dropped [`Sv2Args`], reused by the next ones.
+static SV2_POOL: Mutex<Vec<Sv2Args>> = Mutex::new(Vec::new());
+/// Most buffers kept in [`SV2_POOL`].
+const SV2_POOL_LEN: usize = 64;
+
+/// SEARCH_V2 arguments followed by a heap buffer of `--search-buffer`
+/// bytes, taken from a pool and returned to it on drop.  Should be reused
+/// for different files.
+#[derive(Debug)]
+pub struct Sv2Args {
+    head: NonNull<Sv2Head>,
+    layout: Layout,
+}
+
+// owns its allocation like a `Box`
+unsafe impl Send for Sv2Args {}
+unsafe impl Sync for Sv2Args {}
+
 impl Sv2Args {
-    #[inline]
     pub fn from_sk(sk: IoctlSearchKey) -> Self {
-        Self {
-            key: sk,
-            buf_size: 16384,
-            buf: [0; 16384],
-        }
+        let buf_size = config().search_buffer();
+        let pooled = SV2_POOL.lock().unwrap().pop();
+        let mut ret = match pooled {
+            Some(args) if args.buf_size == buf_size as u64 => args,
+            _ => Self::alloc(buf_size),
+        };
+        ret.key = sk;
+        ret
+    }
+
+    fn alloc(buf_size: usize) -> Self {
+        let layout = Layout::new::<Sv2Head>()
+            .extend(Layout::array::<u8>(buf_size).unwrap())
+            .unwrap()
+            .0
+            .pad_to_align();
+        let Some(head) = NonNull::new(unsafe { alloc_zeroed(layout) }) else {
+            handle_alloc_error(layout)
+        };
+        let mut ret = Self {
+            head: head.cast(),
+            layout,
+        };
+        ret.buf_size = buf_size as u64;
+        ret
     }
 
     #[inline]
-    pub fn buf(&self) -> &[u8; 16384] {
-        &self.buf
+    pub fn buf(&self) -> &[u8] {
+        unsafe {
+            let buf = self.head.as_ptr().add(1).cast::<u8>();
+            slice::from_raw_parts(buf, self.buf_size as usize)
+        }
+    }
+
+    /// Run the search, leaving the number of items found in
+    /// `key.nr_items`.
+    pub fn search(&mut self, fd: BorrowedFd) -> Result<(), Errno> {
+        unsafe {
+            let ctl = Updater::<'_, BTRFS_IOCTL_SEARCH_V2, _>::new(self.head.as_mut());
+            ioctl(fd, ctl)
+        }
+    }
+}
+
+impl Deref for Sv2Args {
+    type Target = Sv2Head;
+    fn deref(&self) -> &Sv2Head {
+        unsafe { self.head.as_ref() }
+    }
+}
+
+impl DerefMut for Sv2Args {
+    fn deref_mut(&mut self) -> &mut Sv2Head {
+        unsafe { self.head.as_mut() }
+    }
+}
+
+impl Drop for Sv2Args {
+    fn drop(&mut self) {
+        let mut pool = SV2_POOL.lock().unwrap();
+        if pool.len() < SV2_POOL_LEN {
+            let args = Self {
+                head: self.head,
+                layout: self.layout,
+            };
+            pool.push(args);
+        } else {
+            unsafe { dealloc(self.head.as_ptr().cast(), self.layout) };
+        }
     }
 }
 
@@ -89,11 +187,82 @@ pub struct SearchHeader {
     pub len: u32,
 }
 impl SearchHeader {
-    /// # Safety
-    /// `buf` must contain at least `size_of::<SearchHeader>()` (32) bytes.
-    /// The caller must ensure the buffer is valid for reads.
+    /// The header at the start of `buf`, `None` if `buf` is shorter than
+    /// one.
+    #[inline]
+    pub fn from_raw(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..size_of::<Self>())?;
+        // SAFETY: `buf` holds a whole header, and any bytes make one
+        Some(unsafe { buf.as_ptr().cast::<Self>().read_unaligned() })
+    }
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct InoLookupArgs {
+    pub treeid: u64,
+    pub objectid: u64,
+    name: [u8; 4080],
+}
+
+impl InoLookupArgs {
+    #[inline]
+    pub fn new(treeid: u64, objectid: u64) -> Self {
+        Self {
+            treeid,
+            objectid,
+            name: [0; 4080],
+        }
+    }
+    /// Path of `objectid` inside `treeid` as filled in by the kernel,
+    /// with a trailing '/' unless empty.
+    pub fn name(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+}
+
+/// Ask [`BTRFS_IOCTL_FS_INFO`] for the generation, since Linux 5.11.
+pub const BTRFS_FS_INFO_FLAG_GENERATION: u64 = 1 << 1;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct FsInfoArgs {
+    pub max_id: u64,
+    pub num_devices: u64,
+    pub fsid: [u8; 16],
+    pub nodesize: u32,
+    pub sectorsize: u32,
+    pub clone_alignment: u32,
+    pub csum_type: u16,
+    pub csum_size: u16,
+    /// in: what to fill in besides the basics; out: what was
+    pub flags: u64,
+    pub generation: u64,
+    pub metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+impl FsInfoArgs {
     #[inline]
-    pub unsafe fn from_raw(buf: &[u8]) -> Self {
-        unsafe { buf.as_ptr().cast::<Self>().read_unaligned() }
+    pub fn new(flags: u64) -> Self {
+        Self {
+            max_id: 0,
+            num_devices: 0,
+            fsid: [0; 16],
+            nodesize: 0,
+            sectorsize: 0,
+            clone_alignment: 0,
+            csum_type: 0,
+            csum_size: 0,
+            flags,
+            generation: 0,
+            metadata_uuid: [0; 16],
+            reserved: [0; 944],
+        }
     }
 }
@@ -0,0 +1,183 @@
+//! Typed searches of btrfs trees, for tools built on xsz:
+//!
+//! ```no_run
+//! # use std::os::fd::AsFd;
+//! use xsz::btrfs::{search::TreeSearch, tree::{RootItem, objectid}};
+//!
+//! let fd = std::fs::File::open("/mnt")?;
+//! let search = TreeSearch::new(objectid::ROOT_TREE)
+//!     .objectids(objectid::FIRST_FREE..=objectid::LAST_FREE);
+//! for item in search.items::<RootItem>(fd.as_fd()) {
+//!     let (header, root) = item?;
+//!     println!("{} {}", header.objectid, root.is_readonly());
+//! }
+//! # Ok::<_, std::io::Error>(())
+//! ```
+//!
+//! Searching needs CAP_SYS_ADMIN.
+
+use std::{marker::PhantomData, ops::RangeInclusive, os::fd::BorrowedFd};
+
+use rustix::io::Errno;
+
+use super::{
+    ExtentInfo, IoctlSearchItem, Sv2Wrapper,
+    ioctl::{IoctlSearchKey, SearchHeader, Sv2Args},
+    tree::{DirItem, ExtentData, InodeItem, InodeRef, QgroupInfo, RootItem, TreeItem, r#type},
+};
+
+/// An item type [`TreeSearch::items`] can parse.
+pub trait SearchItem: Sized {
+    /// The key type of the items.
+    const TYPE: u8;
+    /// The item found under `header` in `buf`, `None` if it makes no sense.
+    fn parse(header: &SearchHeader, buf: &[u8]) -> Option<Self>;
+}
+
+impl SearchItem for InodeItem {
+    const TYPE: u8 = r#type::INODE_ITEM;
+    fn parse(_header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        Self::parse(buf)
+    }
+}
+
+/// The entries of a DIR_ITEM, keyed by directory and name hash.
+impl SearchItem for Vec<DirItem> {
+    const TYPE: u8 = r#type::DIR_ITEM;
+    fn parse(_header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        DirItem::parse_all(buf)
+    }
+}
+
+/// The names of an inode in the directory that is the offset of the key.
+impl SearchItem for Vec<InodeRef> {
+    const TYPE: u8 = r#type::INODE_REF;
+    fn parse(_header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        InodeRef::parse_all(buf)
+    }
+}
+
+impl SearchItem for RootItem {
+    const TYPE: u8 = r#type::ROOT_ITEM;
+    fn parse(_header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        Self::parse(buf)
+    }
+}
+
+impl SearchItem for QgroupInfo {
+    const TYPE: u8 = r#type::QGROUP_INFO;
+    fn parse(_header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        Self::parse(buf)
+    }
+}
+
+/// A file extent, with holes as extents at disk address 0.
+impl SearchItem for ExtentInfo {
+    const TYPE: u8 = r#type::EXTENT_DATA;
+    fn parse(header: &SearchHeader, buf: &[u8]) -> Option<Self> {
+        let item = ExtentData::from_le_raw(buf).ok()?;
+        IoctlSearchItem {
+            header: *header,
+            item,
+        }
+        .parse()
+        .ok()
+    }
+}
+
+/// The key ranges of a search of one tree.  Items come in key order: by
+/// objectid, then type, then offset.  The ranges bound the search, they
+/// don't filter it: with several objectids, the items of all types and
+/// offsets between the first and last key come.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeSearch {
+    key: IoctlSearchKey,
+}
+
+impl TreeSearch {
+    /// Search tree `tree_id`, e.g. one of [`super::tree::objectid`], or 0
+    /// for that of the subvolume searched from.
+    pub fn new(tree_id: u64) -> Self {
+        Self {
+            key: IoctlSearchKey::new(tree_id, 0, u64::MAX, 0, u64::MAX, 0, u64::MAX, 0, u8::MAX),
+        }
+    }
+    pub fn objectids(mut self, range: RangeInclusive<u64>) -> Self {
+        (self.key.min_objectid, self.key.max_objectid) = range.into_inner();
+        self
+    }
+    pub fn objectid(self, objectid: u64) -> Self {
+        self.objectids(objectid..=objectid)
+    }
+    pub fn types(mut self, range: RangeInclusive<u8>) -> Self {
+        let (min, max) = range.into_inner();
+        (self.key.min_type, self.key.max_type) = (min as _, max as _);
+        self
+    }
+    pub fn offsets(mut self, range: RangeInclusive<u64>) -> Self {
+        (self.key.min_offset, self.key.max_offset) = range.into_inner();
+        self
+    }
+    /// Only items in tree leaves written in these transactions.
+    pub fn transids(mut self, range: RangeInclusive<u64>) -> Self {
+        (self.key.min_transid, self.key.max_transid) = range.into_inner();
+        self
+    }
+
+    /// Every item found from `fd`, unparsed.
+    pub fn raw<'fd>(&self, fd: BorrowedFd<'fd>) -> RawItems<'fd> {
+        RawItems {
+            sv2: Sv2Wrapper::new(Sv2Args::from_sk(self.key)),
+            fd,
+        }
+    }
+
+    /// The items of type `T` found from `fd`; one that doesn't parse comes
+    /// as `EUCLEAN`.
+    pub fn items<'fd, T: SearchItem>(&self, fd: BorrowedFd<'fd>) -> Items<'fd, T> {
+        Items {
+            raw: self.types(T::TYPE..=T::TYPE).raw(fd),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+pub struct RawItems<'fd> {
+    sv2: Sv2Wrapper,
+    fd: BorrowedFd<'fd>,
+}
+
+impl RawItems<'_> {
+    /// The next item and its header.  Not an [`Iterator`], the item is
+    /// borrowed from the search buffer.
+    pub fn next_item(&mut self) -> Option<Result<(SearchHeader, &[u8]), Errno>> {
+        self.sv2.next(self.fd)
+    }
+}
+
+pub struct Items<'fd, T> {
+    raw: RawItems<'fd>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: SearchItem> Iterator for Items<'_, T> {
+    type Item = Result<(SearchHeader, T), Errno>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (header, buf) = match self.raw.next_item()? {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            // the type range spans other types of the objectids in between
+            if header.r#type != T::TYPE as u32 {
+                continue;
+            }
+            return Some(
+                T::parse(&header, buf)
+                    .map(|item| (header, item))
+                    .ok_or(Errno::UCLEAN),
+            );
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use std::{fmt::Display, mem::transmute};
+use std::fmt::Display;
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct Key {
     pub objectid: u64,
@@ -21,6 +22,8 @@ pub mod objectid {
     pub const FREE_SPACE_TREE: u64 = 10;
     pub const BLOCK_GROUP_TREE: u64 = 11;
     pub const RAID_STRIPE_TREE: u64 = 12;
+    pub const FIRST_FREE: u64 = 256;
+    pub const LAST_FREE: u64 = -256i64 as u64;
     pub const BALANCE: u64 = -4i64 as u64;
     pub const ORPHAN: u64 = -5i64 as u64;
     pub const TREE_LOG: u64 = -6i64 as u64;
@@ -150,31 +153,68 @@ pub mod r#type {
     }
 }
 
-pub trait TreeItem {
+pub trait TreeItem: Sized {
     const TYPE: u8;
     fn raw_size(&self) -> u32;
-    unsafe fn from_le_raw(buf: &[u8]) -> Self;
+    /// The item in `buf`, as the kernel returned it; what is wrong with it
+    /// if it doesn't fit, e.g. on a corrupted filesystem.
+    fn from_le_raw(buf: &[u8]) -> Result<Self, String>;
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Compression {
-    None = 0,
+    None,
     Zlib,
     Lzo,
     Zstd,
+    /// compressed the kernel won't say how, see [`crate::fiemap`]
+    Encoded,
+    /// with a nonzero `encryption` byte, whatever the compression
+    Encrypted,
+    /// with a nonzero `other_encoding`, which no kernel sets yet
+    OtherEncoding,
+    /// an on-disk `compression` byte this version doesn't know, from a
+    /// newer kernel or a corrupted item
+    Unknown(u8),
 }
 impl Compression {
+    /// Every row of the usage table, in order.  All unknown types share
+    /// the last one.
+    pub const ALL: [Self; 8] = [
+        Self::None,
+        Self::Zlib,
+        Self::Lzo,
+        Self::Zstd,
+        Self::Encoded,
+        Self::Encrypted,
+        Self::OtherEncoding,
+        Self::Unknown(0),
+    ];
+
+    /// The row of the usage table, the index in [`Self::ALL`].
     #[inline]
     pub fn as_usize(self) -> usize {
-        self as usize
+        match self {
+            Self::None => 0,
+            Self::Zlib => 1,
+            Self::Lzo => 2,
+            Self::Zstd => 3,
+            Self::Encoded => 4,
+            Self::Encrypted => 5,
+            Self::OtherEncoding => 6,
+            Self::Unknown(_) => 7,
+        }
     }
+    /// The type of an on-disk `compression` byte.
     #[inline]
     pub fn from_u8(n: u8) -> Self {
-        assert!(n <= 3);
-        // safety: the assertion checks that `n`` is in valid `Compression` range.
-        unsafe { transmute(n) }
+        match n {
+            0 => Self::None,
+            1 => Self::Zlib,
+            2 => Self::Lzo,
+            3 => Self::Zstd,
+            n => Self::Unknown(n),
+        }
     }
     pub fn name(&self) -> &'static str {
         match self {
@@ -182,6 +222,10 @@ impl Compression {
             Compression::Zlib => "zlib",
             Compression::Lzo => "lzo",
             Compression::Zstd => "zstd",
+            Compression::Encoded => "encoded",
+            Compression::Encrypted => "encrypted",
+            Compression::OtherEncoding => "other-enc",
+            Compression::Unknown(_) => "unknown",
         }
     }
 }
@@ -201,13 +245,15 @@ pub enum ExtentType {
 }
 
 impl ExtentType {
+    /// The type of an on-disk `type` byte, `None` for an invalid one.
     #[inline]
-    pub fn from_u8(n: u8) -> Self {
-        if n > 2 {
-            panic!("Invalid extent type: {}", n);
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Inline),
+            1 => Some(Self::Regular),
+            2 => Some(Self::Prealloc),
+            _ => None,
         }
-        // Safety: the assertion checks that `n` is in valid `ExtentType` range.
-        unsafe { transmute(n) }
     }
 }
 
@@ -233,7 +279,18 @@ impl ExtentData {
         std::mem::offset_of!(Self, disk_bytenr) as u32
     }
     pub fn is_inline(&self) -> bool {
-        ExtentType::Inline == ExtentType::from_u8(self.r#type)
+        self.r#type == ExtentType::Inline as u8
+    }
+    /// The row the extent is counted in: encrypted or otherwise encoded
+    /// data can't be told apart by its compression.
+    pub fn encoding(&self) -> Compression {
+        if self.encryption != 0 {
+            Compression::Encrypted
+        } else if self.other_encoding != 0 {
+            Compression::OtherEncoding
+        } else {
+            Compression::from_u8(self.compression)
+        }
     }
 }
 
@@ -245,67 +302,242 @@ impl TreeItem for ExtentData {
         }
         Self::inline_header_size() + 8 * 4
     }
-    unsafe fn from_le_raw(buf: &[u8]) -> Self {
-        let mut ptr = buf.as_ptr();
-        assert!(buf.len() >= Self::inline_header_size() as usize);
-        unsafe {
-            let generation = ptr.cast::<u64>().read_unaligned().to_le();
-            ptr = ptr.add(8);
-            let ram_bytes = ptr.cast::<u64>().read_unaligned().to_le();
-            ptr = ptr.add(8);
-            let compression = ptr.cast::<u8>().read_unaligned().to_le();
-            ptr = ptr.add(1);
-            let encryption = ptr.cast::<u8>().read_unaligned().to_le();
-            ptr = ptr.add(1);
-            let other_encoding = ptr.cast::<u16>().read_unaligned().to_le();
-            ptr = ptr.add(2);
-            let r#type = ptr.cast::<u8>().read_unaligned().to_le();
-            ptr = ptr.add(1);
-            if ExtentType::from_u8(r#type) == ExtentType::Inline {
-                return Self {
-                    generation,
-                    ram_bytes,
-                    compression,
-                    encryption,
-                    other_encoding,
-                    r#type,
-                    disk_bytenr: 0,
-                    disk_num_bytes: 0,
-                    offset: 0,
-                    num_bytes: 0,
-                };
-            }
-            let disk_bytenr = ptr.cast::<u64>().read_unaligned().to_le();
-            ptr = ptr.add(8);
-            let disk_num_bytes = ptr.cast::<u64>().read_unaligned().to_le();
-            ptr = ptr.add(8);
-            let offset = ptr.cast::<u64>().read_unaligned().to_le();
-            ptr = ptr.add(8);
-            let num_bytes = ptr.cast::<u64>().read_unaligned().to_le();
-            let ret = Self {
-                generation,
-                ram_bytes,
-                compression,
-                encryption,
-                other_encoding,
-                r#type,
-                disk_bytenr,
-                disk_num_bytes,
-                offset,
-                num_bytes,
-            };
-            assert!(buf.len() == ret.raw_size() as usize);
-            ret
+    fn from_le_raw(buf: &[u8]) -> Result<Self, String> {
+        let header_size = Self::inline_header_size() as usize;
+        if buf.len() < header_size {
+            return Err(format!("Extent item too short ({} bytes)", buf.len()));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        let r#type = buf[20];
+        let Some(extent_type) = ExtentType::from_u8(r#type) else {
+            return Err(format!("Invalid extent type {}", r#type));
+        };
+        let mut ret = Self {
+            generation: u64_at(0),
+            ram_bytes: u64_at(8),
+            compression: buf[16],
+            encryption: buf[17],
+            other_encoding: u16::from_le_bytes([buf[18], buf[19]]),
+            r#type,
+            disk_bytenr: 0,
+            disk_num_bytes: 0,
+            offset: 0,
+            num_bytes: 0,
+        };
+        if extent_type == ExtentType::Inline {
+            return Ok(ret);
+        }
+        if buf.len() != ret.raw_size() as usize {
+            return Err(format!(
+                "Regular extent's item not 53 bytes ({}) long?!?",
+                buf.len()
+            ));
         }
+        ret.disk_bytenr = u64_at(header_size);
+        ret.disk_num_bytes = u64_at(header_size + 8);
+        ret.offset = u64_at(header_size + 16);
+        ret.num_bytes = u64_at(header_size + 24);
+        Ok(ret)
     }
 }
 
-#[allow(unused)]
+/// The parts of a `struct btrfs_inode_item` xsz looks at.
+pub struct InodeItem {
+    pub generation: u64,
+    /// the transaction that last changed the inode
+    pub transid: u64,
+    pub size: u64,
+    /// bytes of extents referenced, holes and inline data excluded
+    pub nbytes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub flags: u64,
+    /// seconds since the epoch
+    pub mtime: i64,
+}
+
+impl InodeItem {
+    /// Parse the item from `buf`, `None` if it is too short.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let u64_at = |off: usize| Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?));
+        let u32_at = |off: usize| Some(u32::from_le_bytes(buf.get(off..off + 4)?.try_into().ok()?));
+        // after 4 reserved u64s, atime and ctime of 12 bytes each
+        const MTIME: usize = 80 + 4 * 8 + 2 * 12;
+        Some(Self {
+            generation: u64_at(0)?,
+            transid: u64_at(8)?,
+            size: u64_at(16)?,
+            nbytes: u64_at(24)?,
+            nlink: u32_at(40)?,
+            uid: u32_at(44)?,
+            gid: u32_at(48)?,
+            mode: u32_at(52)?,
+            flags: u64_at(64)?,
+            mtime: u64_at(MTIME)? as i64,
+        })
+    }
+}
+
+/// The parts of a `struct btrfs_root_item` xsz looks at.
+pub struct RootItem {
+    pub generation: u64,
+    pub flags: u64,
+    /// 0 once the subvolume is deleted, until the cleaner gets to it
+    pub refs: u32,
+    /// all zero in items written before Linux 3.6
+    pub uuid: [u8; 16],
+    /// of the subvolume this is a snapshot of, all zero if none
+    pub parent_uuid: [u8; 16],
+}
+
+impl RootItem {
+    /// `flags` bit of a read-only subvolume
+    pub const RDONLY: u64 = 1 << 0;
+    // after `struct btrfs_inode_item` (160 bytes) and 6 u64s
+    const FLAGS: usize = 160 + 6 * 8;
+    const REFS: usize = Self::FLAGS + 8;
+    // after the refs, drop_progress, drop_level, level and generation_v2
+    const UUID: usize = Self::REFS + 4 + 17 + 1 + 1 + 8;
+
+    /// Parse the item from `buf`, `None` if it is too short.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let u64_at = |off: usize| Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?));
+        let uuid_at = |off: usize| {
+            buf.get(off..off + 16)
+                .map_or([0; 16], |b| b.try_into().unwrap())
+        };
+        Some(Self {
+            generation: u64_at(160)?,
+            flags: u64_at(Self::FLAGS)?,
+            refs: u32::from_le_bytes(buf.get(Self::REFS..Self::REFS + 4)?.try_into().ok()?),
+            uuid: uuid_at(Self::UUID),
+            parent_uuid: uuid_at(Self::UUID + 16),
+        })
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.flags & Self::RDONLY != 0
+    }
+    pub fn is_snapshot(&self) -> bool {
+        self.parent_uuid != [0; 16]
+    }
+}
+
+/// A `struct btrfs_root_ref`, which names a subvolume in the directory
+/// `dirid` of its parent, as the offset of its ROOT_BACKREF key.
+pub struct RootRef<'a> {
+    pub dirid: u64,
+    pub sequence: u64,
+    pub name: &'a [u8],
+}
+
+impl<'a> RootRef<'a> {
+    /// Parse the item from `buf`, `None` if it is too short.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let dirid = u64::from_le_bytes(buf.get(..8)?.try_into().ok()?);
+        let sequence = u64::from_le_bytes(buf.get(8..16)?.try_into().ok()?);
+        let name_len = u16::from_le_bytes(buf.get(16..18)?.try_into().ok()?) as usize;
+        Some(Self {
+            dirid,
+            sequence,
+            name: buf.get(18..18 + name_len)?,
+        })
+    }
+}
+
+/// A `struct btrfs_qgroup_info_item`: the disk usage a qgroup references
+/// and the part of it no other qgroup does.
+pub struct QgroupInfo {
+    pub generation: u64,
+    pub rfer: u64,
+    pub excl: u64,
+}
+
+impl QgroupInfo {
+    /// Parse the item from `buf`, `None` if it is too short.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let u64_at = |off: usize| Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?));
+        // rfer_cmpr and excl_cmpr follow rfer and excl, always equal to them
+        Some(Self {
+            generation: u64_at(0)?,
+            rfer: u64_at(8)?,
+            excl: u64_at(24)?,
+        })
+    }
+}
+
+/// `flags` of the QGROUP_STATUS item.
+pub mod qgroup_status {
+    pub const ON: u64 = 1 << 0;
+    pub const RESCAN: u64 = 1 << 1;
+    pub const INCONSISTENT: u64 = 1 << 2;
+}
+
+/// One entry of a DIR_ITEM or DIR_INDEX item: a `struct btrfs_dir_item`
+/// followed by its name.
 pub struct DirItem {
-    key: Key,
-    transid: u64,
-    data_len: u16,
-    // name_len: u16,
-    r#type: u8,
-    name: String,
+    /// the INODE_ITEM, or for a subvolume the ROOT_ITEM, it points to
+    pub location: Key,
+    pub transid: u64,
+    /// `BTRFS_FT_*`, numbered unlike `d_type`
+    pub r#type: u8,
+    pub name: Vec<u8>,
+}
+
+impl DirItem {
+    const HEADER: usize = 30;
+
+    /// The entries in `buf`, several in a DIR_ITEM whose names hash alike.
+    /// `None` if one is cut short.
+    pub fn parse_all(mut buf: &[u8]) -> Option<Vec<Self>> {
+        let mut ret = vec![];
+        while !buf.is_empty() {
+            let u64_at =
+                |off: usize| Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?));
+            let u16_at =
+                |off: usize| Some(u16::from_le_bytes(buf.get(off..off + 2)?.try_into().ok()?));
+            let (data_len, name_len) = (u16_at(25)? as usize, u16_at(27)? as usize);
+            let name = buf.get(Self::HEADER..Self::HEADER + name_len)?;
+            ret.push(Self {
+                location: Key {
+                    objectid: u64_at(0)?,
+                    r#type: buf[8],
+                    offset: u64_at(9)?,
+                },
+                transid: u64_at(17)?,
+                r#type: buf[29],
+                name: name.to_vec(),
+            });
+            // the data, of XATTR_ITEMs only, follows the name
+            buf = buf.get(Self::HEADER + name_len + data_len..)?;
+        }
+        Some(ret)
+    }
+}
+
+/// One entry of an INODE_REF item, which names the inode in the directory
+/// that is the offset of the item's key.  An inode linked several times
+/// from the same directory has several.
+pub struct InodeRef {
+    /// of the DIR_INDEX entry in the directory
+    pub index: u64,
+    pub name: Vec<u8>,
+}
+
+impl InodeRef {
+    /// The entries in `buf`, `None` if one is cut short.
+    pub fn parse_all(mut buf: &[u8]) -> Option<Vec<Self>> {
+        let mut ret = vec![];
+        while !buf.is_empty() {
+            let index = u64::from_le_bytes(buf.get(..8)?.try_into().ok()?);
+            let name_len = u16::from_le_bytes(buf.get(8..10)?.try_into().ok()?) as usize;
+            ret.push(Self {
+                index,
+                name: buf.get(10..10 + name_len)?.to_vec(),
+            });
+            buf = &buf[10 + name_len..];
+        }
+        Some(ret)
+    }
 }
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    os::fd::BorrowedFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use rustix::io::Errno;
+
+use crate::{
+    btrfs::{ExtentInfo, fs_info, subvol_id},
+    error::Error,
+    fs_util::replace_file,
+    global::config,
+};
+
+const MAGIC: &[u8] = b"XSZCACHE1\n";
+
+/// Where an inode's extents are cached.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    fsid: [u8; 16],
+    subvol: u64,
+    ino: u64,
+}
+
+/// The subvolume a file descriptor lives in, as of when it was first seen.
+#[derive(Clone, Copy)]
+pub struct Subvol {
+    fsid: [u8; 16],
+    id: u64,
+    /// the filesystem's generation; everything written after is newer
+    pub generation: u64,
+}
+
+impl Subvol {
+    /// `None` if the kernel doesn't tell the generation, so nothing on this
+    /// filesystem can be cached.
+    pub fn of(fd: BorrowedFd) -> Result<Option<Self>, Errno> {
+        let (fsid, generation) = fs_info(fd)?;
+        let Some(generation) = generation else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            fsid,
+            id: subvol_id(fd)?,
+            generation,
+        }))
+    }
+
+    pub fn key(&self, ino: u64) -> Key {
+        Key {
+            fsid: self.fsid,
+            subvol: self.id,
+            ino,
+        }
+    }
+}
+
+/// The extents of an inode as of `generation`, holes included.
+pub struct Entry {
+    pub generation: u64,
+    pub extents: Box<[ExtentInfo]>,
+}
+
+/// The `--cache` file: what earlier scans read, and what this one did.
+/// Kept for the next scan of a `--daemon`, which caches in memory only
+/// without a file.
+pub struct Cache {
+    path: Option<PathBuf>,
+    old: RwLock<HashMap<Key, Arc<Entry>>>,
+    new: Mutex<HashMap<Key, Arc<Entry>>>,
+}
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+/// The cache [`open`] loaded, if the scan running uses it.
+#[inline]
+pub fn cache() -> Option<&'static Cache> {
+    CACHE.get().filter(|c| c.path == config().cache)
+}
+
+/// Load the `--cache` file, if one was asked for; a missing one is empty.
+/// Without one, a `--daemon` caches in memory.  A process loads one file,
+/// the scans asking for another fail.
+pub fn open() -> Result<(), Error> {
+    if let Some(cache) = CACHE.get() {
+        return match (&cache.path, &config().cache) {
+            (Some(path), Some(wanted)) if path != wanted => Err(Error::Setup(format!(
+                "{}: this process already uses the cache {}",
+                wanted.display(),
+                path.display()
+            ))),
+            _ => Ok(()),
+        };
+    }
+    let Some(path) = &config().cache else {
+        if config().daemon {
+            CACHE
+                .set(Cache {
+                    path: None,
+                    old: Default::default(),
+                    new: Default::default(),
+                })
+                .ok();
+        }
+        return Ok(());
+    };
+    let old = match File::open(path) {
+        Ok(f) => read(&mut BufReader::new(f))
+            .map_err(|e| io_err(path, e))?
+            .ok_or_else(|| Error::Setup(format!("{}: not an xsz cache", path.display())))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(io_err(path, e)),
+    };
+    CACHE
+        .set(Cache {
+            path: Some(path.clone()),
+            old: RwLock::new(old),
+            new: Default::default(),
+        })
+        .ok();
+    Ok(())
+}
+
+/// Keep what was read this time, along with the earlier entries it didn't
+/// replace, and write it all back to the file.
+pub fn save() -> Result<(), Error> {
+    let Some(cache) = cache() else {
+        return Ok(());
+    };
+    let new = std::mem::take(&mut *cache.new.lock().unwrap());
+    let mut old = cache.old.write().unwrap();
+    old.extend(new);
+    let Some(path) = &cache.path else {
+        return Ok(());
+    };
+    replace_file(path, |w| {
+        w.write_all(MAGIC)?;
+        let mut buf = vec![];
+        for (key, entry) in old.iter() {
+            buf.clear();
+            encode(key, entry, &mut buf);
+            w.write_all(&buf)?;
+        }
+        Ok(())
+    })
+    .map_err(|e| io_err(path, e))
+}
+
+impl Cache {
+    /// What an earlier scan read of the inode at `key`.
+    pub fn get(&self, key: &Key) -> Option<Arc<Entry>> {
+        self.old.read().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: Key, generation: u64, extents: &[ExtentInfo]) {
+        let entry = Entry {
+            generation,
+            extents: extents.into(),
+        };
+        self.new.lock().unwrap().insert(key, Arc::new(entry));
+    }
+}
+
+fn io_err(path: &Path, e: std::io::Error) -> Error {
+    Error::Io {
+        path: path.into(),
+        err: Errno::from_io_error(&e).unwrap_or(Errno::IO),
+    }
+}
+
+/// Each record is the key, the generation, the number of extents and the
+/// extents, all little endian.
+fn encode(key: &Key, entry: &Entry, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&key.fsid);
+    for n in [key.subvol, key.ino, entry.generation] {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+    buf.extend_from_slice(&(entry.extents.len() as u32).to_le_bytes());
+    for extent in &entry.extents {
+        extent.encode(buf);
+    }
+}
+
+/// `None` if `r` holds no cache at all; a truncated last record is dropped.
+fn read(r: &mut impl Read) -> std::io::Result<Option<HashMap<Key, Arc<Entry>>>> {
+    let mut magic = [0; MAGIC.len()];
+    match r.read_exact(&mut magic) {
+        Ok(()) if magic == MAGIC => (),
+        Ok(()) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut ret = HashMap::new();
+    loop {
+        match read_record(r) {
+            Ok(Some((key, entry))) => {
+                ret.insert(key, Arc::new(entry));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Some(ret)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_record(r: &mut impl Read) -> std::io::Result<Option<(Key, Entry)>> {
+    let mut head = [0; 16 + 8 * 3 + 4];
+    r.read_exact(&mut head)?;
+    let u64_at = |i: usize| u64::from_le_bytes(head[i..i + 8].try_into().unwrap());
+    let key = Key {
+        fsid: head[..16].try_into().unwrap(),
+        subvol: u64_at(16),
+        ino: u64_at(24),
+    };
+    let generation = u64_at(32);
+    let n = u32::from_le_bytes(head[40..].try_into().unwrap());
+    let mut extents = Vec::with_capacity((n as usize).min(1024));
+    let mut buf = [0; ExtentInfo::ENCODED_LEN];
+    for _ in 0..n {
+        r.read_exact(&mut buf)?;
+        let Some(extent) = ExtentInfo::decode(key.ino, &buf) else {
+            return Ok(None);
+        };
+        extents.push(extent);
+    }
+    let entry = Entry {
+        generation,
+        extents: extents.into(),
+    };
+    Ok(Some((key, entry)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ino: u64, extents: &[ExtentInfo]) -> (Key, Entry) {
+        let key = Key {
+            fsid: [7; 16],
+            subvol: 256,
+            ino,
+        };
+        let entry = Entry {
+            generation: 1000 + ino,
+            extents: extents.into(),
+        };
+        (key, entry)
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = [
+            record(257, &[ExtentInfo::for_test(257, 1 << 20, 4096)]),
+            record(258, &[]),
+            record(
+                259,
+                &[
+                    ExtentInfo::for_test(259, 0, 100),
+                    ExtentInfo::for_test(259, 2 << 20, 8192),
+                ],
+            ),
+        ];
+        let mut buf = MAGIC.to_vec();
+        for (key, entry) in &records {
+            encode(key, entry, &mut buf);
+        }
+        let cache = read(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(cache.len(), records.len());
+        for (key, entry) in &records {
+            let got = &cache[key];
+            assert_eq!(got.generation, entry.generation);
+            assert!(got.extents == entry.extents);
+        }
+
+        // a truncated last record is dropped, the others kept
+        let cache = read(&mut &buf[..buf.len() - 1]).unwrap().unwrap();
+        assert_eq!(cache.len(), records.len() - 1);
+        assert!(!cache.contains_key(&records[2].0));
+    }
+
+    #[test]
+    fn not_a_cache() {
+        assert!(read(&mut &b""[..]).unwrap().is_none());
+        assert!(read(&mut &b"XSZCACHE0\n"[..]).unwrap().is_none());
+        // an extent of an unknown type
+        let (key, entry) = record(257, &[ExtentInfo::for_test(257, 1 << 20, 4096)]);
+        let mut buf = MAGIC.to_vec();
+        encode(&key, &entry, &mut buf);
+        buf[MAGIC.len() + 44 + 16] = 9;
+        assert!(read(&mut &buf[..]).unwrap().is_none());
+    }
+}
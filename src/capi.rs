@@ -0,0 +1,377 @@
+//! The `capi` feature: [`xsz_scan`] for programs in other languages, built
+//! as a shared library with
+//!
+//! ```sh
+//! cargo rustc --release --lib --features capi --crate-type cdylib
+//! ```
+//!
+//! and declared to C in `include/xsz.h`.  Each call scans with its own
+//! options, see [`crate::Scanner`]; calls from several threads run one at a
+//! time.
+//!
+//! A panic, at the boundary or in a thread of the scan, is caught and
+//! reported as `XSZ_ERR_PANIC`, so the library must be built with the
+//! default `panic = "unwind"`; with `panic = "abort"` it takes the calling
+//! process down.
+
+use std::{
+    ffi::{CStr, CString, OsStr, c_char, c_int, c_void},
+    os::unix::ffi::OsStrExt,
+    panic::{AssertUnwindSafe, catch_unwind},
+    path::Path,
+    ptr,
+    sync::{Mutex, Once, PoisonError},
+};
+
+use rustix::{fs::FileType, io::Errno};
+
+use crate::{
+    Scanner,
+    collector::CompsizeStat,
+    diag,
+    error::Error,
+    report::ALL_FILES,
+    reporter::{Reporter, set_reporter},
+};
+
+/// Rows of the usage table in [`XszStats::rows`].
+pub const XSZ_ROWS: usize = ALL_FILES;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct XszRow {
+    /// NUL terminated, as in the usage table: `none`, `zstd`, `prealloc`...
+    pub name: [c_char; 16],
+    pub disk: u64,
+    pub uncomp: u64,
+    pub refd: u64,
+    pub nfile: u64,
+}
+
+/// The usage table of a scan.
+#[repr(C)]
+pub struct XszStats {
+    pub disk: u64,
+    pub uncomp: u64,
+    pub refd: u64,
+    /// bytes in holes
+    pub sparse: u64,
+    pub nfile: u64,
+    pub nrows: u32,
+    pub rows: [XszRow; XSZ_ROWS],
+}
+
+/// What to scan with; a NULL one means the defaults.
+#[repr(C)]
+pub struct XszOptions {
+    /// threads, 0 for one per CPU; the first call of the process starts
+    /// them, later ones split their work as asked but between as many
+    pub jobs: u8,
+    pub one_fs: bool,
+    pub tree_scan: bool,
+    /// seconds, 0 for none
+    pub timeout: u64,
+    /// called for each path skipped and each note on how the scan went,
+    /// NULL to print them to stderr
+    pub report: Option<XszReport>,
+}
+
+/// [`XszError::kind`], one per variant of [`Error`].
+pub const XSZ_OK: c_int = 0;
+pub const XSZ_ERR_IO: c_int = 1;
+pub const XSZ_ERR_NOT_BTRFS: c_int = 2;
+pub const XSZ_ERR_PARSE: c_int = 3;
+pub const XSZ_ERR_SETUP: c_int = 4;
+pub const XSZ_ERR_CANCELLED: c_int = 5;
+/// xsz panicked, a bug
+pub const XSZ_ERR_PANIC: c_int = 6;
+/// returned without a call, for a NULL callback
+pub const XSZ_ERR_INVALID: c_int = 7;
+
+/// Why a scan stopped.  The strings are only valid during the callback.
+#[repr(C)]
+pub struct XszError {
+    pub kind: c_int,
+    /// with `XSZ_ERR_IO`, else 0
+    pub errno: c_int,
+    /// the path the error is about, NULL if none
+    pub path: *const c_char,
+    /// the whole message, as xsz prints it
+    pub message: *const c_char,
+}
+
+pub type XszCallback =
+    extern "C" fn(stats: *const XszStats, error: *const XszError, user_data: *mut c_void);
+
+/// [`XszOptions::report`]: a path skipped, with the errno if it was an I/O
+/// error, else 0, or a note with a NULL path.  The strings are only valid
+/// during the call.
+pub type XszReport = extern "C" fn(
+    path: *const c_char,
+    errno: c_int,
+    message: *const c_char,
+    user_data: *mut c_void,
+);
+
+/// The [`XszOptions::report`] of the running call and its user data.
+static REPORT: Mutex<Option<(XszReport, usize)>> = Mutex::new(None);
+/// Calls run one at a time, the scan state being process wide.
+static CALLS: Mutex<()> = Mutex::new(());
+
+/// Hands diagnostics to the [`XszReport`] of the running call, if any,
+/// else to [`diag::Stderr`].
+struct CReporter;
+
+impl CReporter {
+    /// `false` if the call has no [`XszReport`].
+    fn report(&self, path: Option<&Path>, errno: c_int, message: &str) -> bool {
+        let Some((report, user_data)) = *REPORT.lock().unwrap_or_else(PoisonError::into_inner)
+        else {
+            return false;
+        };
+        let path = path.map(|p| cstring(p.as_os_str().as_bytes()));
+        let message = cstring(message.as_bytes());
+        report(
+            path.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+            errno,
+            message.as_ptr(),
+            user_data as *mut c_void,
+        );
+        true
+    }
+}
+
+impl Reporter for CReporter {
+    fn file_error(&self, path: &Path, err: Errno) {
+        let msg = std::io::Error::from(err).to_string();
+        if !self.report(Some(path), err.raw_os_error(), &msg) {
+            diag::Stderr.file_error(path, err);
+        }
+    }
+    fn parse_error(&self, path: &Path, msg: &str) {
+        if !self.report(Some(path), 0, msg) {
+            diag::Stderr.parse_error(path, msg);
+        }
+    }
+    fn not_btrfs(&self, path: &Path) {
+        if !self.report(Some(path), 0, "not on btrfs") {
+            diag::Stderr.not_btrfs(path);
+        }
+    }
+    fn special(&self, path: &Path, kind: FileType) {
+        if !self.report(Some(path), 0, "special file, left out") {
+            diag::Stderr.special(path, kind);
+        }
+    }
+    fn note(&self, msg: &str) {
+        if !self.report(None, 0, msg) {
+            diag::Stderr.note(msg);
+        }
+    }
+    fn finished(&self) {
+        if REPORT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_none()
+        {
+            diag::Stderr.finished();
+        }
+    }
+}
+
+/// `bytes` as a C string, without the NULs it can't hold.
+fn cstring(bytes: &[u8]) -> CString {
+    CString::new(
+        bytes
+            .iter()
+            .copied()
+            .filter(|&b| b != 0)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap()
+}
+
+impl From<&CompsizeStat> for XszStats {
+    fn from(stat: &CompsizeStat) -> Self {
+        let total = stat.total();
+        let mut rows = [XszRow {
+            name: [0; 16],
+            disk: 0,
+            uncomp: 0,
+            refd: 0,
+            nfile: 0,
+        }; XSZ_ROWS];
+        for (i, (row, (name, s))) in rows.iter_mut().zip(stat.rows()).enumerate() {
+            // the last byte stays NUL
+            for (dst, &b) in row.name[..15].iter_mut().zip(name.as_bytes()) {
+                *dst = b as c_char;
+            }
+            row.disk = s.disk;
+            row.uncomp = s.uncomp;
+            row.refd = s.refd;
+            row.nfile = stat.nfile[i];
+        }
+        Self {
+            disk: total.disk,
+            uncomp: total.uncomp,
+            refd: total.refd,
+            sparse: stat.sparse,
+            nfile: stat.nfile_total,
+            nrows: XSZ_ROWS as u32,
+            rows,
+        }
+    }
+}
+
+/// Hand `err` to `callback`, returning its kind.
+fn report_error(err: &Error, callback: XszCallback, user_data: *mut c_void) -> c_int {
+    let (kind, errno, path) = match err {
+        Error::Io { path, err } => (XSZ_ERR_IO, err.raw_os_error(), Some(path)),
+        Error::NotBtrfs { path } => (XSZ_ERR_NOT_BTRFS, 0, Some(path)),
+        Error::Parse { path, .. } => (XSZ_ERR_PARSE, 0, Some(path)),
+        Error::Setup(_) => (XSZ_ERR_SETUP, 0, None),
+        Error::Panicked => (XSZ_ERR_PANIC, 0, None),
+        Error::Cancelled => (XSZ_ERR_CANCELLED, 0, None),
+    };
+    let path = path.map(|p| cstring(p.as_os_str().as_bytes()));
+    let message = cstring(err.to_string().as_bytes());
+    let error = XszError {
+        kind,
+        errno,
+        path: path.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+        message: message.as_ptr(),
+    };
+    callback(ptr::null(), &error, user_data);
+    kind
+}
+
+/// Hand a panic to `callback`.
+fn report_panic(callback: XszCallback, user_data: *mut c_void) -> c_int {
+    let message = cstring(b"xsz panicked, a bug: the scan stopped");
+    let error = XszError {
+        kind: XSZ_ERR_PANIC,
+        errno: 0,
+        path: ptr::null(),
+        message: message.as_ptr(),
+    };
+    callback(ptr::null(), &error, user_data);
+    XSZ_ERR_PANIC
+}
+
+/// Scan the NULL terminated array `paths` and call `callback` once, with
+/// the usage table or with why the scan stopped, the other one NULL.
+/// Returns `XSZ_OK` or the kind of the error; `XSZ_ERR_INVALID` without a
+/// call if `callback` is NULL.
+///
+/// # Safety
+///
+/// `paths` must be a NULL terminated array of NUL terminated strings, and
+/// `options` NULL or valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn xsz_scan(
+    paths: *const *const c_char,
+    options: *const XszOptions,
+    callback: Option<XszCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(callback) = callback else {
+        return XSZ_ERR_INVALID;
+    };
+    let _call = CALLS.lock().unwrap_or_else(PoisonError::into_inner);
+    static REPORTER: Once = Once::new();
+    // a Rust program may have set its own, then it gets the diagnostics
+    REPORTER.call_once(|| {
+        set_reporter(Box::new(CReporter)).ok();
+    });
+    // SAFETY: NULL or valid, as the caller promises
+    let options = unsafe { options.as_ref() };
+    *REPORT.lock().unwrap_or_else(PoisonError::into_inner) = options
+        .and_then(|o| o.report)
+        .map(|report| (report, user_data as usize));
+    let ret = catch_unwind(AssertUnwindSafe(|| unsafe { scan(paths, options) }));
+    REPORT.lock().unwrap_or_else(PoisonError::into_inner).take();
+    match ret {
+        Ok(Ok(stat)) => {
+            callback(&XszStats::from(&stat), ptr::null(), user_data);
+            XSZ_OK
+        }
+        Ok(Err(e)) => report_error(&e, callback, user_data),
+        Err(_) => report_panic(callback, user_data),
+    }
+}
+
+/// The scan of [`xsz_scan`].
+///
+/// # Safety
+///
+/// As for [`xsz_scan`].
+unsafe fn scan(
+    paths: *const *const c_char,
+    options: Option<&XszOptions>,
+) -> Result<CompsizeStat, Error> {
+    let mut args = vec![];
+    if !paths.is_null() {
+        let mut p = paths;
+        // SAFETY: NULL terminated, as the caller promises
+        while let Some(path) = unsafe { p.read().as_ref() } {
+            let path = unsafe { CStr::from_ptr(path) };
+            args.push(Path::new(OsStr::from_bytes(path.to_bytes())));
+            p = unsafe { p.add(1) };
+        }
+    }
+    let mut scanner = Scanner::new().paths(args);
+    if let Some(options) = options {
+        scanner = scanner
+            .jobs(options.jobs)
+            .one_fs(options.one_fs)
+            .tree_scan(options.tree_scan);
+        if options.timeout != 0 {
+            scanner = scanner.timeout(options.timeout);
+        }
+    }
+    scanner.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use super::*;
+    use crate::executor::PANIC_IN_TASK;
+
+    static KIND: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn callback(_: *const XszStats, error: *const XszError, _: *mut c_void) {
+        let kind = unsafe { error.as_ref() }.map_or(XSZ_OK, |e| e.kind);
+        KIND.store(kind, Ordering::Relaxed);
+    }
+
+    fn scan(path: &CStr) -> c_int {
+        let paths = [path.as_ptr(), ptr::null()];
+        let options = XszOptions {
+            jobs: 2,
+            one_fs: false,
+            tree_scan: false,
+            timeout: 0,
+            report: None,
+        };
+        let ret = unsafe { xsz_scan(paths.as_ptr(), &options, Some(callback), ptr::null_mut()) };
+        assert_eq!(ret, KIND.load(Ordering::Relaxed));
+        ret
+    }
+
+    #[test]
+    fn panic_in_a_task() {
+        let dir = std::env::temp_dir().join(format!("xsz-capi-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"data").unwrap();
+        let path = cstring(dir.as_os_str().as_bytes());
+        PANIC_IN_TASK.store(true, Ordering::Relaxed);
+        let ret = scan(&path);
+        PANIC_IN_TASK.store(false, Ordering::Relaxed);
+        assert_eq!(ret, XSZ_ERR_PANIC);
+        // the threads of the pool outlived it
+        assert_ne!(scan(&path), XSZ_ERR_PANIC);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
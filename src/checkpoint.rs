@@ -0,0 +1,358 @@
+//! `--checkpoint` and `--resume`: what a walk has counted so far and the
+//! directories it has yet to read, so an interrupted scan can go on from
+//! there.
+//!
+//! The file is the magic, then little endian integers: the paths scanned
+//! and the options they were scanned with, the number of files and paths
+//! skipped, the file counts per root, the usage table, the extents seen on
+//! each filesystem as delta encoded varints, the hard links counted, and
+//! the directories left.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use rustix::io::Errno;
+
+use crate::{
+    btrfs::SizeStat,
+    collector::Snapshot,
+    error::Error,
+    fs_util::{RootId, replace_file},
+    report::{APPARENT, RootCounts},
+    walkdir::PendingDir,
+};
+
+const MAGIC: &[u8] = b"XSZCKPT3\n";
+
+pub struct Checkpoint {
+    /// the command line paths and the options changing what the walk
+    /// counts, which a resumed scan must be given again
+    pub args: Vec<PathBuf>,
+    pub options: Vec<String>,
+    pub nfile: u64,
+    pub skipped: u64,
+    pub counts: Vec<RootCounts>,
+    pub snapshot: Snapshot,
+    /// `--count-links once`: the hard links counted, see [`crate::filter::links`]
+    pub links: Vec<(u64, u64)>,
+    pub pending: Vec<PendingDir>,
+}
+
+impl Checkpoint {
+    /// Write to `path`, replacing it only once complete.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut buf = MAGIC.to_vec();
+        self.encode(&mut buf);
+        replace_file(path, |w| w.write_all(&buf)).map_err(|e| io_err(path, e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let buf = fs::read(path).map_err(|e| io_err(path, e))?;
+        buf.strip_prefix(MAGIC)
+            .and_then(|buf| Self::decode(&mut Reader(buf)))
+            .ok_or_else(|| {
+                Error::Setup(format!(
+                    "{}: not an xsz checkpoint, or a truncated one",
+                    path.display()
+                ))
+            })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.args.len() as u64);
+        for arg in &self.args {
+            put_bytes(buf, arg.as_os_str().as_bytes());
+        }
+        put_u64(buf, self.options.len() as u64);
+        for option in &self.options {
+            put_bytes(buf, option.as_bytes());
+        }
+        put_u64(buf, self.nfile);
+        put_u64(buf, self.skipped);
+        put_u64(buf, self.counts.len() as u64);
+        for c in self.counts.iter().flatten() {
+            put_u64(buf, *c);
+        }
+        let s = &self.snapshot;
+        let rows: Vec<_> = s.stat.rows().collect();
+        put_u64(buf, rows.len() as u64);
+        for (name, row) in rows {
+            put_bytes(buf, name.as_bytes());
+            put_size(buf, row);
+        }
+        for n in [
+            s.stat.sparse,
+            s.nextent,
+            s.ninline,
+            s.inline_disk,
+            s.csum_disk,
+            s.nunique,
+        ] {
+            put_u64(buf, n);
+        }
+        put_u64(buf, s.per_fs.len() as u64);
+        for (fsid, path, stat) in &s.per_fs {
+            buf.extend_from_slice(fsid);
+            put_bytes(buf, path.as_os_str().as_bytes());
+            put_size(buf, stat);
+        }
+        for sets in [&s.extents, &s.inline_inos] {
+            put_u64(buf, sets.len() as u64);
+            for keys in sets {
+                put_set(buf, keys);
+            }
+        }
+        put_u64(buf, self.links.len() as u64);
+        for &(dev, ino) in &self.links {
+            put_u64(buf, dev);
+            put_u64(buf, ino);
+        }
+        put_u64(buf, self.pending.len() as u64);
+        for dir in &self.pending {
+            put_u64(buf, dir.root as u64);
+            put_u64(buf, dir.depth as u64);
+            put_bytes(buf, dir.path.as_os_str().as_bytes());
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Option<Self> {
+        let args = (0..r.u64()?)
+            .map(|_| Some(Path::new(OsStr::from_bytes(r.bytes()?)).into()))
+            .collect::<Option<_>>()?;
+        let options = (0..r.u64()?)
+            .map(|_| String::from_utf8(r.bytes()?.to_vec()).ok())
+            .collect::<Option<_>>()?;
+        let nfile = r.u64()?;
+        let skipped = r.u64()?;
+        let counts = (0..r.u64()?)
+            .map(|_| {
+                let mut c = [0; APPARENT + 1];
+                for n in &mut c {
+                    *n = r.u64()?;
+                }
+                Some(c)
+            })
+            .collect::<Option<_>>()?;
+        let mut snapshot = Snapshot::default();
+        for _ in 0..r.u64()? {
+            let name = std::str::from_utf8(r.bytes()?).ok()?;
+            let size = r.size()?;
+            *snapshot.stat.row_mut(name)?.0 = size;
+        }
+        snapshot.stat.sparse = r.u64()?;
+        snapshot.nextent = r.u64()?;
+        snapshot.ninline = r.u64()?;
+        snapshot.inline_disk = r.u64()?;
+        snapshot.csum_disk = r.u64()?;
+        snapshot.nunique = r.u64()?;
+        snapshot.per_fs = (0..r.u64()?)
+            .map(|_| {
+                let fsid = r.take(16)?.try_into().ok()?;
+                let path = Path::new(OsStr::from_bytes(r.bytes()?)).into();
+                Some((fsid, path, r.size()?))
+            })
+            .collect::<Option<_>>()?;
+        for sets in [&mut snapshot.extents, &mut snapshot.inline_inos] {
+            let n = r.u64()?;
+            // one set per filesystem
+            if n > snapshot.per_fs.len() as u64 {
+                return None;
+            }
+            *sets = (0..n).map(|_| r.set()).collect::<Option<_>>()?;
+        }
+        let links = (0..r.u64()?)
+            .map(|_| Some((r.u64()?, r.u64()?)))
+            .collect::<Option<_>>()?;
+        let pending = (0..r.u64()?)
+            .map(|_| {
+                let root = RootId::try_from(r.u64()?).ok()?;
+                let depth = u32::try_from(r.u64()?).ok()?;
+                let path = Path::new(OsStr::from_bytes(r.bytes()?)).into();
+                Some(PendingDir { path, root, depth })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            args,
+            options,
+            nfile,
+            skipped,
+            counts,
+            snapshot,
+            links,
+            pending,
+        })
+    }
+}
+
+fn io_err(path: &Path, e: std::io::Error) -> Error {
+    Error::Io {
+        path: path.into(),
+        err: Errno::from_io_error(&e).unwrap_or(Errno::IO),
+    }
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_size(buf: &mut Vec<u8>, s: &SizeStat) {
+    for n in [s.disk, s.uncomp, s.refd] {
+        put_u64(buf, n);
+    }
+}
+
+/// The number of `keys`, then each as the LEB128 varint of its distance
+/// from the one before, in order.  Extents are mostly close together.
+fn put_set(buf: &mut Vec<u8>, keys: &[u64]) {
+    let mut keys = keys.to_vec();
+    keys.sort_unstable();
+    put_u64(buf, keys.len() as u64);
+    let mut last = 0;
+    for k in keys {
+        let mut delta = k - last;
+        last = k;
+        while delta >= 0x80 {
+            buf.push(delta as u8 | 0x80);
+            delta >>= 7;
+        }
+        buf.push(delta as u8);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, rest) = self.0.split_at_checked(n)?;
+        self.0 = rest;
+        Some(head)
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).ok()?)
+    }
+    fn size(&mut self) -> Option<SizeStat> {
+        Some(SizeStat {
+            disk: self.u64()?,
+            uncomp: self.u64()?,
+            refd: self.u64()?,
+        })
+    }
+    fn set(&mut self) -> Option<Vec<u64>> {
+        let n = self.u64()?;
+        // each key takes at least a byte
+        let mut keys = Vec::with_capacity(n.min(self.0.len() as u64) as usize);
+        let mut last: u64 = 0;
+        for _ in 0..n {
+            let mut delta = 0u64;
+            for shift in (0..64).step_by(7) {
+                let b = *self.take(1)?.first()?;
+                delta |= ((b & 0x7f) as u64) << shift;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            last = last.checked_add(delta)?;
+            keys.push(last);
+        }
+        Some(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint() -> Checkpoint {
+        let mut snapshot = Snapshot::default();
+        *snapshot.stat.row_mut("zstd").unwrap().0 = SizeStat {
+            disk: 1 << 20,
+            uncomp: 3 << 20,
+            refd: 5 << 20,
+        };
+        snapshot.stat.sparse = 12345;
+        (snapshot.nextent, snapshot.ninline, snapshot.nunique) = (40, 3, 30);
+        (snapshot.inline_disk, snapshot.csum_disk) = (300, 1 << 20);
+        snapshot.per_fs = vec![
+            ([1; 16], Path::new("/a").into(), SizeStat::default()),
+            ([2; 16], Path::new("/b").into(), SizeStat::default()),
+        ];
+        snapshot.extents = vec![vec![1 << 40, 4096, 8192], vec![]];
+        snapshot.inline_inos = vec![vec![257, 300]];
+        let mut counts = [0; APPARENT + 1];
+        counts[APPARENT] = 99;
+        Checkpoint {
+            args: vec!["/a".into(), "/b/\u{e9}".into()],
+            options: vec!["--one-fs".into(), "--exclude=*.o".into()],
+            nfile: 7,
+            skipped: 1,
+            counts: vec![counts, [1; APPARENT + 1]],
+            snapshot,
+            links: vec![(2049, 257), (2050, 1 << 33)],
+            pending: vec![PendingDir {
+                path: Path::new("/a/dir").into(),
+                root: 1,
+                depth: 3,
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let saved = checkpoint();
+        let mut buf = vec![];
+        saved.encode(&mut buf);
+        let c = Checkpoint::decode(&mut Reader(&buf)).unwrap();
+        assert_eq!(c.args, saved.args);
+        assert_eq!(c.options, saved.options);
+        assert_eq!((c.nfile, c.skipped), (7, 1));
+        assert_eq!(c.counts, saved.counts);
+        let (s, t) = (&c.snapshot, &saved.snapshot);
+        assert!(s.stat.rows().eq(t.stat.rows()));
+        assert_eq!(s.stat.sparse, t.stat.sparse);
+        assert_eq!(
+            [s.nextent, s.ninline, s.inline_disk, s.csum_disk, s.nunique],
+            [t.nextent, t.ninline, t.inline_disk, t.csum_disk, t.nunique]
+        );
+        assert_eq!(s.per_fs, t.per_fs);
+        assert_eq!(s.extents, [vec![4096, 8192, 1 << 40], vec![]]);
+        assert_eq!(s.inline_inos, t.inline_inos);
+        assert_eq!(c.links, saved.links);
+        let [dir] = &c.pending[..] else {
+            panic!("{} pending directories", c.pending.len());
+        };
+        assert_eq!(
+            (&*dir.path, dir.root, dir.depth),
+            (Path::new("/a/dir"), 1, 3)
+        );
+
+        for n in 0..buf.len() {
+            assert!(
+                Checkpoint::decode(&mut Reader(&buf[..n])).is_none(),
+                "{}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn more_sets_than_filesystems() {
+        let mut saved = checkpoint();
+        saved.snapshot.extents.push(vec![4096]);
+        let mut buf = vec![];
+        saved.encode(&mut buf);
+        assert!(Checkpoint::decode(&mut Reader(&buf)).is_none());
+    }
+}
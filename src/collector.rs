@@ -0,0 +1,2482 @@
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    io::{Write, stderr, stdout},
+    mem::take,
+    num::NonZeroU64,
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use nohash::IntSet;
+
+use crate::{
+    actor::Actor,
+    btrfs::{
+        ExtentInfo, SizeStat,
+        tree::{Compression, ExtentType},
+    },
+    error::Error,
+    estimate::Codec,
+    extent_set::ExtentSet,
+    fs_util::{FsId, RootId, SubvolId, filesystems, register_fs, replace_file},
+    global::{Column, Format, TopBy, config, get_err},
+    report::{ALL_FILES, APPARENT, FileReport, PREALLOC_ROW, RootCounts, json_path, json_str},
+    reporter::reporter,
+    scale::Scale,
+    spill::Spill,
+};
+
+pub trait ExtentInfoSink: Any + Send {
+    fn duplic(&mut self, extent: &ExtentInfo);
+    fn unique(&mut self, extent: &ExtentInfo);
+    /// Account a hole, see [`ExtentInfo::is_hole`].
+    fn hole(&mut self, _extent: &ExtentInfo) {}
+    /// Make `extent`, passed to [`Self::unique`] after an equal one, a
+    /// duplicate after all, see [`crate::spill`].
+    fn recounted(&mut self, _extent: &ExtentInfo) {}
+    /// Add the statistics of `other`, of the same type, gathered over
+    /// other extents, see [`Collector::merge`].
+    fn merge(&mut self, other: &dyn Any);
+    fn fmt(&self, f: &mut dyn Write, use_bytes: bool) -> std::io::Result<()>;
+    /// Write the statistics as members of the enclosing JSON object.
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()>;
+    /// Write the statistics as a header line and rows of `sep` separated
+    /// fields, sizes in bytes.
+    fn fmt_delimited(&self, f: &mut dyn Write, sep: char) -> std::io::Result<()>;
+    fn as_compsize(&self) -> Option<&CompsizeStat> {
+        None
+    }
+    /// Record the number of files in total and using each row, and the
+    /// sum of their sizes.
+    fn set_nfile(&mut self, _total: u64, _rows: [u64; ALL_FILES], _apparent: u64) {}
+}
+
+#[derive(Debug)]
+struct FragStat {
+    min: u64,
+    max: u64,
+    count: u64,
+    sum: u64,
+    bins: [u64; Self::FRAG_BINS],
+}
+
+impl FragStat {
+    const FRAG_BINS: usize = 16;
+    fn new() -> Self {
+        Self {
+            min: u64::MAX,
+            max: u64::MIN,
+            count: 0,
+            sum: 0,
+            bins: [0; Self::FRAG_BINS],
+        }
+    }
+    fn record(&mut self, len: u64) {
+        if len == 0 {
+            return;
+        }
+        self.min = self.min.min(len);
+        self.max = self.max.max(len);
+        self.count += 1;
+        self.sum += len;
+        let idx = if len < 4096 {
+            0
+        } else {
+            let bit = 63 - len.leading_zeros() as usize;
+            (bit.saturating_sub(12)).min(Self::FRAG_BINS - 1)
+        };
+        self.bins[idx] += 1;
+    }
+
+    fn avg(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.sum += other.sum;
+        for (b, o) in self.bins.iter_mut().zip(other.bins) {
+            *b += o;
+        }
+    }
+
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        write!(
+            f,
+            "{{\"count\": {}, \"min\": {}, \"max\": {}, \"avg\": {}, \"bins\": [",
+            self.count,
+            if self.count == 0 { 0 } else { self.min },
+            self.max,
+            self.avg(),
+        )?;
+        for (i, cnt) in self.bins.iter().enumerate() {
+            // lower bound of the bin, see `record`
+            let lo = if i == 0 { 0 } else { 1u64 << (i + 12) };
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{}{{\"min\": {}, \"count\": {}}}", sep, lo, cnt)?;
+        }
+        write!(f, "]}}")
+    }
+
+    fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        let scale = Scale::BINARY;
+        struct BinLabel {
+            lo: u64,
+            hi: Option<NonZeroU64>,
+        }
+
+        impl Display for BinLabel {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let s = Scale::BINARY;
+                match self.hi {
+                    Some(hi) => write!(f, "{:>4}- {:>4}", s.scale(self.lo), s.scale(hi.into())),
+                    None => write!(f, "    >={:>4}", s.scale(self.lo)),
+                }
+            }
+        }
+
+        let bin_label = |idx| {
+            if idx == 0 {
+                BinLabel {
+                    lo: 0,
+                    hi: NonZeroU64::new(4096),
+                }
+            } else {
+                let lo = 1u64 << (idx + 12);
+                if idx < Self::FRAG_BINS - 1 {
+                    BinLabel {
+                        lo,
+                        hi: NonZeroU64::new(lo << 1),
+                    }
+                } else {
+                    BinLabel { lo, hi: None }
+                }
+            }
+        };
+
+        writeln!(
+            f,
+            "  Count: {}, Min: {}, Max: {}, Avg: {}",
+            self.count,
+            scale.scale(self.min),
+            scale.scale(self.max),
+            scale.scale(self.avg()),
+        )?;
+        if self.count == 0 {
+            return Ok(());
+        }
+        writeln!(f, "  Distribution:")?;
+        for (i, &cnt) in self.bins.iter().enumerate() {
+            if cnt > 0 {
+                let pct = cnt * 1000 / self.count;
+                let label = bin_label(i);
+                writeln!(
+                    f,
+                    "    {}: {:>6} ({:>2}.{}%)",
+                    label,
+                    cnt,
+                    pct / 10,
+                    pct % 10
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files stored inline, and the small files by size around the inline
+/// limit: 512 byte bins up to 4K, then one up to 8K.
+#[derive(Default)]
+struct InlineStat {
+    nfile: u64,
+    disk: u64,
+    uncomp: u64,
+    /// files, and how many of them are inline, per bin
+    bins: [(u64, u64); Self::BINS],
+}
+
+impl InlineStat {
+    const BINS: usize = 9;
+    const BIN: u64 = 512;
+
+    fn record(&mut self, file: &FileReport) {
+        let inline = file.is_inline();
+        if inline {
+            self.nfile += 1;
+            self.disk += file.inline_disk;
+            self.uncomp += file.total().uncomp;
+        }
+        let size = file.total().refd + file.sparse;
+        if size == 0 || size >= 2 * Self::BIN * (Self::BINS as u64 - 1) {
+            return;
+        }
+        let bin = &mut self.bins[(size / Self::BIN).min(Self::BINS as u64 - 1) as usize];
+        bin.0 += 1;
+        bin.1 += inline as u64;
+    }
+
+    /// The size range of bin `i`.
+    fn range(i: usize) -> (u64, u64) {
+        let lo = i as u64 * Self::BIN;
+        if i == Self::BINS - 1 {
+            (lo, 2 * lo - 1)
+        } else {
+            (lo, lo + Self::BIN - 1)
+        }
+    }
+
+    fn fmt(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Inline: {} files, {} on disk, {} uncompressed",
+            self.nfile,
+            scale.scale(self.disk),
+            scale.scale(self.uncomp)
+        )?;
+        writeln!(f, "{:<12} {:>10} {:>10}", "Size", "Files", "Inline")?;
+        for (i, (nfile, ninline)) in self.bins.iter().enumerate() {
+            let (lo, hi) = Self::range(i);
+            let pct = (ninline * 100).checked_div(*nfile).unwrap_or(0);
+            writeln!(
+                f,
+                "{:>5}-{:<6} {:>10} {:>10} {:>3}%",
+                lo, hi, nfile, ninline, pct
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        write!(
+            f,
+            "{{\"files\": {}, \"disk\": {}, \"uncompressed\": {}, \"bins\": [",
+            self.nfile, self.disk, self.uncomp
+        )?;
+        for (i, (nfile, ninline)) in self.bins.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            let (lo, hi) = Self::range(i);
+            write!(
+                f,
+                "{}{{\"min\": {}, \"max\": {}, \"files\": {}, \"inline\": {}}}",
+                sep, lo, hi, nfile, ninline
+            )?;
+        }
+        write!(f, "]}}")
+    }
+}
+
+/// Number of extents per file, in power of two bins: 1, 2-3, 4-7, ...
+struct ExtentCountStat {
+    nfile: u64,
+    max: u64,
+    sum: u64,
+    bins: [u64; Self::BINS],
+}
+
+impl ExtentCountStat {
+    const BINS: usize = 12;
+    fn new() -> Self {
+        Self {
+            nfile: 0,
+            max: 0,
+            sum: 0,
+            bins: [0; Self::BINS],
+        }
+    }
+    fn record(&mut self, nextent: u64) {
+        if nextent == 0 {
+            return;
+        }
+        self.nfile += 1;
+        self.max = self.max.max(nextent);
+        self.sum += nextent;
+        let idx = (63 - nextent.leading_zeros() as usize).min(Self::BINS - 1);
+        self.bins[idx] += 1;
+    }
+
+    fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            f,
+            "  Files: {}, Max: {}, Avg: {}",
+            self.nfile,
+            self.max,
+            self.sum.checked_div(self.nfile).unwrap_or(0)
+        )?;
+        if self.nfile == 0 {
+            return Ok(());
+        }
+        writeln!(f, "  Distribution:")?;
+        for (i, &cnt) in self.bins.iter().enumerate() {
+            if cnt > 0 {
+                let lo = 1u64 << i;
+                let label = if i < Self::BINS - 1 {
+                    format!("{:>4}- {:>4}", lo, (lo << 1) - 1)
+                } else {
+                    format!("    >={:>4}", lo)
+                };
+                let pct = cnt * 1000 / self.nfile;
+                writeln!(
+                    f,
+                    "    {}: {:>6} ({:>2}.{}%)",
+                    label,
+                    cnt,
+                    pct / 10,
+                    pct % 10
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        write!(
+            f,
+            "{{\"files\": {}, \"max\": {}, \"avg\": {}, \"bins\": [",
+            self.nfile,
+            self.max,
+            self.sum.checked_div(self.nfile).unwrap_or(0)
+        )?;
+        for (i, cnt) in self.bins.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{}{{\"min\": {}, \"count\": {}}}", sep, 1u64 << i, cnt)?;
+        }
+        write!(f, "]}}")
+    }
+}
+
+struct XFragStat {
+    refd: FragStat,
+}
+
+impl XFragStat {
+    fn new() -> Self {
+        Self {
+            refd: FragStat::new(),
+        }
+    }
+}
+
+impl ExtentInfoSink for XFragStat {
+    fn duplic(&mut self, extent: &ExtentInfo) {
+        self.unique(extent);
+    }
+
+    fn unique(&mut self, extent: &ExtentInfo) {
+        self.refd.record(extent.stat().uncomp);
+    }
+
+    fn merge(&mut self, other: &dyn Any) {
+        if let Some(other) = other.downcast_ref::<Self>() {
+            self.refd.merge(&other.refd);
+        }
+    }
+
+    fn fmt(&self, f: &mut dyn Write, _: bool) -> std::io::Result<()> {
+        writeln!(f, "File extent size distribution:")?;
+        self.refd.fmt(f)?;
+        Ok(())
+    }
+
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        write!(f, "  \"fragments\": ")?;
+        self.refd.fmt_json(f)
+    }
+
+    fn fmt_delimited(&self, f: &mut dyn Write, sep: char) -> std::io::Result<()> {
+        writeln!(f, "min{}count", sep)?;
+        for (i, cnt) in self.refd.bins.iter().enumerate() {
+            let lo = if i == 0 { 0 } else { 1u64 << (i + 12) };
+            writeln!(f, "{}{}{}", lo, sep, cnt)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CompsizeStat {
+    prealloc: SizeStat,
+    stat: [SizeStat; Compression::ALL.len()],
+    /// bytes referenced by files but unallocated, in holes
+    pub sparse: u64,
+    /// files using each row, prealloc last, and files in total
+    pub nfile: [u64; ALL_FILES],
+    pub nfile_total: u64,
+    /// sum of the file sizes, with `--columns apparent`
+    pub apparent: u64,
+}
+
+impl ExtentInfoSink for CompsizeStat {
+    fn duplic(&mut self, extent: &ExtentInfo) {
+        let comp = extent.comp();
+        let stat = extent.stat();
+        match extent.r#type() {
+            ExtentType::Inline | ExtentType::Regular => {
+                self.stat[comp.as_usize()].refd += stat.refd;
+            }
+            ExtentType::Prealloc => {
+                self.prealloc.refd += stat.refd;
+            }
+        }
+    }
+
+    fn unique(&mut self, extent: &ExtentInfo) {
+        let comp = extent.comp();
+        let stat = extent.stat();
+        match extent.r#type() {
+            ExtentType::Inline | ExtentType::Regular => {
+                self.stat[comp.as_usize()].disk += stat.disk;
+                self.stat[comp.as_usize()].uncomp += stat.uncomp;
+                self.stat[comp.as_usize()].refd += stat.refd;
+            }
+            ExtentType::Prealloc => {
+                self.prealloc.disk += stat.disk;
+                self.prealloc.uncomp += stat.uncomp;
+                self.prealloc.refd += stat.refd;
+            }
+        }
+    }
+    fn hole(&mut self, extent: &ExtentInfo) {
+        self.sparse += extent.stat().refd;
+    }
+    fn recounted(&mut self, extent: &ExtentInfo) {
+        let stat = extent.stat();
+        let row = match extent.r#type() {
+            ExtentType::Inline | ExtentType::Regular => &mut self.stat[extent.comp().as_usize()],
+            ExtentType::Prealloc => &mut self.prealloc,
+        };
+        row.disk -= stat.disk;
+        row.uncomp -= stat.uncomp;
+    }
+    fn merge(&mut self, other: &dyn Any) {
+        if let Some(other) = other.downcast_ref::<Self>() {
+            CompsizeStat::merge(self, other);
+        }
+    }
+    // example compsize output format:
+    // Processed 3356969 files, 653492 regular extents (2242077 refs), 2018321 inline.
+    // Type       Perc     Disk Usage   Uncompressed Referenced
+    // TOTAL       78%     100146085502 127182733170 481020538738
+    // none       100%     88797796415  88797796415  364255758399
+    // zstd        29%     11348289087  38384936755  116764780339
+    fn fmt(&self, f: &mut dyn Write, use_bytes: bool) -> std::io::Result<()> {
+        let scale = if use_bytes {
+            Scale::Bytes
+        } else {
+            Scale::human()
+        };
+        let columns = config().columns();
+        // total
+        self.write_total(f, scale, &columns)?;
+        // normal, then prealloc
+        let total = self.total();
+        let color = config().color();
+        for (i, ((name, s), nfile)) in self.rows().zip(self.nfile).enumerate() {
+            if !s.is_empty() {
+                let mut line = Vec::new();
+                write_table_columns(
+                    &mut line,
+                    [
+                        &name,
+                        &format!("{:>3}%", s.get_percent()),
+                        &scale.scale(s.disk),
+                        &scale.scale(s.uncomp),
+                        &scale.scale(s.refd),
+                        &nfile,
+                    ],
+                    &extra_columns(&columns, s, &total, None, scale),
+                )?;
+                match color.then(|| highlight(Compression::ALL.get(i), s, &total)) {
+                    Some(Some(sgr)) => {
+                        let line = String::from_utf8_lossy(line.trim_ascii_end());
+                        writeln!(f, "\x1b[{}m{}\x1b[0m", sgr, line)?;
+                    }
+                    _ => f.write_all(&line)?,
+                }
+            }
+        }
+        if self.sparse > 0 {
+            write_table(f, "sparse", "", "", "", scale.scale(self.sparse), "")?;
+        }
+        Ok(())
+    }
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "  \"usage\": {{")?;
+        write!(
+            f,
+            "    \"TOTAL\": {}",
+            json_size(&self.total(), self.nfile_total)
+        )?;
+        for ((name, s), nfile) in self.rows().zip(self.nfile) {
+            write!(f, ",\n    \"{}\": {}", name, json_size(s, nfile))?;
+        }
+        write!(f, "\n  }},\n  \"sparse\": {}", self.sparse)?;
+        if config().apparent() {
+            write!(f, ",\n  \"apparent\": {}", self.apparent)?;
+        }
+        Ok(())
+    }
+    fn fmt_delimited(&self, f: &mut dyn Write, sep: char) -> std::io::Result<()> {
+        let total = self.total();
+        let rows = [("TOTAL", &total)].into_iter().chain(self.rows());
+        let nfiles = [self.nfile_total].into_iter().chain(self.nfile);
+        writeln!(
+            f,
+            "type{0}perc{0}disk{0}uncompressed{0}referenced{0}files",
+            sep
+        )?;
+        for ((name, s), nfile) in rows.zip(nfiles) {
+            writeln!(
+                f,
+                "{1}{0}{2}{0}{3}{0}{4}{0}{5}{0}{6}",
+                sep,
+                name,
+                (s.disk * 100).checked_div(s.uncomp).unwrap_or(0),
+                s.disk,
+                s.uncomp,
+                s.refd,
+                nfile,
+            )?;
+        }
+        writeln!(f, "sparse{0}0{0}0{0}0{0}{1}{0}0", sep, self.sparse)?;
+        Ok(())
+    }
+    fn as_compsize(&self) -> Option<&CompsizeStat> {
+        Some(self)
+    }
+    fn set_nfile(&mut self, total: u64, rows: [u64; ALL_FILES], apparent: u64) {
+        self.nfile_total = total;
+        self.nfile = rows;
+        self.apparent = apparent;
+    }
+}
+
+impl CompsizeStat {
+    pub fn rows(&self) -> impl Iterator<Item = (&'static str, &SizeStat)> {
+        let comp = self
+            .stat
+            .iter()
+            .zip(Compression::ALL)
+            .map(|(s, comp)| (comp.name(), s));
+        comp.chain([("prealloc", &self.prealloc)])
+    }
+    pub fn row_mut(&mut self, name: &str) -> Option<(&mut SizeStat, &mut u64)> {
+        if name == "prealloc" {
+            return Some((&mut self.prealloc, &mut self.nfile[PREALLOC_ROW]));
+        }
+        let idx = Compression::ALL.iter().position(|c| c.name() == name)?;
+        Some((&mut self.stat[idx], &mut self.nfile[idx]))
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (s, o) in self
+            .stat
+            .iter_mut()
+            .chain([&mut self.prealloc])
+            .zip(other.stat.iter().chain([&other.prealloc]))
+        {
+            s.disk += o.disk;
+            s.uncomp += o.uncomp;
+            s.refd += o.refd;
+        }
+        for (n, o) in self.nfile.iter_mut().zip(other.nfile) {
+            *n += o;
+        }
+        self.nfile_total += other.nfile_total;
+        self.sparse += other.sparse;
+        self.apparent += other.apparent;
+    }
+    pub fn total(&self) -> SizeStat {
+        self.rows()
+            .fold(SizeStat::default(), |acc, (_, s)| SizeStat {
+                disk: acc.disk + s.disk,
+                uncomp: acc.uncomp + s.uncomp,
+                refd: acc.refd + s.refd,
+            })
+    }
+    fn write_total(
+        &self,
+        f: &mut dyn Write,
+        scale: Scale,
+        columns: &[Column],
+    ) -> Result<(), std::io::Error> {
+        let total = self.total();
+        let SizeStat {
+            disk: total_disk,
+            uncomp: total_uncomp,
+            refd: total_refd,
+        } = total;
+        let headers: String = columns
+            .iter()
+            .map(|c| match c {
+                Column::Ratio => format!(" {:>7}", "Ratio"),
+                Column::RefdPerc => format!(" {:>6}", "Refd%"),
+                Column::DiskPerc => format!(" {:>6}", "Disk%"),
+                Column::Apparent => format!(" {:>14}", "Apparent"),
+            })
+            .collect();
+        write_table_columns(
+            f,
+            [
+                &"Type",
+                &"Perc",
+                &"Disk Usage",
+                &"Uncompressed",
+                &"Referenced",
+                &"NFiles",
+            ],
+            &headers,
+        )?;
+        let total_percentage = (total_disk * 100).checked_div(total_uncomp).unwrap_or(0);
+        write_table_columns(
+            f,
+            [
+                &"TOTAL",
+                &format!("{:>3}%", total_percentage),
+                &scale.scale(total_disk),
+                &scale.scale(total_uncomp),
+                &scale.scale(total_refd),
+                &self.nfile_total,
+            ],
+            &extra_columns(columns, &total, &total, Some(self.apparent), scale),
+        )?;
+        Ok(())
+    }
+}
+
+/// The SGR color of a usage table row worth a look with `--color`: yellow
+/// for compression doing little, red for a `none` row holding much of the
+/// disk usage.  `comp` is `None` for the prealloc row.
+fn highlight(comp: Option<&Compression>, s: &SizeStat, total: &SizeStat) -> Option<&'static str> {
+    let (ratio, none) = config().color_thresholds();
+    match comp? {
+        Compression::None => (s.disk * 100 > total.disk * none).then_some("31"),
+        Compression::Zlib | Compression::Lzo | Compression::Zstd => {
+            (s.get_percent() > ratio).then_some("33")
+        }
+        Compression::Encoded
+        | Compression::Encrypted
+        | Compression::OtherEncoding
+        | Compression::Unknown(_) => None,
+    }
+}
+
+/// `s` as a quoted OpenMetrics label value.
+fn label_str(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// The `--columns` of a row using `s`, out of `total`.  Only the TOTAL
+/// row has an `apparent` size.
+fn extra_columns(
+    columns: &[Column],
+    s: &SizeStat,
+    total: &SizeStat,
+    apparent: Option<u64>,
+    scale: Scale,
+) -> String {
+    let perc = |n: u64, of: u64| (n * 100).checked_div(of).unwrap_or(0);
+    columns
+        .iter()
+        .map(|c| match c {
+            Column::Ratio if s.disk == 0 => format!(" {:>7}", "-"),
+            Column::Ratio => format!(" {:>7}", format!("x{:.2}", s.uncomp as f64 / s.disk as f64)),
+            Column::RefdPerc => format!(" {:>5}%", perc(s.refd, total.refd)),
+            Column::DiskPerc => format!(" {:>5}%", perc(s.disk, total.disk)),
+            Column::Apparent => format!(" {:>14}", apparent.map_or("-".into(), |n| scale.scale(n))),
+        })
+        .collect()
+}
+
+fn write_table(
+    f: &mut dyn Write,
+    ty: impl Display,
+    percentage: impl Display,
+    disk_usage: impl Display,
+    uncomp_usage: impl Display,
+    refd_usage: impl Display,
+    nfiles: impl Display,
+) -> std::io::Result<()> {
+    let cells: [&dyn Display; 6] = [
+        &ty,
+        &percentage,
+        &disk_usage,
+        &uncomp_usage,
+        &refd_usage,
+        &nfiles,
+    ];
+    write_table_columns(f, cells, "")
+}
+
+/// [`write_table`] followed by the `--columns`.
+fn write_table_columns(
+    f: &mut dyn Write,
+    cells: [&dyn Display; 6],
+    columns: &str,
+) -> std::io::Result<()> {
+    if config().parsable {
+        let cells = cells.iter().map(|c| c.to_string().trim().to_string());
+        let line: Vec<_> = cells
+            .chain(columns.split_whitespace().map(str::to_string))
+            .collect();
+        return writeln!(f, "{}", line.join("\t"));
+    }
+    let [ty, percentage, disk_usage, uncomp_usage, refd_usage, nfiles] = cells;
+    let line = format!(
+        "{:<10} {:>4} {:>14} {:>16} {:>16} {:>10}{}",
+        ty, percentage, disk_usage, uncomp_usage, refd_usage, nfiles, columns
+    );
+    writeln!(f, "{}", line.trim_end())
+}
+
+fn json_size(s: &SizeStat, nfile: u64) -> String {
+    format!(
+        "{{\"disk\": {}, \"uncompressed\": {}, \"referenced\": {}, \"files\": {}}}",
+        s.disk, s.uncomp, s.refd, nfile
+    )
+}
+
+/// `fsid` the way `btrfs filesystem show` prints it.
+fn fmt_fsid(fsid: &[u8; 16]) -> String {
+    let hex: Vec<_> = fsid.iter().map(|b| format!("{:02x}", b)).collect();
+    [&hex[..4], &hex[4..6], &hex[6..8], &hex[8..10], &hex[10..]]
+        .map(|part| part.concat())
+        .join("-")
+}
+
+/// On-disk sizes behind [`ExtentStats::metadata`], each item with the
+/// header it takes in its leaf.
+mod meta {
+    const ITEM: u64 = 25;
+    pub const NAME_LEN: u64 = 16;
+    /// the inode item, its inode ref and the two directory entries
+    pub const INODE: u64 = 4 * ITEM + 160 + (10 + NAME_LEN) + 2 * (30 + NAME_LEN);
+    pub const FILE_EXTENT: u64 = ITEM + 53;
+    /// without the data, counted apart
+    pub const INLINE_EXTENT: u64 = ITEM + 21;
+    /// with one inline backref
+    pub const EXTENT_ITEM: u64 = ITEM + 24 + 29;
+    pub const SECTOR: u64 = 4096;
+    pub const CSUM: u64 = 4;
+}
+
+/// Statistics over a set of extents, each counted once however often it
+/// is referenced.
+struct ExtentStats {
+    stat: Box<dyn ExtentInfoSink>,
+    nfile: u64,
+    nextent: u64,
+    ninline: u64,
+    /// disk usage of the unique inline extents, and of the regular ones,
+    /// which are checksummed
+    inline_disk: u64,
+    csum_disk: u64,
+    /// regular extents counted once, kept apart from [`Self::seen`] which
+    /// is not merged
+    nunique: u64,
+    /// by [`FsId`]: addresses and inodes only tell extents apart within a
+    /// filesystem
+    seen: Vec<Seen>,
+    /// `--max-memory`: the extents counted since [`Self::seen`] last
+    /// started over
+    spill: Option<Spill>,
+}
+
+/// The extents of one filesystem counted so far.
+#[derive(Default)]
+struct Seen {
+    /// regular extents by disk address
+    extents: ExtentSet,
+    /// inline extents by inode
+    inline_inos: IntSet<u64>,
+}
+
+impl ExtentStats {
+    fn new() -> Self {
+        let stat: Box<dyn ExtentInfoSink> = if config().frag {
+            Box::new(XFragStat::new())
+        } else {
+            Box::new(CompsizeStat::default())
+        };
+        Self {
+            stat,
+            nfile: 0,
+            nextent: 0,
+            ninline: 0,
+            inline_disk: 0,
+            csum_disk: 0,
+            nunique: 0,
+            seen: vec![],
+            spill: config().max_memory().map(|_| Spill::new()),
+        }
+    }
+
+    fn seen(&mut self, fs: FsId) -> &mut Seen {
+        let fs = fs as usize;
+        if self.seen.len() <= fs {
+            self.seen.resize_with(fs + 1, Default::default);
+        }
+        &mut self.seen[fs]
+    }
+
+    /// Count `extent`, `true` if it was not seen before.
+    fn add(&mut self, extent: &ExtentInfo) -> bool {
+        if extent.is_hole() {
+            self.stat.hole(extent);
+            return false;
+        }
+        self.nextent += 1;
+        let seen = self.seen(extent.fs());
+        let bytenr = extent.disk_bytenr();
+        let unique = if bytenr == 0 {
+            seen.inline_inos.insert(extent.objectid())
+        } else {
+            seen.extents.insert(bytenr)
+        };
+        if !unique {
+            self.stat.duplic(extent);
+            return false;
+        }
+        if bytenr == 0 {
+            self.ninline += 1;
+            self.inline_disk += extent.stat().disk;
+        } else {
+            self.nunique += 1;
+            if extent.r#type() == ExtentType::Regular {
+                self.csum_disk += extent.stat().disk;
+            }
+        }
+        self.stat.unique(extent);
+        if let Some(spill) = &mut self.spill
+            && spill.push(extent)
+        {
+            self.seen
+                .iter_mut()
+                .for_each(|seen| *seen = Seen::default());
+        }
+        true
+    }
+
+    /// Count as duplicates the extents `--max-memory` counted as unique
+    /// again after writing them out, telling `f` about each.
+    fn unspill(&mut self, mut f: impl FnMut(&ExtentInfo)) {
+        let Some(spill) = self.spill.take() else {
+            return;
+        };
+        let ret = spill.for_each_recounted(|extent| {
+            if extent.disk_bytenr() == 0 {
+                self.ninline -= 1;
+                self.inline_disk -= extent.stat().disk;
+            } else {
+                self.nunique -= 1;
+                if extent.r#type() == ExtentType::Regular {
+                    self.csum_disk -= extent.stat().disk;
+                }
+            }
+            self.stat.recounted(extent);
+            f(extent);
+        });
+        if let Err(e) = ret {
+            reporter().note(&format!(
+                "--max-memory: failed to read back extents: {}, some are counted more than once",
+                e
+            ));
+        }
+    }
+
+    fn set_counts(&mut self, nfile: u64, counts: &RootCounts) {
+        self.nfile = nfile;
+        let mut rows = [0; ALL_FILES];
+        rows.copy_from_slice(&counts[..ALL_FILES]);
+        self.stat.set_nfile(nfile, rows, counts[APPARENT]);
+    }
+
+    fn nextent_unique(&self) -> u64 {
+        self.nunique
+    }
+
+    /// Add `other`, which saw none of the extents seen here.
+    fn merge(&mut self, other: Self) {
+        self.stat.merge(&*other.stat);
+        self.nextent += other.nextent;
+        self.ninline += other.ninline;
+        self.inline_disk += other.inline_disk;
+        self.csum_disk += other.csum_disk;
+        self.nunique += other.nunique;
+    }
+
+    /// A rough estimate of the metadata behind these extents, one copy of
+    /// it whatever the metadata profile.  Assumes crc32c checksums over 4K
+    /// sectors, and file names of [`meta::NAME_LEN`] bytes.
+    fn metadata(&self) -> [(&'static str, u64); 5] {
+        use meta::*;
+        let refs = self.nextent - self.ninline;
+        [
+            ("inodes", self.nfile * INODE),
+            (
+                "file extents",
+                refs * FILE_EXTENT + self.ninline * INLINE_EXTENT,
+            ),
+            ("extent items", self.nextent_unique() * EXTENT_ITEM),
+            ("checksums", self.csum_disk.div_ceil(SECTOR) * CSUM),
+            ("inline data", self.inline_disk),
+        ]
+    }
+
+    fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            f,
+            "Processed {} files, {} regular extents ({} refs), {} inline.",
+            self.nfile,
+            self.nextent_unique(),
+            self.nextent - self.ninline,
+            self.ninline,
+        )?;
+        self.stat.fmt(f, config().bytes)
+    }
+
+    /// Members of a JSON object, without the closing newline.
+    fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "  \"files\": {},", self.nfile)?;
+        writeln!(f, "  \"extents\": {},", self.nextent_unique())?;
+        writeln!(f, "  \"refs\": {},", self.nextent - self.ninline)?;
+        writeln!(f, "  \"inline\": {},", self.ninline)?;
+        self.stat.fmt_json(f)
+    }
+}
+
+/// The owners referencing an extent, as far as seen.
+struct Owners<K> {
+    disk: u64,
+    first: K,
+    /// how many distinct owners, those past the first are in
+    /// [`Sharing::more`]
+    nowners: u32,
+}
+
+/// Which owners reference each extent: roots for `--exclusive`, files for
+/// `--reflinks`.  Sharing with files outside the roots scanned is not seen.
+struct Sharing<K> {
+    /// by filesystem and disk location
+    extents: HashMap<(FsId, u64), Owners<K>>,
+    more: HashSet<((FsId, u64), K)>,
+    /// inline extents live in their inode's leaf, exclusive as far as we
+    /// can tell
+    inline: HashMap<K, u64>,
+}
+
+impl<K> Default for Sharing<K> {
+    fn default() -> Self {
+        Self {
+            extents: Default::default(),
+            more: Default::default(),
+            inline: Default::default(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> Sharing<K> {
+    fn add(&mut self, extent: &ExtentInfo, owner: K) {
+        if extent.is_hole() {
+            return;
+        }
+        let bytenr = extent.disk_bytenr();
+        if bytenr == 0 {
+            *self.inline.entry(owner).or_default() += extent.stat().disk;
+            return;
+        }
+        let key = (extent.fs(), bytenr);
+        let owners = self.extents.entry(key).or_insert(Owners {
+            disk: extent.stat().disk,
+            first: owner,
+            nowners: 0,
+        });
+        if owners.nowners == 0 || (owners.first != owner && self.more.insert((key, owner))) {
+            owners.nowners += 1;
+        }
+    }
+
+    /// Add `other`, which saw none of the extents seen here.
+    fn merge(&mut self, other: Self) {
+        self.extents.extend(other.extents);
+        self.more.extend(other.more);
+        for (owner, disk) in other.inline {
+            *self.inline.entry(owner).or_default() += disk;
+        }
+    }
+
+    /// Disk usage exclusive to and shared by each owner.
+    fn per_owner(&self) -> HashMap<K, (u64, u64)> {
+        let mut ret: HashMap<K, (u64, u64)> = HashMap::new();
+        for (&owner, &disk) in &self.inline {
+            ret.entry(owner).or_default().0 += disk;
+        }
+        for owners in self.extents.values() {
+            let (exclusive, shared) = ret.entry(owners.first).or_default();
+            if owners.nowners == 1 {
+                *exclusive += owners.disk;
+            } else {
+                *shared += owners.disk;
+            }
+        }
+        for (key, owner) in &self.more {
+            ret.entry(*owner).or_default().1 += self.extents[key].disk;
+        }
+        ret
+    }
+
+    /// Disk usage by the number of owners sharing it.
+    fn by_nowners(&self) -> BTreeMap<u32, u64> {
+        let mut ret = BTreeMap::new();
+        let inline: u64 = self.inline.values().sum();
+        if inline > 0 {
+            ret.insert(1, inline);
+        }
+        for owners in self.extents.values() {
+            *ret.entry(owners.nowners).or_default() += owners.disk;
+        }
+        ret
+    }
+}
+
+/// What `--exclusive` works out once everything arrived.
+#[derive(Default)]
+struct SharingSummary {
+    /// disk usage exclusive to and shared by each root
+    per_root: Vec<(u64, u64)>,
+    /// disk usage by the number of roots sharing it
+    by_nroots: BTreeMap<u32, u64>,
+}
+
+/// What `--checkpoint` keeps of the collector shards of a scan: the usage
+/// table, and the extents seen, to go on deduplicating against them.
+#[derive(Default)]
+pub struct Snapshot {
+    pub stat: CompsizeStat,
+    pub nextent: u64,
+    pub ninline: u64,
+    pub inline_disk: u64,
+    pub csum_disk: u64,
+    pub nunique: u64,
+    /// by fsid, with the first path seen on it
+    pub per_fs: Vec<([u8; 16], Box<Path>, SizeStat)>,
+    /// disk addresses of the regular extents, per entry of `per_fs`
+    pub extents: Vec<Vec<u64>>,
+    /// inodes of the inline extents, per entry of `per_fs`
+    pub inline_inos: Vec<Vec<u64>>,
+}
+
+pub struct Collector {
+    total: ExtentStats,
+    /// `--per-arg`: the same per command line argument, and their names
+    per_root: Vec<ExtentStats>,
+    root_names: Vec<String>,
+    /// the roots inside another one, their files counted under that one
+    nested: Vec<bool>,
+    /// the totals per filesystem, indexed by [`crate::fs_util::FsId`]
+    per_fs: Vec<SizeStat>,
+    /// usage and number of files per content type
+    by_type: Buckets<&'static str>,
+    /// the same per file extension
+    by_ext: Buckets<String>,
+    /// `--estimate`: files sampled, their uncompressed disk usage, and per
+    /// codec its projections with and without `compress-force` and the
+    /// time spent compressing the samples
+    est_nfile: u64,
+    est_disk: u64,
+    est_read: u64,
+    est_projected: Vec<u64>,
+    est_unforced: Vec<u64>,
+    est_cpu: Vec<Duration>,
+    /// `--list-prealloc`: files with preallocated extents and their size
+    prealloc_files: Vec<(Box<Path>, u64)>,
+    /// `--audit-compression`: files, their `btrfs.compression` property
+    /// and the disk usage of their data stored otherwise
+    audit: Vec<(Box<Path>, Compression, u64)>,
+    /// `--defrag-candidates`: files, their number of extents and average
+    /// extent size, sorted most extents first by [`Self::finish`]
+    defrag: Vec<(Box<Path>, u64, u64)>,
+    /// `--defrag-extents` and `--defrag-avg`, parsed once
+    defrag_limits: (u64, u64),
+    /// `--prealloc-min`, parsed once
+    prealloc_min: u64,
+    /// `--files`: totals of every file
+    files: Vec<(Box<Path>, SizeStat)>,
+    /// `--group-depth`: totals and number of files per directory
+    groups: HashMap<PathBuf, (SizeStat, u64)>,
+    /// sorted by [`Self::finish`]
+    sorted_groups: Vec<(PathBuf, SizeStat, u64)>,
+    /// `--top`: the worst files so far, the best of them on top
+    top: BinaryHeap<Reverse<TopEntry>>,
+    /// sorted worst first by [`Self::finish`]
+    sorted_top: Vec<TopEntry>,
+    /// `--fragmentation`: sizes of all extent refs, and extents per file
+    extent_sizes: FragStat,
+    extents_per_file: ExtentCountStat,
+    /// `--inline-stats`
+    inline: InlineStat,
+    /// `--exclusive`: the roots of every extent, summarized by
+    /// [`Self::finish`]
+    sharing: Sharing<RootId>,
+    sharing_summary: SharingSummary,
+    /// `--reflinks`: the files, by filesystem, subvolume and inode, of
+    /// every extent, summarized by [`Self::finish`]
+    reflinks: Sharing<(FsId, SubvolId, u64)>,
+    reflinks_by_nfiles: BTreeMap<u32, u64>,
+    /// `--refcounts`: the disk usage and number of references of every
+    /// regular extent, by filesystem and disk location, summarized by
+    /// [`Self::finish`]
+    refcounts: HashMap<(FsId, u64), (u64, u32)>,
+    by_nrefs: BTreeMap<u32, u64>,
+    /// the scan was stopped early, see [`crate::global::cancel`]
+    partial: bool,
+}
+
+/// A file ranked by `--top-by`; greater is worse.
+struct TopEntry {
+    key: (u64, u64),
+    path: Box<Path>,
+    stat: SizeStat,
+}
+
+impl PartialEq for TopEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for TopEntry {}
+
+impl PartialOrd for TopEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TopEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.key, &self.path).cmp(&(other.key, &other.path))
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self {
+            total: ExtentStats::new(),
+            per_root: Vec::new(),
+            root_names: Vec::new(),
+            nested: Vec::new(),
+            per_fs: Vec::new(),
+            by_type: Default::default(),
+            by_ext: Default::default(),
+            est_nfile: 0,
+            est_disk: 0,
+            est_read: 0,
+            est_projected: Vec::new(),
+            est_unforced: Vec::new(),
+            est_cpu: Vec::new(),
+            prealloc_files: Vec::new(),
+            audit: Vec::new(),
+            defrag: Vec::new(),
+            defrag_limits: config().defrag_limits(),
+            prealloc_min: config().prealloc_min(),
+            files: Vec::new(),
+            groups: HashMap::new(),
+            sorted_groups: Vec::new(),
+            top: BinaryHeap::new(),
+            sorted_top: Vec::new(),
+            extent_sizes: FragStat::new(),
+            extents_per_file: ExtentCountStat::new(),
+            inline: Default::default(),
+            sharing: Default::default(),
+            sharing_summary: Default::default(),
+            reflinks: Default::default(),
+            reflinks_by_nfiles: Default::default(),
+            refcounts: Default::default(),
+            by_nrefs: Default::default(),
+            partial: false,
+        }
+    }
+    /// Record the number of files seen in total and the per root counts,
+    /// see [`crate::report::FileCounts`].
+    pub fn set_counts(&mut self, nfile: u64, counts: &[RootCounts]) {
+        let mut total = [0; APPARENT + 1];
+        for c in counts {
+            for (t, c) in total.iter_mut().zip(c) {
+                *t += c;
+            }
+        }
+        self.total.set_counts(nfile, &total);
+        for (stats, c) in self.per_root.iter_mut().zip(counts) {
+            stats.set_counts(c[ALL_FILES], c);
+        }
+    }
+    /// Name the roots for `--per-arg`, indexed by [`crate::fs_util::RootId`].
+    pub fn set_root_names(&mut self, names: Vec<String>) {
+        self.root_names = names;
+    }
+    /// Mark the roots inside another one, indexed the same.
+    pub fn set_nested(&mut self, nested: Vec<bool>) {
+        self.nested = nested;
+    }
+    pub fn nfile(&self) -> u64 {
+        self.total.nfile
+    }
+    /// Mark the results as those of a scan stopped early.
+    pub fn set_partial(&mut self) {
+        self.partial = true;
+    }
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+    pub fn nextent_unique(&self) -> u64 {
+        self.total.nextent_unique()
+    }
+    pub fn nextent(&self) -> u64 {
+        self.total.nextent
+    }
+    pub fn ninline(&self) -> u64 {
+        self.total.ninline
+    }
+    /// The usage table, unless fragment statistics were asked for instead.
+    pub fn compsize(&self) -> Option<&CompsizeStat> {
+        self.total.stat.as_compsize()
+    }
+    /// The main table only, as `sep` separated values.
+    pub fn fmt_delimited(&self, f: &mut dyn Write, sep: char) -> std::io::Result<()> {
+        self.total.stat.fmt_delimited(f, sep)
+    }
+    /// Add the extents `shard` collected.  Extents are spread over shards
+    /// by [`shard_of`], so each shard deduplicates its own and only the
+    /// sums are merged.  Per-file results only go to the first shard.
+    pub fn merge(&mut self, mut shard: Collector) {
+        shard.unspill();
+        self.total.merge(shard.total);
+        if self.per_root.len() < shard.per_root.len() {
+            self.per_root
+                .resize_with(shard.per_root.len(), ExtentStats::new);
+        }
+        for (stats, other) in self.per_root.iter_mut().zip(shard.per_root) {
+            stats.merge(other);
+        }
+        if self.per_fs.len() < shard.per_fs.len() {
+            self.per_fs
+                .resize_with(shard.per_fs.len(), Default::default);
+        }
+        for (s, o) in self.per_fs.iter_mut().zip(shard.per_fs) {
+            s.disk += o.disk;
+            s.uncomp += o.uncomp;
+            s.refd += o.refd;
+        }
+        self.extent_sizes.merge(&shard.extent_sizes);
+        self.sharing.merge(shard.sharing);
+        self.reflinks.merge(shard.reflinks);
+        self.refcounts.extend(shard.refcounts);
+    }
+    /// Take back what `--max-memory` counted more than once.
+    fn unspill(&mut self) {
+        let per_fs = &mut self.per_fs;
+        self.total.unspill(|extent| {
+            let s = &mut per_fs[extent.fs() as usize];
+            s.disk -= extent.stat().disk;
+            s.uncomp -= extent.stat().uncomp;
+        });
+        for stats in &mut self.per_root {
+            stats.unspill(|_| ());
+        }
+    }
+    /// The state of `shards`, all of one scan, for [`Self::restore`].  Only
+    /// the usage table is kept, not what other options collect.
+    pub fn snapshot(shards: &[Collector]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        let mut per_fs: Vec<SizeStat> = vec![];
+        for shard in shards {
+            let total = &shard.total;
+            if let Some(stat) = total.stat.as_compsize() {
+                snapshot.stat.merge(stat);
+            }
+            snapshot.nextent += total.nextent;
+            snapshot.ninline += total.ninline;
+            snapshot.inline_disk += total.inline_disk;
+            snapshot.csum_disk += total.csum_disk;
+            snapshot.nunique += total.nunique;
+            if snapshot.extents.len() < total.seen.len() {
+                snapshot.extents.resize_with(total.seen.len(), Vec::new);
+                snapshot.inline_inos.resize_with(total.seen.len(), Vec::new);
+            }
+            for (i, seen) in total.seen.iter().enumerate() {
+                snapshot.extents[i].extend(seen.extents.keys());
+                snapshot.inline_inos[i].extend(&seen.inline_inos);
+            }
+            if per_fs.len() < shard.per_fs.len() {
+                per_fs.resize_with(shard.per_fs.len(), Default::default);
+            }
+            for (s, o) in per_fs.iter_mut().zip(&shard.per_fs) {
+                s.disk += o.disk;
+                s.uncomp += o.uncomp;
+                s.refd += o.refd;
+            }
+        }
+        // every filesystem the extents are of, even with no usage yet
+        let filesystems = filesystems();
+        per_fs.resize_with(filesystems.len().max(per_fs.len()), Default::default);
+        snapshot.per_fs = filesystems
+            .into_iter()
+            .zip(per_fs)
+            .map(|((fsid, path), s)| (fsid, path, s))
+            .collect();
+        snapshot
+    }
+
+    /// `nshards` collectors going on from `snapshot`, each extent seen in
+    /// the shard [`shard_of`] sends it to.
+    pub fn restore(snapshot: Snapshot, nshards: usize) -> Vec<Collector> {
+        let mut shards: Vec<_> = (0..nshards).map(|_| Collector::new()).collect();
+        let first = &mut shards[0];
+        first.total.stat.merge(&snapshot.stat);
+        first.total.nextent = snapshot.nextent;
+        first.total.ninline = snapshot.ninline;
+        first.total.inline_disk = snapshot.inline_disk;
+        first.total.csum_disk = snapshot.csum_disk;
+        first.total.nunique = snapshot.nunique;
+        // this process may number the filesystems differently
+        let mut ids = vec![];
+        for (fsid, path, s) in snapshot.per_fs {
+            let fs = register_fs(fsid, &path);
+            ids.push(fs);
+            let fs = fs as usize;
+            if first.per_fs.len() <= fs {
+                first.per_fs.resize_with(fs + 1, Default::default);
+            }
+            first.per_fs[fs] = s;
+        }
+        for (&fs, bytenrs) in ids.iter().zip(snapshot.extents) {
+            for bytenr in bytenrs {
+                let shard = &mut shards[shard_of_key(fs, bytenr, nshards)];
+                shard.total.seen(fs).extents.insert(bytenr);
+            }
+        }
+        for (&fs, inos) in ids.iter().zip(snapshot.inline_inos) {
+            for ino in inos {
+                let shard = &mut shards[shard_of_key(fs, ino, nshards)];
+                shard.total.seen(fs).inline_inos.insert(ino);
+            }
+        }
+        shards
+    }
+    /// Sort what was collected per file, once everything arrived, and tell
+    /// the [`reporter`].
+    pub fn finish(&mut self) {
+        self.unspill();
+        reporter().finished();
+        self.prealloc_files
+            .sort_unstable_by(|(pa, a), (pb, b)| b.cmp(a).then_with(|| pa.cmp(pb)));
+        self.audit
+            .sort_unstable_by(|(pa, _, a), (pb, _, b)| b.cmp(a).then_with(|| pa.cmp(pb)));
+        self.files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        self.defrag
+            .sort_unstable_by(|(pa, a, _), (pb, b, _)| b.cmp(a).then_with(|| pa.cmp(pb)));
+        self.sorted_groups = self
+            .groups
+            .drain()
+            .map(|(path, (s, nfile))| (path, s, nfile))
+            .collect();
+        self.sorted_top = take(&mut self.top)
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(e)| e)
+            .collect();
+        self.sorted_groups
+            .sort_unstable_by(|(pa, a, _), (pb, b, _)| {
+                b.disk.cmp(&a.disk).then_with(|| pa.cmp(pb))
+            });
+        let sharing = take(&mut self.sharing);
+        let per_root = sharing.per_owner();
+        let nroots = per_root
+            .keys()
+            .map(|&root| root as usize + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.root_names.len());
+        self.sharing_summary = SharingSummary {
+            per_root: (0..nroots)
+                .map(|i| per_root.get(&(i as RootId)).copied().unwrap_or_default())
+                .collect(),
+            by_nroots: sharing.by_nowners(),
+        };
+        self.reflinks_by_nfiles = take(&mut self.reflinks).by_nowners();
+        for (disk, nrefs) in take(&mut self.refcounts).into_values() {
+            *self.by_nrefs.entry(nrefs).or_default() += disk;
+        }
+    }
+    pub fn fmt(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        if config().du {
+            return self.fmt_du(f);
+        }
+        if self.partial {
+            writeln!(f, "Partial: the scan was stopped early.")?;
+        }
+        if self.total.nfile == 0 {
+            eprintln!("No Files.");
+            return Ok(());
+        }
+        if self.total.nextent == 0 {
+            eprintln!("All empty or still-delalloced files.");
+            return Ok(());
+        }
+        if config().defrag_candidates {
+            return self.fmt_defrag(f);
+        }
+        if config().files {
+            self.fmt_files(f, Scale::from_config())?;
+        }
+        if config().per_arg {
+            for (i, stats) in self.per_root.iter().enumerate() {
+                if stats.nextent == 0 {
+                    continue;
+                }
+                writeln!(f, "== {} ==", self.root_name(i))?;
+                stats.fmt(f)?;
+                writeln!(f)?;
+            }
+            writeln!(f, "== total ==")?;
+        }
+        self.total.fmt(f)?;
+        // filesystems of earlier scans stay registered, see `--daemon`
+        if self.per_fs.iter().filter(|s| !s.is_empty()).count() > 1 {
+            self.fmt_filesystems(f, Scale::from_config())?;
+        }
+        if config().by_type {
+            fmt_buckets(f, "Content", &self.by_type, Scale::from_config())?;
+        }
+        if config().by_ext {
+            fmt_buckets(f, "Extension", &self.by_ext, Scale::from_config())?;
+        }
+        if let Some(codecs) = config().estimate_codecs() {
+            self.fmt_estimate(f, &codecs, Scale::from_config())?;
+        }
+        if config().group_depth.is_some() {
+            self.fmt_groups(f, Scale::from_config())?;
+        }
+        if config().top.is_some() {
+            self.fmt_top(f, Scale::from_config())?;
+        }
+        if config().fragmentation {
+            writeln!(f)?;
+            writeln!(f, "Extent size distribution:")?;
+            self.extent_sizes.fmt(f)?;
+            writeln!(f, "Extents per file:")?;
+            self.extents_per_file.fmt(f)?;
+        }
+        if config().inline_stats {
+            self.inline.fmt(f, Scale::from_config())?;
+        }
+        if config().list_prealloc {
+            self.fmt_prealloc(f, Scale::from_config())?;
+        }
+        if config().audit_compression {
+            self.fmt_audit(f, Scale::from_config())?;
+        }
+        if config().exclusive {
+            self.fmt_sharing(f, Scale::from_config())?;
+        }
+        if config().reflinks {
+            self.fmt_reflinks(f, Scale::from_config())?;
+        }
+        if config().refcounts {
+            self.fmt_refcounts(f, Scale::from_config())?;
+        }
+        if config().metadata {
+            self.fmt_metadata(f, Scale::from_config())?;
+        }
+        Ok(())
+    }
+
+    /// `--du`: the disk usage of each file-or-dir, as `du -s` prints it,
+    /// for scripts that parse that, and like it leaving out those inside
+    /// another one.  A partial scan only shows in the exit status.
+    fn fmt_du(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        for (i, name) in self.root_names.iter().enumerate() {
+            if self.nested.get(i) == Some(&true) {
+                continue;
+            }
+            let disk = self.root_compsize(i).map_or(0, |s| s.total().disk);
+            let size = if config().bytes {
+                disk
+            } else {
+                disk.div_ceil(1024)
+            };
+            writeln!(f, "{}\t{}", size, name)?;
+        }
+        Ok(())
+    }
+
+    /// Same content as [`Self::fmt`] as a JSON document, sizes in bytes.
+    /// Every compression type is listed, even when unused, so consumers can
+    /// rely on the keys being there.
+    pub fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "{{")?;
+        writeln!(f, "  \"version\": 1,")?;
+        if self.partial {
+            writeln!(f, "  \"partial\": true,")?;
+        }
+        self.total.fmt_json(f)?;
+        if config().per_arg {
+            write!(f, ",\n  \"roots\": [")?;
+            for (i, stats) in self.per_root.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                writeln!(f, "{}\n{{", sep)?;
+                writeln!(f, "  \"path\": {},", json_str(self.root_name(i)))?;
+                stats.fmt_json(f)?;
+                write!(f, "\n}}")?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        write!(f, ",\n  \"filesystems\": [")?;
+        for (i, (s, (fsid, path))) in self.per_fs.iter().zip(filesystems()).enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(
+                f,
+                "{}\n    {{\"fsid\": \"{}\", \"path\": {}, \"disk\": {}, \"uncompressed\": {}, \"referenced\": {}}}",
+                sep,
+                fmt_fsid(&fsid),
+                json_path(&path),
+                s.disk,
+                s.uncomp,
+                s.refd
+            )?;
+        }
+        write!(f, "\n  ]")?;
+        if config().files {
+            write!(f, ",\n  \"per_file\": [")?;
+            for (i, (path, s)) in self.files.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"disk\": {}, \"uncompressed\": {}, \"referenced\": {}}}",
+                    sep,
+                    json_path(path),
+                    s.disk,
+                    s.uncomp,
+                    s.refd
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().by_type {
+            fmt_buckets_json(f, "by_type", &self.by_type)?;
+        }
+        if config().by_ext {
+            fmt_buckets_json(f, "by_ext", &self.by_ext)?;
+        }
+        if let Some(codecs) = config().estimate_codecs() {
+            write!(
+                f,
+                ",\n  \"estimate\": {{\"files\": {}, \"disk\": {}, \"sampled\": {}, \"algorithms\": [",
+                self.est_nfile, self.est_disk, self.est_read
+            )?;
+            for (i, codec) in codecs.iter().enumerate() {
+                let (projected, unforced, speed) = self.est_row(i);
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"name\": \"{}\", \"projected\": {}, \"projected_unforced\": {}, \"bytes_per_sec\": {}}}",
+                    sep,
+                    codec,
+                    projected,
+                    unforced,
+                    speed.unwrap_or(0)
+                )?;
+            }
+            write!(f, "\n  ]}}")?;
+        }
+        if config().group_depth.is_some() {
+            write!(f, ",\n  \"groups\": [")?;
+            for (i, (path, s, nfile)) in self.sorted_groups.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"usage\": {}}}",
+                    sep,
+                    json_path(path),
+                    json_size(s, *nfile)
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().top.is_some() {
+            write!(f, ",\n  \"top\": [")?;
+            for (i, e) in self.sorted_top.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"disk\": {}, \"uncompressed\": {}, \"referenced\": {}}}",
+                    sep,
+                    json_path(&e.path),
+                    e.stat.disk,
+                    e.stat.uncomp,
+                    e.stat.refd
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().fragmentation {
+            write!(f, ",\n  \"fragmentation\": {{\"extent_sizes\": ")?;
+            self.extent_sizes.fmt_json(f)?;
+            write!(f, ", \"extents_per_file\": ")?;
+            self.extents_per_file.fmt_json(f)?;
+            write!(f, "}}")?;
+        }
+        if config().inline_stats {
+            write!(f, ",\n  \"inline_files\": ")?;
+            self.inline.fmt_json(f)?;
+        }
+        if config().list_prealloc {
+            write!(f, ",\n  \"prealloc_files\": [")?;
+            for (i, (path, size)) in self.prealloc_files.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"prealloc\": {}}}",
+                    sep,
+                    json_path(path),
+                    size
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().audit_compression {
+            write!(f, ",\n  \"compression_audit\": [")?;
+            for (i, (path, property, disk)) in self.audit.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"property\": \"{}\", \"disk_otherwise\": {}}}",
+                    sep,
+                    json_path(path),
+                    property,
+                    disk
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().exclusive {
+            let summary = &self.sharing_summary;
+            write!(f, ",\n  \"sharing\": {{\"roots\": [")?;
+            for (i, (exclusive, shared)) in summary.per_root.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"exclusive\": {}, \"shared\": {}}}",
+                    sep,
+                    json_str(self.root_name(i)),
+                    exclusive,
+                    shared
+                )?;
+            }
+            write!(f, "\n  ], \"by_roots\": [")?;
+            for (i, (nroots, disk)) in summary.by_nroots.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"roots\": {}, \"disk\": {}}}",
+                    sep, nroots, disk
+                )?;
+            }
+            write!(f, "\n  ]}}")?;
+        }
+        if config().defrag_candidates {
+            write!(f, ",\n  \"defrag_candidates\": [")?;
+            for (i, (path, nextent, avg)) in self.defrag.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"path\": {}, \"extents\": {}, \"avg_extent\": {}}}",
+                    sep,
+                    json_path(path),
+                    nextent,
+                    avg
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().reflinks {
+            let by_nfiles = &self.reflinks_by_nfiles;
+            write!(
+                f,
+                ",\n  \"reflinks\": {{\"exclusive\": {}, \"shared\": {}, \"by_files\": [",
+                by_nfiles.get(&1).copied().unwrap_or(0),
+                by_nfiles.range(2..).map(|(_, disk)| disk).sum::<u64>()
+            )?;
+            for (i, (nfiles, disk)) in by_nfiles.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"files\": {}, \"disk\": {}}}",
+                    sep, nfiles, disk
+                )?;
+            }
+            write!(f, "\n  ]}}")?;
+        }
+        if config().refcounts {
+            write!(f, ",\n  \"refcounts\": [")?;
+            for (i, (nrefs, disk)) in self.by_nrefs.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                write!(
+                    f,
+                    "{}\n    {{\"refs\": {}, \"disk\": {}}}",
+                    sep, nrefs, disk
+                )?;
+            }
+            write!(f, "\n  ]")?;
+        }
+        if config().metadata {
+            let rows = self.total.metadata();
+            write!(f, ",\n  \"metadata\": {{")?;
+            for (name, size) in rows {
+                write!(f, "\"{}\": {}, ", name.replace(' ', "_"), size)?;
+            }
+            write!(
+                f,
+                "\"total\": {}}}",
+                rows.iter().map(|(_, s)| s).sum::<u64>()
+            )?;
+        }
+        writeln!(f, "\n}}")
+    }
+
+    /// The usage table as OpenMetrics gauges, one per compression type and
+    /// file-or-dir with `--per-arg`, else for all of them at once.
+    pub fn fmt_openmetrics(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        type Value = fn(&SizeStat, u64) -> u64;
+        const METRICS: [(&str, &str, Value); 4] = [
+            ("disk_bytes", "Disk usage", |s, _| s.disk),
+            ("uncompressed_bytes", "Uncompressed size", |s, _| s.uncomp),
+            ("referenced_bytes", "Referenced size", |s, _| s.refd),
+            ("files", "Files using each compression type", |_, n| n),
+        ];
+        let all = self.root_names.join(" ");
+        let roots: Vec<(&str, &ExtentStats)> = if config().per_arg {
+            (self.per_root.iter().enumerate())
+                .map(|(i, stats)| (self.root_name(i), stats))
+                .collect()
+        } else {
+            vec![(&all, &self.total)]
+        };
+        let roots: Vec<_> = roots
+            .into_iter()
+            .filter_map(|(path, stats)| Some((label_str(path), stats.stat.as_compsize()?)))
+            .collect();
+        for (name, help, value) in METRICS {
+            writeln!(f, "# TYPE xsz_{} gauge", name)?;
+            writeln!(f, "# HELP xsz_{} {}.", name, help)?;
+            for (path, stat) in &roots {
+                for ((comp, s), nfile) in stat.rows().zip(stat.nfile) {
+                    writeln!(
+                        f,
+                        "xsz_{}{{path={},compression=\"{}\"}} {}",
+                        name,
+                        path,
+                        comp,
+                        value(s, nfile)
+                    )?;
+                }
+            }
+        }
+        writeln!(f, "# TYPE xsz_sparse_bytes gauge")?;
+        writeln!(f, "# HELP xsz_sparse_bytes Size of holes in sparse files.")?;
+        for (path, stat) in &roots {
+            writeln!(f, "xsz_sparse_bytes{{path={}}} {}", path, stat.sparse)?;
+        }
+        writeln!(f, "# EOF")
+    }
+
+    /// Write the [`Self::fmt_json`] document to `path`, replacing it only
+    /// once complete.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        replace_file(path, |f| self.fmt_json(f))
+    }
+
+    fn add_extents(&mut self, msg: Box<[ExtentInfo]>) {
+        for extent in msg {
+            let unique = self.total.add(&extent);
+            if !extent.is_hole() {
+                let fs = extent.fs() as usize;
+                if self.per_fs.len() <= fs {
+                    self.per_fs.resize_with(fs + 1, Default::default);
+                }
+                let s = &mut self.per_fs[fs];
+                s.refd += extent.stat().refd;
+                if unique {
+                    s.disk += extent.stat().disk;
+                    s.uncomp += extent.stat().uncomp;
+                }
+            }
+            if config().per_arg {
+                let root = extent.root() as usize;
+                if self.per_root.len() <= root {
+                    self.per_root.resize_with(root + 1, ExtentStats::new);
+                }
+                self.per_root[root].add(&extent);
+            }
+            if config().fragmentation && !extent.is_hole() {
+                self.extent_sizes.record(extent.stat().uncomp);
+            }
+            if config().exclusive {
+                self.sharing.add(&extent, extent.root());
+            }
+            if config().reflinks {
+                self.reflinks
+                    .add(&extent, (extent.fs(), extent.subvol(), extent.objectid()));
+            }
+            if config().refcounts && extent.disk_bytenr() != 0 {
+                let (_, nrefs) = self
+                    .refcounts
+                    .entry((extent.fs(), extent.disk_bytenr()))
+                    .or_insert((extent.stat().disk, 0));
+                *nrefs += 1;
+            }
+        }
+    }
+
+    /// Add `file` to its argument and every directory between them, down
+    /// to `depth` levels below the argument, like `du -d`.
+    fn add_to_groups(&mut self, file: &FileReport, depth: u32) {
+        let Some(root) = self.root_names.get(file.root as usize) else {
+            return;
+        };
+        let root = Path::new(root);
+        // paths from stdin share no root: their directories count from /,
+        // or from the current one
+        let (root, rel) = if root == Path::new("-") {
+            match file.path.strip_prefix("/") {
+                Ok(rel) => (Path::new("/"), rel),
+                Err(_) => (Path::new("."), &*file.path),
+            }
+        } else {
+            let Ok(rel) = file.path.strip_prefix(root) else {
+                return;
+            };
+            (root, rel)
+        };
+        let total = file.total();
+        let mut dir = root.to_path_buf();
+        let components = || rel.components().filter(|c| *c != Component::CurDir);
+        // the last component is the file itself
+        let ndir = components().count().saturating_sub(1);
+        let mut components = components();
+        for level in 0..=ndir.min(depth as usize) {
+            if level > 0 {
+                dir.push(components.next().unwrap());
+            }
+            let (s, nfile) = self.groups.entry(dir.clone()).or_default();
+            s.disk += total.disk;
+            s.uncomp += total.uncomp;
+            s.refd += total.refd;
+            *nfile += 1;
+        }
+    }
+
+    /// Keep `file` if it is among the `n` worst seen so far.
+    fn add_to_top(&mut self, file: &FileReport, n: usize) {
+        let stat = file.total();
+        if n == 0 || stat.uncomp == 0 {
+            return;
+        }
+        let key = match config().top_by {
+            TopBy::Ratio => (stat.disk * 1000 / stat.uncomp, stat.disk),
+            TopBy::Disk => (stat.disk, stat.uncomp),
+        };
+        if self.top.len() == n {
+            match self.top.peek() {
+                Some(Reverse(best)) if best.key < key => {
+                    self.top.pop();
+                }
+                _ => return,
+            }
+        }
+        self.top.push(Reverse(TopEntry {
+            key,
+            path: file.path.clone(),
+            stat,
+        }));
+    }
+
+    /// The names given by [`Self::set_root_names`].
+    pub fn root_names(&self) -> &[String] {
+        &self.root_names
+    }
+    /// The usage table of `root` with `--per-arg`, `None` if nothing of it
+    /// was found.
+    pub fn root_compsize(&self, root: usize) -> Option<&CompsizeStat> {
+        self.per_root.get(root)?.stat.as_compsize()
+    }
+
+    /// `--exclusive`: the disk usage exclusive to `root` and shared with
+    /// others.
+    pub fn root_sharing(&self, root: usize) -> (u64, u64) {
+        (self.sharing_summary.per_root)
+            .get(root)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn root_name(&self, root: usize) -> &str {
+        self.root_names.get(root).map_or("?", String::as_str)
+    }
+
+    fn add_files(&mut self, msg: Box<[FileReport]>) {
+        if config().format == Format::Ndjson {
+            // stdout is line buffered: each line goes out whole, at once
+            let mut out = stdout().lock();
+            for file in &msg {
+                file.fmt_json(&mut out).ok();
+            }
+        }
+        for file in msg {
+            if let Some(content) = file.content {
+                add_bucket(&mut self.by_type, content, &file);
+            }
+            if config().by_ext {
+                add_bucket(&mut self.by_ext, extension(&file.path), &file);
+            }
+            if config().files {
+                self.files.push((file.path.clone(), file.total()));
+            }
+            if let Some(depth) = config().group_depth {
+                self.add_to_groups(&file, depth);
+            }
+            if let Some(n) = config().top {
+                self.add_to_top(&file, n);
+            }
+            if config().fragmentation {
+                self.extents_per_file.record(file.nextent);
+            }
+            if config().inline_stats {
+                self.inline.record(&file);
+            }
+            if config().defrag_candidates {
+                // compressed extents are 128K at most, whatever defrag does
+                let (max_extents, min_avg) = self.defrag_limits;
+                let (nextent, refd) = file.uncompressed;
+                let avg = refd.checked_div(nextent).unwrap_or(0);
+                if nextent > max_extents || (nextent > 1 && avg < min_avg) {
+                    self.defrag.push((file.path.clone(), nextent, avg));
+                }
+            }
+            if let Some(property) = file.property {
+                let disk = file.disk_not(property);
+                if disk > 0 {
+                    self.audit.push((file.path.clone(), property, disk));
+                }
+            }
+            if config().list_prealloc && file.prealloc.disk >= self.prealloc_min {
+                self.prealloc_files.push((file.path, file.prealloc.disk));
+            }
+            if let Some(samples) = file.samples {
+                let disk = file.stat[Compression::None.as_usize()].disk;
+                self.est_nfile += 1;
+                self.est_disk += disk;
+                self.est_read += samples.first().map_or(0, |s| s.read);
+                self.est_projected.resize(samples.len(), 0);
+                self.est_unforced.resize(samples.len(), 0);
+                self.est_cpu.resize(samples.len(), Duration::ZERO);
+                for (i, sample) in samples.iter().enumerate() {
+                    self.est_projected[i] += sample.project(disk);
+                    self.est_unforced[i] += sample.project_unforced(disk);
+                    self.est_cpu[i] += sample.cpu;
+                }
+            }
+        }
+    }
+
+    /// Projected disk usage with the `i`th `--estimate` codec with and
+    /// without `compress-force`, and its compression speed per core in
+    /// bytes per second, if measurable.
+    fn est_row(&self, i: usize) -> (u64, u64, Option<u64>) {
+        let projected = self.est_projected.get(i).copied().unwrap_or(self.est_disk);
+        let unforced = self.est_unforced.get(i).copied().unwrap_or(self.est_disk);
+        let cpu = self.est_cpu.get(i).map_or(0., Duration::as_secs_f64);
+        let speed = (cpu > 0.).then(|| (self.est_read as f64 / cpu) as u64);
+        (projected, unforced, speed)
+    }
+
+    fn fmt_estimate(
+        &self,
+        f: &mut dyn Write,
+        codecs: &[Codec],
+        scale: Scale,
+    ) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Estimate on data stored uncompressed ({} in {} files, {} sampled):",
+            scale.scale(self.est_disk),
+            self.est_nfile,
+            scale.scale(self.est_read),
+        )?;
+        write_table(
+            f,
+            "Algorithm",
+            "Perc",
+            "Projected",
+            "Saving",
+            "Speed/core",
+            "Unforced",
+        )?;
+        for (i, codec) in codecs.iter().enumerate() {
+            let (projected, unforced, speed) = self.est_row(i);
+            let speed = match speed {
+                Some(speed) => format!("{}/s", scale.scale(speed)),
+                None => "-".into(),
+            };
+            write_table(
+                f,
+                codec,
+                format!(
+                    "{:>3}%",
+                    (projected * 100).checked_div(self.est_disk).unwrap_or(0)
+                ),
+                scale.scale(projected),
+                scale.scale(self.est_disk.saturating_sub(projected)),
+                speed,
+                scale.scale(self.est_disk.saturating_sub(unforced)),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fmt_files(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(
+            f,
+            "{:>4} {:>14} {:>16} {:>16}  File",
+            "Perc", "Disk Usage", "Uncompressed", "Referenced"
+        )?;
+        for (path, s) in &self.files {
+            writeln!(
+                f,
+                "{:>3}% {:>14} {:>16} {:>16}  {}",
+                (s.disk * 100).checked_div(s.uncomp).unwrap_or(0),
+                scale.scale(s.disk),
+                scale.scale(s.uncomp),
+                scale.scale(s.refd),
+                path.display()
+            )?;
+        }
+        writeln!(f)
+    }
+
+    fn fmt_groups(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>4} {:>14} {:>16} {:>16} {:>10}  Directory",
+            "Perc", "Disk Usage", "Uncompressed", "Referenced", "NFiles"
+        )?;
+        for (path, s, nfile) in &self.sorted_groups {
+            writeln!(
+                f,
+                "{:>3}% {:>14} {:>16} {:>16} {:>10}  {}",
+                (s.disk * 100).checked_div(s.uncomp).unwrap_or(0),
+                scale.scale(s.disk),
+                scale.scale(s.uncomp),
+                scale.scale(s.refd),
+                nfile,
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fmt_top(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        let by = match config().top_by {
+            TopBy::Ratio => "compression ratio",
+            TopBy::Disk => "disk usage",
+        };
+        writeln!(f)?;
+        writeln!(f, "Worst {} files by {}:", self.sorted_top.len(), by)?;
+        writeln!(
+            f,
+            "{:>4} {:>14} {:>16} {:>16}  File",
+            "Perc", "Disk Usage", "Uncompressed", "Referenced"
+        )?;
+        for e in &self.sorted_top {
+            writeln!(
+                f,
+                "{:>3}% {:>14} {:>16} {:>16}  {}",
+                e.stat.get_percent(),
+                scale.scale(e.stat.disk),
+                scale.scale(e.stat.uncomp),
+                scale.scale(e.stat.refd),
+                e.path.display()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The totals of each filesystem the scan crossed into, by fsid and the
+    /// first path seen on it.
+    fn fmt_filesystems(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>14} {:>16} {:>16}  Filesystem",
+            "Disk Usage", "Uncompressed", "Referenced"
+        )?;
+        for (s, (fsid, path)) in self.per_fs.iter().zip(filesystems()) {
+            if s.is_empty() {
+                continue;
+            }
+            writeln!(
+                f,
+                "{:>14} {:>16} {:>16}  {} ({})",
+                scale.scale(s.disk),
+                scale.scale(s.uncomp),
+                scale.scale(s.refd),
+                fmt_fsid(&fsid),
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fmt_sharing(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        let summary = &self.sharing_summary;
+        writeln!(f)?;
+        writeln!(f, "{:>14} {:>14}  File-or-dir", "Exclusive", "Shared")?;
+        for (i, (exclusive, shared)) in summary.per_root.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>14} {:>14}  {}",
+                scale.scale(*exclusive),
+                scale.scale(*shared),
+                self.root_name(i)
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:>10} {:>14}", "Shared by", "Disk Usage")?;
+        for (nroots, disk) in &summary.by_nroots {
+            writeln!(f, "{:>10} {:>14}", nroots, scale.scale(*disk))?;
+        }
+        Ok(())
+    }
+
+    /// Disk usage referenced by one file and by several, the latter by
+    /// number of files rounded up to powers of two.
+    fn fmt_reflinks(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        let by_nfiles = &self.reflinks_by_nfiles;
+        let mut buckets: BTreeMap<u32, u64> = BTreeMap::new();
+        for (&nfiles, &disk) in by_nfiles.range(2..) {
+            *buckets.entry(nfiles.next_power_of_two()).or_default() += disk;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<16} {:>14}", "Reflinks", "Disk Usage")?;
+        let exclusive = by_nfiles.get(&1).copied().unwrap_or(0);
+        writeln!(f, "{:<16} {:>14}", "exclusive", scale.scale(exclusive))?;
+        let shared = buckets.values().sum();
+        writeln!(f, "{:<16} {:>14}", "shared", scale.scale(shared))?;
+        for (hi, disk) in buckets {
+            let files = match hi / 2 + 1 {
+                lo if lo == hi => format!("  by {} files", hi),
+                lo => format!("  by {}-{} files", lo, hi),
+            };
+            writeln!(f, "{:<16} {:>14}", files, scale.scale(disk))?;
+        }
+        Ok(())
+    }
+
+    /// Disk usage of regular extents by their number of references, those
+    /// past two rounded up to powers of two, with its share of the total.
+    fn fmt_refcounts(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        let mut buckets: BTreeMap<u32, u64> = BTreeMap::new();
+        for (&nrefs, &disk) in &self.by_nrefs {
+            *buckets.entry(nrefs.next_power_of_two()).or_default() += disk;
+        }
+        let total: u64 = buckets.values().sum();
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<16} {:>14} {:>6}",
+            "Referenced", "Disk Usage", "Share"
+        )?;
+        for (hi, disk) in buckets {
+            let refs = match hi {
+                1 => "once".to_string(),
+                2 => "twice".to_string(),
+                _ => format!("{}-{} times", hi / 2 + 1, hi),
+            };
+            let pct = (disk * 1000).checked_div(total).unwrap_or(0);
+            writeln!(
+                f,
+                "{:<16} {:>14} {:>3}.{}%",
+                refs,
+                scale.scale(disk),
+                pct / 10,
+                pct % 10
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The [`ExtentStats::metadata`] estimate over everything scanned.
+    fn fmt_metadata(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        let rows = self.total.metadata();
+        writeln!(f)?;
+        writeln!(f, "{:<16} {:>14}", "Metadata (est.)", "Size")?;
+        for (name, size) in rows {
+            writeln!(f, "{:<16} {:>14}", name, scale.scale(size))?;
+        }
+        let total = rows.iter().map(|(_, s)| s).sum();
+        writeln!(f, "{:<16} {:>14}", "total", scale.scale(total))
+    }
+
+    /// One line per candidate: the number of extents, their average size in
+    /// bytes and the path, tab separated for `sort` and `cut -f3-`.
+    fn fmt_defrag(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        for (path, nextent, avg) in &self.defrag {
+            writeln!(f, "{}\t{}\t{}", nextent, avg, path.display())?;
+        }
+        Ok(())
+    }
+
+    fn fmt_audit(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(f, "Files with data not compressed as their property asks:")?;
+        writeln!(f, "{:<8} {:>14}  Path", "Property", "Otherwise")?;
+        for (path, property, disk) in &self.audit {
+            writeln!(
+                f,
+                "{:<8} {:>14}  {}",
+                property,
+                scale.scale(*disk),
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fmt_prealloc(&self, f: &mut dyn Write, scale: Scale) -> std::io::Result<()> {
+        writeln!(f)?;
+        writeln!(f, "Files with preallocated extents:")?;
+        for (path, size) in &self.prealloc_files {
+            writeln!(f, "{:>14}  {}", scale.scale(*size), path.display())?;
+        }
+        let total = self.prealloc_files.iter().map(|(_, size)| size).sum();
+        writeln!(
+            f,
+            "{:>14}  in {} files",
+            scale.scale(total),
+            self.prealloc_files.len()
+        )
+    }
+}
+
+/// Usage and number of files per key, see `--by-type` and `--by-ext`.
+type Buckets<K> = HashMap<K, (SizeStat, u64)>;
+
+fn add_bucket<K: Eq + Hash>(buckets: &mut Buckets<K>, key: K, file: &FileReport) {
+    let total = file.total();
+    let (s, nfile) = buckets.entry(key).or_default();
+    *nfile += 1;
+    s.disk += total.disk;
+    s.uncomp += total.uncomp;
+    s.refd += total.refd;
+}
+
+fn fmt_buckets<K: Display>(
+    f: &mut dyn Write,
+    title: &str,
+    buckets: &Buckets<K>,
+    scale: Scale,
+) -> std::io::Result<()> {
+    let mut rows: Vec<_> = buckets.iter().collect();
+    rows.sort_unstable_by_key(|(_, (s, _))| Reverse(s.disk));
+    writeln!(f)?;
+    write_table(
+        f,
+        title,
+        "Perc",
+        "Disk Usage",
+        "Uncompressed",
+        "Referenced",
+        "NFiles",
+    )?;
+    for (name, (s, nfile)) in rows.into_iter().filter(|(_, (s, _))| !s.is_empty()) {
+        write_table(
+            f,
+            name,
+            format!("{:>3}%", s.get_percent()),
+            scale.scale(s.disk),
+            scale.scale(s.uncomp),
+            scale.scale(s.refd),
+            nfile,
+        )?;
+    }
+    Ok(())
+}
+
+fn fmt_buckets_json<K: Display + Ord>(
+    f: &mut dyn Write,
+    member: &str,
+    buckets: &Buckets<K>,
+) -> std::io::Result<()> {
+    let mut rows: Vec<_> = buckets.iter().collect();
+    rows.sort_unstable_by_key(|&(name, _)| name);
+    write!(f, ",\n  \"{}\": {{", member)?;
+    for (i, (name, (s, nfile))) in rows.into_iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        let name = json_str(&name.to_string());
+        write!(f, "{}\n    {}: {}", sep, name, json_size(s, *nfile))?;
+    }
+    write!(f, "\n  }}")
+}
+
+/// Lowercased extension of `path` with its dot, keeping `.tar.*` whole;
+/// `(none)` if there is none.
+fn extension(path: &Path) -> String {
+    let none = || "(none)".to_string();
+    let Some(name) = path.file_name() else {
+        return none();
+    };
+    let name = name.to_string_lossy().to_lowercase();
+    // a leading dot marks a hidden file, not an extension
+    let mut parts = name.trim_start_matches('.').rsplitn(3, '.');
+    let (Some(last), Some(prev)) = (parts.next(), parts.next()) else {
+        return none();
+    };
+    if last.is_empty() {
+        return none();
+    }
+    if prev == "tar" && parts.next().is_some() {
+        format!(".tar.{}", last)
+    } else {
+        format!(".{}", last)
+    }
+}
+
+/// The shard out of `nshards` that deduplicates `extent`: by its
+/// filesystem and disk location, or for inline extents and holes, which
+/// have none, by inode.
+pub fn shard_of(extent: &ExtentInfo, nshards: usize) -> usize {
+    let key = match extent.disk_bytenr() {
+        0 => extent.objectid(),
+        bytenr => bytenr,
+    };
+    shard_of_key(extent.fs(), key, nshards)
+}
+
+fn shard_of_key(fs: FsId, key: u64, nshards: usize) -> usize {
+    // bytenrs are sector aligned, spread them with a Fibonacci hash
+    let key = key ^ fs as u64;
+    (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % nshards
+}
+
+pub enum CollectorMsg {
+    Extents(Box<[ExtentInfo]>),
+    Files(Box<[FileReport]>),
+    /// add what was collected so far to the dump, sent to every shard, see
+    /// [`crate::signal`]
+    DumpIntermediate(Arc<Dump>),
+}
+
+/// The main table of what all shards collected so far, printed to stderr
+/// by the last of them to add its part.
+pub struct Dump {
+    left: AtomicUsize,
+    sum: Mutex<ExtentStats>,
+}
+
+impl Dump {
+    /// A dump of `nshards` shards.
+    pub fn new(nshards: usize) -> Self {
+        Self {
+            left: AtomicUsize::new(nshards),
+            sum: Mutex::new(ExtentStats::new()),
+        }
+    }
+
+    fn add(&self, stats: &ExtentStats) {
+        let mut sum = self.sum.lock().unwrap();
+        sum.stat.merge(&*stats.stat);
+        sum.nextent += stats.nextent;
+        sum.ninline += stats.ninline;
+        sum.nunique += stats.nunique;
+        if self.left.fetch_sub(1, Ordering::AcqRel) == 1 {
+            Self::fmt(&sum, &mut stderr().lock()).ok();
+        }
+    }
+
+    /// File counts are only known at the end, so they are left at zero.
+    fn fmt(sum: &ExtentStats, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            f,
+            "Partial: {} regular extents ({} refs), {} inline so far.",
+            sum.nextent_unique(),
+            sum.nextent - sum.ninline,
+            sum.ninline,
+        )?;
+        if sum.nextent > 0 {
+            sum.stat.fmt(f, config().bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Box<[ExtentInfo]>> for CollectorMsg {
+    fn from(v: Box<[ExtentInfo]>) -> Self {
+        Self::Extents(v)
+    }
+}
+
+impl From<Box<[FileReport]>> for CollectorMsg {
+    fn from(v: Box<[FileReport]>) -> Self {
+        Self::Files(v)
+    }
+}
+
+impl Actor for Collector {
+    type Message = CollectorMsg;
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "collect",
+            level = "debug",
+            skip_all,
+            fields(n = match &msg {
+                CollectorMsg::Extents(v) => v.len(),
+                CollectorMsg::Files(v) => v.len(),
+                CollectorMsg::DumpIntermediate(_) => 0,
+            })
+        )
+    )]
+    async fn handle(&mut self, msg: Self::Message) -> Result<(), Error> {
+        get_err()?;
+        match msg {
+            CollectorMsg::Extents(v) => self.add_extents(v),
+            CollectorMsg::Files(v) => self.add_files(v),
+            CollectorMsg::DumpIntermediate(dump) => dump.add(&self.total),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    env, fs, io,
+    os::{
+        fd::{AsFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+    time::Duration,
+};
+
+use rustix::{
+    event::{PollFd, PollFlags, Timespec, poll},
+    fs::Mode,
+    io::{Errno, FdFlags, fcntl_setfd},
+    net::sockopt::socket_peercred,
+    process::{Uid, geteuid, getpid, umask},
+};
+
+/// First fd passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take over the sockets passed by systemd socket activation.
+///
+/// Returns an empty list when the process was not socket-activated, i.e.
+/// `LISTEN_PID` is missing or names another process.  The returned fds are
+/// marked close-on-exec.  As `sd_listen_fds(1)` does, the `LISTEN_*`
+/// variables are removed, so child processes don't take the fds for theirs.
+///
+/// # Safety
+///
+/// Must be called at most once per process, and while no other thread
+/// reads or writes the environment, see [`env::remove_var`].
+pub unsafe fn listen_fds() -> Vec<OwnedFd> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<i32>().ok());
+    let nfds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // Safety: as the caller promises
+        unsafe { env::remove_var(var) };
+    }
+    let (Some(pid), Some(nfds)) = (pid, nfds) else {
+        return vec![];
+    };
+    if pid != getpid().as_raw_nonzero().get() {
+        return vec![];
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(nfds))
+        .map(|raw| {
+            // Safety: systemd hands these fds to us and nothing else in
+            // the process owns them.
+            let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+            fcntl_setfd(&fd, FdFlags::CLOEXEC).ok();
+            fd
+        })
+        .collect()
+}
+
+/// Wait up to `timeout` for a connection to accept on `listener`.  `false`
+/// if none came, for the daemon to exit and leave the socket to systemd.
+pub fn wait_for_connection(listener: impl AsFd, timeout: Duration) -> io::Result<bool> {
+    let timeout = Timespec::try_from(timeout).map_err(|_| Errno::INVAL)?;
+    let mut fds = [PollFd::new(&listener, PollFlags::IN)];
+    loop {
+        match poll(&mut fds, Some(&timeout)) {
+            Ok(n) => return Ok(n > 0),
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Listen at `path`, a socket only its owner can connect to.  One left
+/// behind by a daemon that is gone is replaced, one a daemon still listens
+/// on is not.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let bind = || {
+        let mask = umask(Mode::from_raw_mode(0o177));
+        let ret = UnixListener::bind(path);
+        umask(mask);
+        ret
+    };
+    match bind() {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+                && UnixStream::connect(path)
+                    .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if !stale {
+                return Err(e);
+            }
+            fs::remove_file(path)?;
+            bind()
+        }
+        ret => ret,
+    }
+}
+
+/// The user at the other end of `stream`, if it may send requests: root,
+/// or the one the daemon runs as.  Socket activation may have made the
+/// socket reachable by others.
+pub fn peer_allowed(stream: &UnixStream) -> Result<(), Uid> {
+    match socket_peercred(stream) {
+        Ok(cred) if cred.uid.is_root() || cred.uid == geteuid() => Ok(()),
+        Ok(cred) => Err(cred.uid),
+        Err(_) => Err(Uid::from_raw(u32::MAX)),
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use crate::global::{Config, Format};
+
+/// Per machine defaults for options not given on the command line, from
+/// `XSZ_*` environment variables and a config file, see the README.
+/// `--cross-fs` and `--human` turn off the flags they set.
+#[derive(Default)]
+pub struct Defaults {
+    jobs: Option<u8>,
+    bytes: Option<bool>,
+    si: Option<bool>,
+    one_fs: Option<bool>,
+    exclude: Vec<String>,
+    format: Option<Format>,
+}
+
+enum Value {
+    Int(u64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<String>),
+}
+
+impl Defaults {
+    /// Read the config file, if any, then the environment.
+    pub fn load() -> Result<Self, String> {
+        let mut ret = Self::default();
+        let (path, explicit) = match env::var_os("XSZ_CONFIG") {
+            Some(p) => (Some(PathBuf::from(p)), true),
+            None => (default_path(), false),
+        };
+        if let Some(path) = path {
+            match std::fs::read_to_string(&path) {
+                Ok(s) => ret
+                    .parse_file(&s)
+                    .map_err(|e| format!("{}:{}", path.display(), e))?,
+                Err(e) if explicit || e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("{}: {}", path.display(), e));
+                }
+                Err(_) => (),
+            }
+        }
+        for key in ["jobs", "scale", "one_fs", "exclude", "format"] {
+            let var = format!("XSZ_{}", key.to_ascii_uppercase());
+            let Ok(s) = env::var(&var) else {
+                continue;
+            };
+            let value = match key {
+                "exclude" => Value::Array(
+                    s.split(':')
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                ),
+                _ => parse_value(&s).unwrap_or(Value::Str(s)),
+            };
+            ret.set(key, value).map_err(|e| format!("{}: {}", var, e))?;
+        }
+        Ok(ret)
+    }
+
+    /// Fill in what the command line didn't give.  `given` tells whether
+    /// it gave an option taking a value, by its long name, see
+    /// [`on_command_line`].
+    pub fn apply(self, cfg: &mut Config, given: impl Fn(&str) -> bool) {
+        if let Some(jobs) = self.jobs
+            && !given("jobs")
+        {
+            cfg.jobs = jobs;
+        }
+        // any scale given on the command line replaces the one configured
+        if !(cfg.bytes || cfg.human || cfg.si) {
+            cfg.bytes = self.bytes.unwrap_or(false);
+            cfg.si = self.si.unwrap_or(false);
+        }
+        if !(cfg.one_fs || cfg.cross_fs) {
+            cfg.one_fs = self.one_fs.unwrap_or(false);
+        }
+        if let Some(format) = self.format
+            && !given("format")
+        {
+            cfg.format = format;
+        }
+        // command line patterns come last, for --plan to show them last
+        let mut exclude = self.exclude;
+        exclude.append(&mut cfg.exclude);
+        cfg.exclude = exclude;
+    }
+
+    fn parse_file(&mut self, s: &str) -> Result<(), String> {
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| format!("{}: {}", i + 1, e);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected `key = value`".into()))?;
+            let value = parse_value(value.trim()).ok_or_else(|| err("invalid value".into()))?;
+            self.set(key.trim(), value).map_err(err)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match (key, value) {
+            ("jobs", Value::Int(n)) if n <= u8::MAX as u64 => self.jobs = Some(n as u8),
+            ("scale", Value::Str(s)) => {
+                let (bytes, si) = match s.as_str() {
+                    "bytes" => (true, false),
+                    "human" => (false, false),
+                    "si" => (false, true),
+                    _ => return Err(format!("scale must be bytes, human or si, not '{}'", s)),
+                };
+                self.bytes = Some(bytes);
+                self.si = Some(si);
+            }
+            ("one_fs", Value::Bool(b)) => self.one_fs = Some(b),
+            ("one_fs", Value::Int(n @ (0 | 1))) => self.one_fs = Some(n == 1),
+            ("exclude", Value::Array(v)) => self.exclude = v,
+            ("exclude", Value::Str(s)) => self.exclude = vec![s],
+            ("format", Value::Str(s)) => {
+                self.format = Some(match s.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    "tsv" => Format::Tsv,
+                    "ndjson" => Format::Ndjson,
+                    "openmetrics" => Format::Openmetrics,
+                    _ => return Err(format!("unknown format '{}'", s)),
+                })
+            }
+            ("jobs" | "scale" | "one_fs" | "exclude" | "format", _) => {
+                return Err(format!("invalid value for {}", key));
+            }
+            _ => eprintln!("Ignoring unknown setting '{}'", key),
+        }
+        Ok(())
+    }
+}
+
+/// Whether the command line gives the option `--long`, with `-j` for
+/// `--jobs`: the parsed value can't tell an explicit `-j 0` or `--format
+/// text` from none.
+pub fn on_command_line(long: &str) -> bool {
+    given_in(&env::args_os().skip(1).collect::<Vec<_>>(), long)
+}
+
+/// [`on_command_line`] for the options `args`.
+pub fn given_in(args: &[impl AsRef<OsStr>], long: &str) -> bool {
+    // short options taking a value: the rest of their word is the value
+    const SHORT_VALUES: &[u8] = b"j";
+    let short = match long {
+        "jobs" => Some(b'j'),
+        _ => None,
+    };
+    args.iter()
+        .map(AsRef::as_ref)
+        .take_while(|arg| *arg != "--")
+        .any(|arg| {
+            let arg = arg.as_encoded_bytes();
+            match arg.strip_prefix(b"--") {
+                Some(name) => {
+                    let name = name.split(|&b| b == b'=').next().unwrap_or_default();
+                    name == long.as_bytes()
+                }
+                None => match arg.strip_prefix(b"-") {
+                    Some(shorts) => {
+                        let end = (shorts.iter())
+                            .position(|c| SHORT_VALUES.contains(c))
+                            .map_or(shorts.len(), |i| i + 1);
+                        short.is_some_and(|s| shorts[..end].contains(&s))
+                    }
+                    None => false,
+                },
+            }
+        })
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("xsz.toml"))
+}
+
+/// A TOML integer, boolean, string or single line array of strings,
+/// optionally followed by a comment.
+fn parse_value(s: &str) -> Option<Value> {
+    let (value, rest) = if let Some(s) = s.strip_prefix('[') {
+        let mut items = vec![];
+        let mut s = s.trim_start();
+        loop {
+            if let Some(rest) = s.strip_prefix(']') {
+                break (Value::Array(items), rest);
+            }
+            let (item, rest) = parse_str(s)?;
+            items.push(item);
+            s = rest.trim_start();
+            s = s.strip_prefix(',').unwrap_or(s).trim_start();
+        }
+    } else if s.starts_with(['"', '\'']) {
+        let (s, rest) = parse_str(s)?;
+        (Value::Str(s), rest)
+    } else {
+        let end = s
+            .find(|c: char| c.is_whitespace() || c == '#')
+            .unwrap_or(s.len());
+        let (word, rest) = s.split_at(end);
+        let value = match word {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::Int(word.replace('_', "").parse().ok()?),
+        };
+        (value, rest)
+    };
+    let rest = rest.trim_start();
+    (rest.is_empty() || rest.starts_with('#')).then_some(value)
+}
+
+/// A basic `"..."` or literal `'...'` string and what follows it.
+fn parse_str(s: &str) -> Option<(String, &str)> {
+    if let Some(s) = s.strip_prefix('\'') {
+        let end = s.find('\'')?;
+        return Some((s[..end].to_string(), &s[end + 1..]));
+    }
+    let s = s.strip_prefix('"')?;
+    let mut ret = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((ret, &s[i + 1..])),
+            '\\' => ret.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => ret.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Defaults, String> {
+        let mut ret = Defaults::default();
+        ret.parse_file(s).map(|()| ret)
+    }
+
+    #[test]
+    fn parse_file() {
+        let d = parse(
+            "# pinned\n\
+             jobs = 1_2\n\
+             scale = 'si'  # decimal\n\
+             one_fs = true\n\
+             exclude = [\"a b\", 'c\\d', ]\n\
+             format = \"json\"\n",
+        )
+        .unwrap();
+        assert_eq!(d.jobs, Some(12));
+        assert_eq!((d.bytes, d.si), (Some(false), Some(true)));
+        assert_eq!(d.one_fs, Some(true));
+        assert_eq!(d.exclude, ["a b", "c\\d"]);
+        assert!(d.format == Some(Format::Json));
+    }
+
+    #[test]
+    fn parse_file_malformed() {
+        for s in [
+            "jobs",
+            "jobs = 256",
+            "jobs = \"4\"",
+            "scale = \"kilo\"",
+            "one_fs = 2",
+            "format = \"xml\"",
+            "exclude = [\"a\"",
+            "exclude = \"a\\q\"",
+            "exclude = \"a\" b",
+        ] {
+            assert!(parse(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let d = || parse("jobs = 4\nscale = \"bytes\"\none_fs = true\nformat = \"csv\"").unwrap();
+        let mut cfg = Config::default();
+        d().apply(&mut cfg, |_| false);
+        assert!(cfg.jobs == 4 && cfg.bytes && cfg.one_fs && cfg.format == Format::Csv);
+
+        let mut cfg = Config {
+            human: true,
+            cross_fs: true,
+            ..Default::default()
+        };
+        d().apply(&mut cfg, |long| long == "jobs" || long == "format");
+        assert!(cfg.jobs == 0 && !cfg.bytes && !cfg.one_fs && cfg.format == Format::Text);
+
+        let mut cfg = Config {
+            bytes: true,
+            ..Default::default()
+        };
+        parse("scale = \"si\"").unwrap().apply(&mut cfg, |_| false);
+        assert!(cfg.bytes && !cfg.si);
+    }
+}
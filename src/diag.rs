@@ -0,0 +1,296 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::Path,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use rustix::{fs::FileType, io::Errno};
+
+use crate::{error::Error, global::config, reporter::Reporter};
+
+/// At most this many error lines are printed per second, further errors
+/// are only counted and show up in the final [`summary`].
+const RATE_LIMIT: u32 = 20;
+
+#[derive(Default)]
+struct Group {
+    count: u64,
+    hidden: u64,
+}
+
+/// Errors are coalesced by errno and the directory they happened in:
+/// only the first one of each group is printed.
+type GroupKey = (Errno, Box<Path>);
+
+struct State {
+    groups: HashMap<GroupKey, Group>,
+    /// every error, printed or not
+    totals: HashMap<Errno, u64>,
+    /// files skipped as not on btrfs
+    non_btrfs: u64,
+    /// files skipped for extent items that don't parse
+    parse_errors: u64,
+    /// special files left out, by type
+    special: HashMap<&'static str, u64>,
+    window: Instant,
+    nprinted: u32,
+}
+
+impl State {
+    fn rate_limited(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.nprinted = 0;
+        }
+        self.nprinted += 1;
+        self.nprinted > RATE_LIMIT
+    }
+}
+
+fn state() -> &'static Mutex<State> {
+    static STATE: LazyLock<Mutex<State>> = LazyLock::new(|| {
+        Mutex::new(State {
+            groups: HashMap::new(),
+            totals: HashMap::new(),
+            non_btrfs: 0,
+            parse_errors: 0,
+            special: HashMap::new(),
+            window: Instant::now(),
+            nprinted: 0,
+        })
+    });
+    &STATE
+}
+
+/// The `--errors-to` file, which gets every error instead of stderr.
+static LOG: OnceLock<Mutex<LineWriter<File>>> = OnceLock::new();
+
+/// Create the `--errors-to` file, if one was asked for.  Runs once
+/// privileges are dropped, as the user `--drop-privs` switches to; the roots
+/// it can't open are reported to stderr.
+pub fn open_log() -> Result<(), Error> {
+    let Some(path) = &config().errors_to else {
+        return Ok(());
+    };
+    if LOG.get().is_some() {
+        return Ok(());
+    }
+    let f = File::create(path).map_err(|e| Error::Io {
+        path: path.as_path().into(),
+        err: Errno::from_io_error(&e).unwrap_or(Errno::IO),
+    })?;
+    LOG.set(Mutex::new(LineWriter::new(f))).ok();
+    Ok(())
+}
+
+/// Report an I/O error on `path` that doesn't stop the scan.
+pub fn io_error(path: &Path, err: Errno) {
+    let mut state = state().lock().unwrap();
+    *state.totals.entry(err).or_default() += 1;
+    if let Some(log) = LOG.get() {
+        drop(state);
+        writeln!(log.lock().unwrap(), "{}: {}", path.display(), err).ok();
+        return;
+    }
+    if config().verbose {
+        drop(state);
+        eprintln!("{}: {}", path.display(), err);
+        return;
+    }
+    let key: GroupKey = (err, path.parent().unwrap_or(path).into());
+    if let Some(group) = state.groups.get_mut(&key) {
+        group.count += 1;
+        group.hidden += 1;
+        return;
+    }
+    let hidden = config().quiet || state.rate_limited();
+    state.groups.insert(
+        key,
+        Group {
+            count: 1,
+            hidden: hidden as u64,
+        },
+    );
+    drop(state);
+    if !hidden {
+        eprintln!("{}: {}", path.display(), err);
+    }
+}
+
+/// Report a file skipped for an extent item that doesn't parse.  Printed
+/// like [`io_error`], though never coalesced: there should be few.
+pub fn parse_error(path: &Path, msg: &str) {
+    let mut state = state().lock().unwrap();
+    state.parse_errors += 1;
+    if let Some(log) = LOG.get() {
+        drop(state);
+        writeln!(log.lock().unwrap(), "{}: {}", path.display(), msg).ok();
+        return;
+    }
+    let hidden = !config().verbose && (config().quiet || state.rate_limited());
+    drop(state);
+    if !hidden {
+        eprintln!("{}: {}", path.display(), msg);
+    }
+}
+
+/// Forget the errors of an earlier scan.
+pub fn reset() {
+    let mut state = state().lock().unwrap();
+    state.groups.clear();
+    state.totals.clear();
+    state.non_btrfs = 0;
+    state.parse_errors = 0;
+    state.special.clear();
+}
+
+/// Count a file skipped as not on btrfs, for the [`summary`].
+pub fn not_btrfs() {
+    state().lock().unwrap().non_btrfs += 1;
+}
+
+/// Count a special file left out, for the [`summary`].  With `--verbose`,
+/// name it right away.
+pub fn special(path: &Path, kind: FileType) {
+    let name = special_name(kind);
+    *state().lock().unwrap().special.entry(name).or_default() += 1;
+    if config().verbose {
+        eprintln!("{}: {}, left out", path.display(), name);
+    }
+}
+
+fn special_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::Socket => "socket",
+        FileType::Fifo => "fifo",
+        FileType::CharacterDevice => "char device",
+        FileType::BlockDevice => "block device",
+        _ => "unknown type",
+    }
+}
+
+/// Print what [`io_error`] kept quiet about, most frequent first, then
+/// how many paths were skipped by error, how many files for not being on
+/// btrfs or for corrupted extent items and how many special files were
+/// left out.
+pub fn summary(f: &mut dyn Write) -> io::Result<()> {
+    let state = state().lock().unwrap();
+    if !config().quiet {
+        fmt_hidden(f, &state)?;
+    }
+    if state.non_btrfs > 0 {
+        writeln!(
+            f,
+            "{} files on non-btrfs filesystems were skipped",
+            state.non_btrfs
+        )?;
+    }
+    if state.parse_errors > 0 {
+        writeln!(
+            f,
+            "{} files with corrupted extent items were skipped",
+            state.parse_errors
+        )?;
+    }
+    if !state.special.is_empty() {
+        let mut special: Vec<_> = state.special.iter().collect();
+        special.sort_unstable_by_key(|&(name, n)| (Reverse(n), *name));
+        let total: u64 = special.iter().map(|(_, n)| *n).sum();
+        let by_type: Vec<_> = special
+            .iter()
+            .map(|(name, n)| format!("{} {}", n, name))
+            .collect();
+        writeln!(
+            f,
+            "Left out {} special files: {} (count them with --include-special)",
+            total,
+            by_type.join(", ")
+        )?;
+    }
+    let mut totals: Vec<_> = state.totals.iter().collect();
+    if totals.is_empty() {
+        return Ok(());
+    }
+    totals.sort_unstable_by_key(|&(err, n)| (Reverse(n), err.raw_os_error()));
+    let total: u64 = totals.iter().map(|(_, n)| *n).sum();
+    let by_err: Vec<_> = totals
+        .iter()
+        .map(|(err, n)| format!("{} {}", n, errno_name(**err)))
+        .collect();
+    write!(f, "Skipped {} paths: {}", total, by_err.join(", "))?;
+    match &config().errors_to {
+        Some(path) => writeln!(f, " (listed in {})", path.display()),
+        None => writeln!(f),
+    }
+}
+
+fn fmt_hidden(f: &mut dyn Write, state: &State) -> io::Result<()> {
+    let mut groups: Vec<_> = state.groups.iter().filter(|(_, g)| g.hidden > 0).collect();
+    if groups.is_empty() {
+        return Ok(());
+    }
+    groups.sort_unstable_by_key(|(_, g)| Reverse(g.count));
+    let nhidden: u64 = groups.iter().map(|(_, g)| g.hidden).sum();
+    writeln!(
+        f,
+        "{} more errors were not shown (use --verbose to see them all):",
+        nhidden
+    )?;
+    for ((err, dir), group) in groups {
+        writeln!(f, "  {:>8} x {} in {}", group.count, err, dir.display())?;
+    }
+    Ok(())
+}
+
+/// The default [`Reporter`]: errors go through [`io_error`], and
+/// [`summary`] is printed once the scan is over.
+pub struct Stderr;
+
+impl Reporter for Stderr {
+    fn file_error(&self, path: &Path, err: Errno) {
+        io_error(path, err);
+    }
+    fn parse_error(&self, path: &Path, msg: &str) {
+        parse_error(path, msg);
+    }
+    fn not_btrfs(&self, _path: &Path) {
+        not_btrfs();
+    }
+    fn special(&self, path: &Path, kind: FileType) {
+        special(path, kind);
+    }
+    fn note(&self, msg: &str) {
+        if !config().quiet {
+            eprintln!("{}", msg);
+        }
+    }
+    fn finished(&self) {
+        summary(&mut io::stderr()).ok();
+    }
+}
+
+/// The symbolic name of the errors a walk usually runs into.
+fn errno_name(err: Errno) -> String {
+    let name = match err {
+        Errno::ACCESS => "EACCES",
+        Errno::PERM => "EPERM",
+        Errno::NOENT => "ENOENT",
+        Errno::NOTDIR => "ENOTDIR",
+        Errno::LOOP => "ELOOP",
+        Errno::NAMETOOLONG => "ENAMETOOLONG",
+        Errno::IO => "EIO",
+        Errno::NOMEM => "ENOMEM",
+        Errno::MFILE => "EMFILE",
+        Errno::NFILE => "ENFILE",
+        Errno::STALE => "ESTALE",
+        Errno::INVAL => "EINVAL",
+        Errno::NOTTY => "ENOTTY",
+        _ => return format!("errno {}", err.raw_os_error()),
+    };
+    name.to_string()
+}
@@ -0,0 +1,211 @@
+use std::{cmp::Reverse, collections::HashMap, io::Write, path::Path};
+
+use crate::{btrfs::SizeStat, collector::Collector, json::Json, scale::Scale};
+
+/// What `xsz diff` compares of a result saved with `--output`.
+pub struct Saved {
+    /// rows of the usage table, TOTAL first, with their number of files
+    usage: Vec<(String, SizeStat, u64)>,
+    sparse: u64,
+    /// `--group-depth` directories, if saved with it
+    groups: Option<HashMap<String, SizeStat>>,
+    /// `--by-ext` extensions, if saved with it
+    by_ext: Option<HashMap<String, SizeStat>>,
+}
+
+impl Saved {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&s).map_err(|e| format!("{}:{}", path.display(), e))
+    }
+
+    /// The usage table of a scan just done, for `--watch`.  `None` with
+    /// `--frag`, which has no usage table.
+    pub fn of(collector: &Collector) -> Option<Self> {
+        let stat = collector.compsize()?;
+        let total = ("TOTAL".to_string(), stat.total(), stat.nfile_total);
+        let rows = stat
+            .rows()
+            .zip(stat.nfile)
+            .map(|((name, s), nfile)| (name.to_string(), *s, nfile));
+        Some(Self {
+            usage: [total].into_iter().chain(rows).collect(),
+            sparse: stat.sparse,
+            groups: None,
+            by_ext: None,
+        })
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let doc = Json::parse(s)?;
+        match doc.get("version").and_then(Json::as_u64) {
+            Some(1) => (),
+            Some(v) => return Err(format!(" unsupported version {}", v)),
+            None => return Err(" not an xsz result, save one with --output".into()),
+        }
+        let usage = doc
+            .get("usage")
+            .ok_or(" no usage table")?
+            .members()
+            .iter()
+            .map(|(name, v)| Ok((name.clone(), size(v)?, num(v, "files")?)))
+            .collect::<Result<_, String>>()?;
+        let groups = doc
+            .get("groups")
+            .map(|g| {
+                g.items()
+                    .iter()
+                    .map(|item| {
+                        let path = item.get("path").and_then(Json::as_str);
+                        let usage = item.get("usage").ok_or("group without usage")?;
+                        Ok((path.ok_or("group without path")?.to_string(), size(usage)?))
+                    })
+                    .collect::<Result<_, String>>()
+            })
+            .transpose()?;
+        let by_ext = doc
+            .get("by_ext")
+            .map(|b| {
+                b.members()
+                    .iter()
+                    .map(|(ext, v)| Ok((ext.clone(), size(v)?)))
+                    .collect::<Result<_, String>>()
+            })
+            .transpose()?;
+        Ok(Self {
+            usage,
+            sparse: doc.get("sparse").and_then(Json::as_u64).unwrap_or(0),
+            groups,
+            by_ext,
+        })
+    }
+}
+
+fn num(v: &Json, key: &str) -> Result<u64, String> {
+    v.get(key)
+        .and_then(Json::as_u64)
+        .ok_or_else(|| format!(" missing or malformed '{}'", key))
+}
+
+fn size(v: &Json) -> Result<SizeStat, String> {
+    Ok(SizeStat {
+        disk: num(v, "disk")?,
+        uncomp: num(v, "uncompressed")?,
+        refd: num(v, "referenced")?,
+    })
+}
+
+/// `new - old` with its sign, scaled.
+fn delta(scale: Scale, old: u64, new: u64) -> String {
+    match new.cmp(&old) {
+        std::cmp::Ordering::Equal => "0".into(),
+        std::cmp::Ordering::Greater => format!("+{}", scale.scale(new - old)),
+        std::cmp::Ordering::Less => format!("-{}", scale.scale(old - new)),
+    }
+}
+
+fn write_row(
+    f: &mut dyn Write,
+    scale: Scale,
+    name: &str,
+    old: SizeStat,
+    new: SizeStat,
+) -> std::io::Result<()> {
+    write!(
+        f,
+        "{:>14} {:>16} {:>16}",
+        delta(scale, old.disk, new.disk),
+        delta(scale, old.uncomp, new.uncomp),
+        delta(scale, old.refd, new.refd),
+    )?;
+    writeln!(f, "  {}", name)
+}
+
+/// The changes per key, largest change in disk usage first; unchanged
+/// keys are left out.
+fn fmt_changes(
+    f: &mut dyn Write,
+    title: &str,
+    old: &HashMap<String, SizeStat>,
+    new: &HashMap<String, SizeStat>,
+    scale: Scale,
+) -> std::io::Result<()> {
+    let mut keys: Vec<_> = new
+        .keys()
+        .chain(old.keys().filter(|k| !new.contains_key(*k)))
+        .collect();
+    let get = |m: &HashMap<String, SizeStat>, k: &str| m.get(k).copied().unwrap_or_default();
+    keys.retain(|k| get(old, k) != get(new, k));
+    keys.sort_unstable_by_key(|k| (Reverse(get(old, k).disk.abs_diff(get(new, k).disk)), *k));
+    writeln!(f)?;
+    writeln!(
+        f,
+        "{:>14} {:>16} {:>16}  {}",
+        "Disk Usage", "Uncompressed", "Referenced", title
+    )?;
+    for k in keys {
+        write_row(f, scale, k, get(old, k), get(new, k))?;
+    }
+    Ok(())
+}
+
+/// Print how usage changed from `old` to `new`, per compression type, then
+/// per directory and extension where both have them.
+pub fn fmt_diff(f: &mut dyn Write, old: &Saved, new: &Saved, scale: Scale) -> std::io::Result<()> {
+    writeln!(
+        f,
+        "{:>5} {:>14} {:>16} {:>16} {:>10}  Type",
+        "Perc", "Disk Usage", "Uncompressed", "Referenced", "NFiles"
+    )?;
+    let find = |s: &Saved, name: &str| {
+        s.usage
+            .iter()
+            .find(|(n, ..)| n == name)
+            .map_or((SizeStat::default(), 0), |&(_, s, nfile)| (s, nfile))
+    };
+    let mut names: Vec<&str> = new.usage.iter().map(|(n, ..)| n.as_str()).collect();
+    for (name, ..) in &old.usage {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    for name in names {
+        let ((o, onfile), (n, nnfile)) = (find(old, name), find(new, name));
+        if o.is_empty() && n.is_empty() {
+            continue;
+        }
+        // the change in percentage points of disk to uncompressed size
+        let perc = match (o.is_empty(), n.is_empty()) {
+            (false, false) => format!("{:+}%", n.get_percent() as i64 - o.get_percent() as i64),
+            _ => String::new(),
+        };
+        write!(
+            f,
+            "{:>5} {:>14} {:>16} {:>16} {:>10}",
+            perc,
+            delta(scale, o.disk, n.disk),
+            delta(scale, o.uncomp, n.uncomp),
+            delta(scale, o.refd, n.refd),
+            delta(Scale::Bytes, onfile, nnfile),
+        )?;
+        writeln!(f, "  {}", name)?;
+    }
+    if old.sparse != new.sparse {
+        writeln!(
+            f,
+            "{:>5} {:>14} {:>16} {:>16} {:>10}  sparse",
+            "",
+            "",
+            "",
+            delta(scale, old.sparse, new.sparse),
+            ""
+        )?;
+    }
+    if let (Some(o), Some(n)) = (&old.groups, &new.groups) {
+        fmt_changes(f, "Directory", o, n, scale)?;
+    }
+    if let (Some(o), Some(n)) = (&old.by_ext, &new.by_ext) {
+        fmt_changes(f, "Extension", o, n, scale)?;
+    }
+    Ok(())
+}
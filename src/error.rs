@@ -0,0 +1,69 @@
+use std::{fmt::Display, path::Path};
+
+use rustix::io::Errno;
+
+/// Why a scan stopped.
+#[derive(Debug)]
+pub enum Error {
+    /// an I/O error on `path` the scan can't go on without
+    Io { path: Box<Path>, err: Errno },
+    /// `path` is not on btrfs, or the kernel lacks SEARCH_V2
+    NotBtrfs { path: Box<Path> },
+    /// an extent item of `path` that makes no sense
+    Parse { path: Box<Path>, msg: String },
+    /// the scan couldn't be set up as asked
+    Setup(String),
+    /// a task of the scan panicked, a bug
+    Panicked,
+    /// stopped because of an earlier error, see [`crate::global::get_err`]
+    Cancelled,
+}
+
+/// Exit statuses of the `xsz` binary.
+pub mod exit_status {
+    pub const SUCCESS: i32 = 0;
+    /// the scan stopped, or with `--strict`, paths were skipped
+    pub const FATAL: i32 = 1;
+    /// the scan went on without some paths, e.g. unreadable ones
+    pub const SKIPPED: i32 = 2;
+    /// nothing was left to scan after the filters
+    pub const NO_MATCH: i32 = 3;
+}
+
+impl Error {
+    /// The error for a failed SEARCH_V2 ioctl on `path`.
+    pub fn search(path: &Path, err: Errno) -> Self {
+        let path = path.into();
+        if err == Errno::NOTTY {
+            Self::NotBtrfs { path }
+        } else {
+            Self::Io { path, err }
+        }
+    }
+
+    /// Every error that stops a scan is fatal, see [`exit_status`].
+    pub fn exit_code(&self) -> i32 {
+        exit_status::FATAL
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "{}: {}", path.display(), err),
+            Self::NotBtrfs { path } => {
+                write!(
+                    f,
+                    "{}: Not btrfs (or SEARCH_V2 unsupported)",
+                    path.display()
+                )
+            }
+            Self::Parse { path, msg } => write!(f, "{}: {}", path.display(), msg),
+            Self::Setup(msg) => write!(f, "{}", msg),
+            Self::Panicked => write!(f, "xsz panicked, a bug: the scan stopped"),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
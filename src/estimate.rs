@@ -0,0 +1,201 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    ops::Range,
+    os::fd::BorrowedFd,
+    time::{Duration, Instant},
+};
+
+use flate2::{Compression as ZlibLevel, write::ZlibEncoder};
+use rustix::io::{Errno, pread};
+
+use crate::btrfs::{
+    ExtentInfo,
+    tree::{Compression, ExtentType},
+};
+
+/// btrfs compresses file data in chunks of at most this size.
+pub const CHUNK: usize = 128 * 1024;
+/// Compressed data is stored in whole sectors.
+const SECTOR: u64 = 4096;
+/// Chunks sampled per file by default, spread evenly over its size.
+pub const SAMPLES: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd(i32),
+    Zlib(u32),
+}
+
+impl Codec {
+    /// What `--estimate compare` expands to.
+    const COMPARE: &[Codec] = &[
+        Codec::Zlib(3),
+        Codec::Zstd(1),
+        Codec::Zstd(3),
+        Codec::Zstd(9),
+    ];
+
+    /// Parse `zstd[:LEVEL]` or `zlib[:LEVEL]`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (name, level) = match spec.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (spec, None),
+        };
+        let parse_level = |max: i32, default: i32| match level {
+            Some(l) => l
+                .parse()
+                .ok()
+                .filter(|l| (1..=max).contains(l))
+                .ok_or_else(|| format!("invalid {} level '{}', expected 1-{}", name, l, max)),
+            None => Ok(default),
+        };
+        match name {
+            "zstd" => Ok(Codec::Zstd(parse_level(15, 3)?)),
+            "zlib" => Ok(Codec::Zlib(parse_level(9, 3)? as u32)),
+            // no lzo1x compressor at hand, and guessing its output
+            // would rank it wrongly
+            "lzo" => Err("lzo can't be estimated, only zstd and zlib".into()),
+            _ => Err(format!("unsupported algorithm '{}'", name)),
+        }
+    }
+
+    /// Parse a comma separated list of codecs, or `compare` for a
+    /// representative set of them.
+    pub fn parse_list(spec: &str) -> Result<Vec<Self>, String> {
+        if spec == "compare" {
+            return Ok(Self::COMPARE.to_vec());
+        }
+        spec.split(',').map(Self::parse).collect()
+    }
+
+    /// Size of `data` once compressed the way btrfs does it.
+    fn compress(&self, data: &[u8]) -> io::Result<u64> {
+        Ok(match *self {
+            Codec::Zstd(level) => zstd::bulk::compress(data, level)?.len() as u64,
+            Codec::Zlib(level) => {
+                let mut enc = ZlibEncoder::new(Vec::new(), ZlibLevel::new(level));
+                enc.write_all(data)?;
+                enc.finish()?.len() as u64
+            }
+        })
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Zstd(level) => write!(f, "zstd:{}", level),
+            Codec::Zlib(level) => write!(f, "zlib:{}", level),
+        }
+    }
+}
+
+/// Result of compressing a few sample chunks of one file with one codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    /// bytes read from the file
+    pub read: u64,
+    /// bytes btrfs would need to store them with `compress-force`
+    pub stored: u64,
+    /// the same with `compress` or `defragment -c`, which store the rest
+    /// of a file uncompressed after a chunk that doesn't shrink
+    pub stored_unforced: u64,
+    /// time spent compressing
+    pub cpu: Duration,
+}
+
+impl Sample {
+    /// Scale `disk` bytes by the ratio seen in the sample.
+    pub fn project(&self, disk: u64) -> u64 {
+        self.scale(disk, self.stored)
+    }
+
+    /// [`Self::project`] without `compress-force`.
+    pub fn project_unforced(&self, disk: u64) -> u64 {
+        self.scale(disk, self.stored_unforced)
+    }
+
+    fn scale(&self, disk: u64, stored: u64) -> u64 {
+        if self.read == 0 {
+            return disk;
+        }
+        (disk as u128 * stored as u128 / self.read as u128) as u64
+    }
+}
+
+/// The parts of a file stored uncompressed on disk, from its `extents` in
+/// file order, the adjacent ones joined: what [`sample`] reads.
+pub fn uncompressed_ranges(extents: &[ExtentInfo]) -> Vec<Range<u64>> {
+    let mut ret: Vec<Range<u64>> = vec![];
+    for e in extents {
+        if e.r#type() != ExtentType::Regular || e.comp() != Compression::None || e.is_hole() {
+            continue;
+        }
+        let range = e.offset()..e.offset() + e.stat().refd;
+        match ret.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ret.push(range),
+        }
+    }
+    ret
+}
+
+/// Read up to `nsamples` chunks of the `ranges` of the file `fd`, see
+/// [`uncompressed_ranges`], and compress each with every codec the way
+/// btrfs would: the output is rounded up to whole sectors, and chunks that
+/// don't shrink are stored as they are.
+/// Returns one [`Sample`] per codec, in the same order.
+pub fn sample(
+    fd: BorrowedFd<'_>,
+    ranges: &[Range<u64>],
+    codecs: &[Codec],
+    nsamples: u64,
+) -> io::Result<Vec<Sample>> {
+    let mut ret = vec![Sample::default(); codecs.len()];
+    let size: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+    if size == 0 {
+        return Ok(ret);
+    }
+    let nchunk = size.div_ceil(CHUNK as u64);
+    let step = nchunk.div_ceil(nsamples);
+    let mut buf = vec![0; CHUNK];
+    // per codec, whether btrfs would have given up compressing the file
+    let mut gave_up = vec![false; codecs.len()];
+    for idx in (0..nchunk).step_by(step as usize) {
+        // the chunk `idx` chunks into the ranges, put end to end
+        let mut at = idx * CHUNK as u64;
+        let Some(range) = ranges.iter().find(|r| {
+            let found = at < r.end - r.start;
+            if !found {
+                at -= r.end - r.start;
+            }
+            found
+        }) else {
+            break;
+        };
+        let offset = range.start + at;
+        let want = (range.end - offset).min(CHUNK as u64) as usize;
+        let len = loop {
+            match pread(fd, &mut buf[..want], offset) {
+                Ok(n) => break n,
+                Err(Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if len == 0 {
+            break;
+        }
+        let raw = (len as u64).next_multiple_of(SECTOR);
+        for ((codec, s), gave_up) in codecs.iter().zip(&mut ret).zip(&mut gave_up) {
+            let start = Instant::now();
+            let compressed = codec.compress(&buf[..len])?.next_multiple_of(SECTOR);
+            s.cpu += start.elapsed();
+            s.read += len as u64;
+            s.stored += compressed.min(raw);
+            *gave_up |= compressed >= raw;
+            s.stored_unforced += if *gave_up { raw } else { compressed };
+        }
+    }
+    Ok(ret)
+}
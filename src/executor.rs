@@ -1,8 +1,14 @@
 use std::{
+    cell::Cell,
+    collections::VecDeque,
     future::Future,
     hint::cold_path,
+    panic::{AssertUnwindSafe, catch_unwind},
     pin::pin,
-    sync::{Arc, LazyLock},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
     task::{Context, Poll, Wake, Waker},
     thread::{Builder, Thread, current, park},
 };
@@ -11,39 +17,163 @@ use async_task::{Runnable, Task};
 use futures_lite::FutureExt;
 use kanal::{Receiver, Sender, unbounded};
 
-use crate::global::config;
+use crate::{
+    error::Error,
+    global::{config, set_err},
+    profile,
+};
+
+thread_local! {
+    /// index of this thread's run queue, `None` outside the pool
+    static QUEUE: Cell<Option<usize>> = const { Cell::new(None) };
+}
 
+/// A thread pool where each thread runs what it schedules itself from its
+/// own queue, and steals from the others once that runs dry.  What is
+/// scheduled from outside the pool goes through one shared channel.
 pub struct Executor {
-    sender: Sender<Runnable>,
-    receiver: Receiver<Runnable>,
+    /// runnables from outside the pool, and `None` to wake a thread to
+    /// steal
+    sender: Sender<Option<Runnable>>,
+    receiver: Receiver<Option<Runnable>>,
+    queues: Box<[Mutex<VecDeque<Runnable>>]>,
+    /// threads waiting on `receiver`
+    sleeping: AtomicUsize,
+    /// where the next steal starts looking, so victims take turns
+    next_victim: AtomicUsize,
 }
 
 impl Executor {
     fn new(nthreads: u8) -> Self {
-        let (sender, receiver) = unbounded::<Runnable>();
-        for i in 0..nthreads {
-            let receiver = receiver.clone();
+        let (sender, receiver) = unbounded();
+        for i in 0..nthreads as usize {
             if let Err(e) = Builder::new()
                 .name(format!("xsz-worker{}", i))
-                .stack_size(4 * 1024)
-                .spawn(move || {
-                    while let Ok(r) = receiver.recv() {
-                        r.run();
-                    }
-                })
+                .stack_size(config().stack_size())
+                .spawn(move || global().run(i))
             {
                 cold_path();
                 eprintln!("Failed to spawn worker thread: {}", e);
             }
         }
-        Self { sender, receiver }
+        Self {
+            sender,
+            receiver,
+            queues: (0..nthreads).map(|_| Default::default()).collect(),
+            sleeping: AtomicUsize::new(0),
+            next_victim: AtomicUsize::new(0),
+        }
     }
+
+    /// The loop of pool thread `i`.
+    fn run(&self, i: usize) {
+        QUEUE.set(Some(i));
+        loop {
+            if let Some(r) = self.find(i) {
+                run_task(r, i, false);
+                continue;
+            }
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            let msg = self.receiver.recv();
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            match msg {
+                Ok(Some(r)) => run_task(r, i, false),
+                Ok(None) => (),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Something for pool thread `i` to run: from its own queue, from
+    /// outside the pool, or stolen.
+    fn find(&self, i: usize) -> Option<Runnable> {
+        if let Some(r) = self.queues[i].lock().unwrap().pop_front() {
+            return Some(r);
+        }
+        if let Ok(Some(Some(r))) = self.receiver.try_recv() {
+            return Some(r);
+        }
+        self.steal(Some(i))
+    }
+
+    /// Take half the queue of another thread, keeping the rest in the
+    /// queue of `thief`, or a single runnable if `thief` is not in the
+    /// pool.
+    fn steal(&self, thief: Option<usize>) -> Option<Runnable> {
+        let n = self.queues.len();
+        let start = self.next_victim.fetch_add(1, Ordering::Relaxed);
+        for victim in (start..start + n).map(|v| v % n) {
+            if Some(victim) == thief {
+                continue;
+            }
+            let mut stolen: VecDeque<_> = {
+                let mut queue = self.queues[victim].lock().unwrap();
+                let take = match thief {
+                    Some(_) => queue.len().div_ceil(2),
+                    None => queue.len().min(1),
+                };
+                queue.drain(..take).collect()
+            };
+            let Some(r) = stolen.pop_front() else {
+                continue;
+            };
+            if let Some(thief) = thief
+                && !stolen.is_empty()
+            {
+                self.queues[thief].lock().unwrap().append(&mut stolen);
+            }
+            return Some(r);
+        }
+        None
+    }
+
     #[inline]
     fn schedule(&self, runnable: Runnable) {
-        self.sender.send(runnable).unwrap();
+        match QUEUE.get() {
+            Some(i) => {
+                self.queues[i].lock().unwrap().push_back(runnable);
+                if self.sleeping.load(Ordering::SeqCst) > 0 {
+                    self.sender.send(None).ok();
+                }
+            }
+            None => self.sender.send(Some(runnable)).unwrap(),
+        }
+    }
+}
+
+/// Run `r` on thread `i` of the pool, or of the blocking pool.
+#[inline]
+fn run_task(r: Runnable, i: usize, blocking: bool) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("task", thread = i, blocking).entered();
+    let start = profile::start();
+    run(r);
+    profile::busy(start, i, blocking);
+}
+
+/// Makes the next task run panic, for the tests of [`crate::capi`].
+#[cfg(all(test, feature = "capi"))]
+pub(crate) static PANIC_IN_TASK: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// Run `r`, stopping the scan if it panics instead of taking the thread
+/// down with it.
+fn run(r: Runnable) {
+    let ret = catch_unwind(AssertUnwindSafe(|| {
+        #[cfg(all(test, feature = "capi"))]
+        if PANIC_IN_TASK.swap(false, Ordering::Relaxed) {
+            panic!("a panic for a test");
+        }
+        r.run()
+    }));
+    if ret.is_err() {
+        cold_path();
+        set_err(Error::Panicked);
     }
 }
 
+/// The pool, of as many threads as the jobs of the first scan of the
+/// process: later ones share them, whatever their own.
 pub fn global() -> &'static Executor {
     // jobs - 1 because the main thread is also a worker thread when calling block_on
     static EXECUTOR: LazyLock<Executor> = LazyLock::new(|| Executor::new(config().jobs - 1));
@@ -61,6 +191,43 @@ where
     task
 }
 
+/// Threads for blocking system calls, so they don't hold up the tasks of
+/// the [`Executor`], see [`spawn_blocking`].  Sized as [`global`] is.
+fn blocking_pool() -> &'static Sender<Runnable> {
+    static POOL: LazyLock<Sender<Runnable>> = LazyLock::new(|| {
+        let (sender, receiver) = unbounded::<Runnable>();
+        for i in 0..config().jobs {
+            let receiver = receiver.clone();
+            if let Err(e) = Builder::new()
+                .name(format!("xsz-blocking{}", i))
+                .stack_size(config().stack_size())
+                .spawn(move || {
+                    while let Ok(r) = receiver.recv() {
+                        run_task(r, i as usize, true);
+                    }
+                })
+            {
+                cold_path();
+                eprintln!("Failed to spawn blocking thread: {}", e);
+            }
+        }
+        sender
+    });
+    &POOL
+}
+
+/// Run `f` on a thread of the blocking pool, resolving to what it returns.
+pub fn spawn_blocking<T, F>(f: F) -> Task<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let schedule = |runnable| blocking_pool().send(runnable).unwrap();
+    let (runnable, task) = async_task::spawn(async move { f() }, schedule);
+    runnable.schedule();
+    task
+}
+
 pub fn block_on<F>(fut: F) -> F::Output
 where
     F: Future,
@@ -69,8 +236,11 @@ where
     let f = fut.or(async move {
         loop {
             match recv.recv().await {
-                Ok(r) => {
-                    r.run();
+                Ok(Some(r)) => run(r),
+                Ok(None) => {
+                    if let Some(r) = global().steal(None) {
+                        run(r);
+                    }
                 }
                 Err(e) => {
                     cold_path();
@@ -0,0 +1,131 @@
+//! The set of extents seen so far, keyed by disk address.  With
+//! `--low-memory` it is split like a roaring bitmap: addresses are sector
+//! aligned, so their sector numbers are grouped by the upper bits, and each
+//! group keeps the lower 16 bits in a sorted array or, once that gets
+//! larger, a bitmap.
+
+use nohash::{IntMap, IntSet};
+
+use crate::global::config;
+
+/// Sector size the addresses are divided by.  btrfs sectors are at least
+/// this large, so no two extents share a sector number.
+const SECTOR_SHIFT: u32 = 12;
+/// Past this many keys a bitmap takes less memory than a sorted array.
+const MAX_SPARSE: usize = 4096;
+
+pub enum ExtentSet {
+    Hash(IntSet<u64>),
+    Compact(CompactSet),
+}
+
+impl ExtentSet {
+    pub fn new() -> Self {
+        if config().low_memory {
+            Self::Compact(Default::default())
+        } else {
+            Self::Hash(Default::default())
+        }
+    }
+
+    /// Add `bytenr`, `true` if it wasn't there yet.
+    pub fn insert(&mut self, bytenr: u64) -> bool {
+        match self {
+            Self::Hash(set) => set.insert(bytenr),
+            Self::Compact(set) => set.insert(bytenr),
+        }
+    }
+
+    /// Every address added, in no particular order.
+    pub fn keys(&self) -> Vec<u64> {
+        match self {
+            Self::Hash(set) => set.iter().copied().collect(),
+            Self::Compact(set) => set.keys(),
+        }
+    }
+}
+
+impl Default for ExtentSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The lower 16 bits of the sector numbers in one group.
+enum Container {
+    /// sorted
+    Sparse(Vec<u16>),
+    Dense(Box<[u64; 1 << 10]>),
+}
+
+impl Container {
+    fn insert(&mut self, low: u16) -> bool {
+        match self {
+            Self::Sparse(keys) => match keys.binary_search(&low) {
+                Ok(_) => false,
+                Err(i) if keys.len() < MAX_SPARSE => {
+                    keys.insert(i, low);
+                    true
+                }
+                Err(_) => {
+                    let mut bits = Box::new([0; 1 << 10]);
+                    for &k in keys.iter() {
+                        bits[k as usize / 64] |= 1 << (k % 64);
+                    }
+                    *self = Self::Dense(bits);
+                    self.insert(low)
+                }
+            },
+            Self::Dense(bits) => {
+                let (word, bit) = (low as usize / 64, 1 << (low % 64));
+                let new = bits[word] & bit == 0;
+                bits[word] |= bit;
+                new
+            }
+        }
+    }
+
+    fn for_each(&self, mut f: impl FnMut(u16)) {
+        match self {
+            Self::Sparse(keys) => keys.iter().copied().for_each(f),
+            Self::Dense(bits) => {
+                for (word, &w) in bits.iter().enumerate() {
+                    for bit in 0..64 {
+                        if w >> bit & 1 != 0 {
+                            f((word * 64 + bit) as u16);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CompactSet {
+    containers: IntMap<u64, Container>,
+    /// addresses not sector aligned, which btrfs doesn't make, but which
+    /// must not be confused with their neighbours
+    unaligned: IntSet<u64>,
+}
+
+impl CompactSet {
+    pub fn insert(&mut self, bytenr: u64) -> bool {
+        if bytenr & ((1 << SECTOR_SHIFT) - 1) != 0 {
+            return self.unaligned.insert(bytenr);
+        }
+        let sector = bytenr >> SECTOR_SHIFT;
+        self.containers
+            .entry(sector >> 16)
+            .or_insert_with(|| Container::Sparse(Vec::new()))
+            .insert(sector as u16)
+    }
+
+    fn keys(&self) -> Vec<u64> {
+        let mut keys: Vec<u64> = self.unaligned.iter().copied().collect();
+        for (&high, container) in &self.containers {
+            container.for_each(|low| keys.push((high << 16 | low as u64) << SECTOR_SHIFT));
+        }
+        keys
+    }
+}
@@ -0,0 +1,114 @@
+//! Extents as FS_IOC_FIEMAP reports them, for when TREE_SEARCH_V2 is not
+//! permitted.  FIEMAP works on any file the caller can open, but only tells
+//! that an extent is compressed, not how or to what size, so such extents
+//! are counted as [`Compression::Encoded`] at their uncompressed size.
+
+use std::os::fd::BorrowedFd;
+
+use rustix::{
+    io::Errno,
+    ioctl::{Opcode, Updater, ioctl, opcode::read_write},
+};
+
+use crate::btrfs::{
+    ExtentInfo, SizeStat,
+    tree::{Compression, ExtentType},
+};
+
+const FS_IOC_FIEMAP: Opcode = read_write::<FiemapHeader>(b'f', 11);
+
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+
+/// Extents asked for per call.
+const NEXTENTS: usize = 256;
+/// Set in the disk address of the extents found here: FIEMAP gives it in
+/// bytes, extent items in 4K blocks, and the two must never be taken for
+/// the same extent.
+const ADDR_TAG: u64 = 1 << 63;
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct FiemapHeader {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapArgs {
+    header: FiemapHeader,
+    extents: [FiemapExtent; NEXTENTS],
+}
+
+/// Append the extents of the file open at `fd`, inode `ino`, to `out`, in
+/// offset order and without holes.  Delayed allocations have no place on
+/// disk yet and are left out; the file isn't synced for them first.
+pub fn extents(fd: BorrowedFd, ino: u64, out: &mut Vec<ExtentInfo>) -> Result<(), Errno> {
+    let mut args = Box::new(FiemapArgs {
+        header: Default::default(),
+        extents: [Default::default(); NEXTENTS],
+    });
+    let mut start = 0;
+    loop {
+        args.header = FiemapHeader {
+            start,
+            length: u64::MAX - start,
+            extent_count: NEXTENTS as u32,
+            ..Default::default()
+        };
+        unsafe {
+            let ctl = Updater::<'_, FS_IOC_FIEMAP, _>::new(&mut *args);
+            ioctl(fd, ctl)?;
+        }
+        let mapped = &args.extents[..args.header.mapped_extents as usize];
+        let Some(last) = mapped.last() else {
+            return Ok(());
+        };
+        out.extend(
+            mapped
+                .iter()
+                .filter(|e| e.flags & FIEMAP_EXTENT_DELALLOC == 0)
+                .map(|e| to_extent(ino, e)),
+        );
+        if last.flags & FIEMAP_EXTENT_LAST != 0 {
+            return Ok(());
+        }
+        start = last.logical + last.length;
+    }
+}
+
+fn to_extent(ino: u64, e: &FiemapExtent) -> ExtentInfo {
+    let stat = SizeStat {
+        disk: e.length,
+        uncomp: e.length,
+        refd: e.length,
+    };
+    let (r#type, compression, bytenr) = if e.flags & FIEMAP_EXTENT_DATA_INLINE != 0 {
+        (ExtentType::Inline, Compression::None, 0)
+    } else if e.flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+        (ExtentType::Prealloc, Compression::None, e.physical | ADDR_TAG)
+    } else if e.flags & FIEMAP_EXTENT_ENCODED != 0 {
+        (ExtentType::Regular, Compression::Encoded, e.physical | ADDR_TAG)
+    } else {
+        (ExtentType::Regular, Compression::None, e.physical | ADDR_TAG)
+    };
+    ExtentInfo::new(ino, e.logical, bytenr, r#type, compression, stat)
+}
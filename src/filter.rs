@@ -0,0 +1,288 @@
+use std::{
+    collections::HashSet,
+    ffi::{CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rustix::fs::{AtFlags, CWD, Statx, StatxFlags, statx};
+
+use crate::{
+    fs_util::{DevId, statx_dev},
+    global::{CountLinks, config, filters},
+};
+
+/// An `--exclude` glob, matched with fnmatch(3).
+struct Pattern {
+    glob: CString,
+    /// contains a `/`, so it is matched against the whole path instead of
+    /// the file name
+    full_path: bool,
+    /// had a trailing `/`, so it only matches directories
+    dir_only: bool,
+}
+
+impl Pattern {
+    fn new(s: &str) -> Option<Self> {
+        let dir_only = s.len() > 1 && s.ends_with('/');
+        let s = if dir_only { s.trim_end_matches('/') } else { s };
+        Some(Self {
+            glob: CString::new(s).ok()?,
+            full_path: s.contains('/'),
+            dir_only,
+        })
+    }
+
+    fn matches(&self, s: &CStr) -> bool {
+        let flags = if self.full_path {
+            libc::FNM_PATHNAME
+        } else {
+            0
+        };
+        unsafe { libc::fnmatch(self.glob.as_ptr(), s.as_ptr(), flags) == 0 }
+    }
+}
+
+/// The patterns and limits of a configuration, parsed once, see
+/// [`crate::global::filters`].
+pub(crate) struct Filters {
+    exclude: Vec<Pattern>,
+    limits: Limits,
+}
+
+impl Filters {
+    pub(crate) fn new() -> Self {
+        let cfg = config();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mtime = |age: &Option<String>| {
+            let age = parse_age(age.as_deref()?).ok()?;
+            Some(now.saturating_sub(age).as_secs() as i64)
+        };
+        Self {
+            exclude: cfg.exclude.iter().filter_map(|s| Pattern::new(s)).collect(),
+            limits: Limits {
+                min_size: cfg.min_size.as_deref().and_then(|s| parse_size(s).ok()),
+                max_size: cfg.max_size.as_deref().and_then(|s| parse_size(s).ok()),
+                min_mtime: mtime(&cfg.newer_than),
+                max_mtime: mtime(&cfg.older_than),
+            },
+        }
+    }
+}
+
+/// Whether the walk should skip `path`, named `name` in its directory,
+/// because of `--exclude` or `--exclude-from`.
+pub fn excluded(path: &Path, name: &CStr, is_dir: bool) -> bool {
+    let mut full = None;
+    filters().exclude.iter().any(|p| {
+        if p.dir_only && !is_dir {
+            return false;
+        }
+        if !p.full_path {
+            return p.matches(name);
+        }
+        let full = full.get_or_insert_with(|| CString::new(path.as_os_str().as_bytes()).ok());
+        full.as_deref().is_some_and(|s| p.matches(s))
+    })
+}
+
+/// Patterns read from an `--exclude-from` file: one per line, blank lines
+/// and lines starting with `#` are ignored.
+pub fn read_patterns(file: &Path) -> std::io::Result<Vec<String>> {
+    Ok(std::fs::read_to_string(file)?
+        .lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// `--min-size`, `--max-size`, `--newer-than` and `--older-than`, with the
+/// ages turned into mtimes in seconds since the epoch.
+struct Limits {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_mtime: Option<i64>,
+    max_mtime: Option<i64>,
+}
+
+impl Limits {
+    fn is_empty(&self) -> bool {
+        self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.min_mtime.is_none()
+            && self.max_mtime.is_none()
+    }
+}
+
+/// Whether files need a [`statx`] for [`wanted_stat`].
+pub fn needs_stat() -> bool {
+    !filters().limits.is_empty() || config().count_links == CountLinks::Once || config().apparent()
+}
+
+/// The [`statx`] fields [`wanted_stat`] looks at.
+pub const STAT_MASK: StatxFlags = StatxFlags::SIZE
+    .union(StatxFlags::MTIME)
+    .union(StatxFlags::NLINK);
+
+/// Files with more than one link already counted, by device and inode,
+/// split by inode so that walkers seldom wait for each other.
+static LINKS: [Mutex<Option<Links>>; 16] = [const { Mutex::new(None) }; 16];
+
+type Links = HashSet<(DevId, u64)>;
+
+/// Forget the hard links counted, for a new scan.
+pub fn reset() {
+    for links in &LINKS {
+        links.lock().unwrap().take();
+    }
+}
+
+/// The hard links counted so far, as device and inode, for a checkpoint.
+pub(crate) fn links() -> Vec<(u64, u64)> {
+    let mut ret = vec![];
+    for links in &LINKS {
+        let links = links.lock().unwrap();
+        ret.extend(links.iter().flatten().map(|&(dev, ino)| (dev.get(), ino)));
+    }
+    ret
+}
+
+/// Count `links`, from [`links`], as seen already.
+pub(crate) fn restore_links(links: &[(u64, u64)]) {
+    for &(dev, ino) in links {
+        let Some(dev) = DevId::new(dev) else {
+            continue;
+        };
+        LINKS[ino as usize % LINKS.len()]
+            .lock()
+            .unwrap()
+            .get_or_insert_default()
+            .insert((dev, ino));
+    }
+}
+
+/// Whether this is the first link seen of the file `st`.
+fn first_link(st: &Statx) -> bool {
+    LINKS[st.stx_ino as usize % LINKS.len()]
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert((statx_dev(st), st.stx_ino))
+}
+
+/// Whether the file at `path` passes the size and mtime filters.  Files
+/// that can't be stat'ed are kept, the scan will report them.
+pub fn wanted(path: &Path) -> bool {
+    if !needs_stat() {
+        return true;
+    }
+    statx(CWD, path, AtFlags::SYMLINK_NOFOLLOW, STAT_MASK)
+        .ok()
+        .is_none_or(|st| wanted_stat(&st))
+}
+
+/// [`wanted`], for a file already stat'ed with [`STAT_MASK`].  With
+/// `--count-links once`, only the first link seen of a file is.
+pub fn wanted_stat(st: &Statx) -> bool {
+    let limits = &filters().limits;
+    let mtime = st.stx_mtime.tv_sec;
+    limits.min_size.is_none_or(|min| st.stx_size >= min)
+        && limits.max_size.is_none_or(|max| st.stx_size <= max)
+        && limits.min_mtime.is_none_or(|min| mtime >= min)
+        && limits.max_mtime.is_none_or(|max| mtime < max)
+        && (st.stx_nlink <= 1 || config().count_links == CountLinks::Each || first_link(st))
+}
+
+/// A size like `4096`, `64K` or `1.5G`; suffixes are powers of 1024 and
+/// may be followed by `iB` or `B`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim_end_matches("iB").trim_end_matches('B');
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                'P' => 50,
+                _ => return Err(format!("unknown size suffix '{}'", c)),
+            };
+            (&s[..i], shift)
+        }
+        _ => (s, 0),
+    };
+    let num: f64 = num.parse().map_err(|_| format!("invalid size '{}'", s))?;
+    if !num.is_finite() || num < 0. {
+        return Err(format!("invalid size '{}'", s));
+    }
+    Ok((num * (1u64 << shift) as f64) as u64)
+}
+
+/// An age like `90s`, `30m`, `12h`, `7d` or `2w`; a bare number is in days.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 'd'),
+    };
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        _ => return Err(format!("unknown time unit '{}'", unit)),
+    };
+    let num: u64 = num.parse().map_err(|_| format!("invalid age '{}'", s))?;
+    Ok(Duration::from_secs(num.saturating_mul(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        for (s, n) in [
+            ("0", 0),
+            ("4096", 4096),
+            ("64K", 64 << 10),
+            ("64k", 64 << 10),
+            ("1.5G", 3 << 29),
+            ("2MiB", 2 << 20),
+            ("10MB", 10 << 20),
+            ("1T", 1 << 40),
+            ("1P", 1 << 50),
+        ] {
+            assert_eq!(parse_size(s), Ok(n), "{:?}", s);
+        }
+        for s in ["", "K", "-1", "1X", "inf", "NaN", "1.5.2M"] {
+            assert!(parse_size(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_ages() {
+        for (s, secs) in [
+            ("90s", 90),
+            ("30m", 30 * 60),
+            ("12h", 12 * 3600),
+            ("7d", 7 * 86400),
+            ("7", 7 * 86400),
+            ("2w", 14 * 86400),
+            ("0d", 0),
+        ] {
+            assert_eq!(parse_age(s), Ok(Duration::from_secs(secs)), "{:?}", s);
+        }
+        assert_eq!(
+            parse_age(&format!("{}w", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+        for s in ["", "d", "1y", "-1d", "1.5h", "1 d"] {
+            assert!(parse_age(s).is_err(), "{:?}", s);
+        }
+    }
+}
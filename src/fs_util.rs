@@ -1,19 +1,56 @@
 use std::{
+    ffi::{CStr, OsString},
     num::NonZeroU64,
-    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::ffi::OsStringExt,
+    },
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use rustix::{
-    fs::{Mode, OFlags, fstat, open, stat},
-    io::Result,
+    fs::{
+        AtFlags, FileType, Mode, OFlags, Statx, StatxFlags, fstat, fstatfs, major, makedev, minor,
+        open, stat, statx,
+    },
+    io::{Errno, Result},
 };
 
+use crate::{
+    btrfs::{fs_info, subvol_id, subvol_path},
+    profile::{self, Counter},
+};
+
+/// `f_type` of a btrfs filesystem in statfs(2).
+pub const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+
+pub fn is_btrfs(fd: BorrowedFd) -> Result<bool> {
+    Ok(fstatfs(fd)?.f_type as i64 == BTRFS_SUPER_MAGIC)
+}
+
 pub(crate) type DevId = NonZeroU64;
-pub(crate) fn get_dev(path: impl AsRef<Path>) -> DevId {
-    let dev = stat(path.as_ref()).unwrap().st_dev;
-    NonZeroU64::new(dev).unwrap()
+/// The device of the open file `fd`, without looking its path up again.
+pub(crate) fn fd_dev(fd: BorrowedFd) -> Result<DevId> {
+    Ok(NonZeroU64::new(fstat(fd)?.st_dev).unwrap())
+}
+
+pub(crate) fn statx_dev(st: &Statx) -> DevId {
+    NonZeroU64::new(makedev(st.stx_dev_major, st.stx_dev_minor)).unwrap()
+}
+
+/// statx(2) of each of `names` in directory `dir`, not following
+/// symlinks.  With the `io_uring` feature they go to the kernel as one
+/// batch.
+pub fn statx_all(dir: BorrowedFd, names: &[&CStr], mask: StatxFlags) -> Vec<Result<Statx>> {
+    #[cfg(feature = "io_uring")]
+    if let Some(ret) = crate::uring::statx_all(dir, names, AtFlags::SYMLINK_NOFOLLOW, mask) {
+        return ret;
+    }
+    names
+        .iter()
+        .map(|name| statx(dir, *name, AtFlags::SYMLINK_NOFOLLOW, mask))
+        .collect()
 }
 
 /// Walk up the directory tree from `path` until we find the btrfs
@@ -37,24 +74,273 @@ pub fn find_subvol_root(path: &Path) -> Result<PathBuf> {
             break;
         }
     }
-    Err(rustix::io::Errno::NOENT)
+    Err(Errno::NOENT)
+}
+
+/// Path of subvolume `id` below `mount`, which may be any subvolume of the
+/// same filesystem.  Fails with `EXDEV` if `id` isn't reachable from it.
+pub fn resolve_subvol(mount: &Path, id: u64) -> Result<PathBuf> {
+    let fd = open(mount, OFlags::DIRECTORY, Mode::empty())?;
+    let mount_path = subvol_path(fd.as_fd(), subvol_id(fd.as_fd())?)?;
+    let path = subvol_path(fd.as_fd(), id)?;
+    let rel = path.strip_prefix(&mount_path).map_err(|_| Errno::XDEV)?;
+    Ok(mount.join(rel))
+}
+
+/// A mount of a btrfs filesystem, from /proc/self/mountinfo.
+struct Mount {
+    /// the directory of the filesystem mounted, `/` for its top level
+    root: PathBuf,
+    point: PathBuf,
+    /// the device it was mounted from
+    source: PathBuf,
+}
+
+/// A path field of mountinfo, where the kernel escapes space, tab,
+/// newline and backslash in octal.
+fn unescape(field: &str) -> PathBuf {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match tail {
+            [d0, d1, d2, tail @ ..]
+                if b == b'\\' && [d0, d1, d2].iter().all(|d| (b'0'..=b'7').contains(d)) =>
+            {
+                bytes.push((d0 - b'0') * 64 + (d1 - b'0') * 8 + (d2 - b'0'));
+                rest = tail;
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    OsString::from_vec(bytes).into()
+}
+
+/// The btrfs mounts, see proc_pid_mountinfo(5).
+fn btrfs_mounts() -> std::io::Result<Vec<Mount>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let mounts = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let root = fields.nth(3)?;
+            let point = fields.next()?;
+            // optional fields up to the separator
+            let mut fields = fields.skip_while(|f| *f != "-").skip(1);
+            let fstype = fields.next()?;
+            let source = fields.next()?;
+            (fstype == "btrfs").then(|| Mount {
+                root: unescape(root),
+                point: unescape(point),
+                source: unescape(source),
+            })
+        })
+        .collect();
+    Ok(mounts)
+}
+
+/// For `xsz /dev/sdX`, or a mountpoint with `--whole-fs`: where the top
+/// level of the btrfs filesystem on block device `target`, or mounted at
+/// directory `target`, is mounted.  `Ok(None)` if its top level isn't
+/// mounted anywhere, `ENOTBLK` if `target` is neither.
+pub fn top_level_mount(target: &Path) -> Result<Option<PathBuf>> {
+    let target = target
+        .canonicalize()
+        .map_err(|e| Errno::from_io_error(&e).unwrap_or(Errno::IO))?;
+    let mounts = btrfs_mounts().map_err(|e| Errno::from_io_error(&e).unwrap_or(Errno::IO))?;
+    let st = stat(&target)?;
+    let (source, fsid) = match FileType::from_raw_mode(st.st_mode) {
+        FileType::BlockDevice => (target.clone(), device_fsid(st.st_rdev)),
+        FileType::Directory => match mounts.iter().rfind(|m| m.point == target) {
+            Some(m) => (m.source.clone(), mount_fsid(&target)),
+            None => return Err(Errno::NOTBLK),
+        },
+        _ => return Err(Errno::NOTBLK),
+    };
+    // /dev/mapper names and the like are symlinks
+    let source = source.canonicalize().unwrap_or(source);
+    // the source shown is one device of the filesystem, maybe not the one
+    // given, so rather the fsid tells
+    let mut top = mounts.iter().filter(|m| {
+        m.root == Path::new("/")
+            && match fsid {
+                Some(fsid) => mount_fsid(&m.point) == Some(fsid),
+                None => m.source.canonicalize().is_ok_and(|s| s == source),
+            }
+    });
+    let first = top.next();
+    // rather the mount given, if it is one of them
+    let found = first
+        .into_iter()
+        .chain(top)
+        .find(|m| m.point == target)
+        .or(first);
+    Ok(found.map(|m| m.point.clone()))
+}
+
+/// The fsid of the btrfs filesystem mounted at `point`.
+fn mount_fsid(point: &Path) -> Option<[u8; 16]> {
+    let fd = open(point, OFlags::DIRECTORY | OFlags::NOFOLLOW, Mode::RUSR).ok()?;
+    Some(fs_info(fd.as_fd()).ok()?.0)
+}
+
+/// The fsid of the mounted btrfs filesystem block device `rdev` is one of,
+/// from the devices sysfs lists for each, see btrfs(5).
+fn device_fsid(rdev: u64) -> Option<[u8; 16]> {
+    let dev = format!("{}:{}\n", major(rdev), minor(rdev));
+    std::fs::read_dir("/sys/fs/btrfs")
+        .ok()?
+        .flatten()
+        .find_map(|fs| {
+            let member = std::fs::read_dir(fs.path().join("devices"))
+                .ok()?
+                .flatten()
+                .any(|d| std::fs::read_to_string(d.path().join("dev")).is_ok_and(|s| s == dev));
+            member.then(|| parse_uuid(fs.file_name().to_str()?))?
+        })
+}
+
+/// A UUID as sysfs names it, hex digits in groups split by `-`.
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    let mut ret = [0; 16];
+    if hex.len() != 2 * ret.len() {
+        return None;
+    }
+    for (b, pair) in ret.iter_mut().zip(hex.chunks(2)) {
+        *b = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(ret)
+}
+
+/// Write `path` with `write`, through a file next to it that replaces it
+/// only once complete and synced, so it is never left half written.
+pub fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut dyn std::io::Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let ret = (|| {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        write(&mut w)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if ret.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+    ret
+}
+
+/// Index of the command line argument a file was found under.
+pub type RootId = u16;
+
+/// Index of a filesystem in [`filesystems`]; one byte, for [`SubvolId`]
+/// to fit in the padding of [`crate::btrfs::ExtentInfo`].
+pub type FsId = u8;
+
+/// The filesystems seen so far, by fsid, with the first path seen on each.
+static FILESYSTEMS: Mutex<Vec<([u8; 16], Box<Path>)>> = Mutex::new(Vec::new());
+
+/// The filesystem of `fd`, opened at or below `path`, registering it if
+/// it is new.  One whose fsid can't be read counts as fsid 0.
+pub fn fs_id(fd: BorrowedFd, path: &Path) -> FsId {
+    register_fs(fs_info(fd).map_or([0; 16], |(fsid, _)| fsid), path)
+}
+
+/// The index of the filesystem `fsid`, first seen at `path` if it is new.
+pub fn register_fs(fsid: [u8; 16], path: &Path) -> FsId {
+    let mut filesystems = FILESYSTEMS.lock().unwrap();
+    if let Some(i) = filesystems.iter().position(|(id, _)| *id == fsid) {
+        return i as FsId;
+    }
+    // the rest share the last one
+    if filesystems.len() > FsId::MAX as usize {
+        return FsId::MAX;
+    }
+    filesystems.push((fsid, path.into()));
+    (filesystems.len() - 1) as FsId
+}
+
+/// Index of a subvolume in [`SUBVOLS`].
+pub type SubvolId = u16;
+
+/// The subvolumes seen so far, by filesystem and subvolume id.
+static SUBVOLS: Mutex<Vec<(FsId, u64)>> = Mutex::new(Vec::new());
+
+/// The index of subvolume `id` on filesystem `fs`, registering it if it is
+/// new.
+pub fn register_subvol(fs: FsId, id: u64) -> SubvolId {
+    let mut subvols = SUBVOLS.lock().unwrap();
+    if let Some(i) = subvols.iter().rposition(|s| *s == (fs, id)) {
+        return i as SubvolId;
+    }
+    // the rest share the last one
+    if subvols.len() > SubvolId::MAX as usize {
+        return SubvolId::MAX;
+    }
+    subvols.push((fs, id));
+    (subvols.len() - 1) as SubvolId
+}
+
+/// The fsid and first path seen of each filesystem, indexed by [`FsId`].
+pub fn filesystems() -> Vec<([u8; 16], Box<Path>)> {
+    FILESYSTEMS.lock().unwrap().clone()
 }
 
 pub struct File_ {
     fd: Arc<OwnedFd>,
     path: Box<Path>,
     ino: u64,
+    root: RootId,
+    /// a socket, FIFO or device node, which has no extents
+    special: bool,
+    /// apparent size, if it was stat'ed
+    size: u64,
+}
+
+/// Whether entries of type `t` have no extents to look for: anything but
+/// regular files, directories and symlinks, whose targets btrfs stores as
+/// inline EXTENT_DATA.
+#[inline]
+pub fn is_special(t: FileType) -> bool {
+    !matches!(
+        t,
+        FileType::RegularFile | FileType::Directory | FileType::Symlink
+    )
 }
 
 impl File_ {
     #[inline]
-    pub fn new(fd: Arc<OwnedFd>, path: Box<Path>, ino: u64) -> Self {
-        Self { fd, path, ino }
+    pub fn new(fd: Arc<OwnedFd>, path: Box<Path>, ino: u64, root: RootId) -> Self {
+        Self {
+            fd,
+            path,
+            ino,
+            root,
+            special: false,
+            size: 0,
+        }
+    }
+    /// `self`, marked as special, see [`is_special`].
+    #[inline]
+    pub fn with_special(mut self, special: bool) -> Self {
+        self.special = special;
+        self
     }
     #[inline]
     pub fn borrow_fd(&self) -> BorrowedFd<'_> {
         self.fd.as_fd()
     }
+    /// The descriptor shared by the files opened relative to it.
+    #[inline]
+    pub fn fd(&self) -> &Arc<OwnedFd> {
+        &self.fd
+    }
     #[inline]
     pub fn ino(&self) -> u64 {
         self.ino
@@ -63,10 +349,51 @@ impl File_ {
     pub fn path(&self) -> &Path {
         &self.path
     }
-    pub fn from_path(p: Box<Path>) -> Result<Self> {
-        let fd = Arc::new(open(p.as_ref(), OFlags::NOFOLLOW, Mode::RUSR)?);
+    #[inline]
+    pub fn root(&self) -> RootId {
+        self.root
+    }
+    #[inline]
+    pub fn is_special(&self) -> bool {
+        self.special
+    }
+    #[inline]
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+    /// The apparent size, 0 unless stat'ed for `--columns apparent`.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    /// Open `p`; a `special` one only as a path, so FIFOs don't block and
+    /// device drivers aren't involved.
+    pub fn from_path(p: Box<Path>, root: RootId, special: bool) -> Result<Self> {
+        let flags = match special {
+            true => OFlags::NOFOLLOW | OFlags::PATH,
+            false => OFlags::NOFOLLOW,
+        };
+        profile::add(Counter::Opens, 1);
+        profile::add(Counter::Stats, 1);
+        #[cfg(feature = "io_uring")]
+        if let Ok(path) = std::ffi::CString::new(p.as_os_str().as_encoded_bytes())
+            && let Some(ret) = crate::uring::open_statx(
+                &path,
+                flags,
+                Mode::RUSR,
+                StatxFlags::INO | StatxFlags::SIZE,
+            )
+        {
+            let (fd, stat) = ret?;
+            return Ok(Self::new(Arc::new(fd), p, stat.stx_ino, root)
+                .with_special(special)
+                .with_size(stat.stx_size));
+        }
+        let fd = Arc::new(open(p.as_ref(), flags, Mode::RUSR)?);
         let stat = fstat(fd.as_fd())?;
-        let ino = stat.st_ino;
-        Ok(Self::new(fd, p, ino))
+        Ok(Self::new(fd, p, stat.st_ino, root)
+            .with_special(special)
+            .with_size(stat.st_size as u64))
     }
 }
@@ -1,65 +1,1004 @@
 use std::{
     hint::cold_path,
+    io::IsTerminal,
+    ops::Deref,
+    path::{Path, PathBuf},
     process::exit,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        LazyLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
     },
 };
 
-use palc::Parser;
+use palc::{Parser, Subcommand, ValueEnum};
+use rustix::{
+    fs::{FileType, stat},
+    io::Errno,
+};
+
+use crate::{
+    btrfs::ioctl::SV2_BUF_SIZE,
+    defaults::{Defaults, given_in, on_command_line},
+    error::Error,
+    estimate::Codec,
+    filter,
+    fs_util::{resolve_subvol, top_level_mount},
+    reporter::Reporter,
+};
 
 const HELP_MSG: &str = "xsz displays total space used by set of files, taking into account
 compression, reflinks, partially overwritten extents.
 
 Use -t / --tree-scan when operating on a btrfs subvolume root to scan
 the tree directly instead of walking the directory hierarchy. This can
-be significantly faster on large subvolumes.";
+be significantly faster on large subvolumes.
 
-#[derive(Parser)]
+Use `xsz agent --push tcp://HOST:PORT file-or-dir...` on each machine and
+`xsz collect --listen HOST:PORT` on one of them to merge the results of
+many hosts into a single report.";
+
+#[derive(Parser, Default)]
 #[command(long_about = HELP_MSG)]
 pub struct Config {
     /// don't cross filesystem boundaries
     #[arg(short = 'x', long)]
     pub one_fs: bool,
+    /// cross filesystem boundaries, the default unless `one_fs` is set in
+    /// the config file or XSZ_ONE_FS
+    #[arg(long)]
+    pub cross_fs: bool,
     /// display raw bytes instead of human-readable sizes
     #[arg(short, long)]
     pub bytes: bool,
-    /// allow N jobs at once
-    #[arg(short, long, default_value_t = 1)]
+    /// display human-readable sizes, the default unless `scale` is set in
+    /// the config file or XSZ_SCALE
+    #[arg(long)]
+    pub human: bool,
+    /// use powers of 1000 (kB, MB, GB) instead of 1024 for human-readable
+    /// sizes
+    #[arg(long)]
+    pub si: bool,
+    /// show human-readable sizes with N decimals
+    #[arg(long, value_name = "N")]
+    pub precision: Option<u8>,
+    /// allow N jobs at once; 0 for one per CPU, up to 16
+    #[arg(short, long, default_value_t = 0)]
     pub jobs: u8,
+    /// walk directories with N threads of the jobs, half of them by default
+    #[arg(long, value_name = "N")]
+    pub walkers: Option<u8>,
+    /// stack size of each thread [default: 2M]
+    #[arg(long, value_name = "SIZE")]
+    pub stack_size: Option<String>,
     /// print fragment length distribution summary
     #[arg(short = 'F', long)]
     pub frag: bool,
+    /// also print extent size and extents per file distributions
+    #[arg(long)]
+    pub fragmentation: bool,
+    /// skip files and directories matching GLOB; patterns with a `/` match
+    /// the whole path, a trailing `/` only matches directories
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// read --exclude patterns from FILE, one per line
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Option<PathBuf>,
+    /// don't descend more than N directories below each file-or-dir
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<u32>,
+    /// only count the files directly inside each directory given, like
+    /// `--max-depth 0`
+    #[arg(long)]
+    pub no_recursive: bool,
+    /// only count files of at least SIZE bytes, e.g. 64K or 1G
+    #[arg(long, value_name = "SIZE")]
+    pub min_size: Option<String>,
+    /// only count files of at most SIZE bytes
+    #[arg(long, value_name = "SIZE")]
+    pub max_size: Option<String>,
+    /// only count files modified within AGE, e.g. 12h, 7d or 2w
+    #[arg(long, value_name = "AGE")]
+    pub newer_than: Option<String>,
+    /// only count files last modified more than AGE ago
+    #[arg(long, value_name = "AGE")]
+    pub older_than: Option<String>,
+    /// count files with several hard links under each of their paths, or
+    /// once, like du; `once` stats every file
+    #[arg(long, value_enum, default_value_t)]
+    pub count_links: CountLinks,
+    /// count sockets, FIFOs and device nodes as files, instead of leaving
+    /// them out
+    #[arg(long)]
+    pub include_special: bool,
+    /// only count extents written after btrfs generation N, like
+    /// `btrfs subvolume find-new`
+    #[arg(long, value_name = "N")]
+    pub since_generation: Option<u64>,
+    /// keep the extents of every file scanned in FILE, and only read those
+    /// of files changed since from the filesystem
+    #[arg(long, value_name = "FILE")]
+    pub cache: Option<PathBuf>,
+    /// every few minutes, save what the walk counted so far and the
+    /// directories left to FILE, to go on from with --resume
+    #[arg(long, value_name = "FILE")]
+    pub checkpoint: Option<PathBuf>,
+    /// go on with the scan saved by --checkpoint in FILE, of the same
+    /// paths and filters, and keep saving to it
+    #[arg(long, value_name = "FILE")]
+    pub resume: Option<PathBuf>,
+    /// keep the extents seen in a compact set, slower but taking a
+    /// fraction of the memory on filesystems with many extents
+    #[arg(long)]
+    pub low_memory: bool,
+    /// once the extents seen take more than SIZE, write them out sorted to
+    /// a temporary file and merge those at the end
+    #[arg(long, value_name = "SIZE")]
+    pub max_memory: Option<String>,
+    /// bytes of tree items read per SEARCH_V2 ioctl, more means fewer
+    /// ioctls on files with many extents [default: 16K]
+    #[arg(long, value_name = "SIZE")]
+    pub search_buffer: Option<String>,
+    /// with `-` among the paths, read NUL instead of newline terminated
+    /// paths from stdin, as `find -print0` writes them
+    #[arg(short = '0', long)]
+    pub null: bool,
     /// scan btrfs tree instead of walking directory (faster on subvolumes)
     #[arg(short = 't', long)]
     pub tree_scan: bool,
-    #[arg(required = true, value_name = "file-or-dir")]
-    pub args: Vec<String>,
+    /// scan the trees of all subvolumes of the filesystem mounted at the
+    /// only path, implies --tree-scan
+    #[arg(long)]
+    pub subvol_scan: bool,
+    /// also break down usage by content type, sniffed from each file's first bytes
+    #[arg(long)]
+    pub by_type: bool,
+    /// also break down usage by file extension
+    #[arg(long)]
+    pub by_ext: bool,
+    /// estimate the savings of recompressing uncompressed data with one or
+    /// more comma separated algorithms, e.g. zstd:3,zlib, or `compare`
+    #[arg(long, value_name = "ALGO[:LEVEL],...")]
+    pub estimate: Option<String>,
+    /// chunks of 128K each --estimate compresses per file [default: 4]
+    #[arg(long, value_name = "N")]
+    pub estimate_samples: Option<u64>,
+    /// only show what would be scanned, then exit
+    #[arg(long)]
+    pub plan: bool,
+    /// stay running and answer scan requests on the --socket, or on the
+    /// socket passed by systemd
+    #[arg(long)]
+    pub daemon: bool,
+    /// Unix socket for --daemon to listen on
+    #[arg(long, value_name = "PATH")]
+    pub socket: Option<PathBuf>,
+    /// exit once --daemon got no request for SECONDS, to be started again
+    /// by socket activation
+    #[arg(long, value_name = "SECONDS")]
+    pub idle_exit: Option<u64>,
+    /// scan again every SECONDS and print what changed since the last scan
+    #[arg(long, value_name = "SECONDS")]
+    pub watch: Option<u64>,
+    /// stop each scan after SECONDS and report what was counted so far
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+    /// print every error instead of coalescing repeated ones
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// print no error for each skipped path, only how many were skipped
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// write the error of each path skipped to FILE instead of stderr,
+    /// e.g. /dev/null to drop them; the summary stays on stderr
+    #[arg(long, value_name = "FILE")]
+    pub errors_to: Option<PathBuf>,
+    /// print counters and timings of each stage to stderr at the end:
+    /// syscalls, searches, channel stalls and how busy each thread was
+    #[arg(long)]
+    pub profile: bool,
+    /// exit with status 1 instead of 2 when paths were skipped
+    #[arg(long)]
+    pub strict: bool,
+    /// take each path that is a mountpoint as its whole filesystem, from
+    /// the top-level subvolume, as block devices always are; with -x
+    /// without the other subvolumes
+    #[arg(long)]
+    pub whole_fs: bool,
+    /// scan exactly the subvolume with this id, given the filesystem's mount point
+    #[arg(long, value_name = "ID")]
+    pub subvol: Option<u64>,
+    /// also print one row per file, like a compression-aware `du -s`
+    #[arg(long)]
+    pub files: bool,
+    /// also print usage of directories up to N levels below each file-or-dir
+    #[arg(long, value_name = "N")]
+    pub group_depth: Option<u32>,
+    /// also print the N files ranked worst by --top-by
+    #[arg(long, value_name = "N")]
+    pub top: Option<usize>,
+    /// rank --top files by compression ratio or by disk usage
+    #[arg(long, value_enum, default_value_t)]
+    pub top_by: TopBy,
+    /// print a separate section for each file-or-dir before the total
+    #[arg(long)]
+    pub per_arg: bool,
+    /// instead of the tables, print a `SIZE<TAB>PATH` line per file-or-dir
+    /// like `du -s`, SIZE being the disk usage in 1K blocks, or in bytes
+    /// with --bytes
+    #[arg(long)]
+    pub du: bool,
+    /// also print how much of each file-or-dir's disk usage is exclusive
+    /// to it, as deleting it would free, and how much it shares with others
+    #[arg(long)]
+    pub exclusive: bool,
+    /// with --subvol-scan, also print the qgroup usage of each subvolume
+    /// next to what the scan counted, implies --exclusive
+    #[arg(long)]
+    pub qgroup: bool,
+    /// also print how much disk usage only one file references, and how
+    /// much several share through reflinks
+    #[arg(long)]
+    pub reflinks: bool,
+    /// also print how the disk usage of regular extents is spread over
+    /// the number of references to them
+    #[arg(long)]
+    pub refcounts: bool,
+    /// also print an estimate of the metadata the files take: inodes,
+    /// extent items, checksums and inline data
+    #[arg(long)]
+    pub metadata: bool,
+    /// also print how many files are fully inline, their inline data, and
+    /// how many small files of each size are, for tuning `max_inline`
+    #[arg(long)]
+    pub inline_stats: bool,
+    /// list files with preallocated extents, largest first
+    #[arg(long)]
+    pub list_prealloc: bool,
+    /// only list files with at least SIZE preallocated, implies
+    /// --list-prealloc
+    #[arg(long, value_name = "SIZE")]
+    pub prealloc_min: Option<String>,
+    /// list files whose `btrfs.compression` property names an algorithm
+    /// their data is not stored with, most such data first
+    #[arg(long)]
+    pub audit_compression: bool,
+    /// instead of the usage table, list files split into more than
+    /// --defrag-extents extents or with extents smaller than --defrag-avg
+    /// on average, counting uncompressed extents only
+    #[arg(long)]
+    pub defrag_candidates: bool,
+    /// extents a file may have before it is a defrag candidate [default: 256]
+    #[arg(long, value_name = "N")]
+    pub defrag_extents: Option<u64>,
+    /// average extent size below which a file is a defrag candidate
+    /// [default: 64K]
+    #[arg(long, value_name = "SIZE")]
+    pub defrag_avg: Option<String>,
+    /// output format
+    #[arg(long, value_enum, default_value_t)]
+    pub format: Format,
+    /// print tables as tab separated fields in bytes, without padding;
+    /// fields are only ever added at the end of a row
+    #[arg(long)]
+    pub parsable: bool,
+    /// color rows worth a look: never, auto (if stdout is a terminal and
+    /// NO_COLOR is unset) or always
+    #[arg(long, value_enum, default_value_t)]
+    pub color: ColorWhen,
+    /// with --color, highlight compressed rows using more than PERC percent
+    /// of their uncompressed size [default: 90]
+    #[arg(long, value_name = "PERC")]
+    pub color_ratio: Option<u64>,
+    /// with --color, highlight the none row when it holds more than PERC
+    /// percent of the disk usage [default: 50]
+    #[arg(long, value_name = "PERC")]
+    pub color_none: Option<u64>,
+    /// add comma separated columns to the usage table: `ratio` of
+    /// uncompressed to disk usage, `refd` percent of the referenced data,
+    /// `disk` percent of the disk usage per row and `apparent` size of the
+    /// files in total
+    #[arg(long, value_name = "COL,...")]
+    pub columns: Option<String>,
+    /// also save the complete results as JSON to FILE, for `xsz diff` or
+    /// other tools; with --files they include every file
+    #[arg(long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    /// after opening the roots, switch to USER, keeping only CAP_SYS_ADMIN
+    #[arg(long, value_name = "USER")]
+    pub drop_privs: Option<String>,
+    #[arg(value_name = "file-or-dir")]
+    pub args: Vec<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
+
+#[derive(ValueEnum, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// compsize-style tables
+    #[default]
+    Text,
+    /// a single JSON document, sizes in bytes
+    Json,
+    /// the usage table as comma separated values, sizes in bytes
+    Csv,
+    /// the usage table as tab separated values, sizes in bytes
+    Tsv,
+    /// one JSON line per file as soon as it is scanned, sizes in bytes
+    Ndjson,
+    /// gauges per compression type for the node_exporter textfile
+    /// collector, sizes in bytes
+    Openmetrics,
+}
+
+#[derive(ValueEnum, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TopBy {
+    /// highest disk usage relative to the uncompressed size
+    #[default]
+    Ratio,
+    /// highest disk usage
+    Disk,
+}
+
+#[derive(ValueEnum, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CountLinks {
+    /// count a file with several hard links under every path, as often as
+    /// it is linked
+    #[default]
+    Each,
+    /// count it under the first path seen
+    Once,
+}
+
+#[derive(ValueEnum, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorWhen {
+    /// plain text
+    Never,
+    /// if stdout is a terminal and NO_COLOR is unset
+    #[default]
+    Auto,
+    /// even into pipes and files
+    Always,
+}
+
+/// An optional column of the usage table, see `--columns`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Ratio,
+    RefdPerc,
+    DiskPerc,
+    /// the sum of file sizes, only in the TOTAL row
+    Apparent,
+}
+
+impl Column {
+    pub fn parse_list(spec: &str) -> Result<Vec<Self>, String> {
+        spec.split(',')
+            .map(|s| match s {
+                "ratio" => Ok(Self::Ratio),
+                "refd" => Ok(Self::RefdPerc),
+                "disk" => Ok(Self::DiskPerc),
+                "apparent" => Ok(Self::Apparent),
+                _ => Err(format!(
+                    "unknown column '{}', expected ratio, refd, disk or apparent",
+                    s
+                )),
+            })
+            .collect()
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Scan the given paths and push the result to `xsz collect`, with
+    /// --per-arg also that of each path
+    Agent {
+        /// collector to push to, as tcp://HOST:PORT
+        #[arg(long, value_name = "URL")]
+        push: String,
+        /// file holding a pre-shared key to sign the report with, for the
+        /// collector to check
+        #[arg(long, value_name = "FILE")]
+        psk_file: Option<String>,
+        #[arg(value_name = "file-or-dir")]
+        args: Vec<PathBuf>,
+    },
+    /// Receive results pushed by agents and print a merged report
+    Collect {
+        /// address to listen on, as HOST:PORT
+        #[arg(long, value_name = "ADDR")]
+        listen: String,
+        /// exit after N reports instead of running forever
+        #[arg(long, value_name = "N")]
+        expect: Option<u32>,
+        /// only accept reports signed with the key stored in FILE
+        #[arg(long, value_name = "FILE")]
+        psk_file: Option<String>,
+    },
+    /// Compare two results saved with --output or --format json
+    Diff {
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+    },
+    /// List the subvolumes of the filesystem mounted at MOUNTPOINT with
+    /// the usage of each, from a --subvol-scan
+    Subvolumes {
+        #[arg(value_name = "MOUNTPOINT")]
+        mount: PathBuf,
+    },
+}
+
 impl Config {
+    /// Whether any output needs per-file reports from the workers.
+    pub fn file_reports(&self) -> bool {
+        self.by_type
+            || self.by_ext
+            || self.estimate.is_some()
+            || self.list_prealloc
+            || self.inline_stats
+            || self.audit_compression
+            || self.defrag_candidates
+            || self.files
+            || self.group_depth.is_some()
+            || self.top.is_some()
+            || self.fragmentation
+            || self.format == Format::Ndjson
+    }
+    /// Whether any option skips part of the walk or some of the files.
+    pub fn has_filters(&self) -> bool {
+        !self.exclude.is_empty()
+            || self.max_depth().is_some()
+            || self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.newer_than.is_some()
+            || self.older_than.is_some()
+    }
+    /// The options changing which files the walk finds and what it counts
+    /// of them, as given on the command line, for a `--resume` to check.
+    pub fn walk_options(&self) -> Vec<String> {
+        let mut ret = vec![];
+        let flags = [
+            ("--one-fs", self.one_fs),
+            ("--include-special", self.include_special),
+            ("--count-links=once", self.count_links == CountLinks::Once),
+            ("--columns=apparent", self.apparent()),
+        ];
+        ret.extend(flags.iter().filter(|f| f.1).map(|f| f.0.to_string()));
+        ret.extend(self.exclude.iter().map(|p| format!("--exclude={}", p)));
+        if let Some(depth) = self.max_depth() {
+            ret.push(format!("--max-depth={}", depth));
+        }
+        let values = [
+            ("--min-size", &self.min_size),
+            ("--max-size", &self.max_size),
+            ("--newer-than", &self.newer_than),
+            ("--older-than", &self.older_than),
+        ];
+        for (name, value) in values {
+            ret.extend(value.iter().map(|v| format!("{}={}", name, v)));
+        }
+        if let Some(generation) = self.since_generation {
+            ret.push(format!("--since-generation={}", generation));
+        }
+        ret
+    }
+    /// How many directories below each file-or-dir to descend, `None` for
+    /// no limit.
+    pub fn max_depth(&self) -> Option<u32> {
+        match self.no_recursive {
+            true => Some(0),
+            false => self.max_depth,
+        }
+    }
+    /// The oldest generation of extents to count, 0 for all of them.
+    /// Holes have none, so they aren't counted unless all are.
+    pub fn min_generation(&self) -> u64 {
+        self.since_generation.map_or(0, |n| n.saturating_add(1))
+    }
+    /// Where to save `--checkpoint`s: that file, or the one resumed from.
+    pub fn checkpoint_file(&self) -> Option<&Path> {
+        self.checkpoint.as_deref().or(self.resume.as_deref())
+    }
+    /// The `--prealloc-min` size, validated in `from_args`.  At least 1,
+    /// files without preallocated extents are never listed.
+    pub fn prealloc_min(&self) -> u64 {
+        self.prealloc_min
+            .as_deref()
+            .and_then(|s| filter::parse_size(s).ok())
+            .unwrap_or(1)
+            .max(1)
+    }
+    /// The `--defrag-extents` and `--defrag-avg` limits, the latter in
+    /// bytes and validated in `from_args`.
+    pub fn defrag_limits(&self) -> (u64, u64) {
+        let avg = self
+            .defrag_avg
+            .as_deref()
+            .map_or(Ok(64 << 10), filter::parse_size);
+        (self.defrag_extents.unwrap_or(256), avg.unwrap_or(0))
+    }
+    /// The `--max-memory` budget in bytes, validated in `from_args`.
+    pub fn max_memory(&self) -> Option<u64> {
+        self.max_memory
+            .as_deref()
+            .and_then(|s| filter::parse_size(s).ok())
+    }
+    /// The `--search-buffer` size in bytes, validated in `from_args`.
+    pub fn search_buffer(&self) -> usize {
+        self.search_buffer
+            .as_deref()
+            .map_or(Ok(SV2_BUF_SIZE as u64), filter::parse_size)
+            .map_or(SV2_BUF_SIZE, |n| n as usize)
+    }
+    /// The `--stack-size` in bytes, validated in `from_args`.
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+            .as_deref()
+            .map_or(Ok(2 << 20), filter::parse_size)
+            .map_or(2 << 20, |n| n as usize)
+    }
+    /// How many walkers share the jobs with the workers reading extents.
+    /// Opening directories and SEARCH_V2 scale differently across devices,
+    /// hence `--walkers`.
+    pub fn nwalkers(&self) -> u8 {
+        self.walkers.unwrap_or(self.jobs.div_ceil(2))
+    }
+    /// The `--estimate` codecs, validated in `from_args`.
+    pub fn estimate_codecs(&self) -> Option<Vec<Codec>> {
+        self.estimate
+            .as_deref()
+            .and_then(|s| Codec::parse_list(s).ok())
+    }
+    /// Whether to color the usage table, see `--color`.
+    pub fn color(&self) -> bool {
+        match self.color {
+            ColorWhen::Never => false,
+            ColorWhen::Always => true,
+            ColorWhen::Auto => {
+                !self.parsable
+                    && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        }
+    }
+    /// The `--color-ratio` and `--color-none` thresholds, in percent.
+    pub fn color_thresholds(&self) -> (u64, u64) {
+        (self.color_ratio.unwrap_or(90), self.color_none.unwrap_or(50))
+    }
+    /// The `--columns`, validated in `from_args`.
+    pub fn columns(&self) -> Vec<Column> {
+        self.columns
+            .as_deref()
+            .and_then(|s| Column::parse_list(s).ok())
+            .unwrap_or_default()
+    }
+    /// Whether files need their size for the `apparent` column.
+    pub fn apparent(&self) -> bool {
+        self.columns().contains(&Column::Apparent)
+    }
+    /// Where to scan for the path argument `arg`: the mount of the top
+    /// level subvolume for a block device, or for any path with
+    /// `--whole-fs`, else `arg` itself.  Output names it as given.
+    pub fn scan_path(&self, arg: &Path) -> Result<PathBuf, String> {
+        let block = arg.as_os_str() != "-"
+            && stat(arg)
+                .is_ok_and(|st| FileType::from_raw_mode(st.st_mode) == FileType::BlockDevice);
+        if !block && !self.whole_fs {
+            return Ok(arg.into());
+        }
+        match top_level_mount(arg) {
+            Ok(Some(mount)) => Ok(mount),
+            Ok(None) => Err(format!(
+                "'{}' is not a btrfs filesystem mounted with its top level (subvolid=5)",
+                arg.display()
+            )),
+            Err(Errno::NOTBLK) => Err(format!(
+                "--whole-fs: '{}' is not a mountpoint or block device",
+                arg.display()
+            )),
+            Err(e) => Err(format!("Failed to resolve '{}': {}", arg.display(), e)),
+        }
+    }
     fn from_args() -> Self {
-        let opt = Config::parse();
-        if opt.jobs == 0 {
-            eprintln!("-j requires an non-zero integer");
+        Self::check(Config::parse(), on_command_line).unwrap_or_else(|e| {
+            eprintln!("{}", e);
             exit(1);
+        })
+    }
+    /// The configuration of a `--daemon` request for `paths`, from
+    /// `options` as they would be given on the command line, with the
+    /// defaults of the config file and environment.  Those of the daemon
+    /// itself, such as `--jobs` or `--cache`, can't be given.
+    pub fn from_request(options: &[String], paths: Vec<PathBuf>) -> Result<Self, String> {
+        let args = ["xsz"]
+            .into_iter()
+            .chain(options.iter().map(String::as_str));
+        let mut opt = Config::try_parse_from(args).map_err(|e| e.to_string())?;
+        if !opt.args.is_empty() {
+            return Err("paths go in \"paths\", not in the options".into());
+        }
+        if opt.command.is_some()
+            || opt.jobs != 0
+            || opt.walkers.is_some()
+            || opt.stack_size.is_some()
+            || opt.exclude_from.is_some()
+            || opt.cache.is_some()
+            || opt.checkpoint.is_some()
+            || opt.resume.is_some()
+            || opt.plan
+            || opt.daemon
+            || opt.watch.is_some()
+            || opt.errors_to.is_some()
+            || opt.profile
+            || opt.format != Format::Text
+            || opt.output.is_some()
+            || opt.drop_privs.is_some()
+        {
+            return Err(
+                "subcommands, --jobs, --walkers, --stack-size, --exclude-from, --cache, \
+                 --checkpoint, --resume, --plan, --daemon, --watch, --errors-to, --profile, \
+                 --format, --output and --drop-privs are the daemon's own"
+                    .into(),
+            );
+        }
+        opt.args = paths;
+        let mut opt = Self::check(opt, |long| given_in(options, long))?;
+        // not `config()`, which is the request's own while its scan runs
+        let daemon = &process_scope().config;
+        opt.jobs = daemon.jobs;
+        opt.cache = daemon.cache.clone();
+        Ok(opt)
+    }
+    /// Validate `opt` as parsed, and fill in the defaults of the options
+    /// `given` doesn't tell were given, see [`on_command_line`].
+    fn check(mut opt: Config, given: impl Fn(&str) -> bool) -> Result<Self, String> {
+        if opt.one_fs && opt.cross_fs {
+            return Err("-x and --cross-fs can't be used together".into());
+        }
+        if opt.bytes && opt.human {
+            return Err("--bytes and --human can't be used together".into());
+        }
+        Defaults::load()?.apply(&mut opt, given);
+        if opt.jobs == 0 {
+            opt.jobs = auto_jobs();
+        }
+        if opt.estimate_samples == Some(0) {
+            return Err("--estimate-samples requires an non-zero integer".into());
+        }
+        if opt.timeout == Some(0) {
+            return Err("--timeout requires an non-zero integer".into());
+        }
+        if opt.idle_exit == Some(0) {
+            return Err("--idle-exit requires an non-zero integer".into());
+        }
+        if opt.watch == Some(0) {
+            return Err("--watch requires an non-zero integer".into());
+        }
+        if opt.watch.is_some()
+            && (opt.command.is_some()
+                || opt.format != Format::Text
+                || opt.drop_privs.is_some()
+                || opt.frag)
+        {
+            return Err("--watch only works with text output, and not with subcommands, --frag or --drop-privs".into());
+        }
+        if opt.daemon {
+            if !opt.args.is_empty() {
+                return Err("--daemon takes the paths to scan from each request".into());
+            }
+            if opt.command.is_some() || opt.watch.is_some() || opt.drop_privs.is_some() {
+                return Err(
+                    "--daemon can't be used with subcommands, --watch or --drop-privs".into(),
+                );
+            }
+        } else if opt.socket.is_some() || opt.idle_exit.is_some() {
+            return Err("--socket and --idle-exit are only used with --daemon".into());
+        }
+        if opt.walkers == Some(0) {
+            return Err("--walkers requires an non-zero integer".into());
+        }
+        if let Some(Command::Agent { args, .. }) = &mut opt.command {
+            opt.args.append(args);
+        }
+        if let Some(Command::Subvolumes { mount }) = &opt.command {
+            if !opt.args.is_empty() || opt.format != Format::Text || opt.frag {
+                return Err(
+                    "xsz subvolumes takes no other paths, and not --format or --frag".into(),
+                );
+            }
+            opt.args.push(mount.clone());
+            opt.subvol_scan = true;
+            opt.per_arg = true;
+        }
+        if opt.du {
+            if opt.command.is_some()
+                || opt.format != Format::Text
+                || opt.frag
+                || opt.args.iter().any(|a| a.as_os_str() == "-")
+            {
+                return Err(
+                    "--du prints a line per path argument: it can't be used with subcommands, \
+                     --format, --frag or paths from stdin"
+                        .into(),
+                );
+            }
+            opt.per_arg = true;
+        }
+        for arg in &opt.args {
+            opt.scan_path(arg)?;
+        }
+        if opt.qgroup {
+            if !opt.subvol_scan || opt.format != Format::Text {
+                return Err(
+                    "--qgroup compares whole subvolumes: it needs --subvol-scan and text output"
+                        .into(),
+                );
+            }
+            opt.exclusive = true;
+        }
+        if opt.max_memory.is_some() && (opt.refcounts || opt.exclusive || opt.reflinks) {
+            return Err(
+                "--refcounts, --exclusive and --reflinks keep every extent in memory and can't \
+                 be used with --max-memory"
+                    .into(),
+            );
+        }
+        if opt.subvol_scan {
+            if opt.args.len() != 1 || opt.subvol.is_some() {
+                return Err("--subvol-scan takes the mount point as its only path".into());
+            }
+            opt.tree_scan = true;
+        }
+        if opt.tree_scan && opt.file_reports() {
+            return Err(
+                "per-file output needs file paths and can't be used with --tree-scan".into(),
+            );
+        }
+        if opt.tree_scan && opt.args.iter().any(|a| a.as_os_str() == "-") {
+            return Err("reading paths from stdin can't be used with --tree-scan".into());
+        }
+        if opt.parsable {
+            if opt.format != Format::Text {
+                return Err("--parsable only applies to --format text".into());
+            }
+            opt.bytes = true;
+        }
+        if opt.format == Format::Openmetrics && opt.frag {
+            return Err("--format openmetrics can't be used with --frag".into());
+        }
+        if opt.quiet && opt.verbose {
+            return Err("--quiet and --verbose can't be used together".into());
+        }
+        if opt.no_recursive && opt.max_depth.is_some() {
+            return Err("--no-recursive and --max-depth can't be used together".into());
+        }
+        if let Some(file) = &opt.exclude_from {
+            match filter::read_patterns(file) {
+                Ok(mut patterns) => opt.exclude.append(&mut patterns),
+                Err(e) => {
+                    return Err(format!("Failed to read '{}': {}", file.display(), e));
+                }
+            }
+        }
+        if opt.cache.is_some() && (opt.tree_scan || opt.since_generation.is_some()) {
+            return Err("--cache can't be used with --tree-scan or --since-generation".into());
         }
-        opt
+        if (opt.checkpoint.is_some() || opt.resume.is_some()) && opt.max_memory.is_some() {
+            return Err(
+                "--checkpoint and --resume keep the extents seen in memory, not --max-memory"
+                    .into(),
+            );
+        }
+        if (opt.checkpoint.is_some() || opt.resume.is_some())
+            && (opt.tree_scan
+                || opt.file_reports()
+                || opt.frag
+                || opt.per_arg
+                || opt.exclusive
+                || opt.reflinks
+                || opt.refcounts
+                || opt.watch.is_some()
+                || opt.daemon
+                || opt.drop_privs.is_some()
+                || opt.args.iter().any(|a| a.as_os_str() == "-"))
+        {
+            return Err(
+                "--checkpoint and --resume only keep the usage table of a walk: they can't be \
+                 used with --tree-scan, per-file output, --frag, --per-arg, --exclusive, \
+                 --reflinks, --refcounts, --watch, --daemon, --drop-privs or paths from stdin"
+                    .into(),
+            );
+        }
+        if opt.tree_scan && opt.has_filters() {
+            return Err(
+                "filters need to walk directories and can't be used with --tree-scan".into(),
+            );
+        }
+        for (name, size) in [
+            ("--min-size", &opt.min_size),
+            ("--max-size", &opt.max_size),
+            ("--defrag-avg", &opt.defrag_avg),
+            ("--prealloc-min", &opt.prealloc_min),
+            ("--search-buffer", &opt.search_buffer),
+            ("--stack-size", &opt.stack_size),
+            ("--max-memory", &opt.max_memory),
+        ] {
+            if let Some(Err(e)) = size.as_deref().map(filter::parse_size) {
+                return Err(format!("{}: {}", name, e));
+            }
+        }
+        if opt.prealloc_min.is_some() {
+            opt.list_prealloc = true;
+        }
+        if let Some(Ok(n)) = opt.search_buffer.as_deref().map(filter::parse_size)
+            && !(16 << 10..=16 << 20).contains(&n)
+        {
+            // the kernel caps it at 16M, and items may take a whole sector
+            return Err("--search-buffer: must be between 16K and 16M".into());
+        }
+        if let Some(Ok(n)) = opt.stack_size.as_deref().map(filter::parse_size)
+            && n < 64 << 10
+        {
+            return Err("--stack-size: must be at least 64K".into());
+        }
+        for (name, age) in [
+            ("--newer-than", &opt.newer_than),
+            ("--older-than", &opt.older_than),
+        ] {
+            if let Some(Err(e)) = age.as_deref().map(filter::parse_age) {
+                return Err(format!("{}: {}", name, e));
+            }
+        }
+        if let Some(spec) = &opt.estimate
+            && let Err(e) = Codec::parse_list(spec)
+        {
+            return Err(format!("--estimate: {}", e));
+        }
+        if let Some(spec) = &opt.columns
+            && let Err(e) = Column::parse_list(spec)
+        {
+            return Err(format!("--columns: {}", e));
+        }
+        if opt.tree_scan && opt.apparent() {
+            return Err(
+                "--columns apparent needs file sizes and can't be used with --tree-scan".into(),
+            );
+        }
+        if let Some(id) = opt.subvol {
+            let [mount] = &opt.args[..] else {
+                return Err("--subvol takes the mount point as its only path".into());
+            };
+            match resolve_subvol(&opt.scan_path(mount)?, id) {
+                Ok(path) => opt.args = vec![path],
+                Err(Errno::XDEV) => {
+                    return Err(format!(
+                        "subvolume {} is not reachable below '{}'",
+                        id,
+                        mount.display()
+                    ));
+                }
+                Err(e) => {
+                    return Err(format!("Failed to resolve subvolume {}: {}", id, e));
+                }
+            }
+            // nested subvolumes have their own device number
+            opt.one_fs = true;
+        }
+        if opt.args.is_empty()
+            && !opt.daemon
+            && !matches!(
+                opt.command,
+                Some(Command::Collect { .. } | Command::Diff { .. })
+            )
+        {
+            return Err("at least one file-or-dir is required".into());
+        }
+        Ok(opt)
     }
 }
+/// The default job count: one per CPU, but SEARCH_V2 stops scaling well
+/// before the core counts of large machines.
+pub fn auto_jobs() -> u8 {
+    const MAX_AUTO_JOBS: usize = 16;
+    std::thread::available_parallelism()
+        .map_or(1, |n| n.get().min(MAX_AUTO_JOBS)) as u8
+}
+
 struct Global {
     err: AtomicBool,
-    config: LazyLock<Config>,
+    /// the first error, see [`set_err`]
+    first_err: Mutex<Option<Error>>,
+    /// paths skipped without stopping the scan, see [`add_skipped`]
+    skipped: AtomicU64,
+    /// set by [`cancel`]
+    cancelled: AtomicBool,
+    /// the configuration of the process
+    scope: OnceLock<Scope>,
+    /// the one of the scan running, if it has its own, see [`scoped`]
+    scoped: Mutex<Option<Arc<Scope>>>,
+    /// whether `scoped` is set, so [`config`] only locks it then
+    has_scoped: AtomicBool,
+    /// signalled when `scoped` is cleared, for the next to [`enter`]
+    scope_free: Condvar,
 }
 
 impl Global {
     const fn new() -> Self {
         let err = AtomicBool::new(false);
-        let config: LazyLock<Config> = LazyLock::new(Config::from_args);
-        Self { err, config }
+        let scope = OnceLock::new();
+        Self {
+            err,
+            first_err: Mutex::new(None),
+            skipped: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            scope,
+            scoped: Mutex::new(None),
+            has_scoped: AtomicBool::new(false),
+            scope_free: Condvar::new(),
+        }
     }
 }
 
+/// A configuration, and what is derived from it once.
+struct Scope {
+    config: Config,
+    filters: OnceLock<filter::Filters>,
+    /// instead of the reporter of the process, see [`scope_reporter`]
+    reporter: Option<Arc<dyn Reporter>>,
+}
+
+impl Scope {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            filters: OnceLock::new(),
+            reporter: None,
+        }
+    }
+}
+
+/// A [`Scope`] in use, which outlives the scan it is for while held.
+#[derive(Clone)]
+enum ScopeRef {
+    Process(&'static Scope),
+    Scoped(Arc<Scope>),
+}
+
+impl Deref for ScopeRef {
+    type Target = Scope;
+    fn deref(&self) -> &Scope {
+        match self {
+            Self::Process(scope) => scope,
+            Self::Scoped(scope) => scope,
+        }
+    }
+}
+
+fn process_scope() -> &'static Scope {
+    global()
+        .scope
+        .get_or_init(|| Scope::new(Config::from_args()))
+}
+
+fn scoped_slot() -> MutexGuard<'static, Option<Arc<Scope>>> {
+    global()
+        .scoped
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+#[inline]
+fn scope() -> ScopeRef {
+    if global().has_scoped.load(Ordering::Acquire) {
+        cold_path();
+        if let Some(scope) = &*scoped_slot() {
+            return ScopeRef::Scoped(scope.clone());
+        }
+    }
+    ScopeRef::Process(process_scope())
+}
+
 #[inline]
 const fn global() -> &'static Global {
     static GLOBAL: Global = Global::new();
@@ -71,28 +1010,154 @@ const fn global_err() -> &'static AtomicBool {
     &global().err
 }
 
+/// [`Error::Cancelled`] once an error stopped the scan.
 #[inline]
-const fn bool_to_result(is_err: bool) -> Result<(), ()> {
-    if is_err {
+pub fn get_err() -> Result<(), Error> {
+    if global_err().load(Ordering::Relaxed) {
         cold_path();
-        Err(())
+        Err(Error::Cancelled)
     } else {
         Ok(())
     }
 }
 
-#[inline]
-pub fn get_err() -> Result<(), ()> {
-    bool_to_result(global_err().load(Ordering::Relaxed))
-}
-
+/// Stop the scan because of `e`.  Only the first error is kept, and
+/// [`Error::Cancelled`] never is, it only follows another error.
 #[cold]
-pub fn set_err() -> Result<(), ()> {
+pub fn set_err(e: Error) {
     cold_path();
-    bool_to_result(global_err().swap(true, Ordering::Relaxed))
+    if matches!(e, Error::Cancelled) {
+        return;
+    }
+    let mut first = global().first_err.lock().unwrap();
+    if !global_err().swap(true, Ordering::Relaxed) {
+        *first = Some(e);
+    }
+}
+
+/// The error that stopped the scan, if any, clearing it for the next.
+pub fn take_err() -> Option<Error> {
+    let e = global().first_err.lock().unwrap().take();
+    global_err().store(false, Ordering::Relaxed);
+    e
+}
+
+/// Stop the scan without an error, keeping what was collected so far.
+pub fn cancel() {
+    global().cancelled.store(true, Ordering::Relaxed);
+    global_err().store(true, Ordering::Relaxed);
+}
+
+/// Whether [`cancel`] stopped the scan, clearing it for the next.
+pub fn take_cancelled() -> bool {
+    global().cancelled.swap(false, Ordering::Relaxed)
+}
+
+/// Count a path the scan skipped and went on without.
+pub fn add_skipped() {
+    global().skipped.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count `n` paths skipped, by the scan a checkpoint was taken of.
+pub fn add_skipped_n(n: u64) {
+    global().skipped.fetch_add(n, Ordering::Relaxed);
+}
+
+/// How many paths were skipped so far.
+pub fn skipped() -> u64 {
+    global().skipped.load(Ordering::Relaxed)
+}
+
+/// How many paths were skipped, clearing the count for the next scan.
+pub fn take_skipped() -> u64 {
+    global().skipped.swap(0, Ordering::Relaxed)
+}
+
+/// The configuration of the scan running, see [`config`].
+#[derive(Clone)]
+pub struct ConfigRef(ScopeRef);
+
+impl Deref for ConfigRef {
+    type Target = Config;
+    #[inline]
+    fn deref(&self) -> &Config {
+        &self.0.config
+    }
 }
 
+/// The filters of the scan running, see [`filters`].
+pub(crate) struct FiltersRef(ScopeRef);
+
+impl Deref for FiltersRef {
+    type Target = filter::Filters;
+    #[inline]
+    fn deref(&self) -> &filter::Filters {
+        self.0.filters.get_or_init(filter::Filters::new)
+    }
+}
+
+/// The configuration of the scan running: that of the process, or the one
+/// it was given, see [`scoped`].
 #[inline]
-pub fn config() -> &'static Config {
-    &global().config
+pub fn config() -> ConfigRef {
+    ConfigRef(scope())
+}
+
+/// The filters of [`config`], see [`crate::filter`].
+pub(crate) fn filters() -> FiltersRef {
+    FiltersRef(scope())
+}
+
+/// Use `config` instead of parsing the command line.  Hands it back if the
+/// configuration was already read or set.
+pub fn set_config(config: Config) -> Result<(), Box<Config>> {
+    (global().scope)
+        .set(Scope::new(config))
+        .map_err(|scope| Box::new(scope.config))
+}
+
+/// The reporter the scan running was given, if any, see [`enter`].
+pub(crate) fn scope_reporter() -> Option<Arc<dyn Reporter>> {
+    if !global().has_scoped.load(Ordering::Acquire) {
+        return None;
+    }
+    scoped_slot().as_ref()?.reporter.clone()
+}
+
+/// A scan running with a configuration of its own until dropped, see
+/// [`enter`].
+pub(crate) struct ScopeGuard(());
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let mut scoped = scoped_slot();
+        global().has_scoped.store(false, Ordering::Release);
+        scoped.take();
+        global().scope_free.notify_one();
+    }
+}
+
+/// Use `config`, and `reporter` if any, instead of those of the process
+/// until the guard is dropped, for a scan with options of its own, such as
+/// a `--daemon` request or a [`crate::Scanner`] run.  The scan and its
+/// output must be done by then; such scans wait for each other.  What
+/// [`config`] handed out keeps `config` alive past the guard.
+pub(crate) fn enter(config: Config, reporter: Option<Box<dyn Reporter>>) -> ScopeGuard {
+    let mut scoped = scoped_slot();
+    while scoped.is_some() {
+        scoped = (global().scope_free.wait(scoped)).unwrap_or_else(PoisonError::into_inner);
+    }
+    *scoped = Some(Arc::new(Scope {
+        reporter: reporter.map(Arc::from),
+        ..Scope::new(config)
+    }));
+    global().has_scoped.store(true, Ordering::Release);
+    ScopeGuard(())
+}
+
+/// Run `f` with `config` instead of the configuration of the process, see
+/// [`enter`].
+pub(crate) fn scoped<T>(config: Config, f: impl FnOnce() -> T) -> T {
+    let _scope = enter(config, None);
+    f()
 }
@@ -0,0 +1,308 @@
+use std::str::CharIndices;
+
+/// Nesting of arrays and objects beyond which a document is rejected,
+/// rather than overflowing the stack; what xsz writes nests 4 deep.
+const MAX_DEPTH: usize = 64;
+
+/// Just enough JSON to read back what `--format json` writes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// kept as written, so 64 bit sizes survive
+    Num(String),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut p = Parser {
+            s,
+            pos: 0,
+            depth: 0,
+        };
+        let ret = p.value()?;
+        p.ws();
+        if p.pos != s.len() {
+            return Err(p.err("trailing characters"));
+        }
+        Ok(ret)
+    }
+
+    /// Member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Obj(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Num(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn members(&self) -> &[(String, Json)] {
+        match self {
+            Self::Obj(members) => members,
+            _ => &[],
+        }
+    }
+
+    pub fn items(&self) -> &[Json] {
+        match self {
+            Self::Arr(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    /// arrays and objects open
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn err(&self, msg: &str) -> String {
+        let line = self.s[..self.pos].lines().count().max(1);
+        format!("{}: {}", line, msg)
+    }
+
+    fn ws(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> Result<(), String> {
+        self.ws();
+        if self.peek() != Some(c) {
+            return Err(self.err(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.ws();
+        if matches!(self.peek(), Some(b'{' | b'[')) {
+            if self.depth == MAX_DEPTH {
+                return Err(self.err("nested too deep"));
+            }
+            self.depth += 1;
+            let ret = self.container();
+            self.depth -= 1;
+            return ret;
+        }
+        match self.peek() {
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => {
+                let rest = &self.s[self.pos..];
+                let len = rest
+                    .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                Ok(Json::Num(rest[..len].to_string()))
+            }
+            _ => {
+                for (word, value) in [
+                    ("null", Json::Null),
+                    ("true", Json::Bool(true)),
+                    ("false", Json::Bool(false)),
+                ] {
+                    if self.s[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.err("expected a value"))
+            }
+        }
+    }
+
+    /// An array or object, at `{` or `[`.
+    fn container(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                self.ws();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Obj(members));
+                }
+                loop {
+                    self.ws();
+                    let key = self.string()?;
+                    self.eat(b':')?;
+                    members.push((key, self.value()?));
+                    self.ws();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Obj(members));
+                        }
+                        _ => return Err(self.err("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                self.ws();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Arr(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.ws();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Arr(items));
+                        }
+                        _ => return Err(self.err("expected ',' or ']'")),
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.err("expected a string"));
+        }
+        self.pos += 1;
+        let mut ret = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(ret);
+                }
+                '\\' => ret.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some(c @ ('"' | '\\' | '/')) => c,
+                    Some('u') => {
+                        let Some(unit) = hex4(&mut chars) else {
+                            return Err(self.err("invalid escape"));
+                        };
+                        // a surrogate pair, or a lone surrogate such as
+                        // the `\udcXX` json_path writes for a byte that
+                        // isn't UTF-8
+                        let mut ahead = chars.clone();
+                        let pair = (0xd800..0xdc00).contains(&unit)
+                            && ahead.next().map(|(_, c)| c) == Some('\\')
+                            && ahead.next().map(|(_, c)| c) == Some('u');
+                        match hex4(&mut ahead) {
+                            Some(low @ 0xdc00..0xe000) if pair => {
+                                chars = ahead;
+                                0x10000 + ((unit - 0xd800) << 10 | (low - 0xdc00))
+                            }
+                            _ => unit,
+                        }
+                        .try_into()
+                        .unwrap_or(char::REPLACEMENT_CHARACTER)
+                    }
+                    _ => return Err(self.err("invalid escape")),
+                }),
+                c => ret.push(c),
+            }
+        }
+        Err(self.err("unterminated string"))
+    }
+}
+
+/// The 4 hex digits of a `\u` escape.
+fn hex4(chars: &mut CharIndices) -> Option<u32> {
+    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+    if hex.len() != 4 {
+        return None;
+    }
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let json = Json::parse(
+            r#" {"a": [1, -2.5e3, 18446744073709551615], "b": {"c": null, "d": true},
+                "e": "x\"\\\/\n\té", "f": [], "g": {}} "#,
+        )
+        .unwrap();
+        let a = json.get("a").unwrap().items();
+        assert_eq!(a[0].as_u64(), Some(1));
+        assert_eq!(a[1], Json::Num("-2.5e3".into()));
+        assert_eq!(a[2].as_u64(), Some(u64::MAX));
+        let b = json.get("b").unwrap();
+        assert_eq!(b.get("c"), Some(&Json::Null));
+        assert_eq!(b.get("d"), Some(&Json::Bool(true)));
+        assert_eq!(json.get("e").unwrap().as_str(), Some("x\"\\/\n\t\u{e9}"));
+        assert!(json.get("f").unwrap().items().is_empty());
+        assert!(json.get("g").unwrap().members().is_empty());
+    }
+
+    #[test]
+    fn surrogates() {
+        let s = |s| Json::parse(s).unwrap().as_str().unwrap().to_string();
+        assert_eq!(s(r#""\ud83d\ude00""#), "\u{1f600}");
+        assert_eq!(s(r#""a\udcffb""#), "a\u{fffd}b");
+        assert_eq!(s(r#""\ud83d""#), "\u{fffd}");
+        assert_eq!(s(r#""\ud83dx""#), "\u{fffd}x");
+        assert_eq!(s(r#""\ud83d\u0041""#), "\u{fffd}A");
+        assert!(Json::parse(r#""\u12""#).is_err());
+        assert!(Json::parse(r#""\uzzzz""#).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        for s in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"abc",
+            "nul",
+            "1 2",
+            "{1: 2}",
+        ] {
+            assert!(Json::parse(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn depth() {
+        let nested = |n| "[".repeat(n) + &"]".repeat(n);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"[".repeat(100_000)).is_err());
+    }
+}
@@ -1,13 +1,42 @@
 pub mod actor;
 pub mod btrfs;
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod checkpoint;
+pub mod collector;
+pub mod daemon;
+pub mod defaults;
+pub mod diag;
+pub mod diff;
+pub mod error;
+pub mod estimate;
+pub mod extent_set;
+pub mod fiemap;
 pub mod executor;
+pub mod filter;
 pub mod scan_tree;
 pub mod fs_util;
 pub mod global;
+pub mod hmac;
+pub mod json;
+pub mod magic;
+pub mod privs;
+pub mod profile;
+pub mod report;
+pub mod reporter;
+pub mod scale;
+pub mod scanner;
+pub mod signal;
+pub mod spill;
 pub mod taskpak;
+#[cfg(feature = "io_uring")]
+pub mod uring;
 pub mod walkdir;
 pub mod worker;
 
+pub use scanner::{CancelToken, Files, Scanner};
+
 #[inline]
 pub fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) {
     executor::spawn(future).detach();
@@ -0,0 +1,63 @@
+/// How many leading bytes of a file [`sniff`] looks at.
+pub const SNIFF_LEN: usize = 4096;
+
+/// Guess the content type of a file from its first bytes.
+pub fn sniff(buf: &[u8]) -> &'static str {
+    const MAGIC: &[(usize, &[u8], &str)] = &[
+        (0, b"\xff\xd8\xff", "jpeg"),
+        (0, b"\x89PNG\r\n\x1a\n", "png"),
+        (0, b"GIF8", "gif"),
+        (0, b"%PDF-", "pdf"),
+        (0, b"SQLite format 3\0", "sqlite"),
+        (0, b"QFI\xfb", "qcow2"),
+        (0, b"\x7fELF", "elf"),
+        (0, b"PK\x03\x04", "zip"),
+        (0, b"\x1f\x8b", "gzip"),
+        (0, b"\x28\xb5\x2f\xfd", "zstd"),
+        (0, b"\xfd7zXZ\0", "xz"),
+        (0, b"BZh", "bzip2"),
+        (0, b"7z\xbc\xaf\x27\x1c", "7z"),
+        (0, b"\x1a\x45\xdf\xa3", "matroska"),
+        (0, b"OggS", "ogg"),
+        (0, b"fLaC", "flac"),
+        (0, b"ID3", "mp3"),
+        (4, b"ftyp", "mp4"),
+        (257, b"ustar", "tar"),
+    ];
+    if buf.is_empty() {
+        return "empty";
+    }
+    for &(off, magic, name) in MAGIC {
+        if buf.len() >= off + magic.len() && &buf[off..off + magic.len()] == magic {
+            return name;
+        }
+    }
+    if buf.starts_with(b"RIFF") && buf.len() >= 12 {
+        return match &buf[8..12] {
+            b"WEBP" => "webp",
+            b"WAVE" => "wav",
+            b"AVI " => "avi",
+            _ => "riff",
+        };
+    }
+    if is_text(buf) {
+        return "text";
+    }
+    "data"
+}
+
+/// UTF-8 without NULs or other unusual control characters.  A multi-byte
+/// sequence cut off at the end of the buffer is fine.
+fn is_text(buf: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(buf) {
+        Ok(s) => s,
+        Err(e) if e.error_len().is_none() => {
+            // Safety: `valid_up_to` bytes were just validated.
+            unsafe { std::str::from_utf8_unchecked(&buf[..e.valid_up_to()]) }
+        }
+        Err(_) => return false,
+    };
+    valid
+        .bytes()
+        .all(|b| b >= 0x20 || matches!(b, b'\n' | b'\r' | b'\t' | 0x0c | 0x1b))
+}
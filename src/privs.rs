@@ -0,0 +1,101 @@
+use std::{ffi::CString, io, mem::MaybeUninit, ptr::null_mut};
+
+use rustix::thread::{
+    CapabilitySet, CapabilitySets, Gid, Uid, set_capabilities, set_keep_capabilities,
+    set_thread_gid, set_thread_groups, set_thread_uid,
+};
+
+/// The largest buffer [`getpw`] tries before giving up on an entry.
+const MAX_PW_BUF: usize = 1 << 20;
+
+/// The uid and primary gid of the passwd entry `get` finds, through
+/// getpwnam_r(3) or getpwuid_r(3), with a buffer grown until it fits.
+fn getpw(
+    get: impl Fn(*mut libc::passwd, &mut [libc::c_char], *mut *mut libc::passwd) -> libc::c_int,
+) -> io::Result<Option<(u32, u32)>> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = null_mut();
+        match get(pwd.as_mut_ptr(), &mut buf, &mut result) {
+            libc::ERANGE if buf.len() < MAX_PW_BUF => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                // Safety: the call succeeded and pointed `result` at `pwd`.
+                let pwd = unsafe { pwd.assume_init() };
+                return Ok(Some((pwd.pw_uid, pwd.pw_gid)));
+            }
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Resolve `user` (a login name or a numeric uid) to its uid and primary gid.
+fn lookup_user(user: &str) -> io::Result<(u32, u32)> {
+    let name = CString::new(user).map_err(io::Error::other)?;
+    let by_name = getpw(|pwd, buf, result| unsafe {
+        libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    if let Some(ids) = by_name {
+        return Ok(ids);
+    }
+    // Not a known login name, fall back to a numeric uid.
+    let uid: u32 = user
+        .parse()
+        .map_err(|_| io::Error::other(format!("unknown user '{}'", user)))?;
+    let by_uid = getpw(|pwd, buf, result| unsafe {
+        libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+    });
+    // A bare uid without a passwd entry: use it as its own group.
+    Ok(by_uid.ok().flatten().unwrap_or((uid, uid)))
+}
+
+/// Switch to `user`, keeping only `CAP_SYS_ADMIN`, which TREE_SEARCH_V2
+/// still needs.  Every other privilege, including DAC override, is gone,
+/// so directories the user can't read are reported like for any other user.
+/// `CAP_SYS_ADMIN` itself still allows much of what root does, e.g.
+/// mounting, so this narrows what a bug could do rather than sandboxing.
+///
+/// The uid, gid and capabilities are changed for the calling thread only,
+/// so this must run before the executor spawns its threads: threads
+/// created afterwards inherit the reduced credentials.
+pub fn drop_privs(user: &str) -> io::Result<()> {
+    let (uid, gid) = lookup_user(user)?;
+    if uid == 0 {
+        return Err(io::Error::other(format!(
+            "refusing to drop privileges to '{}' (uid 0)",
+            user
+        )));
+    }
+    let gid = Gid::from_raw(gid);
+    set_keep_capabilities(true)?;
+    set_thread_groups(&[gid])?;
+    set_thread_gid(gid)?;
+    set_thread_uid(Uid::from_raw(uid))?;
+    // setuid() cleared the effective set; re-enable what the ioctl needs
+    // and forget the rest of the permitted set.
+    set_capabilities(
+        None,
+        CapabilitySets {
+            effective: CapabilitySet::SYS_ADMIN,
+            permitted: CapabilitySet::SYS_ADMIN,
+            inheritable: CapabilitySet::empty(),
+        },
+    )?;
+    set_keep_capabilities(false)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), (0, 0));
+        // no entry: its own group
+        assert_eq!(lookup_user("4000000123").unwrap(), (4000000123, 4000000123));
+        assert!(lookup_user("no such user here").is_err());
+    }
+}
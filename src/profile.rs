@@ -0,0 +1,163 @@
+//! `--profile`: counters and timings of each stage of a scan, printed to
+//! stderr once it is over, to see where the time goes when tuning `-j`.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::global::config;
+
+/// What is counted, an index into [`COUNTERS`].
+#[derive(Clone, Copy)]
+pub enum Counter {
+    /// directories read by the walkers
+    Dirs,
+    /// open(2) and openat(2) of files and directories
+    Opens,
+    /// stat(2) and statx(2)
+    Stats,
+    /// TREE_SEARCH_V2 ioctls, the items and bytes they returned
+    Searches,
+    SearchItems,
+    SearchBytes,
+    /// nanoseconds spent waiting for room in a full channel
+    StallNs,
+}
+
+const NCOUNTERS: usize = Counter::StallNs as usize + 1;
+
+static COUNTERS: [AtomicU64; NCOUNTERS] = [const { AtomicU64::new(0) }; NCOUNTERS];
+
+/// Nanoseconds each thread spent running tasks, per pool.
+struct Busy {
+    workers: Box<[AtomicU64]>,
+    blocking: Box<[AtomicU64]>,
+}
+
+static BUSY: LazyLock<Busy> = LazyLock::new(|| {
+    let threads = |n| (0..n).map(|_| AtomicU64::new(0)).collect();
+    // as many as the executor spawns
+    Busy {
+        workers: threads(config().jobs - 1),
+        blocking: threads(config().jobs),
+    }
+});
+
+/// When the scan started, and when the walk was done.
+static TIMES: Mutex<Option<(Instant, Option<Instant>)>> = Mutex::new(None);
+
+#[inline]
+fn enabled() -> bool {
+    config().profile
+}
+
+#[inline]
+pub fn add(counter: Counter, n: u64) {
+    if enabled() {
+        COUNTERS[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// When profiling, the time to pass to [`stalled`] or [`busy`] later.
+#[inline]
+pub fn start() -> Option<Instant> {
+    enabled().then(Instant::now)
+}
+
+/// Count the time since `start` as spent waiting on a channel.
+#[inline]
+pub fn stalled(start: Option<Instant>) {
+    if let Some(start) = start {
+        add(Counter::StallNs, start.elapsed().as_nanos() as u64);
+    }
+}
+
+/// Count the time since `start` as spent running tasks on thread `i` of
+/// the [`crate::executor`] pool, or of its blocking pool.
+#[inline]
+pub fn busy(start: Option<Instant>, i: usize, blocking: bool) {
+    let Some(start) = start else {
+        return;
+    };
+    let threads = match blocking {
+        false => &BUSY.workers,
+        true => &BUSY.blocking,
+    };
+    if let Some(t) = threads.get(i) {
+        t.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Start over, for a new scan.
+pub fn reset() {
+    if !enabled() {
+        return;
+    }
+    for c in &COUNTERS {
+        c.store(0, Ordering::Relaxed);
+    }
+    for t in BUSY.workers.iter().chain(&BUSY.blocking) {
+        t.store(0, Ordering::Relaxed);
+    }
+    *TIMES.lock().unwrap() = Some((Instant::now(), None));
+}
+
+/// The walkers found every file.
+pub fn walk_done() {
+    if let Some((_, done)) = TIMES.lock().unwrap().as_mut() {
+        done.get_or_insert_with(Instant::now);
+    }
+}
+
+pub fn report(f: &mut dyn Write) -> io::Result<()> {
+    let Some((start, walk_done)) = *TIMES.lock().unwrap() else {
+        return Ok(());
+    };
+    let elapsed = start.elapsed();
+    let get = |c: Counter| COUNTERS[c as usize].load(Ordering::Relaxed);
+    let ms = |d: Duration| d.as_millis();
+    writeln!(f, "Profile:")?;
+    write!(f, "  elapsed:       {:>10} ms", ms(elapsed))?;
+    match walk_done {
+        Some(done) => writeln!(f, ", walk done after {} ms", ms(done - start))?,
+        None => writeln!(f)?,
+    }
+    writeln!(f, "  directories:   {:>10}", get(Counter::Dirs))?;
+    writeln!(f, "  opens:         {:>10}", get(Counter::Opens))?;
+    writeln!(f, "  stats:         {:>10}", get(Counter::Stats))?;
+    let (searches, bytes) = (get(Counter::Searches), get(Counter::SearchBytes));
+    writeln!(
+        f,
+        "  searches:      {:>10}, {} items, {} bytes each on average",
+        searches,
+        get(Counter::SearchItems),
+        bytes.checked_div(searches).unwrap_or(0)
+    )?;
+    let stall = Duration::from_nanos(get(Counter::StallNs));
+    writeln!(f, "  channel stall: {:>10} ms", ms(stall))?;
+    for (name, threads) in [("worker", &BUSY.workers), ("blocking", &BUSY.blocking)] {
+        let busy: Vec<_> = threads
+            .iter()
+            .map(|t| Duration::from_nanos(t.load(Ordering::Relaxed)))
+            .collect();
+        let total: Duration = busy.iter().sum();
+        let per_thread: Vec<_> = busy.iter().map(|d| ms(*d).to_string()).collect();
+        writeln!(
+            f,
+            "  {:<8} busy: {:>10} ms ({}% of {} threads): {}",
+            name,
+            ms(total),
+            (total.as_nanos() * 100)
+                .checked_div(elapsed.as_nanos() * busy.len() as u128)
+                .unwrap_or(0),
+            busy.len(),
+            per_thread.join(" ")
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use std::{
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    btrfs::{
+        ExtentInfo, SizeStat,
+        tree::{Compression, ExtentType},
+    },
+    estimate::Sample,
+    fs_util::RootId,
+};
+
+/// Totals of a single file, built by the worker from the extents it saw
+/// for that file.  Only produced when some per-file output needs them.
+///
+/// Extents shared with other files are counted in full here; an extent
+/// referenced several times by the same file is counted once.
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: Box<Path>,
+    pub ino: u64,
+    /// command line argument the file was found under
+    pub root: RootId,
+    /// per compression type, prealloc extents excluded
+    pub stat: [SizeStat; Compression::ALL.len()],
+    pub prealloc: SizeStat,
+    pub nextent: u64,
+    pub ninline: u64,
+    /// the extents stored uncompressed outside of the metadata, and the
+    /// bytes they reference, for `--defrag-candidates`
+    pub uncompressed: (u64, u64),
+    /// disk usage of the inline extents, part of `stat`
+    pub inline_disk: u64,
+    /// bytes in holes, not part of `stat`
+    pub sparse: u64,
+    /// detected content type, see [`crate::magic`]
+    pub content: Option<&'static str>,
+    /// the algorithm the `btrfs.compression` property asks for, with
+    /// `--audit-compression`
+    pub property: Option<Compression>,
+    /// compression estimates for data stored uncompressed, one per
+    /// `--estimate` codec, see [`crate::estimate`]
+    pub samples: Option<Vec<Sample>>,
+}
+
+impl FileReport {
+    pub fn new(path: Box<Path>, ino: u64, root: RootId) -> Self {
+        Self {
+            path,
+            ino,
+            root,
+            stat: Default::default(),
+            prealloc: Default::default(),
+            nextent: 0,
+            ninline: 0,
+            uncompressed: (0, 0),
+            inline_disk: 0,
+            sparse: 0,
+            content: None,
+            property: None,
+            samples: None,
+        }
+    }
+
+    /// Account `extent`; `unique` is false when this file already
+    /// referenced the same extent, so only its referenced bytes count.
+    pub fn add(&mut self, extent: &ExtentInfo, unique: bool) {
+        if extent.is_hole() {
+            self.sparse += extent.stat().refd;
+            return;
+        }
+        self.nextent += 1;
+        let s = extent.stat();
+        let dst = match extent.r#type() {
+            ExtentType::Inline => {
+                self.ninline += 1;
+                &mut self.stat[extent.comp().as_usize()]
+            }
+            ExtentType::Regular => &mut self.stat[extent.comp().as_usize()],
+            ExtentType::Prealloc => &mut self.prealloc,
+        };
+        if extent.r#type() != ExtentType::Inline && extent.comp() == Compression::None {
+            self.uncompressed.0 += 1;
+            self.uncompressed.1 += s.refd;
+        }
+        if unique {
+            dst.disk += s.disk;
+            dst.uncomp += s.uncomp;
+            if extent.r#type() == ExtentType::Inline {
+                self.inline_disk += s.disk;
+            }
+        }
+        dst.refd += s.refd;
+    }
+
+    /// Whether all data of this file is inline, in its metadata leaf.
+    pub fn is_inline(&self) -> bool {
+        self.ninline > 0 && self.ninline == self.nextent
+    }
+
+    /// Sum over all compression types and prealloc.
+    pub fn total(&self) -> SizeStat {
+        self.stat
+            .iter()
+            .chain([&self.prealloc])
+            .fold(SizeStat::default(), |acc, s| SizeStat {
+                disk: acc.disk + s.disk,
+                uncomp: acc.uncomp + s.uncomp,
+                refd: acc.refd + s.refd,
+            })
+    }
+
+    /// Write one line of JSON describing this file: its totals and the split
+    /// by compression type, leaving out unused types.
+    pub fn fmt_json(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        let total = self.total();
+        write!(
+            f,
+            "{{\"path\": {}, \"ino\": {}, \"disk\": {}, \"uncompressed\": {}, \"referenced\": {}",
+            json_path(&self.path),
+            self.ino,
+            total.disk,
+            total.uncomp,
+            total.refd
+        )?;
+        if self.sparse > 0 {
+            write!(f, ", \"sparse\": {}", self.sparse)?;
+        }
+        let rows = self
+            .stat
+            .iter()
+            .zip(Compression::ALL)
+            .map(|(s, comp)| (comp.name(), s))
+            .chain([("prealloc", &self.prealloc)]);
+        write!(f, ", \"compression\": {{")?;
+        for (i, (name, s)) in rows.filter(|(_, s)| !s.is_empty()).enumerate() {
+            write!(
+                f,
+                "{}\"{}\": {{\"disk\": {}, \"uncompressed\": {}, \"referenced\": {}}}",
+                if i == 0 { "" } else { ", " },
+                name,
+                s.disk,
+                s.uncomp,
+                s.refd
+            )?;
+        }
+        writeln!(f, "}}}}")
+    }
+
+    /// Disk usage of the data not stored with `comp`, prealloc excluded.
+    pub fn disk_not(&self, comp: Compression) -> u64 {
+        self.stat
+            .iter()
+            .zip(Compression::ALL)
+            .filter(|(_, c)| c.as_usize() != comp.as_usize())
+            .map(|(s, _)| s.disk)
+            .sum()
+    }
+
+    /// Whether any data of this file is stored with `comp`.
+    pub fn uses(&self, comp: Compression) -> bool {
+        !self.stat[comp.as_usize()].is_empty()
+    }
+}
+
+/// `s` as a quoted JSON string.
+pub fn json_str(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    push_escaped(&mut ret, s);
+    ret.push('"');
+    ret
+}
+
+/// `path` as a quoted JSON string.  Bytes that aren't UTF-8 are escaped as
+/// the lone surrogates `\udc80` to `\udcff`, as Python's `surrogateescape`
+/// does, so the path can be told apart from others and restored.
+pub fn json_path(path: &Path) -> String {
+    let bytes = path.as_os_str().as_bytes();
+    let mut ret = String::with_capacity(bytes.len() + 2);
+    ret.push('"');
+    for chunk in bytes.utf8_chunks() {
+        push_escaped(&mut ret, chunk.valid());
+        for b in chunk.invalid() {
+            ret.push_str(&format!("\\u{:04x}", 0xdc00 | *b as u32));
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+fn push_escaped(ret: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+}
+
+/// Row of `extent` in the usage table: its compression type, or
+/// [`PREALLOC_ROW`] for preallocated extents.
+pub fn row(extent: &ExtentInfo) -> usize {
+    match extent.r#type() {
+        ExtentType::Prealloc => PREALLOC_ROW,
+        _ => extent.comp().as_usize(),
+    }
+}
+
+pub const PREALLOC_ROW: usize = Compression::ALL.len();
+
+/// Per root: the number of files using each row of the usage table, even
+/// partially, at [`ALL_FILES`] the number of files seen and at [`APPARENT`]
+/// the sum of their sizes.
+pub type RootCounts = [u64; APPARENT + 1];
+pub const ALL_FILES: usize = PREALLOC_ROW + 1;
+pub const APPARENT: usize = ALL_FILES + 1;
+
+/// File counts indexed by [`RootId`], shared by everything producing
+/// extents, see [`FileCounter`].
+#[derive(Debug, Default)]
+pub struct FileCounts(Mutex<Vec<RootCounts>>);
+
+impl FileCounts {
+    pub fn load(&self) -> Vec<RootCounts> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Add `counts`, indexed by [`RootId`].
+    pub fn add(&self, counts: &[RootCounts]) {
+        let mut global = self.0.lock().unwrap();
+        if global.len() < counts.len() {
+            global.resize(counts.len(), [0; APPARENT + 1]);
+        }
+        for (g, c) in global.iter_mut().zip(counts) {
+            for (g, c) in g.iter_mut().zip(c) {
+                *g += c;
+            }
+        }
+    }
+
+    /// Sum over all roots.
+    pub fn total(&self) -> RootCounts {
+        self.load().iter().fold([0; APPARENT + 1], |mut acc, c| {
+            for (a, c) in acc.iter_mut().zip(c) {
+                *a += c;
+            }
+            acc
+        })
+    }
+}
+
+/// Counts files locally and publishes the totals to [`FileCounts`] when
+/// dropped.  Extents must be fed file by file, with [`Self::end_file`]
+/// after each.
+pub struct FileCounter {
+    global: Arc<FileCounts>,
+    local: Vec<RootCounts>,
+    /// rows used by the current file
+    mask: u8,
+}
+
+impl FileCounter {
+    pub fn new(global: Arc<FileCounts>) -> Self {
+        Self {
+            global,
+            local: Vec::new(),
+            mask: 0,
+        }
+    }
+
+    pub fn add(&mut self, extent: &ExtentInfo) {
+        if !extent.is_hole() {
+            self.mask |= 1 << row(extent);
+        }
+    }
+
+    /// The file of size `size`, 0 if unknown, is done.
+    pub fn end_file(&mut self, root: RootId, size: u64) {
+        let root = root as usize;
+        if self.local.len() <= root {
+            self.local.resize(root + 1, [0; APPARENT + 1]);
+        }
+        let counts = &mut self.local[root];
+        for (i, n) in counts[..ALL_FILES].iter_mut().enumerate() {
+            *n += (self.mask >> i & 1) as u64;
+        }
+        counts[ALL_FILES] += 1;
+        counts[APPARENT] += size;
+        self.mask = 0;
+    }
+}
+
+impl Drop for FileCounter {
+    fn drop(&mut self) {
+        self.global.add(&self.local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[test]
+    fn json_path_escapes_bytes() {
+        let path = Path::new(OsStr::from_bytes(b"/a \"b\"\n\xffc\xc3"));
+        assert_eq!(json_path(path), r#""/a \"b\"\n\udcffc\udcc3""#);
+        assert_eq!(
+            json_path(Path::new("/d\u{e9}j\u{e0}")),
+            "\"/d\u{e9}j\u{e0}\""
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    ops::Deref,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use rustix::{fs::FileType, io::Errno};
+
+use crate::{
+    diag,
+    global::{add_skipped, scope_reporter},
+};
+
+/// Where the scan reports what happens along the way.  The default,
+/// [`diag::Stderr`], prints to stderr; library users can route it into
+/// their own logging with [`set_reporter`].
+pub trait Reporter: Send + Sync {
+    /// `path` was skipped because of `err`; the scan goes on.
+    fn file_error(&self, path: &Path, err: Errno);
+    /// An extent item of `path` makes no sense, as `msg` says, e.g. on a
+    /// corrupted filesystem.  `path` was skipped; the scan goes on.
+    fn parse_error(&self, _path: &Path, _msg: &str) {}
+    /// `path` was skipped because it is not on btrfs.
+    fn not_btrfs(&self, _path: &Path) {}
+    /// `path` is a socket, FIFO or device node of type `kind`, left out
+    /// without `--include-special`.
+    fn special(&self, _path: &Path, _kind: FileType) {}
+    /// Something worth knowing about how the scan went, e.g. a fallback
+    /// it had to take.
+    fn note(&self, _msg: &str) {}
+    /// All extents of `path` have been read.
+    fn file_done(&self, _path: &Path) {}
+    /// Everything has been collected.
+    fn finished(&self) {}
+}
+
+/// Report `path` skipped because of `err`, counting it for the exit status.
+pub(crate) fn skip(path: &Path, err: Errno) {
+    add_skipped();
+    reporter().file_error(path, err);
+}
+
+/// Report `path` skipped for an extent item that doesn't parse, counting
+/// it for the exit status.
+pub(crate) fn skip_parse_error(path: &Path, msg: &str) {
+    add_skipped();
+    reporter().parse_error(path, msg);
+}
+
+/// Report `path` skipped as not on btrfs, counting it for the exit status.
+pub(crate) fn skip_not_btrfs(path: &Path) {
+    add_skipped();
+    reporter().not_btrfs(path);
+}
+
+static REPORTER: OnceLock<Box<dyn Reporter>> = OnceLock::new();
+
+/// The reporter of the scan running, see [`reporter`].
+pub struct ReporterRef(Inner);
+
+enum Inner {
+    Process(&'static dyn Reporter),
+    Scan(Arc<dyn Reporter>),
+}
+
+impl Deref for ReporterRef {
+    type Target = dyn Reporter;
+    #[inline]
+    fn deref(&self) -> &(dyn Reporter + 'static) {
+        match &self.0 {
+            Inner::Process(r) => *r,
+            Inner::Scan(r) => r.as_ref(),
+        }
+    }
+}
+
+/// The reporter the scan running was given, see
+/// [`crate::Scanner::reporter`], else that of the process.
+#[inline]
+pub fn reporter() -> ReporterRef {
+    ReporterRef(match scope_reporter() {
+        Some(r) => Inner::Scan(r),
+        None => Inner::Process(REPORTER.get_or_init(|| Box::new(diag::Stderr)).as_ref()),
+    })
+}
+
+/// Use `reporter` instead of [`diag::Stderr`] for the scans not given one
+/// of their own.  Hands it back if a scan already reported something.
+pub fn set_reporter(reporter: Box<dyn Reporter>) -> Result<(), Box<dyn Reporter>> {
+    REPORTER.set(reporter)
+}
@@ -0,0 +1,59 @@
+use crate::global::config;
+
+#[derive(Clone, Copy)]
+pub enum Scale {
+    Bytes,
+    /// powers of 1024 as K, M, G..., or of 1000 as kB, MB, GB... if `si`;
+    /// `precision` decimals, or one for values below 10 if `None`
+    Human {
+        si: bool,
+        precision: Option<u8>,
+    },
+}
+impl Scale {
+    /// Binary units whatever the options, for sizes that are powers of two.
+    pub const BINARY: Self = Scale::Human {
+        si: false,
+        precision: None,
+    };
+
+    pub fn from_config() -> Self {
+        if config().bytes {
+            Scale::Bytes
+        } else {
+            Self::human()
+        }
+    }
+    /// Human-readable as `--si` and `--precision` ask.
+    pub fn human() -> Self {
+        Scale::Human {
+            si: config().si,
+            precision: config().precision,
+        }
+    }
+    pub fn scale(&self, num: u64) -> String {
+        const UNITS: [&str; 7] = ["B", "K", "M", "G", "T", "P", "E"];
+        const SI_UNITS: [&str; 7] = ["B", "kB", "MB", "GB", "TB", "PB", "EB"];
+
+        match *self {
+            Scale::Bytes => format!("{}", num),
+            Scale::Human { si, precision } => {
+                let (base, units) = if si { (1000, SI_UNITS) } else { (1024, UNITS) };
+                let mut cnt = 0;
+                let mut div = 1u64;
+                while cnt + 1 < units.len() && num / div >= base {
+                    div *= base;
+                    cnt += 1;
+                }
+                let real_v = num as f64 / div as f64;
+                let precision = match precision {
+                    _ if cnt == 0 => 0,
+                    Some(p) => p as usize,
+                    None if num.is_multiple_of(div) || num / div >= 10 => 0,
+                    None => 1,
+                };
+                format!("{:.*}{}", precision, real_v, units[cnt])
+            }
+        }
+    }
+}
@@ -1,66 +1,104 @@
-use std::{hint::cold_path, os::fd::AsFd, path::Path};
+use std::{
+    hint::cold_path,
+    ops::RangeInclusive,
+    os::fd::{BorrowedFd, OwnedFd},
+    path::Path,
+    sync::Arc,
+};
 
 use rustix::fs::{Mode, OFlags, open};
 
 use crate::{
     actor::Sink,
     btrfs::{
-        ExtentInfo, IoctlSearchItem, Sv2Wrapper,
+        ExtentInfo, HoleFinder, IoctlSearchItem, Sv2Wrapper, ino_path,
         ioctl::{IoctlSearchKey, SearchHeader, Sv2Args},
+        subvol_id,
         tree::{self, ExtentData, TreeItem},
     },
-    global::{get_err, set_err},
+    error::Error,
+    fs_util::{RootId, fs_id, register_subvol},
+    global::{config, get_err},
+    report::{FileCounter, FileCounts},
+    reporter::skip_parse_error,
 };
 
-/// Scan a btrfs subvolume's tree for all EXTENT_DATA items,
-/// parse them into ExtentInfo, and send to sink.
-/// Returns count of unique inodes (files) found.
-pub async fn scan_subvol<S: Sink<Item = ExtentInfo>>(
-    mut sink: S,
-    subvol_path: &Path,
-) -> Result<u64, ()> {
-    let fd = open(
+/// Open a subvolume root for [`scan_subvol`].
+pub fn open_subvol(subvol_path: &Path) -> Result<OwnedFd, Error> {
+    open(
         subvol_path,
         OFlags::DIRECTORY | OFlags::NOFOLLOW,
         Mode::RUSR,
     )
-    .map_err(|e| {
-        eprintln!("Failed to open '{}': {}", subvol_path.display(), e);
-    })?;
+    .map_err(|err| Error::Io {
+        path: subvol_path.into(),
+        err,
+    })
+}
 
-    let mut sv2 = Sv2Wrapper::new(Box::new(Sv2Args::from_sk(IoctlSearchKey::new(
-        0,        // tree_id = 0 → fd's subvolume tree
-        0,        // min_objectid
-        u64::MAX, // max_objectid
+/// Scan a btrfs subvolume's tree for all EXTENT_DATA items,
+/// parse them into ExtentInfo, and send to sink.
+/// Returns count of unique inodes (files) found, and adds them to `counts`.
+/// Extents and files are tagged with `root`.
+pub async fn scan_subvol<S: Sink<Item = ExtentInfo>>(
+    sink: S,
+    subvol_path: &Path,
+    fd: BorrowedFd<'_>,
+    root: RootId,
+    counts: Arc<FileCounts>,
+) -> Result<u64, Error> {
+    // tree_id = 0 → fd's subvolume tree
+    scan_range(sink, subvol_path, fd, 0, 0..=u64::MAX, root, counts).await
+}
+
+/// [`scan_subvol`] for the inodes `objectids` of subvolume `tree_id`,
+/// which need not be the one `fd` lives in.  A file's extents all share
+/// its objectid, so disjoint ranges can be scanned in parallel.
+pub async fn scan_range<S: Sink<Item = ExtentInfo>>(
+    mut sink: S,
+    subvol_path: &Path,
+    fd: BorrowedFd<'_>,
+    tree_id: u64,
+    objectids: RangeInclusive<u64>,
+    root: RootId,
+    counts: Arc<FileCounts>,
+) -> Result<u64, Error> {
+    let mut sv2 = Sv2Wrapper::new(Sv2Args::from_sk(IoctlSearchKey::new(
+        tree_id,
+        *objectids.start(),
+        *objectids.end(),
         0,        // min_offset
         u64::MAX, // max_offset
-        0,        // min_transid
+        // skips whole leaves not written since
+        config().min_generation(),
         u64::MAX, // max_transid
         tree::r#type::EXTENT_DATA,
         tree::r#type::EXTENT_DATA,
-    ))));
+    )));
 
     let mut nfile = 0u64;
     let mut last_ino = 0u64;
+    // the extents of `last_ino` so far, sent once all of them parsed
+    let mut file = vec![];
+    // the rest of its extents are left out with the file
+    let mut bad_ino = 0u64;
+    let mut counter = FileCounter::new(counts);
+    let mut holes = HoleFinder::default();
+    let min_gen = config().min_generation();
+    let fs = fs_id(fd, subvol_path);
+    // tree 0 is the one of `fd`
+    let id = match tree_id {
+        0 => subvol_id(fd).unwrap_or(0),
+        id => id,
+    };
+    let subvol = register_subvol(fs, id);
 
-    while let Some(result) = sv2.next(fd.as_fd()) {
+    while let Some(result) = sv2.next(fd) {
         get_err()?;
-        let (header, buf) = match result {
-            Ok(v) => v,
-            Err(e) => {
-                cold_path();
-                set_err()?;
-                if e.raw_os_error() == 25 {
-                    eprintln!(
-                        "{}: Not btrfs (or SEARCH_V2 unsupported)",
-                        subvol_path.display()
-                    );
-                } else {
-                    eprintln!("Tree scan error: {}", e);
-                }
-                break;
-            }
-        };
+        let (header, buf) = result.map_err(|e| {
+            cold_path();
+            Error::search(subvol_path, e)
+        })?;
 
         // Kernel search returns all items whose key falls in [min, max].
         // The 3-tuple lexicographic order means other types (INODE_ITEM,
@@ -69,34 +107,63 @@ pub async fn scan_subvol<S: Sink<Item = ExtentInfo>>(
             skip_to_extent(&mut sv2, &header);
             continue;
         }
+        if header.objectid == bad_ino {
+            continue;
+        }
 
-        let ext_data = unsafe { ExtentData::from_le_raw(buf) };
-        let item = IoctlSearchItem {
-            header,
-            item: ext_data,
+        let extent = match ExtentData::from_le_raw(buf) {
+            Ok(item) if item.generation < min_gen => continue,
+            Ok(item) => IoctlSearchItem { header, item }.parse(),
+            Err(msg) => Err(msg),
         };
-
-        match item.parse() {
-            Ok(Some(extent)) => {
-                if header.objectid != last_ino {
-                    nfile += 1;
-                    last_ino = header.objectid;
-                }
-                sink.consume(extent).await;
-            }
-            Ok(None) => {} // hole, skip
-            Err(e) => {
+        let extent = match extent {
+            Ok(extent) => extent,
+            Err(msg) => {
                 cold_path();
-                set_err()?;
-                eprintln!("{}", e);
-                break;
+                let path = match ino_path(fd, tree_id, header.objectid) {
+                    Ok(p) => subvol_path.join(p),
+                    Err(_) => subvol_path.into(),
+                };
+                skip_parse_error(&path, &msg);
+                bad_ino = header.objectid;
+                file.clear();
+                continue;
             }
+        };
+        if header.objectid != last_ino {
+            nfile += send_file(&mut sink, &mut counter, &mut file, root).await;
+            last_ino = header.objectid;
+        }
+        let hole = (min_gen == 0).then(|| holes.before(&extent)).flatten();
+        for extent in hole.into_iter().chain([extent]) {
+            file.push(extent.with_root(root).with_fs(fs).with_subvol(subvol));
         }
     }
 
+    nfile += send_file(&mut sink, &mut counter, &mut file, root).await;
     Ok(nfile)
 }
 
+/// Count and send the extents of a file, once all its items parsed, so
+/// that one with a corrupted item is left out whole.  The number of
+/// files sent, none if `extents` is empty.
+async fn send_file<S: Sink<Item = ExtentInfo>>(
+    sink: &mut S,
+    counter: &mut FileCounter,
+    extents: &mut Vec<ExtentInfo>,
+    root: RootId,
+) -> u64 {
+    if extents.is_empty() {
+        return 0;
+    }
+    for extent in extents.drain(..) {
+        counter.add(&extent);
+        sink.consume(extent).await;
+    }
+    counter.end_file(root, 0);
+    1
+}
+
 /// Optimise the next search key to skip irrelevant items.
 ///
 /// The generic `Sv2Wrapper::next()` advances one position at a time
@@ -0,0 +1,843 @@
+use std::{
+    collections::HashSet,
+    ops::RangeInclusive,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use futures_lite::{Stream, StreamExt, stream};
+use kanal::{AsyncSender as Sender, bounded_async as bounded};
+
+use crate::{
+    actor::{Runnable, Sink},
+    btrfs::{ExtentInfo, max_ino, subvol_path, subvolumes, tree::objectid},
+    cache,
+    checkpoint::Checkpoint,
+    collector::{Collector, CollectorMsg, CompsizeStat, shard_of},
+    diag,
+    error::Error,
+    executor::{self, block_on},
+    filter,
+    fs_util::{File_, RootId, find_subvol_root},
+    global::{
+        Config, ScopeGuard, add_skipped_n, auto_jobs, cancel, config, enter, get_err, set_config,
+        set_err, skipped, take_cancelled, take_err, take_skipped,
+    },
+    privs::drop_privs,
+    profile,
+    report::{FileCounts, FileReport},
+    reporter::{Reporter, reporter, skip},
+    scan_tree,
+    signal::{DumpOnSignal, dump_on_usr1},
+    spawn,
+    taskpak::TaskPak,
+    walkdir::{Pause, Roots, WalkDir, nested_paths},
+    worker::Worker,
+};
+
+/// Runs a whole scan in-process:
+///
+/// ```no_run
+/// let stat = xsz::Scanner::new().paths(["/data"]).jobs(4).one_fs(true).run()?;
+/// # Ok::<(), xsz::error::Error>(())
+/// ```
+///
+/// Each scan runs with the configuration and reporter of its scanner, not
+/// together with the command line parser; scans wait for each other.  The
+/// threads and the [`cache`](Self::cache) file are those of the first scan
+/// of the process.
+pub struct Scanner {
+    config: Config,
+    reporter: Option<Box<dyn Reporter>>,
+    cancel: CancelToken,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Self {
+            config: Config {
+                jobs: auto_jobs(),
+                ..Default::default()
+            },
+            reporter: None,
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// A token to stop the scan this scanner runs, from another thread.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Files or directories to scan.
+    pub fn paths(mut self, paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Self {
+        self.config.args = paths
+            .into_iter()
+            .map(|p| p.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Scan with `n` threads, 0 for the default of one per CPU.  The first
+    /// scan of the process starts them; later ones split their work `n`
+    /// ways but between as many threads.
+    pub fn jobs(mut self, n: u8) -> Self {
+        self.config.jobs = if n == 0 { auto_jobs() } else { n };
+        self
+    }
+
+    /// Don't cross filesystem boundaries.
+    pub fn one_fs(mut self, one_fs: bool) -> Self {
+        self.config.one_fs = one_fs;
+        self
+    }
+
+    /// Scan the btrfs trees of the subvolumes holding the paths instead of
+    /// walking them.
+    pub fn tree_scan(mut self, tree_scan: bool) -> Self {
+        self.config.tree_scan = tree_scan;
+        self
+    }
+
+    /// Keep the extents of the files scanned in `path`, and only read those
+    /// of files changed since on later scans.  A process has one such file:
+    /// scans asking for another fail.
+    pub fn cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.cache = Some(path.into());
+        self
+    }
+
+    /// Stop the scan after `secs` seconds, keeping what was counted so far.
+    pub fn timeout(mut self, secs: u64) -> Self {
+        self.config.timeout = Some(secs);
+        self
+    }
+
+    /// Send diagnostics to `reporter` instead of stderr.
+    pub fn reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// Scan and return the usage table.
+    pub fn run(self) -> Result<CompsizeStat, Error> {
+        let _scope = self.enter();
+        Ok(collect(false)?.compsize().cloned().unwrap_or_default())
+    }
+
+    /// Scan, yielding a [`FileReport`] for every file as soon as its extents
+    /// have been read, in no particular order:
+    ///
+    /// ```no_run
+    /// use futures_lite::StreamExt;
+    ///
+    /// let mut files = xsz::Scanner::new().paths(["/data"]).jobs(4).files()?;
+    /// while let Some(file) = xsz::executor::block_on(files.next()) {
+    ///     println!("{}: {}", file.path.display(), file.total().disk);
+    /// }
+    /// files.finish()?;
+    /// # Ok::<(), xsz::error::Error>(())
+    /// ```
+    ///
+    /// The scan runs on threads of its own, so the stream can be polled from
+    /// any executor; it takes at least two jobs for that.  Not available with
+    /// [`tree_scan`](Self::tree_scan).
+    pub fn files(mut self) -> Result<Files, Error> {
+        if self.config.tree_scan {
+            return Err(Error::Setup(
+                "per-file results are not available with tree-scan".into(),
+            ));
+        }
+        self.config.jobs = self.config.jobs.max(2);
+        let scope = self.enter();
+        if take_cancelled() {
+            take_err();
+        }
+        let armed = CancelToken::arm();
+        cache::open()?;
+        let nworkers = config().jobs;
+        let (worker_tx, worker_rx) = bounded(nworkers as usize);
+        let (sender, r) = bounded(nworkers as usize);
+        let nfile = Arc::new(AtomicU64::new(0));
+        let counts = Arc::new(FileCounts::default());
+        let paths = (config().args.iter())
+            .map(|arg| config().scan_path(arg))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::Setup)?;
+        let roots = Roots::open(paths);
+        maybe_drop_privs()?;
+        WalkDir::spawn(
+            move || F {
+                taskpak: TaskPak::new(worker_tx.clone()),
+                global_nfile: nfile.clone(),
+                local_nfile: 0,
+            },
+            roots,
+            config().nwalkers(),
+        );
+        for _ in 0..nworkers {
+            let report = Some(R(TaskPak::new(sender.clone())));
+            let worker = Worker::new(Discard, report, counts.clone());
+            spawn(worker.run(worker_rx.clone()));
+        }
+        let inner = stream::unfold(r, |r| async move {
+            let batch: Box<[FileReport]> = r.recv().await.ok()?;
+            Some((stream::iter(batch.into_vec()), r))
+        })
+        .flatten();
+        Ok(Files {
+            inner: Box::pin(inner),
+            armed,
+            scope,
+        })
+    }
+
+    /// Use the configuration and reporter of this scanner until the guard
+    /// is dropped, once the scans before are done, and make its token the
+    /// one that stops the scan, see [`crate::global::enter`].
+    fn enter(self) -> ScopeGuard {
+        // a library has no command line to parse for the process
+        set_config(Config {
+            jobs: self.config.jobs,
+            ..Default::default()
+        })
+        .ok();
+        let scope = enter(self.config, self.reporter);
+        *CURRENT.lock().unwrap() = Some(self.cancel.0.clone());
+        scope
+    }
+}
+
+/// Stops the scan of the [`Scanner`] it came from, which then returns what
+/// it counted so far, see [`Collector::is_partial`].  Cancelled before the
+/// scan starts, the scan stops right away; other scans run on.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+/// The token of the scan configured last.
+static CURRENT: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        let current = CURRENT.lock().unwrap();
+        self.0.store(true, Ordering::Relaxed);
+        if current.as_ref().is_some_and(|t| Arc::ptr_eq(t, &self.0)) {
+            cancel();
+        }
+    }
+
+    /// Stop the scan starting now if its token was already cancelled.  The
+    /// scan ends with the guard, however it returns.
+    fn arm() -> Armed {
+        let current = CURRENT.lock().unwrap();
+        if current.as_ref().is_some_and(|t| t.load(Ordering::Relaxed)) {
+            cancel();
+        }
+        Armed
+    }
+}
+
+/// The scan of the token armed, see [`CancelToken::arm`].
+struct Armed;
+
+impl Drop for Armed {
+    /// The scan ended, later cancels of its token are not for the next.
+    fn drop(&mut self) {
+        CURRENT.lock().unwrap().take();
+    }
+}
+
+/// Calls [`cancel`] after `--timeout` unless dropped first.
+struct Timeout {
+    done: Option<mpsc::Sender<()>>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Timeout {
+    fn start(secs: Option<u64>) -> Self {
+        let Some(secs) = secs else {
+            return Self {
+                done: None,
+                timer: None,
+            };
+        };
+        let (done, r) = mpsc::channel();
+        let timer = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = r.recv_timeout(Duration::from_secs(secs)) {
+                cancel();
+            }
+        });
+        Self {
+            done: Some(done),
+            timer: Some(timer),
+        }
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        // a scan must not be cancelled by the timer of the one before
+        drop(self.done.take());
+        if let Some(timer) = self.timer.take() {
+            timer.join().ok();
+        }
+    }
+}
+
+/// Per-file results of a scan, see [`Scanner::files`].
+pub struct Files {
+    inner: Pin<Box<dyn Stream<Item = FileReport> + Send>>,
+    /// dropped before `scope`, for the next scan to arm its token
+    armed: Armed,
+    /// the configuration of the scan, until [`Self::finish`]
+    scope: ScopeGuard,
+}
+
+impl Files {
+    /// The error that cut the scan short, if any, or [`Error::Cancelled`]
+    /// if it was cancelled or timed out, the files yielded being only part
+    /// of them.  Call once the stream has ended.
+    pub fn finish(self) -> Result<(), Error> {
+        let Self {
+            armed,
+            scope: _scope,
+            ..
+        } = self;
+        reporter().finished();
+        drop(armed);
+        let cancelled = take_cancelled();
+        take_err().map_or(Ok(()), Err)?;
+        cache::save()?;
+        if cancelled {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Files {
+    type Item = FileReport;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<FileReport>> {
+        self.inner.poll_next(cx)
+    }
+}
+
+struct F {
+    taskpak: TaskPak<File_>,
+    global_nfile: Arc<AtomicU64>,
+    local_nfile: u64,
+}
+impl Sink for F {
+    type Item = File_;
+    fn consume(&mut self, f: File_) -> impl Future + Send {
+        self.local_nfile += 1;
+        self.taskpak.push(f)
+    }
+    fn flush(&mut self) -> impl Future + Send {
+        self.taskpak.flush()
+    }
+}
+impl Drop for F {
+    fn drop(&mut self) {
+        self.global_nfile
+            .fetch_add(self.local_nfile as _, Ordering::Relaxed);
+    }
+}
+
+/// Sends each extent to the collector shard deduplicating it.
+struct S(Box<[TaskPak<ExtentInfo, CollectorMsg>]>);
+impl S {
+    fn new(shards: &[Sender<CollectorMsg>]) -> Self {
+        Self(shards.iter().cloned().map(TaskPak::new).collect())
+    }
+}
+impl Sink for S {
+    type Item = ExtentInfo;
+    fn consume(&mut self, f: ExtentInfo) -> impl Future + Send {
+        let shard = shard_of(&f, self.0.len());
+        self.0[shard].push(f)
+    }
+    fn flush(&mut self) -> impl Future + Send {
+        async {
+            for shard in &mut self.0 {
+                shard.flush().await;
+            }
+        }
+    }
+}
+
+/// Workers per collector shard.  Deduplicating extents is serial within a
+/// shard and keeps up with a few workers.
+const JOBS_PER_SHARD: usize = 4;
+
+struct R<M: From<Box<[FileReport]>> + Send + 'static = CollectorMsg>(TaskPak<FileReport, M>);
+impl<M: From<Box<[FileReport]>> + Send + 'static> Sink for R<M> {
+    type Item = FileReport;
+    fn consume(&mut self, f: FileReport) -> impl Future + Send {
+        self.0.push(f)
+    }
+    fn flush(&mut self) -> impl Future + Send {
+        self.0.flush()
+    }
+}
+
+/// Drops the extents, for scans that only want per-file results.
+struct Discard;
+impl Sink for Discard {
+    type Item = ExtentInfo;
+    fn consume(&mut self, _: ExtentInfo) -> impl Future + Send {
+        async {}
+    }
+}
+
+/// A piece of a btrfs tree for a tree scan task.
+struct TreeJob {
+    /// where errors are reported
+    path: PathBuf,
+    fd: Arc<OwnedFd>,
+    /// 0 for the subvolume of `fd`
+    tree_id: u64,
+    objectids: RangeInclusive<u64>,
+    root: RootId,
+}
+
+/// One job per subvolume holding `paths`, and the names of those
+/// subvolumes.
+fn tree_scan_jobs(paths: &[PathBuf]) -> (Vec<TreeJob>, Vec<String>) {
+    let mut roots = HashSet::new();
+    for arg in paths {
+        match find_subvol_root(arg) {
+            Ok(root) => {
+                roots.insert(root);
+            }
+            Err(e) => skip(arg, e),
+        }
+    }
+    roots
+        .into_iter()
+        .filter_map(|root| match scan_tree::open_subvol(&root) {
+            Ok(fd) => Some((root, fd)),
+            Err(Error::Io { path, err }) => {
+                skip(&path, err);
+                None
+            }
+            Err(e) => unreachable!("{}", e),
+        })
+        .enumerate()
+        .map(|(i, (path, fd))| {
+            let name = path.display().to_string();
+            let job = TreeJob {
+                path,
+                fd: Arc::new(fd),
+                tree_id: 0,
+                objectids: 0..=u64::MAX,
+                // no more subvolumes than paths, see `collect_paths`
+                root: i as RootId,
+            };
+            (job, name)
+        })
+        .unzip()
+}
+
+/// Below this many inodes a subvolume isn't worth splitting.
+const MIN_SPLIT_INODES: u64 = 1 << 16;
+
+/// How `--subvol-scan` names subvolume `id` of the filesystem `fd` is on:
+/// by its path from the top level one.
+pub fn subvol_name(fd: BorrowedFd, id: u64) -> String {
+    match subvol_path(fd, id) {
+        Ok(p) if p.as_os_str().is_empty() => "<FS_TREE>".into(),
+        Ok(p) => p.display().to_string(),
+        Err(_) => format!("<subvolume {}>", id),
+    }
+}
+
+/// Jobs covering every subvolume of the filesystem mounted at `mount`, the
+/// larger ones split in up to `nparts` objectid ranges, and the names of
+/// the subvolumes.
+fn subvol_scan_jobs(mount: &Path, nparts: u8) -> Result<(Vec<TreeJob>, Vec<String>), Error> {
+    let fd = Arc::new(scan_tree::open_subvol(mount)?);
+    let subvols = subvolumes(fd.as_fd()).map_err(|e| Error::search(mount, e))?;
+    let mut jobs = vec![];
+    let mut names = vec![];
+    for (i, (id, _)) in subvols.into_iter().enumerate() {
+        let root = RootId::try_from(i).map_err(|_| {
+            Error::Setup(format!(
+                "{}: more than {} subvolumes, scan them in parts",
+                mount.display(),
+                RootId::MAX as usize + 1
+            ))
+        })?;
+        let name = subvol_name(fd.as_fd(), id);
+        let max = max_ino(fd.as_fd(), id).unwrap_or(0);
+        let inodes = max.saturating_sub(objectid::FIRST_FREE);
+        let nparts = if inodes < MIN_SPLIT_INODES {
+            1
+        } else {
+            nparts as u64
+        };
+        let step = inodes / nparts + 1;
+        for part in 0..nparts {
+            let start = objectid::FIRST_FREE + part * step;
+            let end = if part + 1 == nparts {
+                u64::MAX
+            } else {
+                start + step - 1
+            };
+            jobs.push(TreeJob {
+                path: mount.join(&name),
+                fd: fd.clone(),
+                tree_id: id,
+                objectids: if part == 0 { 0 } else { start }..=end,
+                root,
+            });
+        }
+        names.push(name);
+    }
+    Ok((jobs, names))
+}
+
+/// Give up root once everything that needs it has been opened.
+/// Must run before the first `spawn`, see [`drop_privs`].
+fn maybe_drop_privs() -> Result<(), Error> {
+    match &config().drop_privs {
+        Some(user) => drop_privs(user)
+            .map_err(|e| Error::Setup(format!("Failed to drop privileges to '{}': {}", user, e))),
+        None => Ok(()),
+    }
+}
+
+/// What runs beside the scan: the `--errors-to` log and the `--timeout`
+/// timer.  Started after [`maybe_drop_privs`], so neither keeps root.
+fn start_helpers() -> Result<Timeout, Error> {
+    diag::open_log()?;
+    Ok(Timeout::start(config().timeout))
+}
+
+/// Scan what [`config`] asks for, printing diagnostics to stderr.  With
+/// `dump_on_signal`, SIGUSR1 prints intermediate statistics, see
+/// [`crate::signal`].
+pub fn collect(dump_on_signal: bool) -> Result<Collector, Error> {
+    collect_paths(&config().args, dump_on_signal)
+}
+
+/// [`collect_paths`] with `config` instead of the configuration of the
+/// process, and `output` of its result with it too, for a scan with options
+/// of its own such as a `--daemon` request.  Such scans run one at a time.
+pub fn collect_paths_with<T>(
+    config: Config,
+    args: &[PathBuf],
+    output: impl FnOnce(&Collector) -> T,
+) -> Result<T, Error> {
+    crate::global::scoped(config, || Ok(output(&collect_paths(args, false)?)))
+}
+
+/// [`collect`] of `paths` instead of the command line paths, the rest as
+/// [`config`] asks, for `--daemon`.
+pub fn collect_paths(args: &[PathBuf], dump_on_signal: bool) -> Result<Collector, Error> {
+    if args.len() > RootId::MAX as usize + 1 {
+        return Err(Error::Setup(format!(
+            "at most {} paths can be scanned at once",
+            RootId::MAX as usize + 1
+        )));
+    }
+    let nworkers = config().jobs;
+    filter::reset();
+    take_skipped();
+    profile::reset();
+    // a cancel that came after the last scan ended
+    if take_cancelled() {
+        take_err();
+    }
+    let armed = CancelToken::arm();
+    let paths = (args.iter())
+        .map(|arg| config().scan_path(arg))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::Setup)?;
+    let paths = &paths[..];
+    let timeout;
+    // the first shard also gets per-file results
+    let nshards = (nworkers as usize).div_ceil(JOBS_PER_SHARD);
+    let nfile = Arc::new(AtomicU64::new(0));
+    let counts = Arc::new(FileCounts::default());
+
+    let shards = if config().tree_scan {
+        let (jobs, names) = if config().subvol_scan {
+            let [mount] = paths else {
+                return Err(Error::Setup(
+                    "--subvol-scan takes the mount point as its only path".into(),
+                ));
+            };
+            subvol_scan_jobs(mount, (nworkers - 1).max(1))?
+        } else {
+            tree_scan_jobs(paths)
+        };
+        maybe_drop_privs()?;
+        timeout = start_helpers()?;
+        let mut shards: Vec<_> = (0..nshards).map(|_| Collector::new()).collect();
+        shards[0].set_root_names(names);
+        run_shards(shards, |senders| {
+            let dump = dump_on_signal.then(|| dump_on_usr1(senders));
+            let jobs = Arc::new(jobs);
+            let next_job = Arc::new(AtomicUsize::new(0));
+            let n_tree_workers = (nworkers - 1).max(1) as usize;
+            for _ in 0..n_tree_workers.min(jobs.len()) {
+                let nfile = nfile.clone();
+                let counts = Arc::clone(&counts);
+                let jobs = Arc::clone(&jobs);
+                let next_job = Arc::clone(&next_job);
+                let dump = dump.clone();
+                let shards = senders.to_vec();
+                spawn(async move {
+                    let _dump = dump;
+                    loop {
+                        let idx = next_job.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = jobs.get(idx) else {
+                            break;
+                        };
+                        let sink = S::new(&shards);
+                        match scan_tree::scan_range(
+                            sink,
+                            &job.path,
+                            job.fd.as_fd(),
+                            job.tree_id,
+                            job.objectids.clone(),
+                            job.root,
+                            counts.clone(),
+                        )
+                        .await
+                        {
+                            Ok(cnt) => {
+                                nfile.fetch_add(cnt, Ordering::Relaxed);
+                            }
+                            Err(e) => set_err(e),
+                        }
+                    }
+                });
+            }
+        })
+    } else {
+        // a nested path's files are counted under the one it is in, which
+        // `--du` shows like `du -s` does
+        let nested = nested_paths(paths);
+        if ((config().per_arg && !config().du)
+            || config().group_depth.is_some()
+            || config().exclusive)
+            && let Some((p, outer)) = args
+                .iter()
+                .zip(&nested)
+                .find_map(|(p, outer)| Some((p, outer.as_ref()?)))
+        {
+            return Err(Error::Setup(format!(
+                "{} is inside {}: --per-arg, --group-depth and --exclusive take \
+                 paths apart",
+                p.display(),
+                outer.display()
+            )));
+        }
+        let mut shards: Vec<_> = (0..nshards).map(|_| Collector::new()).collect();
+        let mut pending = None;
+        if let Some(file) = &config().resume {
+            let saved = Checkpoint::load(file)?;
+            if saved.args != args {
+                return Err(Error::Setup(format!(
+                    "{}: a checkpoint of a scan of other paths",
+                    file.display()
+                )));
+            }
+            if saved.options != config().walk_options() {
+                return Err(Error::Setup(format!(
+                    "{}: a checkpoint of a scan with other options: {}",
+                    file.display(),
+                    saved.options.join(" ")
+                )));
+            }
+            filter::restore_links(&saved.links);
+            nfile.store(saved.nfile, Ordering::Relaxed);
+            add_skipped_n(saved.skipped);
+            counts.add(&saved.counts);
+            shards = Collector::restore(saved.snapshot, nshards);
+            pending = Some(saved.pending);
+        }
+        shards[0].set_root_names(args.iter().map(|p| p.display().to_string()).collect());
+        shards[0].set_nested(nested.iter().map(Option::is_some).collect());
+        cache::open()?;
+        let mut roots = match pending {
+            Some(dirs) => Roots::resume(dirs),
+            None => Roots::open(paths),
+        };
+        maybe_drop_privs()?;
+        timeout = start_helpers()?;
+        loop {
+            let pause = config()
+                .checkpoint_file()
+                .map(|_| Arc::new(Pause::new(Instant::now() + CHECKPOINT_INTERVAL)));
+            if let Some(pause) = &pause {
+                roots = roots.with_pause(pause.clone());
+            }
+            shards = run_shards(shards, |senders| {
+                let dump = dump_on_signal.then(|| dump_on_usr1(senders));
+                walk(roots, senders, &nfile, &counts, dump);
+            });
+            // each pause drains the pipeline, so the collectors hold all
+            // that was found outside of the directories left
+            let cfg = config();
+            let (Some(file), Some(pause)) = (cfg.checkpoint_file(), pause) else {
+                break;
+            };
+            let pending = pause.take_pending();
+            if pending.is_empty() || get_err().is_err() {
+                break;
+            }
+            let checkpoint = Checkpoint {
+                args: args.to_vec(),
+                options: config().walk_options(),
+                nfile: nfile.load(Ordering::Relaxed),
+                skipped: skipped(),
+                counts: counts.load(),
+                snapshot: Collector::snapshot(&shards),
+                links: filter::links(),
+                pending,
+            };
+            checkpoint.save(file)?;
+            roots = Roots::resume(checkpoint.pending);
+        }
+        shards
+    };
+
+    let mut shards = shards.into_iter();
+    let mut collector = shards.next().unwrap();
+    for shard in shards {
+        collector.merge(shard);
+    }
+    drop(timeout);
+    drop(armed);
+    collector.finish();
+    if config().profile {
+        profile::report(&mut std::io::stderr()).ok();
+    }
+    if take_cancelled() {
+        collector.set_partial();
+    }
+    if let Some(e) = take_err() {
+        return Err(e);
+    }
+    cache::save()?;
+    if let Some(file) = config().checkpoint_file()
+        && !collector.is_partial()
+    {
+        std::fs::remove_file(file).ok();
+    }
+    collector.set_counts(nfile.load(Ordering::Relaxed), &counts.load());
+    Ok(collector)
+}
+
+/// How often `--checkpoint` saves.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Run `shards` on what the tasks `produce` spawns sends them, until all of
+/// those are done.  The first shard runs on the calling thread.
+fn run_shards(
+    shards: Vec<Collector>,
+    produce: impl FnOnce(&[Sender<CollectorMsg>]),
+) -> Vec<Collector> {
+    let nworkers = config().jobs as usize;
+    let (senders, rxs): (Vec<_>, Vec<_>) = shards.iter().map(|_| bounded(nworkers)).unzip();
+    produce(&senders);
+    drop(senders);
+    let mut shards = shards.into_iter().zip(rxs);
+    let (first, r) = shards.next().unwrap();
+    let tasks: Vec<_> = shards
+        .map(|(shard, rx)| executor::spawn(shard.run(rx)))
+        .collect();
+    let mut ret = vec![block_on(first.run(r))];
+    ret.extend(tasks.into_iter().map(block_on));
+    ret
+}
+
+/// Spawn the walk of `roots` and the workers reading the files found,
+/// sending extents to `shards` and per-file results to the first one.
+fn walk(
+    roots: Roots,
+    shards: &[Sender<CollectorMsg>],
+    nfile: &Arc<AtomicU64>,
+    counts: &Arc<FileCounts>,
+    dump: Option<Arc<DumpOnSignal>>,
+) {
+    let nworkers = config().jobs;
+    let (worker_tx, worker_rx) = bounded(nworkers as usize);
+    let fcb = {
+        let nfile = nfile.clone();
+        move || F {
+            taskpak: TaskPak::new(worker_tx.clone()),
+            global_nfile: nfile.clone(),
+            local_nfile: 0,
+        }
+    };
+    WalkDir::spawn(fcb, roots, config().nwalkers());
+    for _ in 0..nworkers {
+        let report = config()
+            .file_reports()
+            .then(|| R(TaskPak::new(shards[0].clone())));
+        let worker = Worker::new(S::new(shards), report, counts.clone());
+        let (worker_rx, dump) = (worker_rx.clone(), dump.clone());
+        spawn(async move {
+            worker.run(worker_rx).await;
+            drop(dump);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the paths it is told are not on btrfs.
+    #[derive(Clone, Default)]
+    struct Count(Arc<AtomicUsize>);
+
+    impl Reporter for Count {
+        fn file_error(&self, _: &Path, _: rustix::io::Errno) {}
+        fn not_btrfs(&self, _: &Path) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn scans_configured_apart() {
+        let dir = std::env::temp_dir().join(format!("xsz-scanner-{}", std::process::id()));
+        for name in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join("file"), b"data").unwrap();
+        }
+        std::fs::write(dir.join("b/other"), b"data").unwrap();
+        let (a, b) = (Count::default(), Count::default());
+        let scan = |path: &str, reporter: &Count, jobs| {
+            Scanner::new()
+                .paths([dir.join(path)])
+                .jobs(jobs)
+                .reporter(reporter.clone())
+                .run()
+        };
+        scan("a", &a, 2).unwrap();
+        scan("b", &b, 3).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+        assert_eq!(b.0.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    mem::MaybeUninit,
+    ptr::null_mut,
+    sync::{Arc, Mutex, OnceLock},
+    thread::Builder,
+};
+
+use kanal::AsyncSender as Sender;
+
+use crate::collector::{CollectorMsg, Dump};
+
+/// The collector shards the listener asks, while a scan runs.
+static SLOT: Mutex<Vec<Sender<CollectorMsg>>> = Mutex::new(vec![]);
+/// Whether the listener is running; it is started once per process.
+static LISTENING: OnceLock<bool> = OnceLock::new();
+
+/// Keeps the SIGUSR1 listener able to reach the collector.  Every task
+/// producing collector messages holds a clone; once the last one is
+/// dropped the listener lets go of its sender, so the collector's channel
+/// can close.
+pub struct DumpOnSignal(());
+
+impl Drop for DumpOnSignal {
+    fn drop(&mut self) {
+        SLOT.lock().unwrap().clear();
+    }
+}
+
+/// Ask the collector shards behind `senders` for intermediate statistics
+/// on every SIGUSR1, summed over all of them, until the returned guard is
+/// dropped.  The first call blocks
+/// SIGUSR1 in the calling thread, so it must run before any other thread
+/// is spawned, for them to inherit the mask; later calls point the same
+/// listener at other collectors.
+pub fn dump_on_usr1(senders: &[Sender<CollectorMsg>]) -> Arc<DumpOnSignal> {
+    if *LISTENING.get_or_init(listen) {
+        *SLOT.lock().unwrap() = senders.to_vec();
+    }
+    Arc::new(DumpOnSignal(()))
+}
+
+fn listen() -> bool {
+    let set = unsafe {
+        let mut set = MaybeUninit::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGUSR1);
+        set.assume_init()
+    };
+    if unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut()) } != 0 {
+        return false;
+    }
+    Builder::new()
+        .name("xsz-signal".into())
+        .spawn(move || {
+            loop {
+                let mut sig = 0;
+                if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                    break;
+                }
+                // between scans there is no one to ask
+                let senders = SLOT.lock().unwrap().clone();
+                let dump = Arc::new(Dump::new(senders.len()));
+                for sender in senders {
+                    let msg = CollectorMsg::DumpIntermediate(dump.clone());
+                    sender.as_sync().send(msg).ok();
+                }
+            }
+        })
+        .is_ok()
+}
@@ -0,0 +1,298 @@
+//! `--max-memory`: once the extents seen take more than the budget, the
+//! sets of them are written out as a run sorted by filesystem and disk
+//! address, or inode for inline extents, to an unnamed file in `$TMPDIR`,
+//! and start over empty.  An
+//! extent already in a run looks new to the set again and is counted once
+//! more; merging the runs at the end finds those, see
+//! [`Spill::for_each_recounted`].
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    env::temp_dir,
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    sync::{
+        Once,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use rustix::fs::{Mode, OFlags, open};
+
+use crate::{btrfs::ExtentInfo, fs_util::FsId, global::config, reporter::reporter};
+
+/// What an extent of a run takes in memory, with its share of the set.
+const COST: u64 = (size_of::<ExtentInfo>() + 16) as u64;
+/// An extent in a run file: [`ExtentInfo::encode`], then the inode and the
+/// filesystem.
+const RECORD_LEN: usize = ExtentInfo::ENCODED_LEN + size_of::<u64>() + size_of::<FsId>();
+/// Records read at once from each run while merging.
+const READ_AHEAD: usize = 1024;
+
+/// Bytes held by the runs in memory of all sets.
+static USED: AtomicU64 = AtomicU64::new(0);
+static FAILED: Once = Once::new();
+
+pub struct Spill {
+    /// the extents first seen since the last run was written
+    run: Vec<ExtentInfo>,
+    /// created with the first run written
+    file: Option<File>,
+    /// offset and number of records of each run written
+    runs: Vec<(u64, u64)>,
+    len: u64,
+}
+
+/// What `--max-memory` makes of `nextent` extents seen: about the memory
+/// they take, at most the budget, and the bytes of the runs written to
+/// `$TMPDIR` past it.  `None` without `--max-memory`.
+pub fn estimate(nextent: u64) -> Option<(u64, u64)> {
+    let budget = config().max_memory()?;
+    let memory = nextent * COST;
+    if memory <= budget {
+        return Some((memory, 0));
+    }
+    Some((budget, nextent * RECORD_LEN as u64))
+}
+
+impl Spill {
+    pub fn new() -> Self {
+        Self {
+            run: vec![],
+            file: None,
+            runs: vec![],
+            len: 0,
+        }
+    }
+
+    /// Keep `extent`, just counted as unique.  `true` if the run was
+    /// written out, so the set must start over.
+    pub fn push(&mut self, extent: &ExtentInfo) -> bool {
+        self.run.push(*extent);
+        let used = USED.fetch_add(COST, Ordering::Relaxed) + COST;
+        let budget = config().max_memory().unwrap_or(u64::MAX);
+        // the set that pushed over the budget writes out its run, unless it
+        // is too small to be worth a run of its own
+        if used <= budget || (self.run.len() as u64) * COST < budget / 16 || FAILED.is_completed() {
+            return false;
+        }
+        match self.write_run() {
+            Ok(()) => true,
+            Err(e) => {
+                FAILED.call_once(|| {
+                    reporter().note(&format!(
+                        "--max-memory: failed to write extents to {}: {}, keeping them in memory",
+                        temp_dir().display(),
+                        e
+                    ))
+                });
+                false
+            }
+        }
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let fd = open(
+                    temp_dir(),
+                    OFlags::TMPFILE | OFlags::RDWR | OFlags::CLOEXEC,
+                    Mode::RUSR | Mode::WUSR,
+                )?;
+                self.file.insert(File::from(fd))
+            }
+        };
+        self.run.sort_unstable_by_key(key);
+        let mut buf = Vec::with_capacity(self.run.len() * RECORD_LEN);
+        for extent in &self.run {
+            extent.encode(&mut buf);
+            buf.extend_from_slice(&extent.objectid().to_le_bytes());
+            buf.extend_from_slice(&extent.fs().to_le_bytes());
+        }
+        file.write_all_at(&buf, self.len)?;
+        self.runs.push((self.len, self.run.len() as u64));
+        self.len += buf.len() as u64;
+        USED.fetch_sub(self.run.len() as u64 * COST, Ordering::Relaxed);
+        self.run = vec![];
+        Ok(())
+    }
+
+    /// Merge the runs with what is still in memory, and call `f` with each
+    /// extent that was counted as unique again after its first run, once
+    /// per time.
+    pub fn for_each_recounted(mut self, mut f: impl FnMut(&ExtentInfo)) -> io::Result<()> {
+        self.run.sort_unstable_by_key(key);
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut readers: Vec<_> = self
+            .runs
+            .iter()
+            .map(|&(offset, n)| RunReader::new(offset, n))
+            .collect();
+        // runs in the order they were written, the one in memory last: the
+        // first of equal extents is the one counted rightly
+        let mut heap = BinaryHeap::new();
+        for (i, r) in readers.iter_mut().enumerate() {
+            if let Some(extent) = r.next(file)? {
+                heap.push(Reverse((key(&extent), i)));
+            }
+        }
+        let mut in_memory = self.run.iter().peekable();
+        let mut last = None;
+        loop {
+            let from_runs = heap.peek().map(|Reverse((bytenr, _))| *bytenr);
+            let from_memory = in_memory.peek().map(|e| key(e));
+            let extent = match (from_runs, from_memory) {
+                (Some(a), Some(b)) if b < a => *in_memory.next().unwrap(),
+                (Some(_), _) => {
+                    let Reverse((_, i)) = heap.pop().unwrap();
+                    let extent = readers[i].current.unwrap();
+                    if let Some(next) = readers[i].next(file)? {
+                        heap.push(Reverse((key(&next), i)));
+                    }
+                    extent
+                }
+                (None, Some(_)) => *in_memory.next().unwrap(),
+                (None, None) => break,
+            };
+            if last == Some(key(&extent)) {
+                f(&extent);
+            }
+            last = Some(key(&extent));
+        }
+        Ok(())
+    }
+}
+
+/// What runs are sorted and extents told apart by: the same address on
+/// two filesystems is two extents, and inline ones, all at address 0, are
+/// one per inode.
+fn key(extent: &ExtentInfo) -> (FsId, u64, u64) {
+    let ino = match extent.disk_bytenr() {
+        0 => extent.objectid(),
+        _ => 0,
+    };
+    (extent.fs(), extent.disk_bytenr(), ino)
+}
+
+impl Default for Spill {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        USED.fetch_sub(self.run.len() as u64 * COST, Ordering::Relaxed);
+    }
+}
+
+/// Reads the records of one run a chunk at a time.
+struct RunReader {
+    offset: u64,
+    left: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    /// the last record read
+    current: Option<ExtentInfo>,
+}
+
+impl RunReader {
+    fn new(offset: u64, n: u64) -> Self {
+        Self {
+            offset,
+            left: n,
+            buf: vec![],
+            pos: 0,
+            current: None,
+        }
+    }
+
+    fn next(&mut self, file: &File) -> io::Result<Option<ExtentInfo>> {
+        if self.pos == self.buf.len() {
+            let n = self.left.min(READ_AHEAD as u64);
+            if n == 0 {
+                self.current = None;
+                return Ok(None);
+            }
+            self.buf.resize(n as usize * RECORD_LEN, 0);
+            file.read_exact_at(&mut self.buf, self.offset)?;
+            self.offset += self.buf.len() as u64;
+            self.left -= n;
+            self.pos = 0;
+        }
+        let record = &self.buf[self.pos..self.pos + RECORD_LEN];
+        self.pos += RECORD_LEN;
+        let (encoded, rest) = record.split_at(ExtentInfo::ENCODED_LEN);
+        let (ino, fs) = rest.split_at(size_of::<u64>());
+        let extent = ExtentInfo::decode(
+            u64::from_le_bytes(ino.try_into().unwrap()),
+            encoded.try_into().unwrap(),
+        )
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?
+        .with_fs(FsId::from_le_bytes(fs.try_into().unwrap()));
+        self.current = Some(extent);
+        Ok(Some(extent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(fs: FsId, bytenr: u64, ino: u64) -> ExtentInfo {
+        ExtentInfo::for_test(ino, bytenr, 4096).with_fs(fs)
+    }
+
+    /// Add `extents` to the run in memory, as [`Spill::push`] does.
+    fn add(spill: &mut Spill, extents: &[ExtentInfo]) {
+        spill.run.extend_from_slice(extents);
+        USED.fetch_add(extents.len() as u64 * COST, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn merge_finds_recounted() {
+        let mut spill = Spill::new();
+        add(
+            &mut spill,
+            &[extent(0, 8192, 2), extent(0, 4096, 1), extent(0, 0, 5)],
+        );
+        spill.write_run().unwrap();
+        // the same address on another filesystem, another inline inode
+        add(
+            &mut spill,
+            &[extent(0, 4096, 3), extent(1, 4096, 4), extent(0, 0, 6)],
+        );
+        spill.write_run().unwrap();
+        add(
+            &mut spill,
+            &[extent(0, 0, 5), extent(0, 8192, 7), extent(0, 4096, 8)],
+        );
+        let mut recounted = vec![];
+        spill
+            .for_each_recounted(|e| recounted.push((key(e), e.stat().disk)))
+            .unwrap();
+        assert_eq!(
+            recounted,
+            [
+                ((0, 0, 5), 4096),
+                ((0, 4096, 0), 4096),
+                ((0, 4096, 0), 4096),
+                ((0, 8192, 0), 4096)
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_without_runs() {
+        let mut spill = Spill::new();
+        add(&mut spill, &[extent(0, 4096, 1)]);
+        let mut n = 0;
+        spill.for_each_recounted(|_| n += 1).unwrap();
+        assert_eq!(n, 0);
+    }
+}
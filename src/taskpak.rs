@@ -1,33 +1,67 @@
-use std::mem::take;
+use std::{
+    mem::take,
+    time::{Duration, Instant},
+};
 
 use kanal::AsyncSender as Sender;
 
-use crate::spawn;
+use crate::{profile, spawn};
 
-pub struct TaskPak<T: Send + 'static> {
+/// Batches items of type `T` and sends each full batch as one message `M`,
+/// so several kinds of batches can share one actor's channel.  A batch
+/// goes out early when the receiver has nothing queued or its first item
+/// waited [`MAX_DELAY`], so small scans don't leave the receiver idle;
+/// both are checked every [`Self::MIN_SIZE`] items, not on each push.
+pub struct TaskPak<T: Send + 'static, M: From<Box<[T]>> + Send + 'static = Box<[T]>> {
     pub(crate) inner: Vec<T>,
-    pub(crate) sender: Sender<Box<[T]>>,
+    pub(crate) sender: Sender<M>,
+    /// when the first item of the batch arrived
+    since: Instant,
 }
 
-impl<T: Send + 'static> TaskPak<T> {
+/// Longest a batched item waits for more to arrive, as far as pushes go on.
+const MAX_DELAY: Duration = Duration::from_millis(10);
+
+impl<T: Send + 'static, M: From<Box<[T]>> + Send + 'static> TaskPak<T, M> {
     const SIZE: usize = 1024 * 16 / size_of::<T>();
-    pub fn new(sender: Sender<Box<[T]>>) -> Self {
+    /// smallest batch sent early to an idle receiver
+    const MIN_SIZE: usize = if Self::SIZE < 16 { 1 } else { Self::SIZE / 16 };
+    pub fn new(sender: Sender<M>) -> Self {
         Self {
             inner: Vec::with_capacity(Self::SIZE),
             sender,
+            since: Instant::now(),
         }
     }
     pub async fn push(&mut self, item: T) {
+        if self.is_empty() {
+            self.since = Instant::now();
+        }
         self.inner.push(item);
-        if self.is_full() {
-            let mut tmp = Vec::with_capacity(Self::SIZE);
-            tmp.append(&mut self.inner);
-            self.sender.send(tmp.into_boxed_slice()).await.ok();
+        if self.is_full() || (self.inner.len().is_multiple_of(Self::MIN_SIZE) && self.is_due()) {
+            self.flush().await;
         }
     }
 
+    /// Send the batch so far, if any.
+    pub async fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let mut tmp = Vec::with_capacity(self.inner.len());
+        tmp.append(&mut self.inner);
+        let start = profile::start();
+        self.sender.send(tmp.into_boxed_slice().into()).await.ok();
+        profile::stalled(start);
+    }
+
+    /// Whether to send the batch before it is full.
+    fn is_due(&self) -> bool {
+        self.sender.is_empty() || self.since.elapsed() >= MAX_DELAY
+    }
+
     #[inline]
-    pub fn sender(&self) -> &Sender<Box<[T]>> {
+    pub fn sender(&self) -> &Sender<M> {
         &self.sender
     }
 
@@ -42,13 +76,13 @@ impl<T: Send + 'static> TaskPak<T> {
     }
 }
 
-impl<T: Send + 'static> Drop for TaskPak<T> {
+impl<T: Send + 'static, M: From<Box<[T]>> + Send + 'static> Drop for TaskPak<T, M> {
     fn drop(&mut self) {
         if !self.is_empty() {
             let handler = self.sender.clone();
             let item = take(&mut self.inner).into_boxed_slice();
             spawn(async move {
-                handler.send(item).await.ok();
+                handler.send(item.into()).await.ok();
             });
         }
     }
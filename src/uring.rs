@@ -0,0 +1,305 @@
+use std::{
+    cell::RefCell,
+    ffi::{CStr, c_void},
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr::{null_mut, write_volatile},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use rustix::{
+    fs::{AtFlags, CWD, Mode, OFlags, Statx, StatxFlags},
+    io::{Errno, Result},
+    io_uring::{
+        IORING_OFF_CQ_RING, IORING_OFF_SQ_RING, IORING_OFF_SQES, IoringEnterFlags,
+        IoringFeatureFlags, IoringOp, IoringSqeFlags, addr_or_splice_off_in_union, io_uring_cqe,
+        io_uring_enter, io_uring_params, io_uring_ptr, io_uring_setup, io_uring_sqe,
+        io_uring_user_data, len_union, off_or_addr2_union, op_flags_union,
+    },
+    mm::{MapFlags, ProtFlags, mmap, munmap},
+};
+
+/// Entries of each ring; larger batches are submitted in several rounds.
+const ENTRIES: u32 = 256;
+
+struct Map {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Map {
+    fn new(ring: &OwnedFd, len: usize, offset: u64) -> Result<Self> {
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                ring,
+                offset,
+            )?
+        };
+        Ok(Self { ptr, len })
+    }
+
+    /// The `T` at byte offset `off`.
+    fn at<T>(&self, off: u32) -> *mut T {
+        unsafe { self.ptr.byte_add(off as usize).cast() }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len).ok() };
+    }
+}
+
+/// A minimal io_uring: submit a batch, wait for all of it.
+struct Ring {
+    fd: OwnedFd,
+    params: io_uring_params,
+    sq: Map,
+    /// `None` when the kernel maps both rings at once
+    cq: Option<Map>,
+    sqes: Map,
+    /// numbers the batches, in the high half of `user_data`, so that a
+    /// completion left from an earlier one is told apart
+    generation: u32,
+}
+
+impl Ring {
+    fn new() -> Result<Self> {
+        let mut params = io_uring_params::default();
+        let fd = unsafe { io_uring_setup(ENTRIES, &mut params)? };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<io_uring_cqe>();
+        let single = params.features.contains(IoringFeatureFlags::SINGLE_MMAP);
+        let sq = Map::new(
+            &fd,
+            if single { sq_len.max(cq_len) } else { sq_len },
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq = if single {
+            None
+        } else {
+            Some(Map::new(&fd, cq_len, IORING_OFF_CQ_RING)?)
+        };
+        let sqes = Map::new(
+            &fd,
+            params.sq_entries as usize * size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        Ok(Self {
+            fd,
+            params,
+            sq,
+            cq,
+            sqes,
+            generation: 0,
+        })
+    }
+
+    fn cq(&self) -> &Map {
+        self.cq.as_ref().unwrap_or(&self.sq)
+    }
+
+    /// Submit `sqes` and return their results in the same order: what the
+    /// syscall would have returned, or the negated errno.  On an error the
+    /// ring may still hold some of them and must not be used again.
+    fn run(&mut self, sqes: &mut [io_uring_sqe]) -> Result<Vec<i32>> {
+        let mut ret = vec![0; sqes.len()];
+        self.generation = self.generation.wrapping_add(1);
+        for (i, sqe) in sqes.iter_mut().enumerate() {
+            sqe.user_data = io_uring_user_data::from_u64((self.generation as u64) << 32 | i as u64);
+        }
+        for chunk in sqes.chunks(self.params.sq_entries as usize) {
+            self.submit(chunk)?;
+            let mut pending = chunk.len() as u32;
+            while pending > 0 {
+                match unsafe { io_uring_enter(&self.fd, 0, pending, IoringEnterFlags::GETEVENTS) } {
+                    // EBUSY: the completion ring is full, reaping makes room
+                    Ok(_) | Err(Errno::INTR | Errno::AGAIN | Errno::BUSY) => (),
+                    Err(e) => return Err(e),
+                }
+                pending = pending.saturating_sub(self.reap(&mut ret));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn submit(&mut self, sqes: &[io_uring_sqe]) -> Result<()> {
+        let off = self.params.sq_off;
+        let tail = unsafe { &*self.sq.at::<AtomicU32>(off.tail) };
+        let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        let array = self.sq.at::<u32>(off.array);
+        let mut t = tail.load(Ordering::Relaxed);
+        for sqe in sqes {
+            let idx = t & mask;
+            unsafe {
+                write_volatile(self.sqes.at::<io_uring_sqe>(0).add(idx as usize), *sqe);
+                write_volatile(array.add(idx as usize), idx);
+            }
+            t = t.wrapping_add(1);
+        }
+        tail.store(t, Ordering::Release);
+        let mut left = sqes.len() as u32;
+        while left > 0 {
+            match unsafe { io_uring_enter(&self.fd, left, 0, IoringEnterFlags::empty()) } {
+                Ok(n) => left -= n,
+                Err(Errno::INTR) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect what completed of the current batch into `ret`, returning
+    /// how many did.
+    fn reap(&mut self, ret: &mut [i32]) -> u32 {
+        let off = self.params.cq_off;
+        let cq = self.cq();
+        let head = unsafe { &*cq.at::<AtomicU32>(off.head) };
+        let tail = unsafe { &*cq.at::<AtomicU32>(off.tail) };
+        let mask = unsafe { *cq.at::<u32>(off.ring_mask) };
+        let cqes = cq.at::<io_uring_cqe>(off.cqes);
+        let mut h = head.load(Ordering::Relaxed);
+        let t = tail.load(Ordering::Acquire);
+        let mut n = 0;
+        while h != t {
+            let cqe = unsafe { &*cqes.add((h & mask) as usize) };
+            let data = cqe.user_data.u64_();
+            h = h.wrapping_add(1);
+            if (data >> 32) as u32 != self.generation {
+                continue;
+            }
+            if let Some(r) = ret.get_mut(data as u32 as usize) {
+                *r = cqe.res;
+                n += 1;
+            }
+        }
+        head.store(h, Ordering::Release);
+        n
+    }
+}
+
+thread_local! {
+    /// `None` if the kernel has no io_uring or doesn't let us use it.
+    static RING: RefCell<Option<Ring>> = RefCell::new(Ring::new().ok());
+}
+
+/// Run `sqes` through this thread's ring, or return `None` if it has none.
+/// A ring that fails is dropped, for the thread to fall back to syscalls
+/// from then on; the kernel may still write to the buffers of that batch.
+fn run(sqes: &mut [io_uring_sqe]) -> Option<Result<Vec<i32>>> {
+    RING.with_borrow_mut(|ring| {
+        let ret = ring.as_mut()?.run(sqes);
+        if ret.is_err() {
+            *ring = None;
+        }
+        Some(ret)
+    })
+}
+
+fn statx_sqe(
+    dir: BorrowedFd,
+    path: &CStr,
+    buf: *mut Statx,
+    flags: AtFlags,
+    mask: StatxFlags,
+) -> io_uring_sqe {
+    io_uring_sqe {
+        opcode: IoringOp::Statx,
+        fd: dir.as_raw_fd(),
+        addr_or_splice_off_in: addr_or_splice_off_in_union {
+            addr: io_uring_ptr::new(path.as_ptr().cast_mut().cast()),
+        },
+        len: len_union { len: mask.bits() },
+        off_or_addr2: off_or_addr2_union {
+            addr2: io_uring_ptr::new(buf.cast()),
+        },
+        op_flags: op_flags_union { statx_flags: flags },
+        ..Default::default()
+    }
+}
+
+fn result(res: i32) -> Result<u32> {
+    if res < 0 {
+        Err(Errno::from_raw_os_error(-res))
+    } else {
+        Ok(res as u32)
+    }
+}
+
+/// statx(2) of all `paths` relative to `dir` in one go, or `None` without
+/// a working ring.
+pub fn statx_all(
+    dir: BorrowedFd,
+    paths: &[&CStr],
+    flags: AtFlags,
+    mask: StatxFlags,
+) -> Option<Vec<Result<Statx>>> {
+    let mut bufs: Vec<MaybeUninit<Statx>> = Vec::with_capacity(paths.len());
+    bufs.resize_with(paths.len(), MaybeUninit::uninit);
+    let mut sqes: Vec<_> = paths
+        .iter()
+        .zip(&mut bufs)
+        .map(|(p, buf)| statx_sqe(dir, p, buf.as_mut_ptr(), flags, mask))
+        .collect();
+    let Ok(res) = run(&mut sqes)? else {
+        // still the kernel's to write
+        std::mem::forget(bufs);
+        return None;
+    };
+    Some(
+        res.into_iter()
+            .zip(bufs)
+            .map(|(res, buf)| result(res).map(|_| unsafe { buf.assume_init() }))
+            .collect(),
+    )
+}
+
+/// Open `path` and statx(2) it in one submission, or `None` without a
+/// working ring.
+pub fn open_statx(
+    path: &CStr,
+    oflags: OFlags,
+    mode: Mode,
+    mask: StatxFlags,
+) -> Option<Result<(OwnedFd, Statx)>> {
+    let buf = Box::leak(Box::new(MaybeUninit::<Statx>::uninit()));
+    let open = io_uring_sqe {
+        opcode: IoringOp::Openat,
+        fd: CWD.as_raw_fd(),
+        addr_or_splice_off_in: addr_or_splice_off_in_union {
+            addr: io_uring_ptr::new(path.as_ptr().cast_mut().cast()),
+        },
+        len: len_union { len: mode.bits() },
+        op_flags: op_flags_union {
+            open_flags: oflags | OFlags::CLOEXEC,
+        },
+        // no statx if the open failed
+        flags: IoringSqeFlags::IO_LINK,
+        ..Default::default()
+    };
+    let flags = if oflags.contains(OFlags::NOFOLLOW) {
+        AtFlags::SYMLINK_NOFOLLOW
+    } else {
+        AtFlags::empty()
+    };
+    let stat = statx_sqe(CWD, path, buf.as_mut_ptr(), flags, mask);
+    let ret = run(&mut [open, stat]);
+    let Some(Ok(res)) = ret else {
+        // left to the kernel if the ring failed
+        if ret.is_none() {
+            drop(unsafe { Box::from_raw(buf) });
+        }
+        return None;
+    };
+    let buf = unsafe { Box::from_raw(buf) };
+    let fd = match result(res[0]) {
+        Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd as _) },
+        Err(e) => return Some(Err(e)),
+    };
+    Some(result(res[1]).map(|_| (fd, unsafe { *buf.assume_init() })))
+}
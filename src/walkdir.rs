@@ -1,28 +1,68 @@
 use std::{
     collections::{HashMap, VecDeque, hash_map::Entry},
+    ffi::{CStr, OsString},
     hint::cold_path,
-    io,
+    io::{self, BufRead},
     marker::Send,
-    os::fd::OwnedFd,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::ffi::OsStringExt,
+    },
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Instant,
 };
 
+use futures_lite::future::block_on;
 use kanal::{AsyncSender as Sender, bounded_async as bounded};
 use nohash::BuildNoHashHasher;
 use rustix::{
-    fs::{Dir, Mode, OFlags, open},
+    fs::{Dir, FileType, Mode, OFlags, open, openat, stat},
+    io::{Errno, dup},
     path::Arg,
 };
 
 use crate::{
     actor::{Actor, Runnable as _, Sink},
-    fs_util::{DevId, File_, get_dev},
-    global::{config, get_err},
+    error::Error,
+    filter,
+    fs_util::{DevId, File_, RootId, fd_dev, is_btrfs, is_special, statx_all, statx_dev},
+    global::{config, get_err, set_err},
+    profile::{self, Counter},
+    reporter::{reporter, skip, skip_not_btrfs},
     spawn,
 };
 
-const MAX_LOCAL_LEN: usize = 4096 / size_of::<Box<Path>>();
+/// Directory entries stat'ed at once, see [`statx_all`].
+const STAT_BATCH: usize = 256;
+
+const MAX_LOCAL_LEN: usize = 4096 / size_of::<DirJob>();
+
+/// A directory to walk.
+struct DirJob {
+    path: Box<Path>,
+    /// the command line argument it was found under
+    root: RootId,
+    /// how far below `root`
+    depth: u32,
+    /// the directory holding it, to open it by name instead of walking
+    /// `path` again; `None` for the command line arguments
+    parent: Option<Arc<OwnedFd>>,
+}
+
+impl DirJob {
+    fn open(&self) -> rustix::io::Result<OwnedFd> {
+        let flags = OFlags::DIRECTORY | OFlags::NOFOLLOW;
+        match (&self.parent, self.path.file_name()) {
+            (Some(parent), Some(name)) => openat(parent, name, flags, Mode::RUSR),
+            _ => open(self.path.as_ref(), flags, Mode::RUSR),
+        }
+    }
+}
 
 pub struct JobChunk {
     dev: DevId,
@@ -30,33 +70,57 @@ pub struct JobChunk {
 }
 
 impl JobChunk {
-    fn from_path(path: impl Into<Box<Path>>) -> Result<Self, io::Error> {
+    fn from_path(path: impl Into<Box<Path>>, root: RootId, depth: u32) -> Result<Self, io::Error> {
         let path: Box<Path> = path.into();
-        let dev = get_dev(&path);
         let fd = open(
             path.as_ref(),
             OFlags::DIRECTORY | OFlags::NOFOLLOW,
             Mode::RUSR,
         )?;
+        let dev = fd_dev(fd.as_fd())?;
+        let btrfs = check_btrfs(&fd, &path);
         Ok(Self {
             dev,
             wq: SubvolWQ {
                 fd: Arc::new(fd),
-                paths: vec![path],
+                paths: vec![DirJob {
+                    path,
+                    root,
+                    depth,
+                    parent: None,
+                }],
+                btrfs,
             },
         })
     }
 }
 
+/// Whether `fd`, opened at `path`, is on btrfs, with a note if not.  If
+/// statfs(2) fails, the tree search will tell.
+fn check_btrfs(fd: &OwnedFd, path: &Path) -> bool {
+    let btrfs = is_btrfs(fd.as_fd()).unwrap_or(true);
+    if !btrfs {
+        reporter().note(&format!("{}: not on btrfs, skipping", path.display()));
+    }
+    btrfs
+}
+
 struct SubvolWQ {
-    paths: Vec<Box<Path>>,
+    /// directories to walk
+    paths: Vec<DirJob>,
     fd: Arc<OwnedFd>,
+    /// `false` if the files found are only counted as skipped
+    btrfs: bool,
 }
 impl SubvolWQ {
     fn split(&mut self, n: usize) -> Self {
         let paths = self.paths.drain(0..n).collect();
         let fd = self.fd.clone();
-        Self { paths, fd }
+        Self {
+            paths,
+            fd,
+            btrfs: self.btrfs,
+        }
     }
 }
 struct JobMgr {
@@ -102,56 +166,260 @@ impl JobMgr {
     }
 }
 
+/// A directory a walk stopped by a [`Pause`] didn't get to.
+pub struct PendingDir {
+    pub path: Box<Path>,
+    pub root: RootId,
+    /// how far below the command line argument
+    pub depth: u32,
+}
+
+/// Stops a walk at `deadline`, for `--checkpoint`: each walker hands back
+/// the directories it holds after the one it is reading, and those not
+/// walked yet end up in [`Self::take_pending`] once the walk is over.
+pub struct Pause {
+    deadline: Instant,
+    /// a walker stopped at the deadline, so no more jobs are handed out
+    stopped: AtomicBool,
+    pending: Mutex<Vec<PendingDir>>,
+}
+
+impl Pause {
+    pub fn new(deadline: Instant) -> Self {
+        Self {
+            deadline,
+            stopped: AtomicBool::new(false),
+            pending: Default::default(),
+        }
+    }
+
+    #[inline]
+    fn is_due(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// The directories left, none if the walk was done before the deadline.
+    pub fn take_pending(&self) -> Vec<PendingDir> {
+        std::mem::take(&mut self.pending.lock().unwrap())
+    }
+}
+
+/// Paths that keep arriving while the walk runs, all under one [`RootId`].
+pub type PathSource = Box<dyn Iterator<Item = PathBuf> + Send>;
+
+/// The command line roots, opened before any walker is spawned.
+///
+/// Opening everything up front lets the caller give up privileges
+/// between [`Roots::open`] and [`WalkDir::spawn`].  Paths from a
+/// [`PathSource`] are the exception, they are opened as they arrive.
+pub struct Roots {
+    files: Vec<File_>,
+    joblist: JobMgr,
+    source: Option<(PathSource, RootId)>,
+    pause: Option<Arc<Pause>>,
+}
+
+enum Root {
+    Dir(JobChunk),
+    File(File_),
+}
+
+impl Root {
+    fn open(p: impl Into<PathBuf>, root: RootId) -> Option<Self> {
+        let p = p.into().into_boxed_path();
+        profile::add(Counter::Stats, 1);
+        let file_type = stat(&*p).map(|st| FileType::from_raw_mode(st.st_mode));
+        let ret = if file_type == Ok(FileType::Directory) {
+            JobChunk::from_path(p.clone(), root, 0)
+                .map(Self::Dir)
+                .map_err(|e| Errno::from_io_error(&e).unwrap_or(Errno::IO))
+        } else if let Ok(t) = file_type
+            && is_special(t)
+            && !config().include_special
+        {
+            reporter().special(&p, t);
+            return None;
+        } else if filter::wanted(&p) {
+            match File_::from_path(p.clone(), root, file_type.is_ok_and(is_special)) {
+                Ok(f) if !check_btrfs(f.fd(), &p) => {
+                    skip_not_btrfs(&p);
+                    return None;
+                }
+                r => r.map(Self::File),
+            }
+        } else {
+            return None;
+        };
+        ret.inspect_err(|&e| skip(&p, e)).ok()
+    }
+}
+
+/// For each of `paths`, the one it is inside of, if any.  Of the same path
+/// given twice, the first one is kept.
+pub(crate) fn nested_paths(paths: &[PathBuf]) -> Vec<Option<PathBuf>> {
+    let canonical: Vec<_> = paths
+        .iter()
+        .map(|p| {
+            (p.as_os_str() != "-")
+                .then(|| p.canonicalize().ok())
+                .flatten()
+        })
+        .collect();
+    canonical
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let p = p.as_ref()?;
+            let outer = canonical.iter().enumerate().position(|(j, q)| {
+                q.as_ref()
+                    .is_some_and(|q| p.starts_with(q) && (p != q || j < i))
+                    && j != i
+            })?;
+            Some(paths[outer].clone())
+        })
+        .collect()
+}
+
+impl Roots {
+    /// Open `paths`; files found under the `n`th one get [`RootId`] `n`.
+    /// A `-` stands for the paths read from stdin, see [`stdin_paths`].
+    /// Paths inside another one are left out with a note, so nothing is
+    /// counted twice.
+    pub fn open(paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let mut files = vec![];
+        let mut joblist = JobMgr::new();
+        let mut source = None;
+        let paths: Vec<PathBuf> = paths.into_iter().map(Into::into).collect();
+        let nested = nested_paths(&paths);
+        for (root, p) in paths.into_iter().enumerate() {
+            // no more than RootId has, see `scanner::collect_paths`
+            let root = root as RootId;
+            if p.as_os_str() == "-" {
+                source = Some((stdin_paths(config().null), root));
+                continue;
+            }
+            if let Some(outer) = &nested[root as usize] {
+                reporter().note(&format!(
+                    "{}: inside {}, counted once",
+                    p.display(),
+                    outer.display()
+                ));
+                continue;
+            }
+            match Root::open(p, root) {
+                Some(Root::Dir(chunk)) => joblist.push(chunk),
+                Some(Root::File(f)) => files.push(f),
+                None => (),
+            }
+        }
+        Self {
+            files,
+            joblist,
+            source,
+            pause: None,
+        }
+    }
+
+    /// Open the directories a paused walk left, to go on with it.
+    pub fn resume(dirs: Vec<PendingDir>) -> Self {
+        let mut joblist = JobMgr::new();
+        for dir in dirs {
+            match JobChunk::from_path(dir.path.clone(), dir.root, dir.depth) {
+                Ok(chunk) => joblist.push(chunk),
+                Err(e) => skip(&dir.path, Errno::from_io_error(&e).unwrap_or(Errno::IO)),
+            }
+        }
+        Self {
+            files: vec![],
+            joblist,
+            source: None,
+            pause: None,
+        }
+    }
+
+    /// Stop the walk as `pause` says.
+    pub fn with_pause(self, pause: Arc<Pause>) -> Self {
+        Self {
+            pause: Some(pause),
+            ..self
+        }
+    }
+
+    /// Walk the paths of `source` too, tagged with `root`.
+    pub fn with_source(self, source: PathSource, root: RootId) -> Self {
+        Self {
+            source: Some((source, root)),
+            ..self
+        }
+    }
+}
+
+/// Paths read from stdin, one per line or NUL terminated if `null`.
+/// Empty entries are skipped.  A read error ends the list, reported as a
+/// skipped `-`.
+pub fn stdin_paths(null: bool) -> PathSource {
+    let delim = if null { b'\0' } else { b'\n' };
+    Box::new(
+        io::BufReader::new(io::stdin())
+            .split(delim)
+            .map_while(|p| {
+                p.inspect_err(|e| {
+                    skip(Path::new("-"), Errno::from_io_error(e).unwrap_or(Errno::IO))
+                })
+                .ok()
+            })
+            .filter(|p| !p.is_empty())
+            .map(|p| OsString::from_vec(p).into()),
+    )
+}
+
 type WalkerId = u8;
 pub struct WalkDir {
     walkers: Box<[Sender<JobChunk>]>,
     pending_walkers: Vec<WalkerId>,
     global_joblist: JobMgr,
+    /// a [`PathSource`] may still push jobs
+    source_open: bool,
+    pause: Option<Arc<Pause>>,
 }
 
 impl WalkDir {
-    pub fn spawn<F, FC>(
-        mut file_consumer: F,
-        paths: impl IntoIterator<Item = impl Into<PathBuf>>,
-        nwalker: u8,
-    ) where
+    pub fn spawn<F, FC>(mut file_consumer: F, roots: Roots, nwalker: u8)
+    where
         F: FnMut() -> FC + Send + 'static,
         FC: Sink<Item = File_> + Send + 'static,
     {
         assert_ne!(nwalker, 0);
-        let mut files = vec![];
-        let chunks = paths
-            .into_iter()
-            .map(|p| p.into().into_boxed_path())
-            .filter_map(|p| {
-                if p.is_dir() {
-                    Some(p)
-                } else {
-                    files.push(p);
-                    None
-                }
-            })
-            .filter_map(|p| JobChunk::from_path(p).ok());
-        let mut global_joblist = JobMgr::new();
-        for chunk in chunks {
-            global_joblist.push(chunk);
-        }
+        let Roots {
+            files,
+            joblist: global_joblist,
+            source,
+            pause,
+        } = roots;
         let mut cb = file_consumer();
         spawn(async move {
-            for p in files {
-                let Ok(f) = File_::from_path(p) else {
-                    continue;
-                };
+            for f in files {
                 cb.consume(f).await;
             }
         });
-        if global_joblist.is_empty() {
+        if global_joblist.is_empty() && source.is_none() {
             return;
         }
         let (sender, rx) = bounded(64);
+        let source_open = source.is_some();
+        if let Some((source, root)) = source {
+            let master = sender.clone();
+            let cb = file_consumer();
+            // reading blocks, keep it off the executor
+            thread::spawn(move || {
+                if let Err(e) = block_on(read_source(source, root, master, cb)) {
+                    set_err(e);
+                }
+            });
+        }
         let walkers = (0..nwalker)
             .map(|i| {
-                let walker = Walker::new(i, sender.clone(), file_consumer());
+                let walker = Walker::new(i, sender.clone(), file_consumer(), pause.clone());
                 let (s, r) = bounded(0); // the walker must be waiting for jobs
                 spawn(walker.run(r));
                 s
@@ -162,6 +430,8 @@ impl WalkDir {
             pending_walkers: (0..nwalker).collect(),
             global_joblist,
             walkers,
+            source_open,
+            pause,
         };
         spawn(async {
             self_.job_balance().await;
@@ -171,18 +441,37 @@ impl WalkDir {
 
     #[inline(never)]
     fn cleanup(&mut self) {
+        profile::walk_done();
         self.global_joblist.clear();
         self.pending_walkers = Default::default();
         self.walkers = Default::default();
     }
 
     async fn job_balance(&mut self) {
+        if let Some(pause) = &self.pause
+            && pause.stopped.load(Ordering::Relaxed)
+        {
+            // keep what is left once every walker handed back its share
+            if self.pending_walkers.len() == self.walkers.len() {
+                let mut pending = pause.pending.lock().unwrap();
+                for wq in self.global_joblist.jobs.drain().map(|(_, wq)| wq) {
+                    pending.extend(wq.paths.into_iter().map(|job| PendingDir {
+                        path: job.path,
+                        root: job.root,
+                        depth: job.depth,
+                    }));
+                }
+                drop(pending);
+                self.cleanup();
+            }
+            return;
+        }
         // no global job pending, no need to balance
         if self.global_joblist.is_empty() {
             // no job pending and all walkers free
             // we have our jobs done
             // cleanup and exit
-            if self.pending_walkers.len() == self.walkers.len() {
+            if self.pending_walkers.len() == self.walkers.len() && !self.source_open {
                 self.cleanup();
             }
             return;
@@ -199,12 +488,41 @@ impl WalkDir {
 pub enum WalkDirMsg {
     PushJobs(JobChunk),
     RequireJobs(WalkerId),
+    /// the [`PathSource`] is exhausted
+    SourceDone,
+}
+
+/// Open every path of `source` as it arrives: files go straight to `cb`,
+/// directories to the walkers.
+async fn read_source<FC: Sink<Item = File_>>(
+    source: PathSource,
+    root: RootId,
+    master: Sender<WalkDirMsg>,
+    mut cb: FC,
+) -> Result<(), Error> {
+    for p in source {
+        get_err()?;
+        match Root::open(p, root) {
+            Some(Root::Dir(chunk)) => master
+                .send(WalkDirMsg::PushJobs(chunk))
+                .await
+                .map_err(|_| Error::Cancelled)?,
+            Some(Root::File(f)) => {
+                cb.consume(f).await;
+            }
+            None => (),
+        }
+    }
+    master
+        .send(WalkDirMsg::SourceDone)
+        .await
+        .map_err(|_| Error::Cancelled)
 }
 
 impl Actor for WalkDir {
     type Message = WalkDirMsg;
 
-    async fn handle(&mut self, msg: Self::Message) -> Result<(), ()> {
+    async fn handle(&mut self, msg: Self::Message) -> Result<(), Error> {
         if get_err().is_ok() {
             match msg {
                 WalkDirMsg::PushJobs(chunk) => {
@@ -213,6 +531,9 @@ impl Actor for WalkDir {
                 WalkDirMsg::RequireJobs(id) => {
                     self.pending_walkers.push(id);
                 }
+                WalkDirMsg::SourceDone => {
+                    self.source_open = false;
+                }
             }
             self.job_balance().await;
         } else {
@@ -227,13 +548,20 @@ struct Walker<F> {
     id: WalkerId,
     master: Sender<WalkDirMsg>,
     file_handler: F,
+    pause: Option<Arc<Pause>>,
 }
 impl<F> Walker<F> {
-    fn new(id: WalkerId, master: Sender<WalkDirMsg>, file_handler: F) -> Self {
+    fn new(
+        id: WalkerId,
+        master: Sender<WalkDirMsg>,
+        file_handler: F,
+        pause: Option<Arc<Pause>>,
+    ) -> Self {
         Self {
             id,
             master,
             file_handler,
+            pause,
         }
     }
 }
@@ -244,83 +572,164 @@ where
 {
     type Message = JobChunk;
 
-    async fn handle(&mut self, msg: Self::Message) -> Result<(), ()> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "walk",
+            level = "debug",
+            skip_all,
+            fields(dev = msg.dev.get(), dirs = msg.wq.paths.len())
+        )
+    )]
+    async fn handle(&mut self, msg: Self::Message) -> Result<(), Error> {
         let JobChunk {
             dev,
-            wq: SubvolWQ { paths, fd },
+            wq: SubvolWQ { paths, fd, btrfs },
         } = msg;
         let mut dirs = VecDeque::from(paths);
         let mut newfs_dirs = Vec::new();
-        while let Some(dir_path) = dirs.pop_back() {
+        let mut batch = Vec::new();
+        while let Some(job) = dirs.pop_back() {
             if get_err().is_err() {
                 cold_path();
                 break;
             }
-            let read_dir = match open(
-                dir_path.as_ref(),
-                OFlags::DIRECTORY | OFlags::NOFOLLOW,
-                Mode::RUSR,
-            )
-            .and_then(Dir::new)
-            {
-                Ok(rd) => rd,
+            // the walk reads one copy, subdirectories are opened in the other
+            profile::add(Counter::Opens, 1);
+            let opened = job
+                .open()
+                .and_then(|fd| Ok((Dir::new(dup(&fd)?)?, Arc::new(fd))));
+            let DirJob {
+                path: dir_path,
+                root,
+                depth,
+                ..
+            } = job;
+            let (mut read_dir, dir_fd) = match opened {
+                Ok(opened) => opened,
                 Err(e) => {
                     cold_path();
-                    eprintln!("{}: {}", dir_path.display(), e);
+                    skip(&dir_path, e);
                     continue;
                 }
             };
+            profile::add(Counter::Dirs, 1);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(path = %dir_path.display(), "read directory");
 
-            for entry in read_dir {
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(e) => {
-                        cold_path();
-                        eprintln!("{}: {}", dir_path.display(), e);
+            loop {
+                batch.clear();
+                for entry in read_dir.by_ref() {
+                    let entry = match entry {
+                        Ok(e) => e,
+                        Err(e) => {
+                            cold_path();
+                            skip(&dir_path, e);
+                            continue;
+                        }
+                    };
+                    if entry.file_name() == c"." || entry.file_name() == c".." {
                         continue;
                     }
-                };
-                if entry.file_name() == c"." || entry.file_name() == c".." {
-                    continue;
-                }
 
-                let file_type = entry.file_type();
-                let path = dir_path
-                    .join(entry.file_name().as_str().unwrap())
-                    .into_boxed_path();
+                    let file_type = entry.file_type();
+                    let path = dir_path
+                        .join(entry.file_name().as_str().unwrap())
+                        .into_boxed_path();
+                    if filter::excluded(&path, entry.file_name(), file_type.is_dir()) {
+                        continue;
+                    }
+                    let is_dir = file_type.is_dir();
+                    if is_dir && config().max_depth().is_some_and(|max| depth + 1 > max) {
+                        continue;
+                    }
+                    if is_special(file_type) && !config().include_special {
+                        reporter().special(&path, file_type);
+                        continue;
+                    }
+                    batch.push((path, entry.file_name().to_owned(), entry.ino(), file_type));
+                    if batch.len() == STAT_BATCH {
+                        break;
+                    }
+                }
+                if batch.is_empty() {
+                    break;
+                }
 
-                if file_type.is_dir() {
-                    let dir_dev = get_dev(&path);
+                // directories for their device, files for the filters
+                let stat_files = filter::needs_stat();
+                let names: Vec<&CStr> = batch
+                    .iter()
+                    .filter(|(_, _, _, t)| t.is_dir() || stat_files)
+                    .map(|(_, name, _, _)| name.as_c_str())
+                    .collect();
+                profile::add(Counter::Stats, names.len() as u64);
+                let mut stats = statx_all(dir_fd.as_fd(), &names, filter::STAT_MASK).into_iter();
+                for (path, name, ino, file_type) in batch.drain(..) {
+                    let is_dir = file_type.is_dir();
+                    let st = (is_dir || stat_files).then(|| stats.next().unwrap());
+                    if !is_dir {
+                        if !st.is_none_or(|st| st.as_ref().ok().is_none_or(filter::wanted_stat)) {
+                            continue;
+                        }
+                        if btrfs {
+                            self.file_handler
+                                .consume(
+                                    File_::new(fd.clone(), path, ino, root)
+                                        .with_special(is_special(file_type))
+                                        .with_size(
+                                            st.and_then(Result::ok).map_or(0, |st| st.stx_size),
+                                        ),
+                                )
+                                .await;
+                        } else {
+                            skip_not_btrfs(&path);
+                        }
+                        continue;
+                    }
+                    let dir_dev = match st.unwrap() {
+                        Ok(st) => statx_dev(&st),
+                        Err(e) => {
+                            cold_path();
+                            skip(&path, e);
+                            continue;
+                        }
+                    };
+                    let job = DirJob {
+                        path,
+                        root,
+                        depth: depth + 1,
+                        parent: Some(dir_fd.clone()),
+                    };
                     if dir_dev == dev {
-                        dirs.push_back(path);
+                        dirs.push_back(job);
                     } else if !config().one_fs {
-                        let Ok(fd) = open(
-                            path.as_ref(),
+                        profile::add(Counter::Opens, 1);
+                        let Ok(fd) = openat(
+                            &dir_fd,
+                            name.as_c_str(),
                             OFlags::DIRECTORY | OFlags::NOFOLLOW,
                             Mode::RUSR,
                         ) else {
                             continue;
                         };
+                        let btrfs = check_btrfs(&fd, &job.path);
                         newfs_dirs.push(JobChunk {
                             dev: dir_dev,
                             wq: SubvolWQ {
                                 fd: Arc::new(fd),
-                                paths: vec![path],
+                                paths: vec![job],
+                                btrfs,
                             },
                         });
                     }
-                } else if file_type.is_file() || file_type.is_symlink() {
-                    // Symlink targets are stored as inline EXTENT_DATA in btrfs.
-                    self.file_handler
-                        .consume(File_::new(fd.clone(), path, entry.ino()))
-                        .await;
                 }
             }
             for chunk in newfs_dirs.drain(..) {
                 self.master
                     .send(WalkDirMsg::PushJobs(chunk))
                     .await
-                    .map_err(|_| ())?;
+                    .map_err(|_| Error::Cancelled)?;
             }
             if dirs.len() > MAX_LOCAL_LEN {
                 let r = dirs.len() - MAX_LOCAL_LEN / 2;
@@ -331,16 +740,41 @@ where
                         wq: SubvolWQ {
                             fd: fd.clone(),
                             paths: v,
+                            btrfs,
                         },
                     }))
                     .await
-                    .map_err(|_| ())?;
+                    .map_err(|_| Error::Cancelled)?;
+            }
+            // after a directory at least, so every walk goes forward
+            if let Some(pause) = &self.pause
+                && pause.is_due()
+            {
+                pause.stopped.store(true, Ordering::Relaxed);
+                if !dirs.is_empty() {
+                    // opened again by path on resume, their parents can close
+                    dirs.iter_mut().for_each(|job| job.parent = None);
+                    self.master
+                        .send(WalkDirMsg::PushJobs(JobChunk {
+                            dev,
+                            wq: SubvolWQ {
+                                fd: fd.clone(),
+                                paths: dirs.drain(..).collect(),
+                                btrfs,
+                            },
+                        }))
+                        .await
+                        .map_err(|_| Error::Cancelled)?;
+                }
+                break;
             }
         }
+        // the workers shouldn't wait on what this walker holds while idle
+        self.file_handler.flush().await;
         self.master
             .send(WalkDirMsg::RequireJobs(self.id))
             .await
-            .map_err(|_| ())?;
+            .map_err(|_| Error::Cancelled)?;
         Ok(())
     }
 }
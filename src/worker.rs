@@ -1,77 +1,426 @@
+use std::{
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    sync::{Arc, Once},
+};
+
+use futures_lite::future::zip;
+use kanal::Sender;
+use nohash::IntSet;
+use rustix::{
+    fs::{Mode, OFlags, fgetxattr, open},
+    io::{Errno, read},
+};
+
 use crate::{
     actor::{Actor, Sink},
     btrfs::{
-        ExtentInfo, Sv2ItemIter, Sv2Wrapper,
+        ExtentInfo, HoleFinder, Sv2ItemIter, Sv2Wrapper, inode_changed_since,
         ioctl::{IoctlSearchKey, Sv2Args},
-        tree,
+        subvol_id,
+        tree::{self, Compression, ExtentData},
     },
-    fs_util::File_,
-    global::{get_err, set_err},
+    cache::{Subvol, cache},
+    error::Error,
+    estimate::{self, Codec, Sample},
+    executor::spawn_blocking,
+    fiemap,
+    fs_util::{File_, FsId, SubvolId, fs_id, register_subvol},
+    global::{Format, config, get_err},
+    magic::{SNIFF_LEN, sniff},
+    profile::{self, Counter},
+    report::{FileCounter, FileCounts, FileReport},
+    reporter::{reporter, skip, skip_not_btrfs, skip_parse_error},
 };
 
-pub struct Worker<S> {
+pub struct Worker<S, R> {
+    /// dropped first: file counts must be published before the sinks
+    /// close and the collector finishes
+    counter: FileCounter,
     sink: S,
+    /// receives a [`FileReport`] per file, if per-file output is wanted
+    report: Option<R>,
+    /// extents already seen in the current file
+    seen: IntSet<u64>,
+    /// lent to the blocking pool while it reads a batch of files
+    reader: Option<Reader>,
+    /// the last file descriptor seen, its filesystem and subvolume
+    fs: Option<(Arc<OwnedFd>, FsId, SubvolId)>,
+}
+
+/// Files read ahead of the one being passed on.
+const READ_AHEAD: usize = 16;
+
+/// What reading the extents of files takes, in ioctls that block, hence
+/// run through [`spawn_blocking`].
+struct Reader {
+    holes: HoleFinder,
     sv2: Sv2Wrapper,
+    /// the extents of the current file
+    extents: Vec<ExtentInfo>,
+    /// the last file descriptor seen and its subvolume, for `--cache`
+    subvol: Option<(Arc<OwnedFd>, Option<Subvol>)>,
+    /// tree search is not permitted, extents come from FIEMAP instead
+    fiemap: bool,
+    /// per-file output is wanted, see [`Self::inspect`]
+    reports: bool,
+    /// the first bytes of the current file, for `--by-type`
+    head: Vec<u8>,
+    /// `--estimate`, parsed once
+    codecs: Option<Vec<Codec>>,
 }
 
-impl<S: Sink<Item = ExtentInfo>> Worker<S> {
-    pub fn new(sink: S) -> Self {
+static FIEMAP_NOTE: Once = Once::new();
+
+/// What the blocking pool read of a file.
+struct Read {
+    extents: Vec<ExtentInfo>,
+    /// `--by-type`
+    content: Option<&'static str>,
+    /// `--audit-compression`
+    property: Option<Compression>,
+    /// `--estimate`
+    samples: Option<Vec<Sample>>,
+}
+
+/// What was read of a file, `None` if it had to be skipped.
+type ReadResult = Result<Option<Read>, Error>;
+
+impl Reader {
+    fn new(reports: bool) -> Self {
         Self {
-            sink,
-            sv2: Sv2Wrapper::new(Box::new(Sv2Args::from_sk(IoctlSearchKey::new(
+            holes: Default::default(),
+            sv2: Sv2Wrapper::new(Sv2Args::from_sk(IoctlSearchKey::new(
                 0,
                 0,
                 0,
                 0,
                 u64::MAX,
-                0,
+                // skips whole leaves not written since
+                config().min_generation(),
                 u64::MAX,
                 tree::r#type::EXTENT_DATA,
                 tree::r#type::EXTENT_DATA,
-            )))),
+            ))),
+            extents: vec![],
+            subvol: None,
+            fiemap: false,
+            reports,
+            head: vec![],
+            codecs: config().estimate_codecs(),
+        }
+    }
+
+    /// Send the extents of each of `files` to `tx`, as soon as they are
+    /// read, until the scan stops or `tx` is closed.
+    fn read_all(&mut self, files: Box<[File_]>, tx: &Sender<(File_, ReadResult)>) {
+        for f in files {
+            if get_err().is_err() {
+                break;
+            }
+            let ret = self
+                .read_extents(&f)
+                .map(|read| read.then(|| self.inspect(&f)));
+            if tx.send((f, ret)).is_err() {
+                break;
+            }
         }
     }
 
-    pub(crate) async fn handle_file(&mut self, f: File_) -> Result<(), ()> {
-        let iter = Sv2ItemIter::new(&mut self.sv2, f.borrow_fd(), f.ino());
-        for extent in iter {
-            let extent = match extent {
-                Ok(extent) => extent,
-                Err(e) => {
-                    set_err()?;
-                    if e.raw_os_error() == 25 {
-                        eprintln!(
-                            "{}: Not btrfs (or SEARCH_V2 unsupported)",
-                            f.path().display()
-                        );
-                    } else {
-                        eprintln!("{}: SEARCH_V2: {}", f.path().display(), e);
+    /// The extents just read of `f`, and what per-file output wants to
+    /// know of it, read here too as it blocks.
+    fn inspect(&mut self, f: &File_) -> Read {
+        let mut read = Read {
+            extents: vec![],
+            content: None,
+            property: None,
+            samples: None,
+        };
+        let by_type = self.reports && config().by_type;
+        if f.is_special() {
+            read.content = by_type.then_some("special");
+        } else if self.reports {
+            // only data of its own can be compressed other than asked
+            let audit = config().audit_compression && self.extents.iter().any(|e| !e.is_hole());
+            let ranges = self
+                .codecs
+                .as_ref()
+                .map(|_| estimate::uncompressed_ranges(&self.extents))
+                .filter(|ranges| !ranges.is_empty());
+            if by_type || audit || ranges.is_some() {
+                // one open for all of them
+                profile::add(Counter::Opens, 1);
+                match open(f.path(), OFlags::RDONLY | OFlags::NOFOLLOW, Mode::empty()) {
+                    Ok(fd) => {
+                        if by_type {
+                            read.content = Some(self.content_type(fd.as_fd()));
+                        }
+                        if audit {
+                            read.property = compression_property(fd.as_fd());
+                        }
+                        if let (Some(codecs), Some(ranges)) = (&self.codecs, ranges) {
+                            let nsamples = config().estimate_samples.unwrap_or(estimate::SAMPLES);
+                            read.samples =
+                                estimate::sample(fd.as_fd(), &ranges, codecs, nsamples).ok();
+                        }
                     }
-                    break;
+                    Err(Errno::LOOP) => read.content = by_type.then_some("symlink"),
+                    Err(_) => read.content = by_type.then_some("unreadable"),
                 }
-            };
-            match extent.parse() {
-                Ok(Some(extent)) => {
-                    self.sink.consume(extent).await;
+            }
+        }
+        read.extents = std::mem::take(&mut self.extents);
+        read
+    }
+
+    /// Sniff the content type from the first bytes of the file `fd`.
+    fn content_type(&mut self, fd: BorrowedFd<'_>) -> &'static str {
+        self.head.resize(SNIFF_LEN, 0);
+        let mut len = 0;
+        while len < self.head.len() {
+            match read(fd, &mut self.head[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(Errno::INTR) => continue,
+                Err(_) => return "unreadable",
+            }
+        }
+        sniff(&self.head[..len])
+    }
+
+    /// The subvolume of `f`, if its extents may be cached.
+    fn subvol(&mut self, f: &File_) -> Option<Subvol> {
+        match &self.subvol {
+            Some((fd, subvol)) if Arc::ptr_eq(fd, f.fd()) => *subvol,
+            _ => {
+                let subvol = Subvol::of(f.borrow_fd()).ok().flatten();
+                self.subvol = Some((f.fd().clone(), subvol));
+                subvol
+            }
+        }
+    }
+
+    /// Fill `self.extents` with those of `f`.  `false` if `f` had to be
+    /// skipped.
+    fn read_extents(&mut self, f: &File_) -> Result<bool, Error> {
+        self.extents.clear();
+        self.holes.reset();
+        if f.is_special() {
+            return Ok(true);
+        }
+        if !self.fiemap {
+            match self.search_extents(f) {
+                Err(Error::Io {
+                    err: Errno::PERM, ..
+                }) => {
+                    self.fiemap = true;
+                    FIEMAP_NOTE.call_once(|| {
+                        reporter().note(
+                            "not permitted to search btrfs trees, falling back to FIEMAP: \
+                             compressed sizes are unknown",
+                        )
+                    });
+                    self.extents.clear();
+                    self.holes.reset();
                 }
-                Err(e) => {
-                    set_err()?;
-                    eprintln!("{}", e);
-                    break;
+                Err(Error::Parse { path, msg }) => {
+                    skip_parse_error(&path, &msg);
+                    return Ok(false);
                 }
-                _ => (),
+                r => return r.map(|()| true),
+            }
+        }
+        self.fiemap_extents(f)
+    }
+
+    /// Best effort without CAP_SYS_ADMIN, see [`fiemap`].
+    fn fiemap_extents(&mut self, f: &File_) -> Result<bool, Error> {
+        profile::add(Counter::Opens, 1);
+        let fd = match open(f.path(), OFlags::RDONLY | OFlags::NOFOLLOW, Mode::empty()) {
+            Ok(fd) => fd,
+            // symlinks have no extents
+            Err(Errno::LOOP) => return Ok(true),
+            Err(e) => {
+                skip(f.path(), e);
+                return Ok(false);
+            }
+        };
+        let mut extents = std::mem::take(&mut self.extents);
+        let ret = fiemap::extents(fd.as_fd(), f.ino(), &mut extents);
+        for extent in extents {
+            if let Some(hole) = self.holes.before(&extent) {
+                self.extents.push(hole);
+            }
+            self.extents.push(extent);
+        }
+        match ret {
+            Ok(()) => Ok(true),
+            Err(e) => Err(Error::Io {
+                path: f.path().into(),
+                err: e,
+            }),
+        }
+    }
+
+    /// Fill `self.extents` from a tree search, or from the cache if `f` is
+    /// unchanged since.
+    fn search_extents(&mut self, f: &File_) -> Result<(), Error> {
+        let min_gen = config().min_generation();
+        // the cache holds every extent
+        let cached = cache().filter(|_| min_gen == 0).zip(self.subvol(f));
+        if let Some((cache, subvol)) = cached
+            && let Some(entry) = cache.get(&subvol.key(f.ino()))
+            && !inode_changed_since(f.borrow_fd(), f.ino(), entry.generation)
+                .map_err(|e| Error::search(f.path(), e))?
+        {
+            self.extents.extend_from_slice(&entry.extents);
+            return Ok(());
+        }
+        let iter = Sv2ItemIter::<ExtentData>::new(&mut self.sv2, f.borrow_fd(), f.ino());
+        for item in iter {
+            let extent = match item.map_err(|e| Error::search(f.path(), e))? {
+                Ok(item) if item.item.generation < min_gen => continue,
+                Ok(item) => item.parse(),
+                Err(msg) => Err(msg),
+            };
+            let extent = extent.map_err(|msg| Error::Parse {
+                path: f.path().into(),
+                msg,
+            })?;
+            if min_gen == 0
+                && let Some(hole) = self.holes.before(&extent)
+            {
+                self.extents.push(hole);
+            }
+            self.extents.push(extent);
+        }
+        if let Some((cache, subvol)) = cached {
+            cache.insert(subvol.key(f.ino()), subvol.generation, &self.extents);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Sink<Item = ExtentInfo>, R: Sink<Item = FileReport>> Worker<S, R> {
+    pub fn new(sink: S, report: Option<R>, counts: Arc<FileCounts>) -> Self {
+        let reports = report.is_some();
+        Self {
+            counter: FileCounter::new(counts),
+            sink,
+            report,
+            seen: Default::default(),
+            reader: Some(Reader::new(reports)),
+            fs: None,
+        }
+    }
+
+    /// The filesystem and subvolume of `f`, see [`fs_id`] and
+    /// [`register_subvol`].
+    fn fs(&mut self, f: &File_) -> (FsId, SubvolId) {
+        match &self.fs {
+            Some((fd, fs, subvol)) if Arc::ptr_eq(fd, f.fd()) => (*fs, *subvol),
+            _ => {
+                let fs = fs_id(f.borrow_fd(), f.path());
+                let subvol = register_subvol(fs, subvol_id(f.borrow_fd()).unwrap_or(0));
+                self.fs = Some((f.fd().clone(), fs, subvol));
+                (fs, subvol)
             }
         }
+    }
+
+    /// Pass on the extents `read` of `f`.
+    async fn handle_file(&mut self, f: File_, read: ReadResult) -> Result<(), Error> {
+        let mut file_report = self
+            .report
+            .is_some()
+            .then(|| FileReport::new(f.path().into(), f.ino(), f.root()));
+        self.seen.clear();
+        let Read {
+            extents,
+            content,
+            property,
+            samples,
+        } = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(()),
+            Err(Error::NotBtrfs { path }) => {
+                skip_not_btrfs(&path);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let (fs, subvol) = self.fs(&f);
+        for extent in extents {
+            self.counter.add(&extent);
+            if let Some(r) = &mut file_report {
+                let bytenr = extent.disk_bytenr();
+                r.add(&extent, bytenr == 0 || self.seen.insert(bytenr));
+            }
+            self.sink
+                .consume(extent.with_root(f.root()).with_fs(fs).with_subvol(subvol))
+                .await;
+        }
+        self.counter.end_file(f.root(), f.size());
+        if let (Some(mut r), Some(report)) = (file_report, &mut self.report) {
+            r.content = content;
+            r.property = property;
+            r.samples = samples;
+            report.consume(r).await;
+            // each line is printed as soon as its file is done
+            if config().format == Format::Ndjson {
+                report.flush().await;
+            }
+        }
+        reporter().file_done(f.path());
         Ok(())
     }
 }
 
-impl<S: Sink<Item = ExtentInfo>> Actor for Worker<S> {
+/// The algorithm the `btrfs.compression` property of the file `fd` asks
+/// for, if any.
+fn compression_property(fd: BorrowedFd<'_>) -> Option<Compression> {
+    let mut buf = [0; 16];
+    let len = fgetxattr(fd, "btrfs.compression", &mut buf).ok()?;
+    // a level, as in `zstd:3`, is only taken by the mount option
+    let name = buf[..len].split(|&b| b == b':').next()?;
+    [Compression::Zlib, Compression::Lzo, Compression::Zstd]
+        .into_iter()
+        .find(|c| c.name().as_bytes() == name)
+}
+
+impl<S: Sink<Item = ExtentInfo>, R: Sink<Item = FileReport>> Actor for Worker<S, R> {
     type Message = Box<[File_]>;
-    async fn handle(&mut self, files: Self::Message) -> Result<(), ()> {
-        for f in files {
-            get_err()?;
-            self.handle_file(f).await?;
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "files", level = "debug", skip_all, fields(n = files.len()))
+    )]
+    async fn handle(&mut self, files: Self::Message) -> Result<(), Error> {
+        let mut reader = self.reader.take().unwrap();
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        // each file is passed on while the next ones are read
+        let (tx, rx) = kanal::bounded(READ_AHEAD);
+        let read = spawn_blocking(move || {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(parent: &span, "read_extents").entered();
+            reader.read_all(files, &tx);
+            reader
+        });
+        let handled = async {
+            // dropped on error, which stops the reading
+            let rx = rx.to_async();
+            while let Ok((f, read)) = rx.recv().await {
+                get_err()?;
+                self.handle_file(f, read).await?;
+            }
+            Ok::<_, Error>(())
+        };
+        let (reader, ret) = zip(read, handled).await;
+        self.reader = Some(reader);
+        ret?;
+        // the collector shouldn't wait on what this batch left behind
+        self.sink.flush().await;
+        if let Some(report) = &mut self.report {
+            report.flush().await;
         }
         Ok(())
     }